@@ -1,3 +1,129 @@
-fn main() {
+use std::{
+    env, fs,
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Directory packed into the initramfs.
+const INITRAMFS_DIR: &str = "initramfs";
+/// Source of `/init`, which is added to the initramfs.
+const INIT_SOURCE: &str = "user/init.S";
+/// Address `/init` is linked to, the start of the user addresses (`process::memory`).
+const INIT_TEXT_ADDR: &str = "0x40000000";
+
+/// Append a newc cpio header for `name`, the name and `data` to `out`.
+fn write_cpio_entry(out: &mut Vec<u8>, ino: u32, mode: u32, name: &str, data: &[u8]) {
+    let fields = [
+        ino,
+        mode,
+        0, // uid
+        0, // gid
+        1, // nlink
+        0, // mtime, left out so the archive only changes with its contents
+        data.len() as u32,
+        0, // devmajor
+        0, // devminor
+        0, // rdevmajor
+        0, // rdevminor
+        name.len() as u32 + 1,
+        0, // check
+    ];
+    out.extend_from_slice(b"070701");
+    for field in fields {
+        out.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.resize((out.len() + 3) & !3, 0);
+    out.extend_from_slice(data);
+    out.resize((out.len() + 3) & !3, 0);
+}
+
+/// Add the contents of `dir`, with paths relative to `root`, sorted so parents come first.
+fn pack_dir(out: &mut Vec<u8>, root: &Path, dir: &Path, ino: &mut u32) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    entries.sort();
+    for path in entries {
+        let metadata = fs::symlink_metadata(&path)?;
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_str()
+            .expect("non UTF-8 path");
+        let mode = metadata.permissions().mode() & 0o7777;
+        *ino += 1;
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            let target = target.to_str().expect("non UTF-8 symlink target");
+            write_cpio_entry(out, *ino, 0o120000 | 0o777, name, target.as_bytes());
+        } else if metadata.is_dir() {
+            write_cpio_entry(out, *ino, 0o040000 | mode, name, &[]);
+            pack_dir(out, root, &path, ino)?;
+        } else {
+            let data = fs::read(&path)?;
+            write_cpio_entry(out, *ino, 0o100000 | mode, name, &data);
+        }
+    }
+    Ok(())
+}
+
+/// Assemble `INIT_SOURCE` into a static executable, with the compiler building the kernel and
+/// its linker, so that no other toolchain is needed.
+fn build_init(out_dir: &Path) -> io::Result<Vec<u8>> {
+    let source = fs::canonicalize(INIT_SOURCE)?;
+    let shim = out_dir.join("init.rs");
+    fs::write(
+        &shim,
+        format!(
+            "#![no_std]\n#![no_main]\n\
+             core::arch::global_asm!(include_str!({:?}));\n\
+             #[panic_handler]\n\
+             fn panic(_: &core::panic::PanicInfo) -> ! {{\n    loop {{}}\n}}\n",
+            source
+        ),
+    )?;
+
+    let init = out_dir.join("init");
+    let status = Command::new(env::var_os("RUSTC").unwrap())
+        .args(["--target", &env::var("TARGET").unwrap()])
+        .args(["--crate-type", "bin", "--edition", "2021"])
+        .args(["-C", "panic=abort", "-C", "relocation-model=static"])
+        .args(["-C", "link-arg=--entry=_start"])
+        .arg(format!("-Clink-arg=-Ttext={}", INIT_TEXT_ADDR))
+        // No page alignment, the segments are copied rather than mapped.
+        .args(["-C", "link-arg=-n", "-C", "link-arg=--build-id=none"])
+        .args(["-C", "link-arg=--strip-all"])
+        .arg("-o")
+        .arg(&init)
+        .arg(&shim)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "failed to build /init",
+        ));
+    }
+    fs::read(init)
+}
+
+fn main() -> io::Result<()> {
     println!("cargo:rerun-if-changed=link.ld");
+    println!("cargo:rerun-if-changed={}", INITRAMFS_DIR);
+    println!("cargo:rerun-if-changed={}", INIT_SOURCE);
+
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let root = Path::new(INITRAMFS_DIR);
+    let mut archive = Vec::new();
+    let mut ino = 1;
+    write_cpio_entry(&mut archive, ino, 0o100755, "init", &build_init(&out_dir)?);
+    if root.is_dir() {
+        pack_dir(&mut archive, root, root, &mut ino)?;
+    }
+    write_cpio_entry(&mut archive, 0, 0, "TRAILER!!!", &[]);
+
+    fs::File::create(out_dir.join("initramfs.cpio"))?.write_all(&archive)
 }
@@ -0,0 +1,77 @@
+use crate::error::OsError;
+
+pub mod cache;
+pub mod partition;
+pub mod queue;
+pub mod ram_disk;
+
+/// A device storing data in fixed size blocks, like an SD card.
+///
+/// Transfers are made of whole blocks, the buffers must be a multiple of `block_size` long.
+pub trait BlockDevice {
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Read the blocks starting at `start` into `buf`.
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), OsError>;
+
+    /// Write `buf` to the blocks starting at `start`.
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), OsError>;
+
+    /// Make sure everything written so far is stored on the device.
+    fn flush(&self) -> Result<(), OsError> {
+        Ok(())
+    }
+
+    /// Size of the device in bytes.
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+impl<T: BlockDevice + ?Sized> BlockDevice for &T {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        (**self).block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        (**self).read_blocks(start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), OsError> {
+        (**self).write_blocks(start, buf)
+    }
+
+    fn flush(&self) -> Result<(), OsError> {
+        (**self).flush()
+    }
+
+    fn size(&self) -> u64 {
+        (**self).size()
+    }
+}
+
+/// Check that a transfer of `len` bytes starting at block `start` fits on `device`, and return
+/// the number of blocks it covers.
+pub fn check_transfer(
+    device: &(impl BlockDevice + ?Sized),
+    start: u64,
+    len: usize,
+) -> Result<u64, OsError> {
+    let block_size = device.block_size();
+    if len % block_size != 0 {
+        return Err(OsError::InvalidBufferSize(len));
+    }
+    let count = (len / block_size) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(OsError::BlockOutOfRange(start)),
+    }
+}
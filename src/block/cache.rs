@@ -0,0 +1,258 @@
+use core::alloc::{AllocError, Allocator};
+
+use std_alloc::vec::Vec;
+
+use crate::{error::OsError, sync::NullLock};
+
+use super::{queue::RequestQueue, BlockDevice};
+
+/// Blocks read after a missed one, if they are not cached yet. Filesystems mostly read
+/// sequentially, and one larger transfer is much cheaper than several small ones.
+const READ_AHEAD_BLOCKS: u64 = 4;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct CacheEntry {
+    /// The cached block, `None` for a free entry.
+    block: Option<u64>,
+    /// Modified since read from the device.
+    dirty: bool,
+    /// Value of `CacheInner::clock` when the entry was last used.
+    last_used: u64,
+}
+
+/// How well the cache works.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks written back to the device.
+    pub write_backs: u64,
+    /// Requests merged by the request queue.
+    pub merged: usize,
+}
+
+struct CacheInner<A: Allocator + Clone> {
+    block_size: usize,
+    entries: Vec<CacheEntry, A>,
+    /// The data of entry `n` is at `n * block_size`.
+    data: Vec<u8, A>,
+    clock: u64,
+    queue: RequestQueue<A>,
+    stats: CacheStats,
+}
+
+impl<A: Allocator + Clone> CacheInner<A> {
+    fn find(&self, block: u64) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.block == Some(block))
+    }
+
+    fn touch(&mut self, idx: usize) {
+        self.clock += 1;
+        self.entries[idx].last_used = self.clock;
+    }
+
+    fn data(&self, idx: usize) -> &[u8] {
+        &self.data[idx * self.block_size..(idx + 1) * self.block_size]
+    }
+
+    fn data_mut(&mut self, idx: usize) -> &mut [u8] {
+        &mut self.data[idx * self.block_size..(idx + 1) * self.block_size]
+    }
+
+    /// Free the least recently used entry, writing it back if it is dirty.
+    fn evict(&mut self, device: &(impl BlockDevice + ?Sized)) -> Result<usize, OsError> {
+        let idx = match self.entries.iter().position(|entry| entry.block.is_none()) {
+            Some(idx) => idx,
+            None => {
+                let (idx, _) = self
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .unwrap();
+                idx
+            }
+        };
+        let entry = self.entries[idx];
+        if let (Some(block), true) = (entry.block, entry.dirty) {
+            device.write_blocks(block, self.data(idx))?;
+            self.stats.write_backs += 1;
+        }
+        self.entries[idx] = CacheEntry::default();
+        Ok(idx)
+    }
+
+    /// Put `data` into a free entry for `block`, which must not be cached yet.
+    fn insert(
+        &mut self,
+        device: &(impl BlockDevice + ?Sized),
+        block: u64,
+        data: &[u8],
+    ) -> Result<usize, OsError> {
+        let idx = self.evict(device)?;
+        self.data_mut(idx).copy_from_slice(data);
+        self.entries[idx].block = Some(block);
+        self.touch(idx);
+        Ok(idx)
+    }
+
+    /// Returns the entry of `block`, reading it from the device if needed. With `load` false
+    /// the block is about to be overwritten completely, and a new entry is not read.
+    fn lookup(
+        &mut self,
+        device: &(impl BlockDevice + ?Sized),
+        block: u64,
+        load: bool,
+    ) -> Result<usize, OsError> {
+        if let Some(idx) = self.find(block) {
+            self.stats.hits += 1;
+            self.touch(idx);
+            return Ok(idx);
+        }
+        self.stats.misses += 1;
+
+        if !load {
+            let idx = self.evict(device)?;
+            self.entries[idx].block = Some(block);
+            self.touch(idx);
+            return Ok(idx);
+        }
+
+        // Don't read ahead more than fits, or the wanted block would be evicted again.
+        let max_count = READ_AHEAD_BLOCKS.min(self.entries.len() as u64);
+        let mut count = 1;
+        while count < max_count
+            && block + count < device.block_count()
+            && self.find(block + count).is_none()
+        {
+            count += 1;
+        }
+        self.queue.read(block, count)?;
+
+        let mut queue = core::mem::replace(
+            &mut self.queue,
+            RequestQueue::new(self.block_size, self.entries.allocator().clone()),
+        );
+        let mut result = Ok(());
+        let dispatched = queue.dispatch(device, |read_block, data| {
+            if result.is_ok() {
+                result = self.insert(device, read_block, data).map(|_| ());
+            }
+        });
+        self.queue = queue;
+        dispatched?;
+        result?;
+
+        // The blocks read ahead were inserted last, make the wanted one the most recent.
+        let idx = self.find(block).ok_or(OsError::BlockOutOfRange(block))?;
+        self.touch(idx);
+        Ok(idx)
+    }
+
+    /// Write all dirty blocks back, adjacent ones in one transfer.
+    fn flush(&mut self, device: &(impl BlockDevice + ?Sized)) -> Result<(), OsError> {
+        let mut count = 0;
+        for (idx, entry) in self.entries.iter().enumerate() {
+            if let (Some(block), true) = (entry.block, entry.dirty) {
+                let data = &self.data[idx * self.block_size..(idx + 1) * self.block_size];
+                self.queue.write(block, data)?;
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        // Should a write fail, all blocks stay dirty and are written again next time.
+        self.queue.dispatch(device, |_, _| {})?;
+        for entry in self.entries.iter_mut() {
+            entry.dirty = false;
+        }
+        self.stats.write_backs += count;
+        Ok(())
+    }
+}
+
+/// A write-back cache of the most recently used blocks of a device.
+///
+/// Writes only go to the device when a dirty block is evicted, or on `flush`. The cache is
+/// itself a `BlockDevice`, so that filesystems can sit on top of it.
+pub struct BlockCache<D: BlockDevice, A: Allocator + Clone> {
+    device: D,
+    inner: NullLock<CacheInner<A>>,
+}
+
+impl<D: BlockDevice, A: Allocator + Clone> BlockCache<D, A> {
+    /// Create a cache of `capacity` blocks, at least one, in front of `device`.
+    pub fn new(device: D, capacity: usize, alloc: A) -> Result<Self, OsError> {
+        let capacity = capacity.max(1);
+        let block_size = device.block_size();
+        let mut entries = Vec::new_in(alloc.clone());
+        entries
+            .try_reserve_exact(capacity)
+            .map_err(|_| AllocError)?;
+        entries.resize(capacity, CacheEntry::default());
+        let mut data = Vec::new_in(alloc.clone());
+        data.try_reserve_exact(capacity * block_size)
+            .map_err(|_| AllocError)?;
+        data.resize(capacity * block_size, 0);
+
+        Ok(Self {
+            device,
+            inner: NullLock::new(CacheInner {
+                block_size,
+                entries,
+                data,
+                clock: 0,
+                queue: RequestQueue::new(block_size, alloc),
+                stats: CacheStats::default(),
+            }),
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock(|inner| CacheStats {
+            merged: inner.queue.merged(),
+            ..inner.stats
+        })
+    }
+}
+
+impl<D: BlockDevice, A: Allocator + Clone> BlockDevice for BlockCache<D, A> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        super::check_transfer(&self.device, start, buf.len())?;
+        self.inner.lock(|inner| {
+            for (block, chunk) in (start..).zip(buf.chunks_exact_mut(inner.block_size)) {
+                let idx = inner.lookup(&self.device, block, true)?;
+                chunk.copy_from_slice(inner.data(idx));
+            }
+            Ok(())
+        })
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), OsError> {
+        super::check_transfer(&self.device, start, buf.len())?;
+        self.inner.lock(|inner| {
+            for (block, chunk) in (start..).zip(buf.chunks_exact(inner.block_size)) {
+                let idx = inner.lookup(&self.device, block, false)?;
+                inner.data_mut(idx).copy_from_slice(chunk);
+                inner.entries[idx].dirty = true;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> Result<(), OsError> {
+        self.inner.lock(|inner| inner.flush(&self.device))?;
+        self.device.flush()
+    }
+}
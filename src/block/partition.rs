@@ -0,0 +1,472 @@
+use core::{
+    alloc::{AllocError, Allocator},
+    fmt,
+};
+
+use std_alloc::vec::Vec;
+
+use crate::error::OsError;
+
+use super::BlockDevice;
+
+/// Size of the MBR and of the blocks its addresses count.
+const SECTOR_SIZE: usize = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_BOOTABLE: u8 = 0x80;
+/// Partition type of the protective MBR in front of a GPT.
+const MBR_TYPE_GPT: u8 = 0xEE;
+/// Logical partitions are numbered from 5, after the primary ones.
+const FIRST_LOGICAL_NUMBER: u32 = 5;
+/// Protection against loops in the chain of extended boot records.
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// Limit on the entry array, the specification reserves 16 KiB for it.
+const GPT_MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+/// Length of a partition name in UTF-16 code units.
+pub const GPT_NAME_LEN: usize = 36;
+
+/// A GUID, as stored in a GPT: the first three fields are little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Parse a GUID from its usual textual form, at compile time.
+    pub const fn parse(s: &str) -> Self {
+        const fn hex(c: u8) -> u8 {
+            match c {
+                b'0'..=b'9' => c - b'0',
+                b'a'..=b'f' => c - b'a' + 10,
+                b'A'..=b'F' => c - b'A' + 10,
+                _ => panic!("invalid GUID"),
+            }
+        }
+        // Position of each byte of the GUID in the text, in storage order.
+        const POSITIONS: [usize; 16] = [6, 4, 2, 0, 11, 9, 16, 14, 19, 21, 24, 26, 28, 30, 32, 34];
+        let s = s.as_bytes();
+        let mut bytes = [0; 16];
+        let mut i = 0;
+        while i < 16 {
+            let pos = POSITIONS[i];
+            bytes[i] = (hex(s[pos]) << 4) | hex(s[pos + 1]);
+            i += 1;
+        }
+        Guid(bytes)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&b| b == 0)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+const GPT_TYPES: [(Guid, &str); 5] = [
+    (
+        Guid::parse("C12A7328-F81F-11D2-BA4B-00A0C93EC93B"),
+        "EFI system",
+    ),
+    (
+        Guid::parse("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"),
+        "Basic data",
+    ),
+    (
+        Guid::parse("0FC63DAF-8483-4772-8E79-3D69D8477DE4"),
+        "Linux filesystem",
+    ),
+    (
+        Guid::parse("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F"),
+        "Linux swap",
+    ),
+    (
+        Guid::parse("E6D6D379-F507-44C2-A23C-238F2A3DF928"),
+        "Linux LVM",
+    ),
+];
+
+/// The type of a partition, as given by the partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The system ID of an MBR partition.
+    Mbr(u8),
+    /// The partition type GUID of a GPT partition.
+    Gpt(Guid),
+}
+
+impl PartitionKind {
+    fn is_extended(&self) -> bool {
+        matches!(self, PartitionKind::Mbr(0x05 | 0x0F | 0x85))
+    }
+
+    /// Whether the partition is meant to hold a FAT filesystem.
+    pub fn is_fat(&self) -> bool {
+        match self {
+            PartitionKind::Mbr(id) => matches!(id, 0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E),
+            PartitionKind::Gpt(guid) => *guid == GPT_TYPES[0].0 || *guid == GPT_TYPES[1].0,
+        }
+    }
+
+    /// A name for the type, if it is a well known one.
+    pub fn description(&self) -> Option<&'static str> {
+        match self {
+            PartitionKind::Mbr(id) => Some(match id {
+                0x01 => "FAT12",
+                0x04 | 0x06 => "FAT16",
+                0x0E => "FAT16 (LBA)",
+                0x0B => "FAT32",
+                0x0C => "FAT32 (LBA)",
+                0x05 | 0x0F | 0x85 => "Extended",
+                0x07 => "NTFS/exFAT",
+                0x82 => "Linux swap",
+                0x83 => "Linux",
+                0x8E => "Linux LVM",
+                0xEF => "EFI system",
+                _ => return None,
+            }),
+            PartitionKind::Gpt(guid) => GPT_TYPES
+                .iter()
+                .find(|(ty, _)| ty == guid)
+                .map(|(_, name)| *name),
+        }
+    }
+}
+
+impl fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.description(), self) {
+            (Some(description), _) => write!(f, "{}", description),
+            (None, PartitionKind::Mbr(id)) => write!(f, "type {:#04x}", id),
+            (None, PartitionKind::Gpt(guid)) => write!(f, "type {}", guid),
+        }
+    }
+}
+
+/// A partition found in a partition table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Number of the partition, starting at 1, as in "mmcblk0p1".
+    pub number: u32,
+    /// First block of the partition.
+    pub start: u64,
+    pub block_count: u64,
+    pub kind: PartitionKind,
+    pub bootable: bool,
+    /// The name of a GPT partition, in UTF-16 and padded with zeros.
+    pub name: [u16; GPT_NAME_LEN],
+}
+
+impl PartitionInfo {
+    /// The name of a GPT partition, empty for MBR partitions.
+    pub fn name(&self) -> impl Iterator<Item = char> + '_ {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(GPT_NAME_LEN);
+        char::decode_utf16(self.name[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+}
+
+/// The kind of partition table found on a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Mbr,
+    Gpt { disk_guid: Guid },
+}
+
+/// A partition of a device, itself a block device.
+pub struct Partition<D: BlockDevice> {
+    device: D,
+    info: PartitionInfo,
+}
+
+impl<D: BlockDevice> Partition<D> {
+    /// Fails if the partition does not fit on `device`.
+    pub fn new(device: D, info: PartitionInfo) -> Result<Self, OsError> {
+        match info.start.checked_add(info.block_count) {
+            Some(end) if end <= device.block_count() => Ok(Self { device, info }),
+            _ => Err(OsError::BlockOutOfRange(info.start)),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Partition<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.block_count
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        super::check_transfer(self, start, buf.len())?;
+        self.device.read_blocks(self.info.start + start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), OsError> {
+        super::check_transfer(self, start, buf.len())?;
+        self.device.write_blocks(self.info.start + start, buf)
+    }
+
+    fn flush(&self) -> Result<(), OsError> {
+        self.device.flush()
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// CRC-32 (IEEE 802.3), as used by GPT.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Read `block` of `device` into `buf`, which is resized to the block size.
+fn read_block<A: Allocator>(
+    device: &(impl BlockDevice + ?Sized),
+    block: u64,
+    buf: &mut Vec<u8, A>,
+) -> Result<(), OsError> {
+    let block_size = device.block_size();
+    buf.clear();
+    buf.try_reserve_exact(block_size).map_err(|_| AllocError)?;
+    buf.resize(block_size, 0);
+    device.read_blocks(block, buf)
+}
+
+/// One of the four entries of an MBR or EBR, if it is used.
+fn mbr_entry(sector: &[u8], idx: usize) -> Option<(PartitionKind, bool, u64, u64)> {
+    let entry = &sector[MBR_ENTRIES_OFFSET + idx * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+    let kind = entry[4];
+    let start = read_u32(entry, 8) as u64;
+    let count = read_u32(entry, 12) as u64;
+    if kind == 0 || count == 0 {
+        return None;
+    }
+    Some((
+        PartitionKind::Mbr(kind),
+        entry[0] & MBR_BOOTABLE != 0,
+        start,
+        count,
+    ))
+}
+
+fn mbr_partition(
+    number: u32,
+    kind: PartitionKind,
+    bootable: bool,
+    start: u64,
+    count: u64,
+) -> PartitionInfo {
+    PartitionInfo {
+        number,
+        start,
+        block_count: count,
+        kind,
+        bootable,
+        name: [0; GPT_NAME_LEN],
+    }
+}
+
+/// Follow the chain of extended boot records of the extended partition at `ext_start`.
+fn read_logical_partitions<A: Allocator + Clone>(
+    device: &(impl BlockDevice + ?Sized),
+    ext_start: u64,
+    partitions: &mut Vec<PartitionInfo, A>,
+    buf: &mut Vec<u8, A>,
+) -> Result<(), OsError> {
+    let mut ebr = ext_start;
+    for number in FIRST_LOGICAL_NUMBER..FIRST_LOGICAL_NUMBER + MAX_LOGICAL_PARTITIONS {
+        read_block(device, ebr, buf)?;
+        if buf[510..512] != MBR_SIGNATURE {
+            return Err(OsError::InvalidPartitionTable("missing EBR signature"));
+        }
+        // The partition is relative to its EBR, the next EBR to the extended partition.
+        if let Some((kind, bootable, start, count)) = mbr_entry(buf, 0) {
+            partitions.try_reserve(1).map_err(|_| AllocError)?;
+            partitions.push(mbr_partition(number, kind, bootable, ebr + start, count));
+        }
+        match mbr_entry(buf, 1) {
+            Some((_, _, next, _)) => ebr = ext_start + next,
+            None => return Ok(()),
+        }
+    }
+    Err(OsError::InvalidPartitionTable(
+        "too many logical partitions",
+    ))
+}
+
+fn read_gpt<A: Allocator + Clone>(
+    device: &(impl BlockDevice + ?Sized),
+    partitions: &mut Vec<PartitionInfo, A>,
+    buf: &mut Vec<u8, A>,
+) -> Result<Guid, OsError> {
+    let block_size = device.block_size();
+    read_block(device, 1, buf)?;
+    if &buf[0..8] != GPT_SIGNATURE {
+        return Err(OsError::InvalidPartitionTable("missing GPT signature"));
+    }
+    let header_size = read_u32(buf, 12) as usize;
+    if !(GPT_HEADER_MIN_SIZE..=block_size).contains(&header_size) {
+        return Err(OsError::InvalidPartitionTable("bad GPT header size"));
+    }
+    let header_crc = read_u32(buf, 16);
+    buf[16..20].fill(0);
+    if crc32(&buf[..header_size]) != header_crc {
+        return Err(OsError::InvalidPartitionTable("bad GPT header checksum"));
+    }
+
+    let mut disk_guid = [0; 16];
+    disk_guid.copy_from_slice(&buf[56..72]);
+    let entries_lba = read_u64(buf, 72);
+    let entry_count = read_u32(buf, 80) as usize;
+    let entry_size = read_u32(buf, 84) as usize;
+    let entries_crc = read_u32(buf, 88);
+    let entries_len = entry_count.saturating_mul(entry_size);
+    if entry_size < GPT_ENTRY_MIN_SIZE || entries_len > GPT_MAX_ENTRIES_SIZE {
+        return Err(OsError::InvalidPartitionTable("bad GPT entry array"));
+    }
+
+    let blocks = (entries_len + block_size - 1) / block_size;
+    buf.clear();
+    buf.try_reserve_exact(blocks * block_size)
+        .map_err(|_| AllocError)?;
+    buf.resize(blocks * block_size, 0);
+    device.read_blocks(entries_lba, buf)?;
+    if crc32(&buf[..entries_len]) != entries_crc {
+        return Err(OsError::InvalidPartitionTable(
+            "bad GPT entry array checksum",
+        ));
+    }
+
+    for (idx, entry) in buf[..entries_len].chunks_exact(entry_size).enumerate() {
+        let mut type_guid = [0; 16];
+        type_guid.copy_from_slice(&entry[0..16]);
+        let type_guid = Guid(type_guid);
+        if type_guid.is_zero() {
+            continue;
+        }
+        let first = read_u64(entry, 32);
+        let last = read_u64(entry, 40);
+        if last < first {
+            return Err(OsError::InvalidPartitionTable(
+                "GPT partition ends before its start",
+            ));
+        }
+        let attributes = read_u64(entry, 48);
+        let mut name = [0; GPT_NAME_LEN];
+        for (i, c) in name.iter_mut().enumerate() {
+            *c = read_u16(entry, 56 + 2 * i);
+        }
+        partitions.try_reserve(1).map_err(|_| AllocError)?;
+        partitions.push(PartitionInfo {
+            number: idx as u32 + 1,
+            start: first,
+            block_count: last - first + 1,
+            kind: PartitionKind::Gpt(type_guid),
+            // "Legacy BIOS bootable" attribute.
+            bootable: attributes & (1 << 2) != 0,
+            name,
+        });
+    }
+    Ok(Guid(disk_guid))
+}
+
+/// Read the partition table of `device`, a GPT or an MBR with logical partitions.
+///
+/// The backup GPT at the end of the device is not looked at. Addresses in an MBR are taken to
+/// be in blocks of the device, which is only right for devices with 512 byte blocks.
+pub fn read_partition_table<A: Allocator + Clone>(
+    device: &(impl BlockDevice + ?Sized),
+    alloc: A,
+) -> Result<(TableKind, Vec<PartitionInfo, A>), OsError> {
+    if device.block_size() < SECTOR_SIZE {
+        return Err(OsError::InvalidPartitionTable(
+            "blocks smaller than a sector",
+        ));
+    }
+    let mut partitions = Vec::new_in(alloc.clone());
+    let mut buf = Vec::new_in(alloc);
+
+    read_block(device, 0, &mut buf)?;
+    if buf[510..512] != MBR_SIGNATURE {
+        return Err(OsError::InvalidPartitionTable("missing MBR signature"));
+    }
+    let mut primaries = [None; 4];
+    for (idx, primary) in primaries.iter_mut().enumerate() {
+        *primary = mbr_entry(&buf, idx);
+    }
+
+    if primaries
+        .iter()
+        .flatten()
+        .any(|(kind, ..)| *kind == PartitionKind::Mbr(MBR_TYPE_GPT))
+    {
+        let disk_guid = read_gpt(device, &mut partitions, &mut buf)?;
+        return Ok((TableKind::Gpt { disk_guid }, partitions));
+    }
+
+    for (idx, primary) in primaries.iter().enumerate() {
+        if let Some((kind, bootable, start, count)) = *primary {
+            partitions.try_reserve(1).map_err(|_| AllocError)?;
+            partitions.push(mbr_partition(idx as u32 + 1, kind, bootable, start, count));
+        }
+    }
+    let extended = partitions
+        .iter()
+        .find(|partition| partition.kind.is_extended())
+        .map(|partition| partition.start);
+    if let Some(ext_start) = extended {
+        read_logical_partitions(device, ext_start, &mut partitions, &mut buf)?;
+    }
+    Ok((TableKind::Mbr, partitions))
+}
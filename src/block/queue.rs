@@ -0,0 +1,190 @@
+use core::alloc::{AllocError, Allocator};
+
+use std_alloc::vec::Vec;
+
+use crate::error::OsError;
+
+use super::BlockDevice;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+struct Request<A: Allocator> {
+    op: Operation,
+    start: u64,
+    count: u64,
+    /// The data to write, or the data read once dispatched.
+    data: Vec<u8, A>,
+}
+
+impl<A: Allocator> Request<A> {
+    fn end(&self) -> u64 {
+        self.start + self.count
+    }
+}
+
+/// Transfers waiting to be sent to a device.
+///
+/// Requests are kept sorted by block, and a request for the blocks right before or after a
+/// queued request of the same kind is merged into it. Dispatching then sweeps over the device
+/// once, with as few and as large transfers as possible.
+///
+/// Requests for the same block should not be queued twice, their order is unspecified.
+pub struct RequestQueue<A: Allocator + Clone> {
+    block_size: usize,
+    requests: Vec<Request<A>, A>,
+    alloc: A,
+    merged: usize,
+}
+
+impl<A: Allocator + Clone> RequestQueue<A> {
+    pub fn new(block_size: usize, alloc: A) -> Self {
+        Self {
+            block_size,
+            requests: Vec::new_in(alloc.clone()),
+            alloc,
+            merged: 0,
+        }
+    }
+
+    /// Queue a read of `count` blocks from `start`. The data is handed out by `dispatch`.
+    pub fn read(&mut self, start: u64, count: u64) -> Result<(), OsError> {
+        self.insert(Operation::Read, start, count, &[])
+    }
+
+    /// Queue a write of `data`, a whole number of blocks, to the blocks from `start`.
+    pub fn write(&mut self, start: u64, data: &[u8]) -> Result<(), OsError> {
+        if data.len() % self.block_size != 0 {
+            return Err(OsError::InvalidBufferSize(data.len()));
+        }
+        let count = (data.len() / self.block_size) as u64;
+        self.insert(Operation::Write, start, count, data)
+    }
+
+    fn insert(
+        &mut self,
+        op: Operation,
+        start: u64,
+        count: u64,
+        data: &[u8],
+    ) -> Result<(), OsError> {
+        if count == 0 {
+            return Ok(());
+        }
+        let idx = self.requests.partition_point(|req| req.start <= start);
+
+        // Append to the request ending right before.
+        if idx > 0 {
+            let prev = &mut self.requests[idx - 1];
+            if prev.op == op && prev.end() == start {
+                prev.data.try_reserve(data.len()).map_err(|_| AllocError)?;
+                prev.data.extend_from_slice(data);
+                prev.count += count;
+                self.merged += 1;
+                // The gap to the next request may be closed now. Should there be no memory to
+                // join them, they are just sent separately.
+                if idx < self.requests.len() {
+                    let (prev, next) = self.requests.split_at_mut(idx);
+                    let (prev, next) = (&mut prev[idx - 1], &next[0]);
+                    if next.op == op
+                        && prev.end() == next.start
+                        && prev.data.try_reserve(next.data.len()).is_ok()
+                    {
+                        prev.data.extend_from_slice(&next.data);
+                        prev.count += next.count;
+                        self.requests.remove(idx);
+                    }
+                }
+                return Ok(());
+            }
+        }
+
+        // Prepend to the request starting right after.
+        if idx < self.requests.len() {
+            let next = &mut self.requests[idx];
+            if next.op == op && start + count == next.start {
+                next.data.try_reserve(data.len()).map_err(|_| AllocError)?;
+                next.data.splice(0..0, data.iter().copied());
+                next.start = start;
+                next.count += count;
+                self.merged += 1;
+                return Ok(());
+            }
+        }
+
+        let mut buf = Vec::new_in(self.alloc.clone());
+        buf.try_reserve_exact(data.len()).map_err(|_| AllocError)?;
+        buf.extend_from_slice(data);
+        self.requests.try_reserve(1).map_err(|_| AllocError)?;
+        self.requests.insert(
+            idx,
+            Request {
+                op,
+                start,
+                count,
+                data: buf,
+            },
+        );
+        Ok(())
+    }
+
+    /// Number of transfers queued.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Number of requests merged into another one so far.
+    pub fn merged(&self) -> usize {
+        self.merged
+    }
+
+    /// Send the queued requests to `device` in block order. `on_read` is called with every
+    /// block read, and its number.
+    ///
+    /// The queue is empty afterwards, even if a transfer failed and the ones after it were not
+    /// sent.
+    pub fn dispatch(
+        &mut self,
+        device: &(impl BlockDevice + ?Sized),
+        mut on_read: impl FnMut(u64, &[u8]),
+    ) -> Result<(), OsError> {
+        for idx in 0..self.requests.len() {
+            let req = &mut self.requests[idx];
+            let result = match req.op {
+                Operation::Read => Self::read_request(req, self.block_size, device, &mut on_read),
+                Operation::Write => device.write_blocks(req.start, &req.data),
+            };
+            if let Err(err) = result {
+                self.requests.clear();
+                return Err(err);
+            }
+        }
+        self.requests.clear();
+        Ok(())
+    }
+
+    fn read_request(
+        req: &mut Request<A>,
+        block_size: usize,
+        device: &(impl BlockDevice + ?Sized),
+        on_read: &mut impl FnMut(u64, &[u8]),
+    ) -> Result<(), OsError> {
+        let len = req.count as usize * block_size;
+        req.data.try_reserve_exact(len).map_err(|_| AllocError)?;
+        req.data.resize(len, 0);
+        device.read_blocks(req.start, &mut req.data)?;
+        for (block, data) in (req.start..).zip(req.data.chunks_exact(block_size)) {
+            on_read(block, data);
+        }
+        Ok(())
+    }
+}
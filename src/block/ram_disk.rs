@@ -0,0 +1,65 @@
+use core::alloc::{AllocError, Allocator};
+
+use std_alloc::vec::Vec;
+
+use crate::{error::OsError, sync::NullLock};
+
+use super::BlockDevice;
+
+/// A block device in memory, e.g. to try out filesystems without an SD card.
+pub struct RamDisk<A: Allocator> {
+    block_size: usize,
+    block_count: u64,
+    data: NullLock<Vec<u8, A>>,
+}
+
+impl<A: Allocator> RamDisk<A> {
+    /// Create a zeroed disk of `block_count` blocks of `block_size` bytes.
+    pub fn new(block_size: usize, block_count: u64, alloc: A) -> Result<Self, OsError> {
+        let len = block_size * block_count as usize;
+        let mut data = Vec::new_in(alloc);
+        data.try_reserve_exact(len).map_err(|_| AllocError)?;
+        data.resize(len, 0);
+        Ok(Self {
+            block_size,
+            block_count,
+            data: NullLock::new(data),
+        })
+    }
+
+    /// Create a disk holding a copy of `image`, padded with zeros to a whole number of blocks.
+    #[allow(dead_code)]
+    pub fn from_image(block_size: usize, image: &[u8], alloc: A) -> Result<Self, OsError> {
+        let block_count = ((image.len() + block_size - 1) / block_size) as u64;
+        let disk = Self::new(block_size, block_count, alloc)?;
+        disk.data
+            .lock(|data| data[..image.len()].copy_from_slice(image));
+        Ok(disk)
+    }
+}
+
+impl<A: Allocator> BlockDevice for RamDisk<A> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        super::check_transfer(self, start, buf.len())?;
+        let offset = start as usize * self.block_size;
+        self.data
+            .lock(|data| buf.copy_from_slice(&data[offset..offset + buf.len()]));
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), OsError> {
+        super::check_transfer(self, start, buf.len())?;
+        let offset = start as usize * self.block_size;
+        self.data
+            .lock(|data| data[offset..offset + buf.len()].copy_from_slice(buf));
+        Ok(())
+    }
+}
@@ -0,0 +1 @@
+pub mod ring_buffer;
@@ -0,0 +1,57 @@
+/// A fixed-capacity FIFO queue which does not need an allocator.
+///
+/// Pushing into a full buffer fails instead of overwriting the oldest element, so that a
+/// producer (typically an interrupt handler) can account for the dropped data.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    buf: [T; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /// Create an empty buffer. `fill` is only used to initialise the backing storage.
+    pub const fn new(fill: T) -> Self {
+        Self {
+            buf: [fill; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        let tail = (self.head + self.len) % N;
+        self.buf[tail] = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let value = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+}
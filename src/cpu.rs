@@ -1,6 +1,13 @@
-use cortex_a::asm;
+use core::time::Duration;
+
+use cortex_a::{
+    asm,
+    registers::{CNTFRQ_EL0, CNTPCT_EL0},
+};
 use tock_registers::interfaces::Readable;
 
+pub mod info;
+
 #[inline(always)]
 pub fn wait_forever() -> ! {
     loop {
@@ -15,6 +22,24 @@ pub fn spin_for_cycles(n: usize) {
     }
 }
 
+/// Time since the system counter started counting, which is at reset.
+pub fn uptime() -> Duration {
+    let ticks = CNTPCT_EL0.get();
+    // The firmware sets the frequency up, but don't divide by zero should it not have.
+    let freq = CNTFRQ_EL0.get().max(1);
+    let secs = ticks / freq;
+    let nanos = (ticks % freq) * 1_000_000_000 / freq;
+    Duration::new(secs, nanos as u32)
+}
+
+/// Busy wait for at least `duration`.
+pub fn spin_for(duration: Duration) {
+    let deadline = uptime() + duration;
+    while uptime() < deadline {
+        asm::nop();
+    }
+}
+
 pub fn current_el() -> Option<u8> {
     use cortex_a::registers;
 
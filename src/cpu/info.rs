@@ -0,0 +1,202 @@
+//! What the CPU tells about itself through its ID registers.
+
+use core::{arch::asm, fmt};
+
+/// Read a system register by name with `mrs`.
+macro_rules! read_sysreg {
+    ($name:literal) => {{
+        let value: u64;
+        unsafe {
+            asm!(
+                concat!("mrs {}, ", $name),
+                out(reg) value,
+                options(nomem, nostack, preserves_flags)
+            )
+        };
+        value
+    }};
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IdRegister {
+    Pfr0,
+    Isar0,
+    Isar1,
+}
+
+/// A feature, with the 4 bit field of an ID register telling about it, and the lowest value of
+/// the field meaning it is there.
+struct Feature {
+    /// The name Linux gives it in `/proc/cpuinfo`.
+    name: &'static str,
+    register: IdRegister,
+    shift: u32,
+    min: i8,
+    /// Signed fields use 0xF for "not implemented".
+    signed: bool,
+}
+
+const fn feature(name: &'static str, register: IdRegister, shift: u32, min: i8) -> Feature {
+    Feature {
+        name,
+        register,
+        shift,
+        min,
+        signed: false,
+    }
+}
+
+const fn signed_feature(name: &'static str, register: IdRegister, shift: u32, min: i8) -> Feature {
+    Feature {
+        signed: true,
+        ..feature(name, register, shift, min)
+    }
+}
+
+/// In the order Linux lists them.
+const FEATURES: [Feature; 23] = [
+    signed_feature("fp", IdRegister::Pfr0, 16, 0),
+    signed_feature("asimd", IdRegister::Pfr0, 20, 0),
+    feature("aes", IdRegister::Isar0, 4, 1),
+    feature("pmull", IdRegister::Isar0, 4, 2),
+    feature("sha1", IdRegister::Isar0, 8, 1),
+    feature("sha2", IdRegister::Isar0, 12, 1),
+    feature("crc32", IdRegister::Isar0, 16, 1),
+    feature("atomics", IdRegister::Isar0, 20, 2),
+    signed_feature("fphp", IdRegister::Pfr0, 16, 1),
+    signed_feature("asimdhp", IdRegister::Pfr0, 20, 1),
+    feature("asimdrdm", IdRegister::Isar0, 28, 1),
+    feature("jscvt", IdRegister::Isar1, 12, 1),
+    feature("fcma", IdRegister::Isar1, 16, 1),
+    feature("lrcpc", IdRegister::Isar1, 20, 1),
+    feature("dcpop", IdRegister::Isar1, 0, 1),
+    feature("sha3", IdRegister::Isar0, 32, 1),
+    feature("sm3", IdRegister::Isar0, 36, 1),
+    feature("sm4", IdRegister::Isar0, 40, 1),
+    feature("asimddp", IdRegister::Isar0, 44, 1),
+    feature("sha512", IdRegister::Isar0, 12, 2),
+    feature("asimdfhm", IdRegister::Isar0, 48, 1),
+    feature("flagm", IdRegister::Isar0, 52, 1),
+    feature("rng", IdRegister::Isar0, 60, 1),
+];
+
+/// The ID registers of the executing core. It displays like Linux's `/proc/cpuinfo`, with the
+/// exception level and physical address size added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuInfo {
+    midr: u64,
+    pfr0: u64,
+    isar0: u64,
+    isar1: u64,
+    mmfr0: u64,
+    el: Option<u8>,
+}
+
+impl CpuInfo {
+    pub fn read() -> Self {
+        Self {
+            midr: read_sysreg!("MIDR_EL1"),
+            pfr0: read_sysreg!("ID_AA64PFR0_EL1"),
+            isar0: read_sysreg!("ID_AA64ISAR0_EL1"),
+            isar1: read_sysreg!("ID_AA64ISAR1_EL1"),
+            mmfr0: read_sysreg!("ID_AA64MMFR0_EL1"),
+            el: super::current_el(),
+        }
+    }
+
+    pub fn implementer(&self) -> u8 {
+        (self.midr >> 24) as u8
+    }
+
+    pub fn variant(&self) -> u8 {
+        (self.midr >> 20) as u8 & 0xF
+    }
+
+    pub fn architecture(&self) -> u8 {
+        (self.midr >> 16) as u8 & 0xF
+    }
+
+    pub fn part(&self) -> u16 {
+        (self.midr >> 4) as u16 & 0xFFF
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.midr as u8 & 0xF
+    }
+
+    /// The name of the cores found on Raspberry Pis, and in QEMU.
+    pub fn model(&self) -> Option<&'static str> {
+        match (self.implementer(), self.part()) {
+            (0x41, 0xD03) => Some("Cortex-A53"),
+            (0x41, 0xD07) => Some("Cortex-A57"),
+            (0x41, 0xD08) => Some("Cortex-A72"),
+            (0x41, 0xD0B) => Some("Cortex-A76"),
+            _ => None,
+        }
+    }
+
+    /// Size of physical addresses, in bits.
+    pub fn physical_address_bits(&self) -> Option<u8> {
+        match self.mmfr0 & 0xF {
+            0 => Some(32),
+            1 => Some(36),
+            2 => Some(40),
+            3 => Some(42),
+            4 => Some(44),
+            5 => Some(48),
+            6 => Some(52),
+            _ => None,
+        }
+    }
+
+    fn has(&self, feature: &Feature) -> bool {
+        let register = match feature.register {
+            IdRegister::Pfr0 => self.pfr0,
+            IdRegister::Isar0 => self.isar0,
+            IdRegister::Isar1 => self.isar1,
+        };
+        let field = ((register >> feature.shift) & 0xF) as i8;
+        let field = if feature.signed && field == 0xF {
+            -1
+        } else {
+            field
+        };
+        field >= feature.min
+    }
+
+    /// The names of the features implemented.
+    pub fn features(&self) -> impl Iterator<Item = &'static str> + '_ {
+        FEATURES
+            .iter()
+            .filter(|feature| self.has(feature))
+            .map(|feature| feature.name)
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only the boot core runs.
+        writeln!(f, "processor\t: 0")?;
+        if let Some(model) = self.model() {
+            writeln!(f, "model name\t: {}", model)?;
+        }
+        write!(f, "Features\t:")?;
+        for feature in self.features() {
+            write!(f, " {}", feature)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "CPU implementer\t: {:#04x}", self.implementer())?;
+        writeln!(f, "CPU architecture: {}", self.architecture())?;
+        writeln!(f, "CPU variant\t: {:#x}", self.variant())?;
+        writeln!(f, "CPU part\t: {:#05x}", self.part())?;
+        writeln!(f, "CPU revision\t: {}", self.revision())?;
+        match self.el {
+            Some(el) => writeln!(f, "Exception level\t: EL{}", el)?,
+            None => writeln!(f, "Exception level\t: unknown")?,
+        }
+        match self.physical_address_bits() {
+            Some(bits) => writeln!(f, "Address sizes\t: {} bits physical", bits),
+            None => writeln!(f, "Address sizes\t: unknown"),
+        }
+    }
+}
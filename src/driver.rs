@@ -1,28 +1,105 @@
-use self::{mini_uart::MINI_UART, qemu::QEMU_OUTPUT, uart::PL011_UART};
+use crate::{error::OsError, print, sync::NullLock};
+
+use self::{
+    emmc::EMMC, framebuffer::FRAMEBUFFER_DEVICE, gpio::GPIO, interrupt::INTERRUPT_CONTROLLER,
+    mailbox::videocore::VIDEOCORE_MAILBOX, mini_uart::MINI_UART, qemu::QEMU_OUTPUT, rng::RNG,
+    uart::PL011_UART,
+};
 
 pub mod console;
+pub mod emmc;
 pub mod framebuffer;
 pub mod gpio;
+pub mod interrupt;
 pub mod mailbox;
 pub mod mini_uart;
 pub mod mmio;
 pub mod qemu;
+pub mod rng;
 pub mod uart;
 
-pub fn serial_console() -> &'static impl crate::print::Write {
-    &PL011_UART
+/// The UARTs which can serve as the serial console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialPort {
+    Pl011,
+    MiniUart,
+}
+
+impl SerialPort {
+    /// The port named by the last Linux style `console=` argument of `command_line` which names
+    /// one, like `console=ttyS0,115200`.
+    pub fn from_command_line(command_line: &[u8]) -> Option<Self> {
+        command_line
+            .split(u8::is_ascii_whitespace)
+            .rev()
+            .filter_map(|arg| arg.strip_prefix(b"console="))
+            .find_map(|console| match console.split(|&byte| byte == b',').next() {
+                Some(b"ttyAMA0") => Some(SerialPort::Pl011),
+                Some(b"ttyS0") => Some(SerialPort::MiniUart),
+                _ => None,
+            })
+    }
+}
+
+/// A UART. `print::Write` takes text for `kprint!`, `write_bytes` sends any data as is.
+pub trait SerialConsole: print::Write + print::Read + Sync {
+    /// Send the bytes of `buf`, without any conversion.
+    fn write_bytes(&self, buf: &[u8]);
+}
+
+static SERIAL_CONSOLE_PORT: NullLock<SerialPort> = NullLock::new(SerialPort::Pl011);
+
+/// Choose the UART used as the serial console.
+///
+/// This must happen before the drivers are initialised, since only the console UART gets the
+/// GPIO pins.
+pub fn select_serial_console(port: SerialPort) {
+    SERIAL_CONSOLE_PORT.lock(|console_port| *console_port = port);
+}
+
+pub fn serial_console_port() -> SerialPort {
+    SERIAL_CONSOLE_PORT.lock(|port| *port)
+}
+
+pub fn serial_port(port: SerialPort) -> &'static dyn SerialConsole {
+    match port {
+        SerialPort::Pl011 => &PL011_UART,
+        SerialPort::MiniUart => &MINI_UART,
+    }
+}
+
+pub fn serial_console() -> &'static dyn SerialConsole {
+    serial_port(serial_console_port())
 }
 
 #[allow(dead_code)]
-pub fn qemu_console() -> &'static impl crate::print::Write {
+pub fn qemu_console() -> &'static impl print::Write {
     &QEMU_OUTPUT
 }
 
 pub trait DeviceDriver {
     fn init(&self);
+
+    /// Register the driver's interrupt handlers, if any. Called after all drivers are
+    /// initialised, with IRQs still masked.
+    fn register_irq_handler(&'static self) {}
+
+    /// Add the driver's devices to `/dev`. Called after all drivers are initialised.
+    fn register_device_nodes(&'static self) -> Result<(), OsError> {
+        Ok(())
+    }
 }
 
-static DRIVERS: [&'static (dyn DeviceDriver + Sync); 2] = [&MINI_UART, &PL011_UART];
+static DRIVERS: [&'static (dyn DeviceDriver + Sync); 8] = [
+    &INTERRUPT_CONTROLLER,
+    &VIDEOCORE_MAILBOX,
+    &GPIO,
+    &EMMC,
+    &MINI_UART,
+    &PL011_UART,
+    &RNG,
+    &FRAMEBUFFER_DEVICE,
+];
 
 pub fn drivers() -> &'static [&'static (dyn DeviceDriver + Sync)] {
     &DRIVERS
@@ -1,14 +1,694 @@
-use core::alloc::Allocator;
+use core::{alloc::Allocator, fmt, mem};
 
 use std_alloc::vec::Vec;
 
-use crate::fonts::psf::PsfFont;
+use crate::{
+    error::OsError,
+    fonts::{psf::PsfFont, Font, TextStyle},
+    print,
+    sync::NullLock,
+};
 
-type Line<A> = Vec<u8, A>;
+use self::ansi::{Action, Csi, Parser};
 
-pub struct Console<A: Allocator> {
-    font: PsfFont<'static>,
+use super::framebuffer::{Framebuffer, Pixel};
+
+mod ansi;
+
+type Line<A> = Vec<Cell, A>;
+
+const TAB_WIDTH: usize = 8;
+
+/// Number of lines kept in `Console::lines`, including the ones on screen.
+const SCROLLBACK_LINES: usize = 1000;
+
+/// A color as selected by SGR escape sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Default,
+    /// An entry of the xterm 256 color palette, the first 16 are the standard ANSI colors.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// The standard and bright ANSI colors, as used by xterm.
+const ANSI_COLORS: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+const DEFAULT_FG_INDEX: u8 = 7;
+const DEFAULT_BG_INDEX: u8 = 0;
+
+impl Color {
+    /// Resolve the color to RGB. There is no bold font, so bold brightens the first 8 colors.
+    fn rgb(self, default_index: u8, bold: bool) -> (u8, u8, u8) {
+        match self {
+            Color::Default => Color::Indexed(default_index).rgb(default_index, bold),
+            Color::Indexed(idx) if idx < 8 && bold => ANSI_COLORS[idx as usize + 8],
+            Color::Indexed(idx) if idx < 16 => ANSI_COLORS[idx as usize],
+            Color::Indexed(idx) if idx < 232 => {
+                // 6x6x6 color cube
+                const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+                let idx = (idx - 16) as usize;
+                (LEVELS[idx / 36], LEVELS[idx / 6 % 6], LEVELS[idx % 6])
+            }
+            Color::Indexed(idx) => {
+                // Grayscale ramp
+                let level = 8 + (idx - 232) * 10;
+                (level, level, level)
+            }
+            Color::Rgb(red, green, blue) => (red, green, blue),
+        }
+    }
+}
+
+/// Rendition of a character cell, set with SGR escape sequences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Attributes {
+    fg: Color,
+    bg: Color,
+    bold: bool,
+    inverse: bool,
+}
+
+impl Attributes {
+    const DEFAULT: Attributes = Attributes {
+        fg: Color::Default,
+        bg: Color::Default,
+        bold: false,
+        inverse: false,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    attrs: Attributes,
+}
+
+impl Cell {
+    const BLANK: Cell = Cell {
+        ch: ' ',
+        attrs: Attributes::DEFAULT,
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    col: usize,
+    row: usize,
+    attrs: Attributes,
+}
+
+/// A text console drawn on a framebuffer with a fixed cell PSF font.
+///
+/// Output is interpreted as a VT100-like terminal, see `Console::csi` for the supported escape
+/// sequences. Every line written is also kept in `lines`, so that the view can be scrolled back
+/// into the history.
+pub struct Console<A: Allocator + Clone> {
+    framebuffer: Framebuffer,
+    font: PsfFont<'static, A>,
+    /// The history, ending with the lines on screen. Lines may be shorter than `cols`, the
+    /// missing cells are blank.
     lines: Vec<Line<A>, A>,
+    alloc: A,
+    parser: Parser,
+    cell_width: usize,
+    cell_height: usize,
+    cols: usize,
+    rows: usize,
+    /// Cursor column, `cols` means the next character wraps.
+    cursor_col: usize,
+    /// Cursor row, relative to the top of the screen.
+    cursor_row: usize,
+    saved_cursor: Option<SavedCursor>,
+    /// Attributes of the characters written.
+    attrs: Attributes,
+    /// First row of the scrolling region.
+    scroll_top: usize,
+    /// Last row (inclusive) of the scrolling region.
+    scroll_bottom: usize,
+    /// Index into `lines` of the top row of the screen.
+    top_line: usize,
+    /// Number of lines the view is scrolled back from `top_line`.
+    view_offset: usize,
+    /// Whether a line was completed or the screen scrolled since the last `present`.
+    present_pending: bool,
+}
+
+impl<A: Allocator + Clone> Console<A> {
+    pub fn new(
+        framebuffer: Framebuffer,
+        font: PsfFont<'static, A>,
+        alloc: A,
+    ) -> Result<Self, OsError> {
+        let cell_width = font.width();
+        let cell_height = font.height();
+        let cols = framebuffer.width() / cell_width;
+        let rows = framebuffer.height() / cell_height;
+        if cols == 0 || rows == 0 {
+            return Err(OsError::ConsoleTooSmall);
+        }
+
+        let mut lines = Vec::new_in(alloc.clone());
+        for _ in 0..rows {
+            lines.push(Vec::new_in(alloc.clone()));
+        }
+
+        let mut console = Self {
+            framebuffer,
+            font,
+            lines,
+            alloc,
+            parser: Parser::new(),
+            cell_width,
+            cell_height,
+            cols,
+            rows,
+            cursor_col: 0,
+            cursor_row: 0,
+            saved_cursor: None,
+            attrs: Attributes::DEFAULT,
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            top_line: 0,
+            view_offset: 0,
+            present_pending: false,
+        };
+        // Take over the whole screen, including the margins the cells don't cover, e.g. from
+        // the splash screen.
+        let background = console.pixel(Color::Default.rgb(DEFAULT_BG_INDEX, false));
+        let height = console.framebuffer.height();
+        console.framebuffer.fill_rows(0, height, background);
+        console.redraw();
+        Ok(console)
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Clear the screen and put the cursor in the top left corner.
+    pub fn clear(&mut self) {
+        self.erase_rows(0, self.rows);
+        self.move_cursor(0, 0);
+    }
+
+    pub fn write_char(&mut self, ch: char) {
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.redraw();
+        }
+
+        match self.parser.advance(ch) {
+            Some(Action::Print(ch)) => self.put_char(ch),
+            Some(Action::Execute(ch)) => self.execute(ch),
+            Some(Action::Escape(ch)) => self.escape(ch),
+            Some(Action::Csi(csi)) => self.csi(&csi),
+            None => {}
+        }
+    }
+
+    /// Make what was written visible, see `Framebuffer::present`.
+    pub fn present(&mut self) -> Result<(), OsError> {
+        self.present_pending = false;
+        self.framebuffer.present()
+    }
+
+    /// Scroll the view `count` lines back into the history (or forward, if negative).
+    pub fn scroll_view(&mut self, count: isize) {
+        let max_offset = self.top_line;
+        let offset = if count < 0 {
+            self.view_offset.saturating_sub(count.unsigned_abs())
+        } else {
+            (self.view_offset + count as usize).min(max_offset)
+        };
+        if offset != self.view_offset {
+            self.view_offset = offset;
+            self.redraw();
+        }
+    }
+
+    fn execute(&mut self, ch: char) {
+        match ch {
+            // Output is not post-processed like on the serial console, so a newline also
+            // returns the carriage.
+            '\n' => {
+                self.cursor_col = 0;
+                self.line_feed();
+            }
+            '\r' => self.cursor_col = 0,
+            '\t' => {
+                let next_stop = (self.cursor_col / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.cursor_col = next_stop.min(self.cols - 1);
+            }
+            '\x08' => self.cursor_col = self.cursor_col.min(self.cols - 1).saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, ch: char) {
+        match ch {
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            // Index
+            'D' => self.line_feed(),
+            // Next line
+            'E' => {
+                self.cursor_col = 0;
+                self.line_feed();
+            }
+            // Reverse index
+            'M' => {
+                if self.cursor_row == self.scroll_top {
+                    self.scroll_down(1);
+                } else {
+                    self.cursor_row = self.cursor_row.saturating_sub(1);
+                }
+            }
+            // Full reset
+            'c' => {
+                self.attrs = Attributes::DEFAULT;
+                self.saved_cursor = None;
+                self.scroll_top = 0;
+                self.scroll_bottom = self.rows - 1;
+                self.clear();
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a CSI sequence. Supported are:
+    /// - cursor movement: `A`, `B`, `C`, `D`, `E`, `F`, `G`, `H`, `d` and `f`
+    /// - erasing: `J` (display) and `K` (line)
+    /// - scrolling: `S`, `T` and setting the scrolling region with `r`
+    /// - saving and restoring the cursor: `s` and `u`
+    /// - SGR (`m`): reset, bold, inverse and 16, 256 and 24-bit colors
+    fn csi(&mut self, csi: &Csi) {
+        if csi.private {
+            // DEC private modes, like showing and hiding the cursor, are not supported.
+            return;
+        }
+
+        let count = csi.param_or(0, 1) as usize;
+        let (col, row) = (self.cursor_col.min(self.cols - 1), self.cursor_row);
+        match csi.final_char {
+            'A' => self.move_cursor(col, row.saturating_sub(count).max(self.top_margin())),
+            'B' => self.move_cursor(col, (row + count).min(self.bottom_margin())),
+            'C' => self.move_cursor(col + count, row),
+            'D' => self.move_cursor(col.saturating_sub(count), row),
+            'E' => self.move_cursor(0, (row + count).min(self.bottom_margin())),
+            'F' => self.move_cursor(0, row.saturating_sub(count).max(self.top_margin())),
+            'G' => self.move_cursor(count - 1, row),
+            'd' => self.move_cursor(col, count - 1),
+            'H' | 'f' => {
+                let row = csi.param_or(0, 1) as usize - 1;
+                let col = csi.param_or(1, 1) as usize - 1;
+                self.move_cursor(col, row);
+            }
+            'J' => match csi.param_or(0, 0) {
+                0 => {
+                    self.erase_cells(row, col, self.cols);
+                    self.erase_rows(row + 1, self.rows);
+                }
+                1 => {
+                    self.erase_rows(0, row);
+                    self.erase_cells(row, 0, col + 1);
+                }
+                2 | 3 => self.erase_rows(0, self.rows),
+                _ => {}
+            },
+            'K' => match csi.param_or(0, 0) {
+                0 => self.erase_cells(row, col, self.cols),
+                1 => self.erase_cells(row, 0, col + 1),
+                2 => self.erase_cells(row, 0, self.cols),
+                _ => {}
+            },
+            'S' => self.scroll_up(count),
+            'T' => self.scroll_down(count),
+            'r' => {
+                let top = csi.param_or(0, 1) as usize - 1;
+                let bottom = (csi.param_or(1, self.rows as u16) as usize).min(self.rows) - 1;
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.move_cursor(0, 0);
+                }
+            }
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(),
+            'm' => self.select_graphic_rendition(csi.params()),
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.attrs = Attributes::DEFAULT;
+            return;
+        }
+
+        let mut params = params.iter().copied();
+        while let Some(param) = params.next() {
+            match param {
+                0 => self.attrs = Attributes::DEFAULT,
+                1 => self.attrs.bold = true,
+                22 => self.attrs.bold = false,
+                7 => self.attrs.inverse = true,
+                27 => self.attrs.inverse = false,
+                30..=37 => self.attrs.fg = Color::Indexed((param - 30) as u8),
+                38 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.attrs.fg = color;
+                    }
+                }
+                39 => self.attrs.fg = Color::Default,
+                40..=47 => self.attrs.bg = Color::Indexed((param - 40) as u8),
+                48 => {
+                    if let Some(color) = extended_color(&mut params) {
+                        self.attrs.bg = color;
+                    }
+                }
+                49 => self.attrs.bg = Color::Default,
+                90..=97 => self.attrs.fg = Color::Indexed((param - 90 + 8) as u8),
+                100..=107 => self.attrs.bg = Color::Indexed((param - 100 + 8) as u8),
+                _ => {}
+            }
+        }
+    }
+
+    /// The first row the cursor can be moved to with relative movements.
+    fn top_margin(&self) -> usize {
+        if self.cursor_row >= self.scroll_top {
+            self.scroll_top
+        } else {
+            0
+        }
+    }
+
+    /// The last row the cursor can be moved to with relative movements.
+    fn bottom_margin(&self) -> usize {
+        if self.cursor_row <= self.scroll_bottom {
+            self.scroll_bottom
+        } else {
+            self.rows - 1
+        }
+    }
+
+    fn move_cursor(&mut self, col: usize, row: usize) {
+        self.cursor_col = col.min(self.cols - 1);
+        self.cursor_row = row.min(self.rows - 1);
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Some(SavedCursor {
+            col: self.cursor_col,
+            row: self.cursor_row,
+            attrs: self.attrs,
+        });
+    }
+
+    fn restore_cursor(&mut self) {
+        match self.saved_cursor {
+            Some(saved) => {
+                self.cursor_col = saved.col;
+                self.cursor_row = saved.row;
+                self.attrs = saved.attrs;
+            }
+            None => {
+                self.move_cursor(0, 0);
+                self.attrs = Attributes::DEFAULT;
+            }
+        }
+    }
+
+    /// Draw a character at the cursor and advance it, wrapping at the end of the row.
+    fn put_char(&mut self, ch: char) {
+        if self.cursor_col >= self.cols {
+            self.cursor_col = 0;
+            self.line_feed();
+        }
+
+        let cell = Cell {
+            ch,
+            attrs: self.attrs,
+        };
+        let col = self.cursor_col;
+        let line = &mut self.lines[self.top_line + self.cursor_row];
+        if line.len() <= col {
+            line.resize(col, Cell::BLANK);
+            line.push(cell);
+        } else {
+            line[col] = cell;
+        }
+
+        self.draw_cell(col, self.cursor_row, cell);
+        self.cursor_col += 1;
+    }
+
+    /// Move the cursor down a row, scrolling if it is at the bottom of the scrolling region.
+    fn line_feed(&mut self) {
+        self.present_pending = true;
+        if self.cursor_row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor_row + 1 < self.rows {
+            self.cursor_row += 1;
+        }
+    }
+
+    /// Scroll the scrolling region up by `count` rows, adding blank rows at the bottom.
+    ///
+    /// If the region is the whole screen, the rows scrolled off the top go to the history.
+    fn scroll_up(&mut self, count: usize) {
+        self.present_pending = true;
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let count = count.min(bottom + 1 - top);
+        if top == 0 && bottom == self.rows - 1 {
+            for _ in 0..count {
+                self.push_line();
+            }
+        } else {
+            for _ in 0..count {
+                self.lines.remove(self.top_line + top);
+                let line = Vec::new_in(self.alloc.clone());
+                self.lines.insert(self.top_line + bottom, line);
+            }
+        }
+
+        let moved_rows = bottom + 1 - top - count;
+        self.framebuffer.copy_rows(
+            (top + count) * self.cell_height,
+            top * self.cell_height,
+            moved_rows * self.cell_height,
+        );
+        self.fill_rows(top + moved_rows, count);
+    }
+
+    /// Scroll the scrolling region down by `count` rows, adding blank rows at the top.
+    fn scroll_down(&mut self, count: usize) {
+        self.present_pending = true;
+        let (top, bottom) = (self.scroll_top, self.scroll_bottom);
+        let count = count.min(bottom + 1 - top);
+        for _ in 0..count {
+            self.lines.remove(self.top_line + bottom);
+            let line = Vec::new_in(self.alloc.clone());
+            self.lines.insert(self.top_line + top, line);
+        }
+
+        let moved_rows = bottom + 1 - top - count;
+        self.framebuffer.copy_rows(
+            top * self.cell_height,
+            (top + count) * self.cell_height,
+            moved_rows * self.cell_height,
+        );
+        self.fill_rows(top, count);
+    }
+
+    /// Add a blank line at the bottom of the screen, moving the top line into the history.
+    fn push_line(&mut self) {
+        self.lines.push(Vec::new_in(self.alloc.clone()));
+        self.top_line += 1;
+
+        let max_lines = SCROLLBACK_LINES.max(self.rows);
+        if self.lines.len() > max_lines {
+            let excess = self.lines.len() - max_lines;
+            self.lines.drain(..excess);
+            self.top_line -= excess;
+        }
+    }
+
+    /// Erase the cells `start..end` of `row`, filling them with the current background color.
+    fn erase_cells(&mut self, row: usize, start: usize, end: usize) {
+        let end = end.min(self.cols);
+        if start >= end {
+            return;
+        }
+
+        let blank = Cell {
+            ch: ' ',
+            attrs: Attributes {
+                bg: self.attrs.bg,
+                ..Attributes::DEFAULT
+            },
+        };
+        let line = &mut self.lines[self.top_line + row];
+        if end >= line.len() && blank == Cell::BLANK {
+            line.truncate(start);
+        } else {
+            if line.len() < end {
+                line.resize(end, Cell::BLANK);
+            }
+            line[start..end].fill(blank);
+        }
+
+        for col in start..end {
+            self.draw_cell(col, row, blank);
+        }
+    }
+
+    /// Erase the rows `start..end`, filling them with the current background color.
+    fn erase_rows(&mut self, start: usize, end: usize) {
+        if self.attrs.bg == Color::Default {
+            for row in start..end {
+                self.lines[self.top_line + row].clear();
+            }
+            self.fill_rows(start, end.saturating_sub(start));
+        } else {
+            for row in start..end {
+                self.erase_cells(row, 0, self.cols);
+            }
+        }
+    }
+
+    /// Fill `count` rows starting at `row` with the default background color.
+    fn fill_rows(&mut self, row: usize, count: usize) {
+        let pixel = self.pixel(Color::Default.rgb(DEFAULT_BG_INDEX, false));
+        self.framebuffer
+            .fill_rows(row * self.cell_height, count * self.cell_height, pixel);
+    }
+
+    /// Redraw the whole screen from the history.
+    fn redraw(&mut self) {
+        self.fill_rows(0, self.rows);
+        let first_line = self.top_line - self.view_offset;
+        for row in 0..self.rows {
+            let idx = first_line + row;
+            for col in 0..self.lines[idx].len().min(self.cols) {
+                let cell = self.lines[idx][col];
+                if cell != Cell::BLANK {
+                    self.draw_cell(col, row, cell);
+                }
+            }
+        }
+    }
+
+    fn draw_cell(&mut self, col: usize, row: usize, cell: Cell) {
+        let attrs = cell.attrs;
+        let mut fg = attrs.fg.rgb(DEFAULT_FG_INDEX, attrs.bold);
+        let mut bg = attrs.bg.rgb(DEFAULT_BG_INDEX, false);
+        if attrs.inverse {
+            (fg, bg) = (bg, fg);
+        }
+        let (fg, bg) = (self.pixel(fg), self.pixel(bg));
+
+        let x = (col * self.cell_width) as isize;
+        let y = (row * self.cell_height) as isize;
+        let style = TextStyle { fg, bg: Some(bg) };
+        self.font
+            .render_char(cell.ch, &mut self.framebuffer, x, y, &style);
+    }
+
+    fn pixel(&self, rgb: (u8, u8, u8)) -> Pixel {
+        let (red, green, blue) = rgb;
+        Pixel::new((red, green, blue, 255), self.framebuffer.pixel_order())
+    }
+}
+
+/// Parse the color following SGR 38 or 48: either `5;n` for the 256 color palette or `2;r;g;b`.
+fn extended_color(params: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match params.next()? {
+        5 => Some(Color::Indexed(params.next()?.min(255) as u8)),
+        2 => {
+            let red = params.next()?.min(255) as u8;
+            let green = params.next()?.min(255) as u8;
+            let blue = params.next()?.min(255) as u8;
+            Some(Color::Rgb(red, green, blue))
+        }
+        _ => None,
+    }
+}
+
+impl<A: Allocator + Clone> fmt::Write for Console<A> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            self.write_char(ch);
+        }
+        Ok(())
+    }
+}
+
+/// A `Console` which can be shared, e.g. to mirror `kprint!` output to the screen.
+pub struct FramebufferConsole<A: Allocator + Clone> {
+    inner: NullLock<Console<A>>,
 }
 
-impl<A: Allocator> Console<A> {}
+impl<A: Allocator + Clone> FramebufferConsole<A> {
+    pub fn new(console: Console<A>) -> Self {
+        Self {
+            inner: NullLock::new(console),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn clear(&self) -> Result<(), OsError> {
+        self.inner.lock(|inner| {
+            inner.clear();
+            inner.present()
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn scroll_view(&self, count: isize) -> Result<(), OsError> {
+        self.inner.lock(|inner| {
+            inner.scroll_view(count);
+            inner.present()
+        })
+    }
+}
+
+impl<A: Allocator + Clone> print::Write for FramebufferConsole<A> {
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        self.inner.lock(|inner| {
+            fmt::Write::write_fmt(inner, args)?;
+            // Presenting waits for the display, so partial lines are held back until `flush`.
+            if mem::take(&mut inner.present_pending) {
+                inner.present().map_err(|_| fmt::Error)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> fmt::Result {
+        self.inner
+            .lock(|inner| inner.present().map_err(|_| fmt::Error))
+    }
+}
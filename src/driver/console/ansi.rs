@@ -0,0 +1,199 @@
+//! Parser for the ANSI/VT100 escape sequences understood by the framebuffer console.
+//!
+//! This is a subset of the state machine described at <https://vt100.net/emu/dec_ansi_parser>:
+//! C0 controls, `ESC` sequences, CSI sequences with numeric parameters and OSC strings (which
+//! are parsed only to be ignored). Sub-parameters separated by `:` are not supported, such
+//! sequences are ignored as a whole.
+
+const ESC: char = '\x1B';
+const BEL: char = '\x07';
+const CAN: char = '\x18';
+const SUB: char = '\x1A';
+
+/// Maximum number of CSI parameters kept, further ones are dropped.
+pub const MAX_PARAMS: usize = 16;
+
+/// A parsed CSI (`ESC [`) sequence.
+#[derive(Debug, Clone, Copy)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    num_params: usize,
+    /// Set if the parameters start with `?`, as in DEC private modes.
+    pub private: bool,
+    pub final_char: char,
+}
+
+impl Csi {
+    const fn new() -> Self {
+        Self {
+            params: [0; MAX_PARAMS],
+            num_params: 0,
+            private: false,
+            final_char: '\0',
+        }
+    }
+
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.num_params]
+    }
+
+    /// Returns parameter `idx`, or `default` if it is missing or zero.
+    pub fn param_or(&self, idx: usize, default: u16) -> u16 {
+        match self.params().get(idx) {
+            Some(&param) if param != 0 => param,
+            _ => default,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    /// A printable character.
+    Print(char),
+    /// A C0 control character, like `\n` or `\x08`.
+    Execute(char),
+    /// An `ESC` sequence, with the character following the `ESC`.
+    Escape(char),
+    Csi(Csi),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    /// `ESC (` and friends, which designate a character set with the next character.
+    CharsetDesignation,
+    CsiParam,
+    /// A malformed CSI sequence, ignored up to its final character.
+    CsiIgnore,
+    OscString,
+}
+
+pub struct Parser {
+    state: State,
+    csi: Csi,
+    /// Index of the CSI parameter being parsed, may be past `MAX_PARAMS`.
+    param_idx: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            csi: Csi::new(),
+            param_idx: 0,
+        }
+    }
+
+    /// Feed one character to the parser, returning what to do once a sequence is complete.
+    pub fn advance(&mut self, ch: char) -> Option<Action> {
+        match ch {
+            ESC => {
+                self.state = State::Escape;
+                return None;
+            }
+            CAN | SUB => {
+                self.state = State::Ground;
+                return None;
+            }
+            BEL if self.state == State::OscString => {
+                self.state = State::Ground;
+                return None;
+            }
+            // C0 controls take effect even in the middle of a sequence.
+            ch if (ch as u32) < 0x20 || ch == '\x7F' => {
+                return match self.state {
+                    State::OscString => None,
+                    _ if ch == '\x7F' => None,
+                    _ => Some(Action::Execute(ch)),
+                };
+            }
+            _ => {}
+        }
+
+        match self.state {
+            State::Ground => Some(Action::Print(ch)),
+            State::Escape => self.escape(ch),
+            State::CharsetDesignation => {
+                // Only the default character set is supported.
+                self.state = State::Ground;
+                None
+            }
+            State::CsiParam => self.csi_param(ch),
+            State::CsiIgnore => {
+                if is_final(ch) {
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::OscString => None,
+        }
+    }
+
+    fn escape(&mut self, ch: char) -> Option<Action> {
+        match ch {
+            '[' => {
+                self.csi = Csi::new();
+                self.param_idx = 0;
+                self.state = State::CsiParam;
+                None
+            }
+            ']' => {
+                self.state = State::OscString;
+                None
+            }
+            '(' | ')' | '*' | '+' => {
+                self.state = State::CharsetDesignation;
+                None
+            }
+            // String terminator, ends an OSC string which was started by `ESC ]`.
+            '\\' => {
+                self.state = State::Ground;
+                None
+            }
+            ch => {
+                self.state = State::Ground;
+                Some(Action::Escape(ch))
+            }
+        }
+    }
+
+    fn csi_param(&mut self, ch: char) -> Option<Action> {
+        let csi = &mut self.csi;
+        match ch {
+            '0'..='9' => {
+                if let Some(param) = csi.params.get_mut(self.param_idx) {
+                    let digit = ch as u16 - '0' as u16;
+                    *param = param.saturating_mul(10).saturating_add(digit);
+                    csi.num_params = self.param_idx + 1;
+                }
+                None
+            }
+            ';' => {
+                // Omitted parameters are left at zero, which stands for their default value.
+                self.param_idx += 1;
+                if self.param_idx < MAX_PARAMS {
+                    csi.num_params = self.param_idx + 1;
+                }
+                None
+            }
+            '?' if csi.num_params == 0 && !csi.private => {
+                csi.private = true;
+                None
+            }
+            ch if is_final(ch) => {
+                csi.final_char = ch;
+                self.state = State::Ground;
+                Some(Action::Csi(*csi))
+            }
+            _ => {
+                self.state = State::CsiIgnore;
+                None
+            }
+        }
+    }
+}
+
+fn is_final(ch: char) -> bool {
+    ('\x40'..='\x7E').contains(&ch)
+}
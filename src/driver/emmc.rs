@@ -0,0 +1,849 @@
+use core::{fmt, time::Duration};
+
+use tock_registers::{
+    fields::FieldValue,
+    interfaces::{ReadWriteable, Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+use crate::{
+    block::{self, BlockDevice},
+    cpu,
+    driver::{
+        gpio::{Function, Pin, Pull, GPIO},
+        mailbox::tags::{self, ClockId, DeviceId},
+        mmio::{MMIODerefWrapper, MMIO_BASE},
+        DeviceDriver,
+    },
+    error::OsError,
+    fs::{
+        devfs::{self, Device},
+        DeviceNumber,
+    },
+    sync::NullLock,
+};
+
+const EMMC_OFFSET: usize = 0x0030_0000;
+const EMMC_BASE: usize = MMIO_BASE + EMMC_OFFSET;
+
+// Arasan SD host controller (EMMC), which follows the SD Host Controller specification.
+//
+// Descriptions taken from "BCM2835 ARM Peripherals", section 5, and the "SD Host Controller
+// Simplified Specification" version 3.00.
+register_bitfields! {
+    u32,
+
+    /// Block Size and Count.
+    BLKSIZECNT [
+        /// Number of blocks to be transferred.
+        BLKCNT OFFSET(16) NUMBITS(16) [],
+
+        /// Block size in bytes.
+        BLKSIZE OFFSET(0) NUMBITS(10) []
+    ],
+
+    /// Command and Transfer Mode.
+    CMDTM [
+        /// Index of the command to be issued to the card.
+        CMD_INDEX OFFSET(24) NUMBITS(6) [],
+
+        /// Check that the response has the same index as the command.
+        CMD_IXCHK_EN OFFSET(20) NUMBITS(1) [],
+
+        /// Check the response's CRC.
+        CMD_CRCCHK_EN OFFSET(19) NUMBITS(1) [],
+
+        /// The command transfers data.
+        CMD_ISDATA OFFSET(21) NUMBITS(1) [],
+
+        /// Type of the expected response.
+        CMD_RSPNS_TYPE OFFSET(16) NUMBITS(2) [
+            None = 0b00,
+            Bits136 = 0b01,
+            Bits48 = 0b10,
+            Bits48Busy = 0b11
+        ],
+
+        /// The transfer is made of multiple blocks.
+        TM_MULTI_BLOCK OFFSET(5) NUMBITS(1) [],
+
+        /// Direction of the data transfer.
+        TM_DAT_DIR OFFSET(4) NUMBITS(1) [
+            HostToCard = 0,
+            CardToHost = 1
+        ],
+
+        /// Command sent automatically after the transfer.
+        TM_AUTO_CMD_EN OFFSET(2) NUMBITS(2) [
+            None = 0b00,
+            Cmd12 = 0b01
+        ],
+
+        /// Stop the transfer after BLKCNT blocks.
+        TM_BLKCNT_EN OFFSET(1) NUMBITS(1) []
+    ],
+
+    /// Status.
+    STATUS [
+        /// The data lines are in use.
+        DAT_INHIBIT OFFSET(1) NUMBITS(1) [],
+
+        /// The command line is in use.
+        CMD_INHIBIT OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Host Configuration bits.
+    CONTROL0 [
+        /// Use 4 data lines.
+        HCTL_DWIDTH OFFSET(1) NUMBITS(1) []
+    ],
+
+    /// Host Configuration bits.
+    CONTROL1 [
+        /// Reset the data handling circuit.
+        SRST_DATA OFFSET(26) NUMBITS(1) [],
+
+        /// Reset the command handling circuit.
+        SRST_CMD OFFSET(25) NUMBITS(1) [],
+
+        /// Reset the complete host circuit.
+        SRST_HC OFFSET(24) NUMBITS(1) [],
+
+        /// Data timeout unit exponent, the timeout is `TMCLK * 2^(DATA_TOUNIT + 13)`.
+        DATA_TOUNIT OFFSET(16) NUMBITS(4) [],
+
+        /// SD clock base divider LSBs.
+        CLK_FREQ8 OFFSET(8) NUMBITS(8) [],
+
+        /// SD clock base divider MSBs.
+        CLK_FREQ_MS2 OFFSET(6) NUMBITS(2) [],
+
+        /// SD clock enable.
+        CLK_EN OFFSET(2) NUMBITS(1) [],
+
+        /// SD clock stable.
+        CLK_STABLE OFFSET(1) NUMBITS(1) [],
+
+        /// Clock enable for internal EMMC clocks for power saving.
+        CLK_INTLEN OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Interrupt flags. Writing a 1 clears a flag.
+    INTERRUPT [
+        /// Auto command error.
+        ACMD_ERR OFFSET(24) NUMBITS(1) [],
+
+        /// End bit on data line not 1.
+        DEND_ERR OFFSET(22) NUMBITS(1) [],
+
+        /// Data CRC error.
+        DCRC_ERR OFFSET(21) NUMBITS(1) [],
+
+        /// Timeout on data line.
+        DTO_ERR OFFSET(20) NUMBITS(1) [],
+
+        /// Incorrect command index in response.
+        CBAD_ERR OFFSET(19) NUMBITS(1) [],
+
+        /// End bit on command line not 1.
+        CEND_ERR OFFSET(18) NUMBITS(1) [],
+
+        /// Command CRC error.
+        CCRC_ERR OFFSET(17) NUMBITS(1) [],
+
+        /// Timeout on command line.
+        CTO_ERR OFFSET(16) NUMBITS(1) [],
+
+        /// An error has occurred.
+        ERR OFFSET(15) NUMBITS(1) [],
+
+        /// DATA register contains data to be read.
+        READ_RDY OFFSET(5) NUMBITS(1) [],
+
+        /// Data can be written to DATA register.
+        WRITE_RDY OFFSET(4) NUMBITS(1) [],
+
+        /// Data transfer has finished.
+        DATA_DONE OFFSET(1) NUMBITS(1) [],
+
+        /// Command has finished.
+        CMD_DONE OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Slot Interrupt Status and Version.
+    SLOTISR_VER [
+        /// Host Controller specification version.
+        SDVERSION OFFSET(16) NUMBITS(8) []
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => _reserved1),
+        (0x04 => BLKSIZECNT: WriteOnly<u32, BLKSIZECNT::Register>),
+        (0x08 => ARG1: WriteOnly<u32>),
+        (0x0C => CMDTM: WriteOnly<u32, CMDTM::Register>),
+        (0x10 => RESP: [ReadOnly<u32>; 4]),
+        (0x20 => DATA: ReadWrite<u32>),
+        (0x24 => STATUS: ReadOnly<u32, STATUS::Register>),
+        (0x28 => CONTROL0: ReadWrite<u32, CONTROL0::Register>),
+        (0x2C => CONTROL1: ReadWrite<u32, CONTROL1::Register>),
+        (0x30 => INTERRUPT: ReadWrite<u32, INTERRUPT::Register>),
+        (0x34 => IRPT_MASK: WriteOnly<u32>),
+        (0x38 => IRPT_EN: WriteOnly<u32>),
+        (0x3C => CONTROL2: WriteOnly<u32>),
+        (0x40 => _reserved2),
+        (0xFC => SLOTISR_VER: ReadOnly<u32, SLOTISR_VER::Register>),
+        (0x100 => @END),
+    }
+}
+
+type Registers = MMIODerefWrapper<RegisterBlock>;
+
+/// The error summary bit and the error flags in the upper half of INTERRUPT.
+const INTERRUPT_ERRORS: u32 = 0xFFFF_8000;
+
+/// SDVERSION of controllers following version 3.00 of the specification, which have a 10 bit
+/// clock divider.
+const HOST_SPEC_V3: u32 = 2;
+
+/// Base clock assumed if the firmware cannot be asked for it.
+const DEFAULT_BASE_CLOCK: u32 = 50_000_000;
+/// Clock during card identification.
+const IDENTIFICATION_CLOCK: u32 = 400_000;
+/// Clock in default speed mode.
+const TRANSFER_CLOCK: u32 = 25_000_000;
+
+const BLOCK_SIZE: usize = 512;
+/// Major number of MMC block devices on Linux. Partition `n` of the card has minor number `n`.
+pub const MMC_BLOCK_MAJOR: u32 = 179;
+/// Most blocks a single command can transfer, limited by BLKCNT.
+const MAX_BLOCKS_PER_COMMAND: usize = 0xFFFF;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+const DATA_TIMEOUT: Duration = Duration::from_millis(500);
+const RESET_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long a card may stay busy after ACMD41, 1 second according to the specification.
+const POWER_UP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Argument of SEND_IF_COND: 2.7-3.6V, and a check pattern echoed by the card.
+const IF_COND_ARG: u32 = 0x1AA;
+/// OCR bits of SD_SEND_OP_COND.
+const OCR_BUSY: u32 = 1 << 31;
+const OCR_CCS: u32 = 1 << 30;
+const OCR_HCS: u32 = 1 << 30;
+const OCR_VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+/// Argument of SET_BUS_WIDTH for 4 data lines.
+const BUS_WIDTH_4: u32 = 0b10;
+
+/// GPIO pins 48 to 53 carry the SD card's CLK, CMD and DAT0-3 lines.
+const SD_PINS: core::ops::RangeInclusive<u32> = 48..=53;
+const SD_CLK_PIN: u32 = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResponseType {
+    None,
+    /// Normal response, 48 bits.
+    R1,
+    /// Normal response, after which the card signals busy on DAT0.
+    R1b,
+    /// CID or CSD register, 136 bits.
+    R2,
+    /// OCR register, without CRC.
+    R3,
+    /// Published RCA response.
+    R6,
+    /// Card interface condition.
+    R7,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Command {
+    index: u32,
+    response: ResponseType,
+    /// An application specific command, which must be preceded by APP_CMD.
+    app: bool,
+}
+
+impl Command {
+    const fn new(index: u32, response: ResponseType) -> Self {
+        Self {
+            index,
+            response,
+            app: false,
+        }
+    }
+
+    const fn app(index: u32, response: ResponseType) -> Self {
+        Self {
+            index,
+            response,
+            app: true,
+        }
+    }
+
+    fn cmdtm(&self) -> FieldValue<u32, CMDTM::Register> {
+        let response = match self.response {
+            ResponseType::None => CMDTM::CMD_RSPNS_TYPE::None,
+            ResponseType::R2 => CMDTM::CMD_RSPNS_TYPE::Bits136 + CMDTM::CMD_CRCCHK_EN::SET,
+            ResponseType::R3 => CMDTM::CMD_RSPNS_TYPE::Bits48,
+            ResponseType::R1 | ResponseType::R6 | ResponseType::R7 => {
+                CMDTM::CMD_RSPNS_TYPE::Bits48 + CMDTM::CMD_CRCCHK_EN::SET + CMDTM::CMD_IXCHK_EN::SET
+            }
+            ResponseType::R1b => {
+                CMDTM::CMD_RSPNS_TYPE::Bits48Busy
+                    + CMDTM::CMD_CRCCHK_EN::SET
+                    + CMDTM::CMD_IXCHK_EN::SET
+            }
+        };
+        CMDTM::CMD_INDEX.val(self.index) + response
+    }
+}
+
+const GO_IDLE_STATE: Command = Command::new(0, ResponseType::None);
+const ALL_SEND_CID: Command = Command::new(2, ResponseType::R2);
+const SEND_RELATIVE_ADDR: Command = Command::new(3, ResponseType::R6);
+const SELECT_CARD: Command = Command::new(7, ResponseType::R1b);
+const SEND_IF_COND: Command = Command::new(8, ResponseType::R7);
+const SEND_CSD: Command = Command::new(9, ResponseType::R2);
+const SET_BLOCKLEN: Command = Command::new(16, ResponseType::R1);
+const READ_SINGLE_BLOCK: Command = Command::new(17, ResponseType::R1);
+const READ_MULTIPLE_BLOCK: Command = Command::new(18, ResponseType::R1);
+const WRITE_BLOCK: Command = Command::new(24, ResponseType::R1);
+const WRITE_MULTIPLE_BLOCK: Command = Command::new(25, ResponseType::R1);
+const APP_CMD: Command = Command::new(55, ResponseType::R1);
+const SET_BUS_WIDTH: Command = Command::app(6, ResponseType::R1);
+const SD_SEND_OP_COND: Command = Command::app(41, ResponseType::R3);
+
+/// Data phase of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    None,
+    /// Read this many blocks from the card.
+    Read(usize),
+    /// Write this many blocks to the card.
+    Write(usize),
+}
+
+/// A 128 bit card register (CID or CSD), as sent by the card.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CardRegister(u128);
+
+impl CardRegister {
+    /// The controller strips the CRC from R2 responses, shifting the register 8 bits to the
+    /// right.
+    fn from_response(resp: [u32; 4]) -> Self {
+        let value = resp
+            .iter()
+            .rev()
+            .fold(0u128, |value, word| (value << 32) | *word as u128);
+        CardRegister(value << 8)
+    }
+
+    /// Bits `lo` to `hi` (inclusive), numbered as in the SD specification.
+    fn bits(&self, hi: u32, lo: u32) -> u64 {
+        ((self.0 >> lo) & ((1 << (hi - lo + 1)) - 1)) as u64
+    }
+}
+
+/// What was found out about the card during identification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardInfo {
+    /// Relative card address, assigned by the card.
+    rca: u32,
+    /// SDHC or SDXC card, addressed in blocks rather than bytes.
+    high_capacity: bool,
+    block_count: u64,
+    cid: CardRegister,
+}
+
+impl CardInfo {
+    /// Manufacturer ID.
+    pub fn manufacturer_id(&self) -> u8 {
+        self.cid.bits(127, 120) as u8
+    }
+
+    /// Product name, 5 ASCII characters.
+    pub fn product_name(&self) -> [u8; 5] {
+        let mut name = [0; 5];
+        for (i, c) in name.iter_mut().enumerate() {
+            let lo = 96 - 8 * i as u32;
+            *c = self.cid.bits(lo + 7, lo) as u8;
+        }
+        name
+    }
+
+    pub fn serial_number(&self) -> u32 {
+        self.cid.bits(55, 24) as u32
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.block_count * BLOCK_SIZE as u64
+    }
+}
+
+impl fmt::Display for CardInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.product_name();
+        write!(
+            f,
+            "{} {} (manufacturer {:#04x}, serial {:#010x}), {} MiB",
+            if self.high_capacity { "SDHC" } else { "SDSC" },
+            core::str::from_utf8(&name).unwrap_or("?????"),
+            self.manufacturer_id(),
+            self.serial_number(),
+            self.capacity() >> 20
+        )
+    }
+}
+
+/// Number of blocks of a card, from its CSD register.
+fn csd_block_count(csd: &CardRegister) -> Result<u64, OsError> {
+    match csd.bits(127, 126) {
+        // CSD version 1.0: capacity = (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN
+        0 => {
+            let c_size = csd.bits(73, 62);
+            let c_size_mult = csd.bits(49, 47);
+            let read_bl_len = csd.bits(83, 80);
+            let capacity = (c_size + 1) << (c_size_mult + 2 + read_bl_len);
+            Ok(capacity / BLOCK_SIZE as u64)
+        }
+        // CSD version 2.0: capacity = (C_SIZE + 1) * 512 KiB
+        1 => Ok((csd.bits(69, 48) + 1) * 1024),
+        _ => Err(OsError::SdUnsupportedCard),
+    }
+}
+
+struct EmmcInner {
+    registers: Registers,
+    base_clock: u32,
+    card: Option<CardInfo>,
+}
+
+impl EmmcInner {
+    const fn new() -> Self {
+        Self {
+            registers: unsafe { Registers::new(EMMC_BASE) },
+            base_clock: DEFAULT_BASE_CLOCK,
+            card: None,
+        }
+    }
+
+    /// Route the SD card to the controller and power it on.
+    fn init(&mut self) {
+        // The firmware may have given the card to the other SD host controller (Alt0).
+        for num in SD_PINS {
+            let pin = Pin::new(num).unwrap();
+            GPIO.set_function(pin, Function::Alt3);
+            let pull = if num == SD_CLK_PIN {
+                Pull::Off
+            } else {
+                Pull::Up
+            };
+            GPIO.set_pull(pin, pull);
+        }
+        // QEMU does not know about the power domain, the card is powered either way.
+        let _ = tags::set_power_state(DeviceId::SdCard, true);
+        self.base_clock = tags::query_clock_rate(ClockId::Emmc).unwrap_or(DEFAULT_BASE_CLOCK);
+    }
+
+    /// Wait until `cond` holds, or fail with `SdTimeout(what)`.
+    fn wait_for(
+        &self,
+        timeout: Duration,
+        what: &'static str,
+        cond: impl Fn(&Registers) -> bool,
+    ) -> Result<(), OsError> {
+        let deadline = cpu::uptime() + timeout;
+        while !cond(&self.registers) {
+            if cpu::uptime() > deadline {
+                return Err(OsError::SdTimeout(what));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset the whole controller and start the identification clock.
+    fn reset(&mut self) -> Result<(), OsError> {
+        self.registers.CONTROL0.set(0);
+        self.registers.CONTROL2.set(0);
+        self.registers.CONTROL1.write(CONTROL1::SRST_HC::SET);
+        self.wait_for(RESET_TIMEOUT, "controller reset", |regs| {
+            !regs.CONTROL1.is_set(CONTROL1::SRST_HC)
+        })?;
+
+        self.registers
+            .CONTROL1
+            .write(CONTROL1::CLK_INTLEN::SET + CONTROL1::DATA_TOUNIT.val(0xE));
+        self.set_clock(IDENTIFICATION_CLOCK)?;
+
+        // Commands are polled: flag everything, but don't raise interrupts.
+        self.registers.IRPT_EN.set(0);
+        self.registers.IRPT_MASK.set(u32::MAX);
+        self.registers.INTERRUPT.set(u32::MAX);
+        Ok(())
+    }
+
+    /// Reset the command and data circuits after an error.
+    fn reset_lines(&mut self) {
+        self.registers
+            .CONTROL1
+            .modify(CONTROL1::SRST_CMD::SET + CONTROL1::SRST_DATA::SET);
+        let _ = self.wait_for(RESET_TIMEOUT, "line reset", |regs| {
+            !regs.CONTROL1.is_set(CONTROL1::SRST_CMD) && !regs.CONTROL1.is_set(CONTROL1::SRST_DATA)
+        });
+        self.registers.INTERRUPT.set(u32::MAX);
+    }
+
+    /// Run the SD clock at `rate` or the fastest rate below it.
+    ///
+    /// The card clock is `base_clock / (2 * divisor)`, or the base clock with a divisor of 0.
+    /// Controllers older than version 3.00 of the specification only take powers of two.
+    fn set_clock(&mut self, rate: u32) -> Result<(), OsError> {
+        self.wait_for(COMMAND_TIMEOUT, "idle lines", |regs| {
+            !regs.STATUS.is_set(STATUS::CMD_INHIBIT) && !regs.STATUS.is_set(STATUS::DAT_INHIBIT)
+        })?;
+        self.registers.CONTROL1.modify(CONTROL1::CLK_EN::CLEAR);
+
+        let version = self.registers.SLOTISR_VER.read(SLOTISR_VER::SDVERSION);
+        let divisor = if rate >= self.base_clock {
+            0
+        } else if version >= HOST_SPEC_V3 {
+            let divisor = (self.base_clock + 2 * rate - 1) / (2 * rate);
+            divisor.min(0x3FF)
+        } else {
+            let mut divisor = 1;
+            while divisor < 0x80 && self.base_clock / (2 * divisor) > rate {
+                divisor *= 2;
+            }
+            divisor
+        };
+        self.registers.CONTROL1.modify(
+            CONTROL1::CLK_FREQ8.val(divisor & 0xFF) + CONTROL1::CLK_FREQ_MS2.val(divisor >> 8),
+        );
+        self.wait_for(COMMAND_TIMEOUT, "stable clock", |regs| {
+            regs.CONTROL1.is_set(CONTROL1::CLK_STABLE)
+        })?;
+        self.registers.CONTROL1.modify(CONTROL1::CLK_EN::SET);
+        // The card needs a few clock cycles before it accepts a command.
+        cpu::spin_for(Duration::from_micros(100));
+        Ok(())
+    }
+
+    /// Wait for all of the `flags` in INTERRUPT, and acknowledge them.
+    fn wait_interrupt(
+        &mut self,
+        cmd: &Command,
+        flags: u32,
+        timeout: Duration,
+    ) -> Result<(), OsError> {
+        let deadline = cpu::uptime() + timeout;
+        loop {
+            let irpt = self.registers.INTERRUPT.extract();
+            if irpt.get() & INTERRUPT_ERRORS != 0 {
+                self.reset_lines();
+                return Err(if irpt.is_set(INTERRUPT::CTO_ERR) {
+                    OsError::SdCommandTimeout(cmd.index)
+                } else {
+                    OsError::SdCommandFailed(cmd.index, irpt.get())
+                });
+            }
+            if irpt.get() & flags == flags {
+                self.registers.INTERRUPT.set(flags);
+                return Ok(());
+            }
+            if cpu::uptime() > deadline {
+                self.reset_lines();
+                return Err(OsError::SdCommandTimeout(cmd.index));
+            }
+        }
+    }
+
+    /// Send `cmd` with `arg`, and return its response.
+    ///
+    /// For a transfer, the data must be moved with `read_data` or `write_data` afterwards.
+    fn command(&mut self, cmd: Command, arg: u32, transfer: Transfer) -> Result<[u32; 4], OsError> {
+        if cmd.app {
+            let rca = self.card.map_or(0, |card| card.rca);
+            self.command(APP_CMD, rca << 16, Transfer::None)?;
+        }
+
+        let uses_data = transfer != Transfer::None || cmd.response == ResponseType::R1b;
+        self.wait_for(COMMAND_TIMEOUT, "idle command line", |regs| {
+            !regs.STATUS.is_set(STATUS::CMD_INHIBIT)
+                && !(uses_data && regs.STATUS.is_set(STATUS::DAT_INHIBIT))
+        })?;
+        self.registers.INTERRUPT.set(u32::MAX);
+
+        let mut cmdtm = cmd.cmdtm();
+        match transfer {
+            Transfer::None => {}
+            Transfer::Read(count) | Transfer::Write(count) => {
+                self.registers.BLKSIZECNT.write(
+                    BLKSIZECNT::BLKCNT.val(count as u32)
+                        + BLKSIZECNT::BLKSIZE.val(BLOCK_SIZE as u32),
+                );
+                cmdtm += CMDTM::CMD_ISDATA::SET + CMDTM::TM_BLKCNT_EN::SET;
+                if count > 1 {
+                    cmdtm += CMDTM::TM_MULTI_BLOCK::SET + CMDTM::TM_AUTO_CMD_EN::Cmd12;
+                }
+                cmdtm += match transfer {
+                    Transfer::Read(_) => CMDTM::TM_DAT_DIR::CardToHost,
+                    _ => CMDTM::TM_DAT_DIR::HostToCard,
+                };
+            }
+        }
+
+        self.registers.ARG1.set(arg);
+        self.registers.CMDTM.write(cmdtm);
+        self.wait_interrupt(&cmd, INTERRUPT::CMD_DONE::SET.value, COMMAND_TIMEOUT)?;
+        if cmd.response == ResponseType::R1b {
+            self.wait_interrupt(&cmd, INTERRUPT::DATA_DONE::SET.value, DATA_TIMEOUT)?;
+        }
+
+        let regs = &self.registers;
+        Ok([
+            regs.RESP[0].get(),
+            regs.RESP[1].get(),
+            regs.RESP[2].get(),
+            regs.RESP[3].get(),
+        ])
+    }
+
+    /// Read the data of a read command into `buf`, a whole number of blocks.
+    fn read_data(&mut self, cmd: &Command, buf: &mut [u8]) -> Result<(), OsError> {
+        for block in buf.chunks_exact_mut(BLOCK_SIZE) {
+            self.wait_interrupt(cmd, INTERRUPT::READ_RDY::SET.value, DATA_TIMEOUT)?;
+            for word in block.chunks_exact_mut(4) {
+                word.copy_from_slice(&self.registers.DATA.get().to_le_bytes());
+            }
+        }
+        self.wait_interrupt(cmd, INTERRUPT::DATA_DONE::SET.value, DATA_TIMEOUT)
+    }
+
+    /// Write `buf`, a whole number of blocks, as the data of a write command.
+    fn write_data(&mut self, cmd: &Command, buf: &[u8]) -> Result<(), OsError> {
+        for block in buf.chunks_exact(BLOCK_SIZE) {
+            self.wait_interrupt(cmd, INTERRUPT::WRITE_RDY::SET.value, DATA_TIMEOUT)?;
+            for word in block.chunks_exact(4) {
+                let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                self.registers.DATA.set(word);
+            }
+        }
+        self.wait_interrupt(cmd, INTERRUPT::DATA_DONE::SET.value, DATA_TIMEOUT)
+    }
+
+    /// Reset the controller and identify the card, following the initialisation flow of the
+    /// "SD Physical Layer Simplified Specification", section 4.2.
+    fn init_card(&mut self) -> Result<CardInfo, OsError> {
+        self.card = None;
+        self.reset()?;
+
+        self.command(GO_IDLE_STATE, 0, Transfer::None)?;
+
+        // Version 1.x cards don't know SEND_IF_COND, and don't answer it.
+        let v2 = match self.command(SEND_IF_COND, IF_COND_ARG, Transfer::None) {
+            Ok(resp) if resp[0] & 0xFFF == IF_COND_ARG => true,
+            Ok(_) => return Err(OsError::SdUnsupportedCard),
+            Err(OsError::SdCommandTimeout(_)) => false,
+            Err(err) => return Err(err),
+        };
+
+        let mut arg = OCR_VOLTAGE_WINDOW;
+        if v2 {
+            arg |= OCR_HCS;
+        }
+        let deadline = cpu::uptime() + POWER_UP_TIMEOUT;
+        let ocr = loop {
+            let ocr = self.command(SD_SEND_OP_COND, arg, Transfer::None)?[0];
+            if ocr & OCR_BUSY != 0 {
+                break ocr;
+            }
+            if cpu::uptime() > deadline {
+                return Err(OsError::SdTimeout("card power up"));
+            }
+            cpu::spin_for(Duration::from_millis(10));
+        };
+        let high_capacity = ocr & OCR_CCS != 0;
+
+        let cid = CardRegister::from_response(self.command(ALL_SEND_CID, 0, Transfer::None)?);
+        let rca = self.command(SEND_RELATIVE_ADDR, 0, Transfer::None)?[0] >> 16;
+        let csd = CardRegister::from_response(self.command(SEND_CSD, rca << 16, Transfer::None)?);
+        let card = CardInfo {
+            rca,
+            high_capacity,
+            block_count: csd_block_count(&csd)?,
+            cid,
+        };
+        self.card = Some(card);
+
+        self.set_clock(TRANSFER_CLOCK)?;
+        self.command(SELECT_CARD, rca << 16, Transfer::None)?;
+        if !high_capacity {
+            self.command(SET_BLOCKLEN, BLOCK_SIZE as u32, Transfer::None)?;
+        }
+        // Every SD card supports 4 data lines.
+        self.command(SET_BUS_WIDTH, BUS_WIDTH_4, Transfer::None)?;
+        self.registers.CONTROL0.modify(CONTROL0::HCTL_DWIDTH::SET);
+
+        Ok(card)
+    }
+
+    fn card(&self) -> Result<CardInfo, OsError> {
+        self.card.ok_or(OsError::NoSdCard)
+    }
+
+    /// Address of `block` in the argument of read and write commands.
+    fn block_address(card: &CardInfo, block: u64) -> u32 {
+        if card.high_capacity {
+            block as u32
+        } else {
+            (block * BLOCK_SIZE as u64) as u32
+        }
+    }
+
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        let card = self.card()?;
+        let mut block = start;
+        for chunk in buf.chunks_mut(MAX_BLOCKS_PER_COMMAND * BLOCK_SIZE) {
+            let count = chunk.len() / BLOCK_SIZE;
+            let cmd = if count > 1 {
+                READ_MULTIPLE_BLOCK
+            } else {
+                READ_SINGLE_BLOCK
+            };
+            let addr = Self::block_address(&card, block);
+            self.command(cmd, addr, Transfer::Read(count))?;
+            self.read_data(&cmd, chunk)?;
+            block += count as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), OsError> {
+        let card = self.card()?;
+        let mut block = start;
+        for chunk in buf.chunks(MAX_BLOCKS_PER_COMMAND * BLOCK_SIZE) {
+            let count = chunk.len() / BLOCK_SIZE;
+            let cmd = if count > 1 {
+                WRITE_MULTIPLE_BLOCK
+            } else {
+                WRITE_BLOCK
+            };
+            let addr = Self::block_address(&card, block);
+            self.command(cmd, addr, Transfer::Write(count))?;
+            self.write_data(&cmd, chunk)?;
+            block += count as u64;
+        }
+        Ok(())
+    }
+}
+
+/// The SD card, behind the Arasan SD host controller.
+pub struct Emmc {
+    inner: NullLock<EmmcInner>,
+    /// First block and block count of the partition a filesystem is mounted from.
+    mounted: NullLock<Option<(u64, u64)>>,
+}
+
+impl Emmc {
+    const fn new() -> Self {
+        Self {
+            inner: NullLock::new(EmmcInner::new()),
+            mounted: NullLock::new(None),
+        }
+    }
+
+    /// Refuse writes to `count` blocks from `start` through "/dev/mmcblk0": a filesystem mounted
+    /// from them caches them.
+    pub fn set_mounted(&self, start: u64, count: u64) {
+        self.mounted.lock(|mounted| *mounted = Some((start, count)));
+    }
+
+    /// Identify the card in the slot. Block transfers fail until this succeeded.
+    pub fn init_card(&self) -> Result<CardInfo, OsError> {
+        self.inner.lock(|inner| inner.init_card())
+    }
+
+    /// The card found by `init_card`.
+    pub fn card(&self) -> Option<CardInfo> {
+        self.inner.lock(|inner| inner.card)
+    }
+}
+
+impl DeviceDriver for Emmc {
+    fn init(&self) {
+        self.inner.lock(|inner| inner.init());
+    }
+
+    /// The partitions get their nodes once the card is initialised and they are read.
+    fn register_device_nodes(&'static self) -> Result<(), OsError> {
+        let number = DeviceNumber {
+            major: MMC_BLOCK_MAJOR,
+            minor: 0,
+        };
+        devfs::register(
+            format_args!("mmcblk0"),
+            number,
+            0o660,
+            Device::Block(&WHOLE_CARD),
+        )
+    }
+}
+
+impl BlockDevice for Emmc {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.inner
+            .lock(|inner| inner.card.map_or(0, |card| card.block_count))
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        self.card().ok_or(OsError::NoSdCard)?;
+        block::check_transfer(self, start, buf.len())?;
+        self.inner.lock(|inner| inner.read_blocks(start, buf))
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), OsError> {
+        self.card().ok_or(OsError::NoSdCard)?;
+        block::check_transfer(self, start, buf.len())?;
+        self.inner.lock(|inner| inner.write_blocks(start, buf))
+    }
+}
+
+/// The "/dev/mmcblk0" node, which keeps writes away from the mounted partition.
+struct WholeCard(&'static Emmc);
+
+impl BlockDevice for WholeCard {
+    fn block_size(&self) -> usize {
+        self.0.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.0.block_count()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        self.0.read_blocks(start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), OsError> {
+        let count = block::check_transfer(self, start, buf.len())?;
+        let mounted = self.0.mounted.lock(|mounted| *mounted);
+        if let Some((first, len)) = mounted {
+            if start < first + len && first < start + count {
+                return Err(OsError::Busy);
+            }
+        }
+        self.0.write_blocks(start, buf)
+    }
+}
+
+pub static EMMC: Emmc = Emmc::new();
+
+static WHOLE_CARD: WholeCard = WholeCard(&EMMC);
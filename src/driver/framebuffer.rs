@@ -1,115 +1,424 @@
-use core::{alloc::Allocator, mem, ptr::NonNull};
+use core::{
+    alloc::{AllocError, Allocator},
+    fmt, mem,
+    ptr::NonNull,
+};
+
+use std_alloc::vec::Vec;
+
+use crate::{
+    error::OsError,
+    fs::{
+        devfs::{self, CharDevice, Device},
+        DeviceNumber,
+    },
+    graphics::{DirtyRegion, Rect},
+    mmu::PAGE_SIZE,
+    sync::NullLock,
+};
+
+use super::{
+    mailbox::{with_stack_mailbox, Mailbox, PropertyTag},
+    DeviceDriver,
+};
+
+mod display;
+mod draw;
+mod pixel;
+
+pub use self::display::{query_display_size, query_edid};
+pub use self::pixel::{Pixel, PixelFormat, PixelOrder};
+
+/// The allocator of the shadow buffer.
+pub type ShadowAlloc = &'static (dyn Allocator + Sync);
+
+/// The mode to ask the firmware for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferConfig {
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel: 16, 24 or 32.
+    pub depth: u32,
+    pub pixel_order: PixelOrder,
+}
 
-use crate::{error::OsError, mmu::PAGE_SIZE};
+impl FramebufferConfig {
+    pub const DEFAULT: Self = Self {
+        width: 1024,
+        height: 768,
+        depth: 32,
+        pixel_order: PixelOrder::Rgb,
+    };
+}
 
-use super::mailbox::{Mailbox, PropertyTag};
+impl Default for FramebufferConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
 
-#[derive(Debug)]
+/// The framebuffer, double buffered if the firmware allows it.
+///
+/// The virtual screen is twice as high as the physical one, giving two pages. Drawing goes to
+/// the back page (or the shadow buffer, if enabled) and `present` makes it visible by moving
+/// the virtual offset to it. The changes are tracked as a `DirtyRegion`, so that only they need
+/// to be copied to keep both pages up to date.
 pub struct Framebuffer {
-    buf: NonNull<Pixel>,
+    buf: NonNull<u8>,
+    /// Size of the buffer in bytes.
     buf_len: usize,
     width: usize,
     height: usize,
+    /// Bytes per row.
     pitch: usize,
+    format: PixelFormat,
     pixel_order: PixelOrder,
+    /// Drawing outside of this rectangle has no effect. It always lies within the screen.
+    clip: Rect,
+    /// Number of bytes in a page, including the padding at the end of each row.
+    page_len: usize,
+    num_pages: usize,
+    back_page: usize,
+    /// A copy of the back page in normal memory, drawn into instead of the back page. Its rows
+    /// are `width` pixels long, without padding.
+    shadow: Option<Vec<Pixel, ShadowAlloc>>,
+    /// What was drawn since the last `present`.
+    dirty: DirtyRegion,
+    /// What was drawn before the last `present`, which the back page lacks when drawing to the
+    /// shadow buffer.
+    presented: DirtyRegion,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-pub enum PixelOrder {
-    Bgr = 0,
-    Rgb = 1,
-}
-
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct Pixel {
-    first: u8,
-    green: u8,
-    third: u8,
-    alpha: u8,
-}
-
-impl Pixel {
-    pub fn new(rgba: (u8, u8, u8, u8), pixel_order: PixelOrder) -> Self {
-        let (red, green, blue, alpha) = rgba;
-        let (first, third) = if pixel_order == PixelOrder::Bgr {
-            (blue, red)
-        } else {
-            (red, blue)
-        };
-        Self {
-            first,
-            green,
-            third,
-            alpha,
-        }
+/// Safety: The framebuffer memory belongs to whoever owns the `Framebuffer`, the pointer is
+/// not shared.
+unsafe impl Send for Framebuffer {}
+
+impl fmt::Debug for Framebuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Framebuffer")
+            .field("buf", &self.buf)
+            .field("buf_len", &self.buf_len)
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("pitch", &self.pitch)
+            .field("format", &self.format)
+            .field("pixel_order", &self.pixel_order)
+            .field("num_pages", &self.num_pages)
+            .field("shadow", &self.shadow.is_some())
+            .finish()
     }
 }
 
 impl Framebuffer {
-    pub fn new<A: Allocator>(alloc: &A) -> Result<Self, OsError> {
+    pub fn new<A: Allocator>(config: &FramebufferConfig, alloc: &A) -> Result<Self, OsError> {
+        let format =
+            PixelFormat::from_depth(config.depth).ok_or(OsError::InvalidDepth(config.depth))?;
         let mut mailbox = Mailbox::new(alloc)?;
-        const DEPTH: u32 = mem::size_of::<Pixel>() as u32 * 8;
-        const WIDTH: u32 = 1024;
-        const HEIGHT: u32 = 768;
-
-        mailbox.append_tag(SetPhysicalSize {
-            width: WIDTH,
-            height: HEIGHT,
-        })?; // 0
-        mailbox.append_tag(SetVirtSize {
-            width: WIDTH,
-            height: HEIGHT,
-        })?; // 1
-        mailbox.append_tag(SetVirtOffset { x_off: 0, y_off: 0 })?; // 2
-        mailbox.append_tag(SetDepth { depth: DEPTH })?; // 3
-        mailbox.append_tag(SetPixelOrder {
-            order: PixelOrder::Rgb as u32,
-        })?; // 4
-        mailbox.append_tag(AllocateFrameBuffer {
+
+        let phys_size = mailbox.append_tag(SetPhysicalSize {
+            width: config.width,
+            height: config.height,
+        })?;
+        let virt_size = mailbox.append_tag(SetVirtSize {
+            width: config.width,
+            height: config.height * 2,
+        })?;
+        mailbox.append_tag(SetVirtOffset { x_off: 0, y_off: 0 })?;
+        let depth = mailbox.append_tag(SetDepth {
+            depth: config.depth,
+        })?;
+        let pixel_order = mailbox.append_tag(SetPixelOrder {
+            order: config.pixel_order as u32,
+        })?;
+        let fb_addr = mailbox.append_tag(AllocateFrameBuffer {
             alignment: PAGE_SIZE as u32,
-        })?; // 5
-        mailbox.append_tag(GetPitch)?; // 6
+        })?;
+        let pitch = mailbox.append_tag(GetPitch)?;
 
         mailbox.call()?;
 
-        if mailbox.read_tag_result::<SetDepth>(3).unwrap() != DEPTH {
-            return Err(OsError::InvalidDepth(DEPTH));
+        let depth = mailbox.read_tag_result(depth)?;
+        if depth != config.depth {
+            return Err(OsError::InvalidDepth(depth));
         }
 
-        let fb_addr = mailbox.read_tag_result::<AllocateFrameBuffer>(5).unwrap();
+        let fb_addr = mailbox.read_tag_result(fb_addr)?;
         if fb_addr.base == 0 {
             return Err(OsError::FramebufferNotAllocated);
         }
-        let virt_size = mailbox.read_tag_result::<SetVirtSize>(1).unwrap();
-        let pitch = mailbox.read_tag_result::<GetPitch>(6).unwrap();
-        let pixel_order = if mailbox.read_tag_result::<SetPixelOrder>(4).unwrap() == 1 {
+        let phys_size = mailbox.read_tag_result(phys_size)?;
+        let virt_size = mailbox.read_tag_result(virt_size)?;
+        let pitch = mailbox.read_tag_result(pitch)? as usize;
+        let pixel_order = if mailbox.read_tag_result(pixel_order)? == 1 {
             PixelOrder::Rgb
         } else {
             PixelOrder::Bgr
         };
 
-        let buf = NonNull::new((fb_addr.base & 0x3FFF_FFFF) as *mut Pixel).unwrap();
-        let buf_len = fb_addr.size as usize / mem::size_of::<Pixel>();
+        let buf = NonNull::new((fb_addr.base & 0x3FFF_FFFF) as *mut u8).unwrap();
+        let buf_len = fb_addr.size as usize;
+
+        let (width, height) = (phys_size.width as usize, phys_size.height as usize);
+        let page_len = pitch * height;
+        if buf_len < page_len || pitch < width * format.bytes_per_pixel() {
+            return Err(OsError::FramebufferNotAllocated);
+        }
+        // Fall back to a single page if the firmware did not give us a virtual screen large
+        // enough for two.
+        let num_pages = if virt_size.height as usize >= 2 * height && buf_len >= 2 * page_len {
+            2
+        } else {
+            1
+        };
+
+        FRAMEBUFFER_DEVICE
+            .memory
+            .lock(|memory| *memory = Some((buf.as_ptr() as usize, buf_len)));
 
         Ok(Self {
             buf,
             buf_len,
-            width: virt_size.width as usize,
-            height: virt_size.height as usize,
-            pitch: pitch as usize,
+            width,
+            height,
+            pitch,
+            format,
             pixel_order,
+            clip: Rect::new(0, 0, width, height),
+            page_len,
+            num_pages,
+            back_page: num_pages - 1,
+            shadow: None,
+            dirty: DirtyRegion::new(),
+            presented: DirtyRegion::new(),
         })
     }
 
-    fn offset(&self, x: usize, y: usize) -> usize {
-        y * (self.pitch / mem::size_of::<Pixel>()) + x
+    /// Give the framebuffer memory back to the firmware.
+    #[allow(dead_code)]
+    pub fn release(self) -> Result<(), OsError> {
+        FRAMEBUFFER_DEVICE.memory.lock(|memory| *memory = None);
+        with_stack_mailbox(|mbox| {
+            let release = mbox.append_tag(ReleaseBuffer)?;
+            mbox.call()?;
+            mbox.read_tag_result(release)
+        })
     }
 
+    /// Release the framebuffer and allocate a new one with the mode in `config`. The shadow
+    /// buffer is kept if it was enabled, but nothing that was drawn is.
+    #[allow(dead_code)]
+    pub fn reallocate<A: Allocator>(
+        self,
+        config: &FramebufferConfig,
+        alloc: &A,
+    ) -> Result<Self, OsError> {
+        let shadow_alloc = self.shadow.as_ref().map(|shadow| *shadow.allocator());
+        self.release()?;
+        let mut framebuffer = Self::new(config, alloc)?;
+        if let Some(shadow_alloc) = shadow_alloc {
+            framebuffer.enable_shadow_buffer(shadow_alloc)?;
+        }
+        Ok(framebuffer)
+    }
+
+    /// Draw into a buffer in normal memory instead of the back page from now on. `present`
+    /// copies the changes to the screen.
+    pub fn enable_shadow_buffer(&mut self, alloc: ShadowAlloc) -> Result<(), OsError> {
+        if self.shadow.is_some() {
+            return Ok(());
+        }
+        let mut shadow = Vec::new_in(alloc);
+        shadow
+            .try_reserve_exact(self.width * self.height)
+            .map_err(|_| AllocError)?;
+        // Start from what was drawn so far.
+        for y in 0..self.height {
+            for x in 0..self.width {
+                shadow.push(self.read_pixel(x, y));
+            }
+        }
+        self.shadow = Some(shadow);
+        Ok(())
+    }
+
+    /// Make everything drawn since the last call visible.
+    pub fn present(&mut self) -> Result<(), OsError> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(shadow) = &self.shadow {
+            let mut region = self.dirty;
+            if self.num_pages > 1 {
+                region.add_region(&self.presented);
+            }
+            for rect in region.rects() {
+                let (x, width) = (rect.x as usize, rect.width);
+                for y in rect.y as usize..rect.bottom() as usize {
+                    let row = &shadow[y * self.width + x..][..width];
+                    unsafe { write_pixels(self.pixel_ptr(self.back_page, x, y), self.format, row) };
+                }
+            }
+        }
+
+        if self.num_pages > 1 {
+            let y_off = (self.back_page * self.height) as u32;
+            with_stack_mailbox(|mbox| {
+                let offset = mbox.append_tag(SetVirtOffset { x_off: 0, y_off })?;
+                mbox.call()?;
+                if mbox.read_tag_result(offset)?.height == y_off {
+                    Ok(())
+                } else {
+                    Err(OsError::MailboxCallFailed)
+                }
+            })?;
+
+            let front_page = self.back_page;
+            self.back_page = (front_page + 1) % self.num_pages;
+            if self.shadow.is_none() {
+                // Bring the new back page up to date with what was just presented.
+                for rect in self.dirty.rects() {
+                    let (x, len) = (rect.x as usize, rect.width * self.format.bytes_per_pixel());
+                    for y in rect.y as usize..rect.bottom() as usize {
+                        let src = self.pixel_ptr(front_page, x, y);
+                        let dst = self.pixel_ptr(self.back_page, x, y);
+                        unsafe { copy_bytes(src, dst, len) };
+                    }
+                }
+            }
+        }
+
+        self.presented = self.dirty;
+        self.dirty.clear();
+        Ok(())
+    }
+
+    /// Mark `rect` as changed, for `present`.
+    pub fn mark_dirty(&mut self, rect: Rect) {
+        if let Some(rect) = rect.intersect(&self.bounds()) {
+            self.dirty.add(rect);
+        }
+    }
+
+    /// Returns a pointer to the pixel at (`x`, `y`) of `page`.
+    fn pixel_ptr(&self, page: usize, x: usize, y: usize) -> *mut u8 {
+        debug_assert!(page < self.num_pages && x <= self.width && y < self.height);
+        let off = page * self.page_len + y * self.pitch + x * self.format.bytes_per_pixel();
+        unsafe { self.buf.as_ptr().add(off) }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    /// Restrict all drawing to `rect`.
+    #[allow(dead_code)]
+    pub fn set_clip(&mut self, rect: Rect) {
+        self.clip = rect
+            .intersect(&self.bounds())
+            .unwrap_or(Rect::new(0, 0, 0, 0));
+    }
+
+    /// Allow drawing on the whole screen again.
+    #[allow(dead_code)]
+    pub fn reset_clip(&mut self) {
+        self.clip = self.bounds();
+    }
+
+    #[allow(dead_code)]
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    /// Set the pixel at (`x`, `y`), if it is inside the clipping rectangle.
+    #[allow(dead_code)]
     pub fn set_pixel(&mut self, x: usize, y: usize, pixel: Pixel) {
-        let off = self.offset(x, y);
-        assert!(off < self.buf_len);
-        unsafe { self.buf.as_ptr().add(off).write_volatile(pixel) };
+        if self.clip.contains(x as isize, y as isize) {
+            self.write_pixel(x, y, pixel);
+            self.dirty.add(Rect::new(x as isize, y as isize, 1, 1));
+        }
+    }
+
+    /// Returns the pixel at (`x`, `y`) as drawn, or `None` if it is outside of the screen.
+    #[allow(dead_code)]
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<Pixel> {
+        if x < self.width && y < self.height {
+            Some(self.read_pixel(x, y))
+        } else {
+            None
+        }
+    }
+
+    /// Fill `height` rows starting at row `y` with `pixel`.
+    pub fn fill_rows(&mut self, y: usize, height: usize, pixel: Pixel) {
+        let rect = Rect::new(0, y as isize, self.width, height);
+        self.fill_rect(rect, pixel);
+    }
+
+    /// Copy `height` rows starting at row `src_y` to row `dst_y`, like `memmove`: the areas
+    /// may overlap. The clipping rectangle is ignored.
+    pub fn copy_rows(&mut self, src_y: usize, dst_y: usize, height: usize) {
+        assert!(src_y + height <= self.height && dst_y + height <= self.height);
+        if height == 0 {
+            return;
+        }
+        self.dirty
+            .add(Rect::new(0, dst_y as isize, self.width, height));
+        match &mut self.shadow {
+            Some(shadow) => {
+                let (src, dst) = (src_y * self.width, dst_y * self.width);
+                shadow.copy_within(src..src + height * self.width, dst);
+            }
+            None => {
+                // The rows are `pitch` bytes apart, so they can be copied as one span, padding
+                // included.
+                let src = self.pixel_ptr(self.back_page, 0, src_y);
+                let dst = self.pixel_ptr(self.back_page, 0, dst_y);
+                unsafe { move_bytes(src, dst, height * self.pitch) };
+            }
+        }
+    }
+
+    // The functions below access the pixels drawn into: the shadow buffer if enabled, the back
+    // page otherwise. They do not clip nor track what changed, their callers do.
+
+    fn read_pixel(&self, x: usize, y: usize) -> Pixel {
+        assert!(x < self.width && y < self.height);
+        match &self.shadow {
+            Some(shadow) => shadow[y * self.width + x],
+            None => {
+                let ptr = self.pixel_ptr(self.back_page, x, y);
+                self.format.decode(unsafe { load(ptr, self.format) })
+            }
+        }
+    }
+
+    fn write_pixel(&mut self, x: usize, y: usize, pixel: Pixel) {
+        self.fill_span(x, y, 1, pixel);
+    }
+
+    /// Fill `len` pixels starting at (`x`, `y`) with `pixel`.
+    fn fill_span(&mut self, x: usize, y: usize, len: usize, pixel: Pixel) {
+        assert!(x + len <= self.width && y < self.height);
+        match &mut self.shadow {
+            Some(shadow) => shadow[y * self.width + x..][..len].fill(pixel),
+            None => {
+                let ptr = self.pixel_ptr(self.back_page, x, y);
+                unsafe { fill_pixels(ptr, self.format, len, self.format.encode(pixel)) };
+            }
+        }
+    }
+
+    /// Write `pixels` starting at (`x`, `y`).
+    fn write_span(&mut self, x: usize, y: usize, pixels: &[Pixel]) {
+        assert!(x + pixels.len() <= self.width && y < self.height);
+        match &mut self.shadow {
+            Some(shadow) => shadow[y * self.width + x..][..pixels.len()].copy_from_slice(pixels),
+            None => {
+                let ptr = self.pixel_ptr(self.back_page, x, y);
+                unsafe { write_pixels(ptr, self.format, pixels) };
+            }
+        }
     }
 
     pub fn width(&self) -> usize {
@@ -120,11 +429,159 @@ impl Framebuffer {
         self.height
     }
 
+    #[allow(dead_code)]
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
     pub fn pixel_order(&self) -> PixelOrder {
         self.pixel_order
     }
 }
 
+// The functions below access the framebuffer memory with volatile accesses, a u64 at a time
+// where possible.
+
+fn is_u64_aligned(ptr: *const u8) -> bool {
+    ptr as usize % mem::align_of::<u64>() == 0
+}
+
+/// Store the lowest `format.depth()` bits of `bits` at `ptr`.
+unsafe fn store(ptr: *mut u8, format: PixelFormat, bits: u32) {
+    match format {
+        PixelFormat::Rgb565 => (ptr as *mut u16).write_volatile(bits as u16),
+        PixelFormat::Rgb888 => {
+            for (i, byte) in bits.to_le_bytes()[..3].iter().enumerate() {
+                ptr.add(i).write_volatile(*byte);
+            }
+        }
+        PixelFormat::Rgba8888 => (ptr as *mut u32).write_volatile(bits),
+    }
+}
+
+unsafe fn load(ptr: *const u8, format: PixelFormat) -> u32 {
+    match format {
+        PixelFormat::Rgb565 => (ptr as *const u16).read_volatile() as u32,
+        PixelFormat::Rgb888 => u32::from_le_bytes([
+            ptr.read_volatile(),
+            ptr.add(1).read_volatile(),
+            ptr.add(2).read_volatile(),
+            0,
+        ]),
+        PixelFormat::Rgba8888 => (ptr as *const u32).read_volatile(),
+    }
+}
+
+/// Returns how many pixels fit in a u64, or `None` if pixels straddle u64 boundaries.
+fn pixels_per_u64(format: PixelFormat) -> Option<usize> {
+    let bytes = format.bytes_per_pixel();
+    if mem::size_of::<u64>() % bytes == 0 {
+        Some(mem::size_of::<u64>() / bytes)
+    } else {
+        None
+    }
+}
+
+/// Fill `len` pixels starting at `ptr` with the encoded pixel `bits`.
+unsafe fn fill_pixels(mut ptr: *mut u8, format: PixelFormat, mut len: usize, bits: u32) {
+    let bytes = format.bytes_per_pixel();
+    if let Some(per_u64) = pixels_per_u64(format) {
+        while len > 0 && !is_u64_aligned(ptr) {
+            store(ptr, format, bits);
+            ptr = ptr.add(bytes);
+            len -= 1;
+        }
+        let word = (0..per_u64).fold(0u64, |word, i| word | (bits as u64) << (i * bytes * 8));
+        for _ in 0..len / per_u64 {
+            (ptr as *mut u64).write_volatile(word);
+            ptr = ptr.add(mem::size_of::<u64>());
+        }
+        len %= per_u64;
+    }
+    for _ in 0..len {
+        store(ptr, format, bits);
+        ptr = ptr.add(bytes);
+    }
+}
+
+/// Encode `pixels` and write them starting at `ptr`.
+unsafe fn write_pixels(mut ptr: *mut u8, format: PixelFormat, mut pixels: &[Pixel]) {
+    let bytes = format.bytes_per_pixel();
+    if let Some(per_u64) = pixels_per_u64(format) {
+        while let [first, rest @ ..] = pixels {
+            if is_u64_aligned(ptr) {
+                break;
+            }
+            store(ptr, format, format.encode(*first));
+            ptr = ptr.add(bytes);
+            pixels = rest;
+        }
+        let mut chunks = pixels.chunks_exact(per_u64);
+        for chunk in &mut chunks {
+            // Little endian, so the first pixel goes to the lower address.
+            let word = chunk.iter().enumerate().fold(0u64, |word, (i, pixel)| {
+                word | (format.encode(*pixel) as u64) << (i * bytes * 8)
+            });
+            (ptr as *mut u64).write_volatile(word);
+            ptr = ptr.add(mem::size_of::<u64>());
+        }
+        pixels = chunks.remainder();
+    }
+    for pixel in pixels {
+        store(ptr, format, format.encode(*pixel));
+        ptr = ptr.add(bytes);
+    }
+}
+
+/// Copy `len` bytes from `src` to `dst`, front to back.
+unsafe fn copy_bytes(mut src: *const u8, mut dst: *mut u8, mut len: usize) {
+    if is_u64_aligned(src) == is_u64_aligned(dst) {
+        while len > 0 && !is_u64_aligned(src) {
+            dst.write_volatile(src.read_volatile());
+            src = src.add(1);
+            dst = dst.add(1);
+            len -= 1;
+        }
+        for _ in 0..len / 8 {
+            (dst as *mut u64).write_volatile((src as *const u64).read_volatile());
+            src = src.add(8);
+            dst = dst.add(8);
+        }
+        len %= 8;
+    }
+    for i in 0..len {
+        dst.add(i).write_volatile(src.add(i).read_volatile());
+    }
+}
+
+/// Copy `len` bytes from `src` to `dst`, like `memmove`: the areas may overlap.
+unsafe fn move_bytes(src: *const u8, dst: *mut u8, len: usize) {
+    if (dst as *const u8) <= src || dst as *const u8 >= src.add(len) {
+        return copy_bytes(src, dst, len);
+    }
+    // Copy backwards, so that the overlapping part is read before it is overwritten.
+    let (mut src_end, mut dst_end, mut len) = (src.add(len), dst.add(len), len);
+    if is_u64_aligned(src_end) == is_u64_aligned(dst_end) {
+        while len > 0 && !is_u64_aligned(src_end) {
+            src_end = src_end.sub(1);
+            dst_end = dst_end.sub(1);
+            dst_end.write_volatile(src_end.read_volatile());
+            len -= 1;
+        }
+        for _ in 0..len / 8 {
+            src_end = src_end.sub(8);
+            dst_end = dst_end.sub(8);
+            (dst_end as *mut u64).write_volatile((src_end as *const u64).read_volatile());
+        }
+        len %= 8;
+    }
+    for _ in 0..len {
+        src_end = src_end.sub(1);
+        dst_end = dst_end.sub(1);
+        dst_end.write_volatile(src_end.read_volatile());
+    }
+}
+
 // Definitions of various tags
 
 #[repr(C)]
@@ -229,3 +686,92 @@ unsafe impl PropertyTag for GetPitch {
         0x0004_0008
     }
 }
+
+struct ReleaseBuffer;
+
+unsafe impl PropertyTag for ReleaseBuffer {
+    type RecvType = ();
+
+    fn identifier(&self) -> u32 {
+        0x0004_8001
+    }
+}
+
+/// The memory of the framebuffer as a device file, `/dev/fb0`. It holds all the pages, with the
+/// layout given by the mode: what is written shows once it is in the visible page, and the
+/// console may draw over it. The file is empty until a framebuffer is allocated.
+pub struct FramebufferDevice {
+    /// Address and length of the framebuffer memory, while there is one.
+    memory: NullLock<Option<(usize, usize)>>,
+}
+
+impl FramebufferDevice {
+    /// Call `f` with the address of `len` bytes from `offset`, cut to the end of the memory.
+    ///
+    /// The memory stays locked during `f`, so it isn't released under it.
+    fn with_range<R>(
+        &self,
+        offset: u64,
+        len: usize,
+        f: impl FnOnce(usize, usize) -> R,
+    ) -> Result<R, OsError> {
+        self.memory.lock(|memory| {
+            let (base, size) = memory.ok_or(OsError::FramebufferNotAllocated)?;
+            if offset >= size as u64 {
+                return Ok(f(base + size, 0));
+            }
+            let offset = offset as usize;
+            Ok(f(base + offset, len.min(size - offset)))
+        })
+    }
+}
+
+impl CharDevice for FramebufferDevice {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        self.with_range(offset, buf.len(), |addr, len| {
+            unsafe { copy_bytes(addr as *const u8, buf.as_mut_ptr(), len) };
+            len
+        })
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        let written = self.with_range(offset, buf.len(), |addr, len| {
+            unsafe { copy_bytes(buf.as_ptr(), addr as *mut u8, len) };
+            len
+        })?;
+        if written == 0 && !buf.is_empty() {
+            return Err(OsError::NoSpace);
+        }
+        Ok(written)
+    }
+
+    fn size(&self) -> u64 {
+        self.memory
+            .lock(|memory| memory.map_or(0, |(_, size)| size as u64))
+    }
+
+    /// The memory is mapped as is, so the whole range must be in it.
+    fn mmap(&self, offset: u64, len: usize) -> Result<usize, OsError> {
+        let (addr, available) = self.with_range(offset, len, |addr, len| (addr, len))?;
+        if available < len || len == 0 {
+            return Err(OsError::InvalidArgument);
+        }
+        Ok(addr)
+    }
+}
+
+impl DeviceDriver for FramebufferDevice {
+    fn init(&self) {}
+
+    fn register_device_nodes(&'static self) -> Result<(), OsError> {
+        let number = DeviceNumber {
+            major: 29,
+            minor: 0,
+        };
+        devfs::register(format_args!("fb0"), number, 0o660, Device::Char(self))
+    }
+}
+
+pub static FRAMEBUFFER_DEVICE: FramebufferDevice = FramebufferDevice {
+    memory: NullLock::new(None),
+};
@@ -0,0 +1,95 @@
+//! Information about the attached display.
+
+use core::alloc::Allocator;
+
+use crate::{
+    driver::mailbox::{query_property, Mailbox, PropertyTag},
+    error::OsError,
+};
+
+use super::ScreenSize;
+
+/// Query the physical size of the display in pixels, as the firmware sees it.
+pub fn query_display_size() -> Result<(usize, usize), OsError> {
+    let size = query_property(GetPhysicalSize)?;
+    Ok((size.width as usize, size.height as usize))
+}
+
+/// Read block `block` of the display's EDID.
+///
+/// The response does not fit in a stack mailbox, so this needs an allocator.
+pub fn query_edid<A: Allocator>(alloc: &A, block: u32) -> Result<Edid, OsError> {
+    let mut mailbox = Mailbox::new(alloc)?;
+    let edid = mailbox.append_tag(GetEdidBlock { block })?;
+    mailbox.call()?;
+    let edid = mailbox.read_tag_result(edid)?;
+    if edid.status != 0 || edid.block != block {
+        return Err(OsError::MailboxCallFailed);
+    }
+    Ok(edid)
+}
+
+/// A 128 byte block of EDID data.
+#[repr(C)]
+pub struct Edid {
+    block: u32,
+    status: u32,
+    data: [u8; 128],
+}
+
+impl Edid {
+    const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+    #[allow(dead_code)]
+    pub fn data(&self) -> &[u8; 128] {
+        &self.data
+    }
+
+    /// Check the checksum, and the header for the base block.
+    pub fn is_valid(&self) -> bool {
+        let sum = self
+            .data
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        sum == 0 && (self.block != 0 || self.data[..8] == Self::HEADER)
+    }
+
+    /// Returns the width and height of the display's preferred mode, from the first detailed
+    /// timing descriptor of the base block.
+    pub fn preferred_mode(&self) -> Option<(usize, usize)> {
+        if self.block != 0 || !self.is_valid() {
+            return None;
+        }
+        let timing = &self.data[54..72];
+        // A zero pixel clock means that this is a display descriptor instead.
+        if timing[0] == 0 && timing[1] == 0 {
+            return None;
+        }
+        let width = timing[2] as usize | (timing[4] as usize >> 4) << 8;
+        let height = timing[5] as usize | (timing[7] as usize >> 4) << 8;
+        Some((width, height))
+    }
+}
+
+struct GetPhysicalSize;
+
+unsafe impl PropertyTag for GetPhysicalSize {
+    type RecvType = ScreenSize;
+
+    fn identifier(&self) -> u32 {
+        0x0004_0003
+    }
+}
+
+#[repr(C)]
+struct GetEdidBlock {
+    block: u32,
+}
+
+unsafe impl PropertyTag for GetEdidBlock {
+    type RecvType = Edid;
+
+    fn identifier(&self) -> u32 {
+        0x0003_0020
+    }
+}
@@ -0,0 +1,229 @@
+//! 2D drawing primitives. Coordinates may lie outside of the screen, everything is clipped
+//! against the framebuffer's clipping rectangle.
+
+use crate::graphics::{DrawTarget, Rect};
+
+use super::{Framebuffer, Pixel, PixelOrder};
+
+#[allow(dead_code)]
+impl Framebuffer {
+    pub fn fill_rect(&mut self, rect: Rect, pixel: Pixel) {
+        if let Some(rect) = rect.intersect(&self.clip) {
+            for y in rect.y..rect.bottom() {
+                self.fill_span(rect.x as usize, y as usize, rect.width, pixel);
+            }
+            self.dirty.add(rect);
+        }
+    }
+
+    /// Draw the outline of `rect`.
+    pub fn draw_rect(&mut self, rect: Rect, pixel: Pixel) {
+        if rect.is_empty() {
+            return;
+        }
+        self.draw_hline(rect.x, rect.y, rect.width, pixel);
+        self.draw_hline(rect.x, rect.bottom() - 1, rect.width, pixel);
+        self.draw_vline(rect.x, rect.y, rect.height, pixel);
+        self.draw_vline(rect.right() - 1, rect.y, rect.height, pixel);
+    }
+
+    /// Draw a line of `len` pixels from (`x`, `y`) to the right.
+    pub fn draw_hline(&mut self, x: isize, y: isize, len: usize, pixel: Pixel) {
+        self.fill_rect(Rect::new(x, y, len, 1), pixel);
+    }
+
+    /// Draw a line of `len` pixels from (`x`, `y`) downwards.
+    pub fn draw_vline(&mut self, x: isize, y: isize, len: usize, pixel: Pixel) {
+        self.fill_rect(Rect::new(x, y, 1, len), pixel);
+    }
+
+    /// Draw a line from `from` to `to`, both inclusive, with Bresenham's algorithm.
+    pub fn draw_line(&mut self, from: (isize, isize), to: (isize, isize), pixel: Pixel) {
+        let ((x0, y0), (x1, y1)) = (from, to);
+        if y0 == y1 {
+            let len = x0.abs_diff(x1) + 1;
+            return self.draw_hline(x0.min(x1), y0, len, pixel);
+        }
+        if x0 == x1 {
+            let len = y0.abs_diff(y1) + 1;
+            return self.draw_vline(x0, y0.min(y1), len, pixel);
+        }
+
+        let bounding_box = Rect::new(
+            x0.min(x1),
+            y0.min(y1),
+            x0.abs_diff(x1) + 1,
+            y0.abs_diff(y1) + 1,
+        );
+        self.mark_dirty(bounding_box);
+
+        let (dx, step_x) = ((x1 - x0).abs(), (x1 - x0).signum());
+        let (dy, step_y) = (-(y1 - y0).abs(), (y1 - y0).signum());
+        let (mut x, mut y) = (x0, y0);
+        let mut err = dx + dy;
+        loop {
+            self.plot(x, y, pixel);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let err2 = 2 * err;
+            if err2 >= dy {
+                err += dy;
+                x += step_x;
+            }
+            if err2 <= dx {
+                err += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draw the outline of a circle, with the midpoint circle algorithm.
+    pub fn draw_circle(&mut self, center: (isize, isize), radius: usize, pixel: Pixel) {
+        let (cx, cy) = center;
+        self.mark_dirty(circle_bounding_box(center, radius));
+        for_each_octant_point(radius, |x, y| {
+            for (px, py) in [(x, y), (y, x), (-y, x), (-x, y)] {
+                self.plot(cx + px, cy + py, pixel);
+                self.plot(cx + px, cy - py, pixel);
+            }
+        });
+    }
+
+    pub fn fill_circle(&mut self, center: (isize, isize), radius: usize, pixel: Pixel) {
+        let (cx, cy) = center;
+        for_each_octant_point(radius, |x, y| {
+            let (width_x, width_y) = (2 * x as usize + 1, 2 * y as usize + 1);
+            self.draw_hline(cx - x, cy + y, width_x, pixel);
+            self.draw_hline(cx - x, cy - y, width_x, pixel);
+            self.draw_hline(cx - y, cy + x, width_y, pixel);
+            self.draw_hline(cx - y, cy - x, width_y, pixel);
+        });
+    }
+
+    /// Copy an image with rows of `width` pixels to (`x`, `y`).
+    pub fn blit(&mut self, x: isize, y: isize, width: usize, pixels: &[Pixel]) {
+        self.for_each_blit_row(x, y, width, pixels, |fb, x, y, row| {
+            fb.write_span(x, y, row)
+        });
+    }
+
+    /// Like `blit`, but blend the pixels over what is on the screen according to their alpha.
+    pub fn blit_blended(&mut self, x: isize, y: isize, width: usize, pixels: &[Pixel]) {
+        self.for_each_blit_row(x, y, width, pixels, |fb, x, y, row| {
+            for (i, pixel) in row.iter().enumerate() {
+                fb.blend_at(x + i, y, *pixel);
+            }
+        });
+    }
+
+    /// Blend `pixel` over the pixel at (`x`, `y`), according to its alpha.
+    pub fn blend_pixel(&mut self, x: isize, y: isize, pixel: Pixel) {
+        if self.clip.contains(x, y) {
+            self.blend_at(x as usize, y as usize, pixel);
+            self.dirty.add(Rect::new(x, y, 1, 1));
+        }
+    }
+
+    /// Blend `pixel` over `rect`, according to its alpha.
+    pub fn fill_rect_blended(&mut self, rect: Rect, pixel: Pixel) {
+        match pixel.alpha() {
+            0 => {}
+            255 => self.fill_rect(rect, pixel),
+            _ => {
+                if let Some(rect) = rect.intersect(&self.clip) {
+                    for y in rect.y as usize..rect.bottom() as usize {
+                        for x in rect.x as usize..rect.right() as usize {
+                            self.blend_at(x, y, pixel);
+                        }
+                    }
+                    self.dirty.add(rect);
+                }
+            }
+        }
+    }
+
+    /// Set a pixel without marking it dirty, for primitives which mark their bounding box.
+    fn plot(&mut self, x: isize, y: isize, pixel: Pixel) {
+        if self.clip.contains(x, y) {
+            self.write_pixel(x as usize, y as usize, pixel);
+        }
+    }
+
+    fn blend_at(&mut self, x: usize, y: usize, pixel: Pixel) {
+        let dst = self.read_pixel(x, y);
+        self.write_pixel(x, y, pixel.blend_over(dst));
+    }
+
+    /// Call `f` with the screen position and source pixels of each visible row of an image.
+    fn for_each_blit_row(
+        &mut self,
+        x: isize,
+        y: isize,
+        width: usize,
+        pixels: &[Pixel],
+        mut f: impl FnMut(&mut Self, usize, usize, &[Pixel]),
+    ) {
+        if width == 0 {
+            return;
+        }
+        let image = Rect::new(x, y, width, pixels.len() / width);
+        if let Some(visible) = image.intersect(&self.clip) {
+            let src_col = (visible.x - x) as usize;
+            for dst_y in visible.y..visible.bottom() {
+                let src_off = (dst_y - y) as usize * width + src_col;
+                let row = &pixels[src_off..src_off + visible.width];
+                f(self, visible.x as usize, dst_y as usize, row);
+            }
+            self.dirty.add(visible);
+        }
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn pixel_order(&self) -> PixelOrder {
+        self.pixel_order
+    }
+
+    fn draw_pixel(&mut self, x: isize, y: isize, pixel: Pixel) {
+        if self.clip.contains(x, y) {
+            self.write_pixel(x as usize, y as usize, pixel);
+            self.dirty.add(Rect::new(x, y, 1, 1));
+        }
+    }
+
+    fn fill_rect(&mut self, rect: Rect, pixel: Pixel) {
+        Framebuffer::fill_rect(self, rect, pixel);
+    }
+
+    fn blit(&mut self, x: isize, y: isize, width: usize, pixels: &[Pixel]) {
+        Framebuffer::blit(self, x, y, width, pixels);
+    }
+}
+
+fn circle_bounding_box(center: (isize, isize), radius: usize) -> Rect {
+    let (cx, cy) = center;
+    let size = 2 * radius + 1;
+    Rect::new(cx - radius as isize, cy - radius as isize, size, size)
+}
+
+/// Call `f` with the points (x, y) of the first octant of a circle around the origin, going
+/// from (radius, 0) to x == y.
+fn for_each_octant_point(radius: usize, mut f: impl FnMut(isize, isize)) {
+    let (mut x, mut y) = (radius as isize, 0);
+    let mut err = 1 - x;
+    while x >= y {
+        f(x, y);
+        y += 1;
+        if err < 0 {
+            err += 2 * y + 1;
+        } else {
+            x -= 1;
+            err += 2 * (y - x) + 1;
+        }
+    }
+}
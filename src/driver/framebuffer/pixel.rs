@@ -0,0 +1,140 @@
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PixelOrder {
+    Bgr = 0,
+    Rgb = 1,
+}
+
+/// How pixels are stored in the framebuffer memory, depending on the depth of the mode.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PixelFormat {
+    /// 16 bits per pixel, 5 bits for the first color, 6 for green and 5 for the third one.
+    Rgb565,
+    /// 24 bits per pixel, one byte per color.
+    Rgb888,
+    /// 32 bits per pixel, one byte per color and one for alpha.
+    Rgba8888,
+}
+
+impl PixelFormat {
+    pub fn from_depth(depth: u32) -> Option<Self> {
+        match depth {
+            16 => Some(PixelFormat::Rgb565),
+            24 => Some(PixelFormat::Rgb888),
+            32 => Some(PixelFormat::Rgba8888),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn depth(self) -> u32 {
+        self.bytes_per_pixel() as u32 * 8
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Rgb888 => 3,
+            PixelFormat::Rgba8888 => 4,
+        }
+    }
+
+    /// Returns the bits of `pixel` in this format, in the lowest `depth` bits. Stored in little
+    /// endian, the first color comes first in memory.
+    pub fn encode(self, pixel: Pixel) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => {
+                let first = (pixel.first >> 3) as u32;
+                let green = (pixel.green >> 2) as u32;
+                let third = (pixel.third >> 3) as u32;
+                first << 11 | green << 5 | third
+            }
+            PixelFormat::Rgb888 => u32::from_le_bytes([pixel.first, pixel.green, pixel.third, 0]),
+            PixelFormat::Rgba8888 => pixel.to_bits(),
+        }
+    }
+
+    pub fn decode(self, bits: u32) -> Pixel {
+        match self {
+            PixelFormat::Rgb565 => {
+                // Replicate the high bits into the low ones, so that white stays white.
+                let first = (bits >> 11 & 0x1F) as u8;
+                let green = (bits >> 5 & 0x3F) as u8;
+                let third = (bits & 0x1F) as u8;
+                Pixel {
+                    first: first << 3 | first >> 2,
+                    green: green << 2 | green >> 4,
+                    third: third << 3 | third >> 2,
+                    alpha: 255,
+                }
+            }
+            PixelFormat::Rgb888 => {
+                let [first, green, third, _] = bits.to_le_bytes();
+                Pixel {
+                    first,
+                    green,
+                    third,
+                    alpha: 255,
+                }
+            }
+            PixelFormat::Rgba8888 => {
+                let [first, green, third, alpha] = bits.to_le_bytes();
+                Pixel {
+                    first,
+                    green,
+                    third,
+                    alpha,
+                }
+            }
+        }
+    }
+}
+
+/// A color, with its red and blue components ordered as the framebuffer expects.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Pixel {
+    first: u8,
+    green: u8,
+    third: u8,
+    alpha: u8,
+}
+
+impl Pixel {
+    pub fn new(rgba: (u8, u8, u8, u8), pixel_order: PixelOrder) -> Self {
+        let (red, green, blue, alpha) = rgba;
+        let (first, third) = if pixel_order == PixelOrder::Bgr {
+            (blue, red)
+        } else {
+            (red, blue)
+        };
+        Self {
+            first,
+            green,
+            third,
+            alpha,
+        }
+    }
+
+    pub fn alpha(&self) -> u8 {
+        self.alpha
+    }
+
+    /// Blend this pixel over `dst`, according to this pixel's alpha. Both pixels need to be in
+    /// the same pixel order.
+    pub fn blend_over(self, dst: Pixel) -> Pixel {
+        let alpha = self.alpha as u32;
+        let mix = |src: u8, dst: u8| {
+            ((src as u32 * alpha + dst as u32 * (255 - alpha) + 127) / 255) as u8
+        };
+        Pixel {
+            first: mix(self.first, dst.first),
+            green: mix(self.green, dst.green),
+            third: mix(self.third, dst.third),
+            alpha: mix(255, dst.alpha),
+        }
+    }
+
+    fn to_bits(self) -> u32 {
+        u32::from_le_bytes([self.first, self.green, self.third, self.alpha])
+    }
+}
@@ -1,29 +1,26 @@
-use crate::{cpu, sync::NullLock};
+use crate::{cpu, driver::DeviceDriver, error::OsError, sync::NullLock};
 
 use super::mmio::{MMIODerefWrapper, MMIO_BASE};
 use tock_registers::{
-    interfaces::{ReadWriteable, Writeable},
+    interfaces::{Readable, Writeable},
     register_bitfields, register_structs,
-    registers::{ReadWrite, WriteOnly},
+    registers::{ReadOnly, ReadWrite, WriteOnly},
 };
 
 const GPIO_OFFSET: usize = 0x0020_0000;
 pub const GPIO_BASE: usize = MMIO_BASE + GPIO_OFFSET;
 
+/// Number of GPIO pins of the BCM2837.
+pub const NUM_PINS: usize = 54;
+
+// BCM2837 GPIO registers.
+//
+// Descriptions taken from "BCM2835 ARM Peripherals", section 6. Registers controlling one bit per
+// pin come in two banks, the first for pins 0 to 31 and the second for pins 32 to 53.
 register_bitfields! {
     u32,
 
-    GPFSEL1 [
-        FSEL14 OFFSET(12) NUMBITS(3) [
-            Alt0 = 0b100,
-            Alt5 = 0b010,
-        ],
-        FSEL15 OFFSET(15) NUMBITS(3) [
-            Alt0 = 0b100,
-            Alt5 = 0b010,
-        ],
-    ],
-
+    /// GPIO Pull-up/down Register.
     GPPUD [
         PUD OFFSET(0) NUMBITS(2) [
             Off = 0b00,
@@ -31,27 +28,98 @@ register_bitfields! {
             PullUp = 0b10,
         ]
     ],
-
-    GPPUDCLK0 [
-        PUDCLK14 14,
-        PUDCLK15 15,
-    ],
 }
 
 register_structs! {
     #[allow(non_snake_case)]
     RegisterBlock {
-        (0x00 => _reserved1),
-        (0x04 => GPFSEL1: ReadWrite<u32, GPFSEL1::Register>),
-        (0x08 => _reserved2),
+        /// GPIO Function Select, 3 bits for each of 10 pins per register.
+        (0x00 => GPFSEL: [ReadWrite<u32>; 6]),
+        (0x18 => _reserved1),
+        /// GPIO Pin Output Set.
+        (0x1C => GPSET: [WriteOnly<u32>; 2]),
+        (0x24 => _reserved2),
+        /// GPIO Pin Output Clear.
+        (0x28 => GPCLR: [WriteOnly<u32>; 2]),
+        (0x30 => _reserved3),
+        /// GPIO Pin Level.
+        (0x34 => GPLEV: [ReadOnly<u32>; 2]),
+        (0x3C => _reserved4),
+        /// GPIO Pin Event Detect Status, cleared by writing a 1.
+        (0x40 => GPEDS: [ReadWrite<u32>; 2]),
+        (0x48 => _reserved5),
+        /// GPIO Pin Rising Edge Detect Enable.
+        (0x4C => GPREN: [ReadWrite<u32>; 2]),
+        (0x54 => _reserved6),
+        /// GPIO Pin Falling Edge Detect Enable.
+        (0x58 => GPFEN: [ReadWrite<u32>; 2]),
+        (0x60 => _reserved7),
+        /// GPIO Pin High Detect Enable.
+        (0x64 => GPHEN: [ReadWrite<u32>; 2]),
+        (0x6C => _reserved8),
+        /// GPIO Pin Low Detect Enable.
+        (0x70 => GPLEN: [ReadWrite<u32>; 2]),
+        (0x78 => _reserved9),
+        /// GPIO Pin Async. Rising Edge Detect.
+        (0x7C => GPAREN: [ReadWrite<u32>; 2]),
+        (0x84 => _reserved10),
+        /// GPIO Pin Async. Falling Edge Detect.
+        (0x88 => GPAFEN: [ReadWrite<u32>; 2]),
+        (0x90 => _reserved11),
         (0x94 => GPPUD: WriteOnly<u32, GPPUD::Register>),
-        (0x98 => GPPUDCLK0: WriteOnly<u32, GPPUDCLK0::Register>),
-        (0x9C => @END),
+        /// GPIO Pin Pull-up/down Enable Clock.
+        (0x98 => GPPUDCLK: [WriteOnly<u32>; 2]),
+        (0xA0 => @END),
     }
 }
 
 type Registers = MMIODerefWrapper<RegisterBlock>;
 
+/// A GPIO pin number, checked to be below `NUM_PINS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pin(u8);
+
+impl Pin {
+    /// TXD0/TXD1, depending on the function.
+    const UART_TX: Pin = Pin(14);
+    /// RXD0/RXD1, depending on the function.
+    const UART_RX: Pin = Pin(15);
+
+    pub fn new(num: u32) -> Result<Self, OsError> {
+        if (num as usize) < NUM_PINS {
+            Ok(Pin(num as u8))
+        } else {
+            Err(OsError::InvalidGpioPin(num))
+        }
+    }
+}
+
+/// Alternate function of a pin, which depends on the pin. See "BCM2835 ARM Peripherals",
+/// section 6.2. Only the functions of the peripherals with a driver are here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Alt0,
+    Alt3,
+    Alt5,
+}
+
+impl Function {
+    fn to_bits(self) -> u32 {
+        match self {
+            Function::Alt0 => 0b100,
+            Function::Alt3 => 0b111,
+            Function::Alt5 => 0b010,
+        }
+    }
+}
+
+/// Pull resistor of a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    Off,
+    Up,
+}
+
 struct GpioInner {
     registers: Registers,
 }
@@ -63,21 +131,43 @@ impl GpioInner {
         }
     }
 
-    fn disable_pull_up_down(&mut self) {
-        self.registers.GPPUD.write(GPPUD::PUD::Off);
-        cpu::spin_for_cycles(150);
-        self.registers
-            .GPPUDCLK0
-            .write(GPPUDCLK0::PUDCLK14::SET + GPPUDCLK0::PUDCLK15::SET);
-        cpu::spin_for_cycles(150);
-        self.registers.GPPUDCLK0.set(0);
+    fn init(&mut self) {
+        // Events are not used, leave the functions to whoever uses the pins.
+        for bank in 0..2 {
+            self.registers.GPREN[bank].set(0);
+            self.registers.GPFEN[bank].set(0);
+            self.registers.GPHEN[bank].set(0);
+            self.registers.GPLEN[bank].set(0);
+            self.registers.GPAREN[bank].set(0);
+            self.registers.GPAFEN[bank].set(0);
+            self.registers.GPEDS[bank].set(u32::MAX);
+        }
     }
 
-    fn map_uart1_pins(&mut self) {
-        self.registers
-            .GPFSEL1
-            .modify(GPFSEL1::FSEL14::Alt5 + GPFSEL1::FSEL15::Alt5);
-        self.disable_pull_up_down();
+    fn set_function(&mut self, pin: Pin, function: Function) {
+        let reg = &self.registers.GPFSEL[pin.0 as usize / 10];
+        let shift = (pin.0 as u32 % 10) * 3;
+        let val = reg.get() & !(0b111 << shift);
+        reg.set(val | (function.to_bits() << shift));
+    }
+
+    /// Set the pull-up/down resistor of the pins in `mask`, bit `n` being pin `n`.
+    ///
+    /// The sequence is described in "BCM2835 ARM Peripherals", section 6.1: the control signal
+    /// is set up, then clocked into the selected pins, with 150 cycles of setup and hold time.
+    fn set_pull(&mut self, mask: u64, pull: Pull) {
+        let pud = match pull {
+            Pull::Off => GPPUD::PUD::Off,
+            Pull::Up => GPPUD::PUD::PullUp,
+        };
+        self.registers.GPPUD.write(pud);
+        cpu::spin_for_cycles(150);
+        self.registers.GPPUDCLK[0].set(mask as u32);
+        self.registers.GPPUDCLK[1].set((mask >> 32) as u32);
+        cpu::spin_for_cycles(150);
+        self.registers.GPPUD.write(GPPUD::PUD::Off);
+        self.registers.GPPUDCLK[0].set(0);
+        self.registers.GPPUDCLK[1].set(0);
     }
 }
 
@@ -92,8 +182,37 @@ impl Gpio {
         }
     }
 
+    pub fn set_function(&self, pin: Pin, function: Function) {
+        self.inner.lock(|inner| inner.set_function(pin, function));
+    }
+
+    pub fn set_pull(&self, pin: Pin, pull: Pull) {
+        self.inner.lock(|inner| inner.set_pull(1 << pin.0, pull));
+    }
+
+    /// Route pins 14 and 15 to the UART selected by `function`: Alt0 for the PL011, Alt5 for the
+    /// mini UART.
+    fn map_uart_pins(&self, function: Function) {
+        self.inner.lock(|inner| {
+            inner.set_function(Pin::UART_TX, function);
+            inner.set_function(Pin::UART_RX, function);
+            let mask = (1 << Pin::UART_TX.0) | (1 << Pin::UART_RX.0);
+            inner.set_pull(mask, Pull::Off);
+        });
+    }
+
+    pub fn map_uart0_pins(&self) {
+        self.map_uart_pins(Function::Alt0);
+    }
+
     pub fn map_uart1_pins(&self) {
-        self.inner.lock(|inner| inner.map_uart1_pins());
+        self.map_uart_pins(Function::Alt5);
+    }
+}
+
+impl DeviceDriver for Gpio {
+    fn init(&self) {
+        self.inner.lock(|inner| inner.init());
     }
 }
 
@@ -0,0 +1,231 @@
+use core::fmt;
+
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_structs,
+    registers::{ReadOnly, WriteOnly},
+};
+
+use crate::sync::NullLock;
+
+use super::{
+    mmio::{MMIODerefWrapper, MMIO_BASE},
+    DeviceDriver,
+};
+
+const INTERRUPT_CONTROLLER_OFFSET: usize = 0x0000_B200;
+const INTERRUPT_CONTROLLER_BASE: usize = MMIO_BASE + INTERRUPT_CONTROLLER_OFFSET;
+
+// BCM2837 ARM peripheral interrupt controller.
+//
+// Descriptions taken from "BCM2835 ARM Peripherals", section 7.
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => IRQ_BASIC_PENDING: ReadOnly<u32>),
+        (0x04 => IRQ_PENDING_1: ReadOnly<u32>),
+        (0x08 => IRQ_PENDING_2: ReadOnly<u32>),
+        (0x0C => FIQ_CONTROL: WriteOnly<u32>),
+        (0x10 => ENABLE_IRQS_1: WriteOnly<u32>),
+        (0x14 => ENABLE_IRQS_2: WriteOnly<u32>),
+        (0x18 => ENABLE_BASIC_IRQS: WriteOnly<u32>),
+        (0x1C => DISABLE_IRQS_1: WriteOnly<u32>),
+        (0x20 => DISABLE_IRQS_2: WriteOnly<u32>),
+        (0x24 => DISABLE_BASIC_IRQS: WriteOnly<u32>),
+        (0x28 => @END),
+    }
+}
+
+type Registers = MMIODerefWrapper<RegisterBlock>;
+
+/// Number of the peripheral (GPU) interrupts, numbered 0 to 63.
+const NUM_PERIPHERAL_IRQS: usize = 64;
+/// Number of the ARM specific ("basic") interrupts, numbered 64 to 71 here.
+const NUM_BASIC_IRQS: usize = 8;
+const NUM_IRQS: usize = NUM_PERIPHERAL_IRQS + NUM_BASIC_IRQS;
+
+/// An interrupt line of the peripheral interrupt controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqNumber(usize);
+
+impl IrqNumber {
+    pub const AUX: IrqNumber = IrqNumber(29);
+    pub const PL011_UART: IrqNumber = IrqNumber(57);
+    pub const EMMC: IrqNumber = IrqNumber(62);
+    pub const ARM_TIMER: IrqNumber = IrqNumber(NUM_PERIPHERAL_IRQS);
+    pub const ARM_MAILBOX: IrqNumber = IrqNumber(NUM_PERIPHERAL_IRQS + 1);
+
+    pub const fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for IrqNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Implemented by drivers which service an interrupt line.
+pub trait IrqHandler {
+    /// Called from the IRQ exception handler, with IRQs masked. It must not `kprint!`, whose
+    /// locks don't mask IRQs.
+    fn handle_irq(&self);
+}
+
+/// How often an interrupt line with a handler, or a spurious one, has fired.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqStats {
+    pub irq: IrqNumber,
+    /// Name of the driver handling the line, or `spurious` for a line which fired without a
+    /// handler and was masked.
+    pub name: &'static str,
+    pub count: u64,
+}
+
+#[derive(Clone, Copy)]
+struct IrqHandlerDescriptor {
+    name: &'static str,
+    handler: &'static (dyn IrqHandler + Sync),
+}
+
+struct InterruptControllerInner {
+    registers: Registers,
+    handlers: [Option<IrqHandlerDescriptor>; NUM_IRQS],
+    /// Number of times each line was found pending.
+    counts: [u64; NUM_IRQS],
+}
+
+pub struct InterruptController {
+    inner: NullLock<InterruptControllerInner>,
+}
+
+impl InterruptControllerInner {
+    const fn new() -> Self {
+        Self {
+            registers: unsafe { Registers::new(INTERRUPT_CONTROLLER_BASE) },
+            handlers: [None; NUM_IRQS],
+            counts: [0; NUM_IRQS],
+        }
+    }
+
+    fn init(&mut self) {
+        // Start with everything disabled, drivers enable what they need.
+        self.registers.DISABLE_IRQS_1.set(u32::MAX);
+        self.registers.DISABLE_IRQS_2.set(u32::MAX);
+        self.registers.DISABLE_BASIC_IRQS.set(u32::MAX);
+        self.registers.FIQ_CONTROL.set(0);
+    }
+
+    fn enable(&mut self, irq: IrqNumber) {
+        let num = irq.get();
+        if num < 32 {
+            self.registers.ENABLE_IRQS_1.set(1 << num);
+        } else if num < NUM_PERIPHERAL_IRQS {
+            self.registers.ENABLE_IRQS_2.set(1 << (num - 32));
+        } else {
+            self.registers
+                .ENABLE_BASIC_IRQS
+                .set(1 << (num - NUM_PERIPHERAL_IRQS));
+        }
+    }
+
+    fn disable(&mut self, irq: IrqNumber) {
+        let num = irq.get();
+        if num < 32 {
+            self.registers.DISABLE_IRQS_1.set(1 << num);
+        } else if num < NUM_PERIPHERAL_IRQS {
+            self.registers.DISABLE_IRQS_2.set(1 << (num - 32));
+        } else {
+            self.registers
+                .DISABLE_BASIC_IRQS
+                .set(1 << (num - NUM_PERIPHERAL_IRQS));
+        }
+    }
+
+    /// Returns a bitmask of all pending interrupts, with bit `n` corresponding to `IrqNumber(n)`.
+    fn pending(&self) -> u128 {
+        let pending_1 = self.registers.IRQ_PENDING_1.get() as u128;
+        let pending_2 = self.registers.IRQ_PENDING_2.get() as u128;
+        // Only the lowest 8 bits of the basic pending register are ARM specific interrupts,
+        // the others are shortcuts to bits of the other two pending registers.
+        let basic = (self.registers.IRQ_BASIC_PENDING.get() & 0xFF) as u128;
+        pending_1 | (pending_2 << 32) | (basic << NUM_PERIPHERAL_IRQS)
+    }
+}
+
+impl InterruptController {
+    const fn new() -> Self {
+        Self {
+            inner: NullLock::new(InterruptControllerInner::new()),
+        }
+    }
+
+    /// Register `handler` for `irq` and enable the interrupt line.
+    ///
+    /// Panics if a handler is already registered for the line.
+    pub fn register_handler(
+        &self,
+        irq: IrqNumber,
+        name: &'static str,
+        handler: &'static (dyn IrqHandler + Sync),
+    ) {
+        self.inner.lock(|inner| {
+            let slot = &mut inner.handlers[irq.get()];
+            if let Some(desc) = slot {
+                panic!("IRQ {} already handled by {}", irq, desc.name);
+            }
+            *slot = Some(IrqHandlerDescriptor { name, handler });
+            inner.enable(irq);
+        });
+    }
+
+    /// Dispatch all pending interrupts to their handlers, masking the lines which have none. These
+    /// show as spurious in `irq_stats`.
+    pub fn handle_pending_irqs(&self) {
+        let mut pending = self.inner.lock(|inner| inner.pending());
+        while pending != 0 {
+            let num = pending.trailing_zeros() as usize;
+            pending &= pending - 1;
+
+            let desc = self.inner.lock(|inner| {
+                inner.counts[num] += 1;
+                inner.handlers[num]
+            });
+            match desc {
+                Some(desc) => desc.handler.handle_irq(),
+                // Nothing would clear it, so it would fire again right away.
+                None => self.inner.lock(|inner| inner.disable(IrqNumber(num))),
+            }
+        }
+    }
+
+    /// The lines with a handler and the spurious ones, by number.
+    pub fn irq_stats(&self) -> impl Iterator<Item = IrqStats> {
+        let (handlers, counts) = self.inner.lock(|inner| (inner.handlers, inner.counts));
+        handlers
+            .into_iter()
+            .zip(counts)
+            .enumerate()
+            .filter_map(|(num, (desc, count))| {
+                let name = match desc {
+                    Some(desc) => desc.name,
+                    None if count > 0 => "spurious",
+                    None => return None,
+                };
+                Some(IrqStats {
+                    irq: IrqNumber(num),
+                    name,
+                    count,
+                })
+            })
+    }
+}
+
+impl DeviceDriver for InterruptController {
+    fn init(&self) {
+        self.inner.lock(|inner| inner.init());
+    }
+}
+
+pub static INTERRUPT_CONTROLLER: InterruptController = InterruptController::new();
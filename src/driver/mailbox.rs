@@ -1,70 +1,61 @@
 use core::{
     alloc::{Allocator, Layout},
+    future::Future,
+    marker::PhantomData,
     mem::{self, MaybeUninit},
+    pin::Pin,
     ptr::{self, NonNull},
+    slice,
+    task::{Context, Poll},
 };
 
-use cortex_a::asm;
-use std_alloc::vec::Vec;
-use tock_registers::{
-    interfaces::{Readable, Writeable},
-    register_bitfields, register_structs,
-    registers::{ReadOnly, WriteOnly},
-};
-
-use crate::{driver::mmio::MMIO_BASE, error::OsError, mmu::align_up};
-
-use super::mmio::MMIODerefWrapper;
-
-const VIDEOCORE_MBOX_OFFSET: usize = 0x0000_B880;
-const VIDEOCORE_MBOX_BASE: usize = MMIO_BASE + VIDEOCORE_MBOX_OFFSET;
+use crate::{error::OsError, kalloc::fixed_buffer_alloc::FixedSliceAlloc, mmu::align_up};
 
-register_bitfields! {
-    u32,
+use self::videocore::{Channel, PendingResponse, VIDEOCORE_MAILBOX};
 
-    Mbox0_Status [
-        FULL 31,
-        EMPTY 30,
-    ],
-}
-
-register_structs! {
-    #[allow(non_snake_case)]
-    RegisterBlock {
-        (0x00 => Mbox0_Read: ReadOnly<u32>),
-        (0x04 => _reserved1),
-        (0x18 => Mbox0_Status: ReadOnly<u32, Mbox0_Status::Register>),
-        (0x1C => _reserved2),
-        (0x20 => Mbox1_Write: WriteOnly<u32>),
-        (0x24 => @END),
-    }
-}
+pub mod tags;
+pub mod videocore;
 
+/// A request of property tags, sent on the property channel.
 pub struct Mailbox<'a, A: Allocator> {
     allocator: &'a A,
     buffer: NonNull<u8>,
     cap: usize,
     len: usize,
-    tag_idx: Vec<usize, &'a A>,
     has_result: bool,
-    registers: MMIODerefWrapper<RegisterBlock>,
 }
 
 const DEFAULT_MAILBOX_SIZE: usize = 36 * mem::size_of::<u32>();
 
+/// Set in a tag's response code once the firmware answered it, the other bits are the length
+/// of the response.
+const TAG_RESPONSE: u32 = 1 << 31;
+
+/// Refers to a tag appended to a `Mailbox`, to read its response after `Mailbox::call`.
+pub struct TagHandle<T: PropertyTag> {
+    /// Offset of the tag in the mailbox buffer.
+    offset: usize,
+    identifier: u32,
+    _tag: PhantomData<fn() -> T>,
+}
+
+impl<T: PropertyTag> Clone for TagHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: PropertyTag> Copy for TagHandle<T> {}
+
 impl<'a, A: Allocator> Mailbox<'a, A> {
     pub fn new(allocator: &'a A) -> Result<Self, OsError> {
         let layout = Layout::array::<u8>(DEFAULT_MAILBOX_SIZE)?.align_to(16)?;
         let buffer = allocator.allocate(layout)?;
-        let tag_idx = Vec::new_in(allocator);
-        let registers = unsafe { MMIODerefWrapper::new(VIDEOCORE_MBOX_BASE) };
         let mut mailbox = Self {
             allocator,
             buffer: buffer.cast(),
             cap: DEFAULT_MAILBOX_SIZE,
             len: 8,
-            tag_idx,
-            registers,
             has_result: false,
         };
         mailbox.write_value(0, 0u32);
@@ -124,14 +115,19 @@ impl<'a, A: Allocator> Mailbox<'a, A> {
         self.len -= val_size;
     }
 
-    pub fn append_tag<T: PropertyTag>(&mut self, tag: T) -> Result<(), OsError> {
+    /// Append `tag` to the request. The returned handle reads its response after `call`.
+    pub fn append_tag<T: PropertyTag>(&mut self, tag: T) -> Result<TagHandle<T>, OsError> {
         self.has_result = false;
-        self.tag_idx.push(self.len);
+        let handle = TagHandle {
+            offset: self.len,
+            identifier: tag.identifier(),
+            _tag: PhantomData,
+        };
 
         self.append_value(tag.identifier())?;
         let buf = tag.send_buffer();
         let buf_len = buf.len();
-        let recv_buf_len = mem::size_of::<T::RecvType>();
+        let recv_buf_len = tag.response_capacity();
         let full_len = buf_len.max(recv_buf_len);
         self.append_value(full_len as u32)?;
         self.append_value(0u32)?;
@@ -148,63 +144,98 @@ impl<'a, A: Allocator> Mailbox<'a, A> {
             self.append_value(0u8)?;
         }
 
-        Ok(())
+        Ok(handle)
+    }
+
+    /// Returns the response to the tag of `handle`, which must be exactly a `T::RecvType`.
+    pub fn read_tag_result<T: PropertyTag>(
+        &self,
+        handle: TagHandle<T>,
+    ) -> Result<T::RecvType, OsError> {
+        let (offset, len) = self.response(&handle)?;
+        if len != mem::size_of::<T::RecvType>() {
+            return Err(OsError::MailboxInvalidResponse(handle.identifier));
+        }
+        Ok(self.read_value(offset))
     }
 
-    pub fn read_tag_result<T: PropertyTag>(&self, tag_idx: usize) -> Option<T::RecvType> {
+    /// Returns the response to a tag whose length varies, like a string. If the firmware had
+    /// more to send than `PropertyTag::response_capacity`, this fails with the length needed.
+    pub fn read_tag_bytes<T: PropertyTag>(&self, handle: TagHandle<T>) -> Result<&[u8], OsError> {
+        let (offset, len) = self.response(&handle)?;
+        let buf_len: u32 = self.read_value(handle.offset + 4);
+        if len > buf_len as usize {
+            return Err(OsError::MailboxResponseTruncated(len));
+        }
+        assert!(offset + len <= self.cap);
+        Ok(unsafe { slice::from_raw_parts(self.buffer.as_ptr().add(offset), len) })
+    }
+
+    /// Returns the offset and length of the response to the tag of `handle`.
+    fn response<T: PropertyTag>(&self, handle: &TagHandle<T>) -> Result<(usize, usize), OsError> {
         if !self.has_result {
-            return None;
+            return Err(OsError::MailboxCallFailed);
+        }
+        let identifier: u32 = self.read_value(handle.offset);
+        if identifier != handle.identifier {
+            // The handle belongs to another mailbox.
+            return Err(OsError::MailboxInvalidResponse(handle.identifier));
         }
-        let tag_buf_idx = *self.tag_idx.get(tag_idx)?;
-        let resp_code: u32 = self.read_value(tag_buf_idx + 8);
-        if (resp_code >> 31) != 1 {
-            return None;
+        let resp_code: u32 = self.read_value(handle.offset + 8);
+        if resp_code & TAG_RESPONSE == 0 {
+            return Err(OsError::MailboxTagNotAnswered(identifier));
         }
-        let recv_type_size = mem::size_of::<T::RecvType>();
-        assert!((resp_code & 0x7FFF_FFFF) == recv_type_size as u32);
-        Some(self.read_value(tag_buf_idx + 12))
+        Ok((handle.offset + 12, (resp_code & !TAG_RESPONSE) as usize))
     }
 
-    pub fn call(&mut self) -> Result<bool, OsError> {
+    /// Send the request and wait for the response. Fails if the firmware could not parse the
+    /// request, each tag's response is checked when reading it.
+    pub fn call(&mut self) -> Result<(), OsError> {
+        // The call is waited for right away.
+        unsafe { self.call_async() }?.wait()
+    }
+
+    /// Send the request without waiting for the response, which the returned `PendingCall`
+    /// collects. The mailbox can't be used until then.
+    ///
+    /// # Safety
+    ///
+    /// The firmware writes its response into the mailbox buffer whenever it is ready. The
+    /// returned `PendingCall` must be waited for, completed, awaited or dropped, which waits for
+    /// the response, before the buffer is freed: it must not be leaked with `mem::forget` or
+    /// the like.
+    pub unsafe fn call_async(&mut self) -> Result<PendingCall<'_, 'a, A>, OsError> {
+        self.has_result = false;
         // Push end tag
         self.append_value(0u32)?;
         // Set length
         self.write_value(0, self.len as u32);
-        let value = (self.addr() | 0x8) as u32;
 
-        const MBOX_RESPONSE: u32 = 0x8000_0000;
-
-        // Wait until we can write mailbox
-        while self
-            .registers
-            .Mbox0_Status
-            .matches_all(Mbox0_Status::FULL::SET)
-        {
-            asm::nop();
-        }
-
-        // Write mailbox
-        self.registers.Mbox1_Write.set(value);
-
-        // Now wait for response
-        loop {
-            while self
-                .registers
-                .Mbox0_Status
-                .matches_all(Mbox0_Status::EMPTY::SET)
-            {
-                asm::nop();
-            }
-            if self.registers.Mbox0_Read.get() == value {
-                let resp: u32 = self.read_value(4);
-                self.has_result = resp == MBOX_RESPONSE;
-                break;
+        match VIDEOCORE_MAILBOX.send(Channel::PropertyArmToVc, self.addr() as u32) {
+            Ok(response) => Ok(PendingCall {
+                mailbox: self,
+                response: Some(response),
+            }),
+            Err(err) => {
+                self.pop_value::<u32>();
+                Err(err)
             }
         }
+    }
+
+    /// Check the response of the firmware, once it answered.
+    fn complete(&mut self, response: u32) -> Result<(), OsError> {
+        const MBOX_RESPONSE: u32 = 0x8000_0000;
 
         // Pop end tag
         self.pop_value::<u32>();
-        Ok(self.has_result)
+        let resp: u32 = self.read_value(4);
+        self.has_result = response == self.addr() as u32 && resp == MBOX_RESPONSE;
+        if self.has_result {
+            Ok(())
+        } else {
+            Err(OsError::MailboxRequestRejected)
+        }
     }
 
     fn addr(&self) -> usize {
@@ -219,6 +250,61 @@ impl<'a, A: Allocator> Drop for Mailbox<'a, A> {
     }
 }
 
+/// A property request sent with `Mailbox::call_async`, whose response has not been checked
+/// yet. The mailbox buffer must outlive the request, so dropping this waits for the response,
+/// and leaking it is not allowed.
+pub struct PendingCall<'m, 'a, A: Allocator> {
+    mailbox: &'m mut Mailbox<'a, A>,
+    /// `None` once completed.
+    response: Option<PendingResponse>,
+}
+
+impl<A: Allocator> PendingCall<'_, '_, A> {
+    /// Returns the result of the call if the firmware answered, without waiting.
+    #[allow(dead_code)]
+    pub fn try_complete(&mut self) -> Option<Result<(), OsError>> {
+        let response = self.response.as_mut()?.try_take()?;
+        self.response = None;
+        Some(self.mailbox.complete(response))
+    }
+
+    /// Wait for the firmware to answer.
+    pub fn wait(mut self) -> Result<(), OsError> {
+        match self.response.take() {
+            Some(response) => self.mailbox.complete(response.wait()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<A: Allocator> Future for PendingCall<'_, '_, A> {
+    type Output = Result<(), OsError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let response = match &mut this.response {
+            Some(response) => response,
+            None => return Poll::Ready(Ok(())),
+        };
+        match Pin::new(response).poll(cx) {
+            Poll::Ready(response) => {
+                this.response = None;
+                Poll::Ready(this.mailbox.complete(response))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<A: Allocator> Drop for PendingCall<'_, '_, A> {
+    fn drop(&mut self) {
+        if let Some(response) = self.response.take() {
+            // The result is lost, but the firmware must be done with the buffer.
+            let _ = self.mailbox.complete(response.wait());
+        }
+    }
+}
+
 /// # Safety
 ///
 /// This is a wildly unsafe trait to use - no compiler guarantees!
@@ -232,4 +318,36 @@ pub unsafe trait PropertyTag {
     fn send_buffer(&self) -> &[u8] {
         unsafe { &*ptr::slice_from_raw_parts(self as *const Self as _, mem::size_of_val(self)) }
     }
+
+    /// Space to reserve for the response. Tags whose response length varies override this, and
+    /// are read with `Mailbox::read_tag_bytes`.
+    fn response_capacity(&self) -> usize {
+        mem::size_of::<Self::RecvType>()
+    }
+}
+
+/// Run `f` with a mailbox whose buffer lives on the stack.
+///
+/// This is meant for the few requests made before an allocator is available, e.g. while
+/// initialising the drivers.
+pub fn with_stack_mailbox<R>(
+    f: impl FnOnce(&mut Mailbox<FixedSliceAlloc>) -> Result<R, OsError>,
+) -> Result<R, OsError> {
+    #[repr(align(16))]
+    struct MboxArr {
+        buf: [u8; 256],
+    }
+    let mut mbox_arr = MboxArr { buf: [0; 256] };
+    let alloc = FixedSliceAlloc::new(&mut mbox_arr.buf);
+    let mut mailbox = Mailbox::new(&alloc)?;
+    f(&mut mailbox)
+}
+
+/// Send `tag` on its own and return the response.
+pub fn query_property<T: PropertyTag>(tag: T) -> Result<T::RecvType, OsError> {
+    with_stack_mailbox(|mbox| {
+        let handle = mbox.append_tag(tag)?;
+        mbox.call()?;
+        mbox.read_tag_result(handle)
+    })
 }
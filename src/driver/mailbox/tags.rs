@@ -0,0 +1,582 @@
+//! Property tags for querying and configuring the board through the firmware.
+//!
+//! See <https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface> for the full
+//! list. The tags drivers need on their own have a function sending them, `BoardInfo` combines
+//! the others in one `Mailbox` call.
+
+use core::{
+    alloc::{AllocError, Allocator},
+    fmt, str,
+};
+
+use std_alloc::vec::Vec;
+
+use crate::error::OsError;
+
+use super::{query_property, Mailbox, PropertyTag};
+
+// Hardware
+
+pub struct GetFirmwareRevision;
+
+unsafe impl PropertyTag for GetFirmwareRevision {
+    type RecvType = u32;
+
+    fn identifier(&self) -> u32 {
+        0x0000_0001
+    }
+}
+
+pub struct GetBoardRevision;
+
+unsafe impl PropertyTag for GetBoardRevision {
+    type RecvType = u32;
+
+    fn identifier(&self) -> u32 {
+        0x0001_0002
+    }
+}
+
+pub struct GetBoardMacAddress;
+
+unsafe impl PropertyTag for GetBoardMacAddress {
+    type RecvType = [u8; 6];
+
+    fn identifier(&self) -> u32 {
+        0x0001_0003
+    }
+}
+
+pub struct GetBoardSerial;
+
+unsafe impl PropertyTag for GetBoardSerial {
+    type RecvType = u64;
+
+    fn identifier(&self) -> u32 {
+        0x0001_0004
+    }
+}
+
+/// A board revision code, which identifies the model and its memory size.
+///
+/// See <https://www.raspberrypi.com/documentation/computers/raspberry-pi.html#raspberry-pi-revision-codes>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardRevision(pub u32);
+
+impl BoardRevision {
+    const NEW_STYLE: u32 = 1 << 23;
+
+    /// Old style codes are only used by the first models, which have no 64 bit CPU.
+    fn is_new_style(&self) -> bool {
+        self.0 & Self::NEW_STYLE != 0
+    }
+
+    pub fn model(&self) -> Option<&'static str> {
+        if !self.is_new_style() {
+            return None;
+        }
+        let name = match self.0 >> 4 & 0xFF {
+            0x00 => "A",
+            0x01 => "B",
+            0x02 => "A+",
+            0x03 => "B+",
+            0x04 => "2B",
+            0x06 => "CM1",
+            0x08 => "3B",
+            0x09 => "Zero",
+            0x0A => "CM3",
+            0x0C => "Zero W",
+            0x0D => "3B+",
+            0x0E => "3A+",
+            0x10 => "CM3+",
+            0x11 => "4B",
+            0x12 => "Zero 2 W",
+            0x13 => "400",
+            0x14 => "CM4",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    pub fn processor(&self) -> Option<&'static str> {
+        if !self.is_new_style() {
+            return None;
+        }
+        match self.0 >> 12 & 0xF {
+            0 => Some("BCM2835"),
+            1 => Some("BCM2836"),
+            2 => Some("BCM2837"),
+            3 => Some("BCM2711"),
+            _ => None,
+        }
+    }
+
+    /// Returns the memory size in MiB.
+    pub fn memory_size(&self) -> Option<usize> {
+        if !self.is_new_style() {
+            return None;
+        }
+        Some(256 << (self.0 >> 20 & 0x7))
+    }
+
+    pub fn revision(&self) -> u32 {
+        self.0 & 0xF
+    }
+}
+
+impl fmt::Display for BoardRevision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.model(), self.processor(), self.memory_size()) {
+            (Some(model), Some(processor), Some(memory_size)) => write!(
+                f,
+                "Raspberry Pi {} rev 1.{} ({}, {} MiB)",
+                model,
+                self.revision(),
+                processor,
+                memory_size
+            ),
+            _ => write!(f, "unknown board (revision {:#x})", self.0),
+        }
+    }
+}
+
+// Power
+
+/// Devices whose power can be switched through the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum DeviceId {
+    SdCard = 0,
+}
+
+/// Bits of the power state.
+const POWER_ON: u32 = 1 << 0;
+/// When setting the state, wait until the power is stable.
+const POWER_WAIT: u32 = 1 << 1;
+/// In responses, the device does not exist.
+const POWER_NO_DEVICE: u32 = 1 << 1;
+
+#[repr(C)]
+pub struct PowerState {
+    pub device_id: u32,
+    pub state: u32,
+}
+
+#[repr(C)]
+pub struct SetPowerState {
+    pub device_id: u32,
+    pub state: u32,
+}
+
+unsafe impl PropertyTag for SetPowerState {
+    type RecvType = PowerState;
+
+    fn identifier(&self) -> u32 {
+        0x0002_8001
+    }
+}
+
+/// Power `device` on or off, waiting until the power is stable. Returns whether it is on.
+pub fn set_power_state(device: DeviceId, on: bool) -> Result<bool, OsError> {
+    let state = if on {
+        POWER_ON | POWER_WAIT
+    } else {
+        POWER_WAIT
+    };
+    let power = query_property(SetPowerState {
+        device_id: device as u32,
+        state,
+    })?;
+    if power.state & POWER_NO_DEVICE != 0 {
+        return Err(OsError::MailboxCallFailed);
+    }
+    Ok(power.state & POWER_ON != 0)
+}
+
+// Clocks
+
+/// Clocks whose rate can be queried through the property channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ClockId {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+}
+
+#[repr(C)]
+pub struct ClockRate {
+    pub clock_id: u32,
+    pub rate: u32,
+}
+
+#[repr(C)]
+pub struct GetClockRate {
+    pub clock_id: u32,
+}
+
+unsafe impl PropertyTag for GetClockRate {
+    type RecvType = ClockRate;
+
+    fn identifier(&self) -> u32 {
+        0x0003_0002
+    }
+}
+
+#[repr(C)]
+pub struct GetMaxClockRate {
+    pub clock_id: u32,
+}
+
+unsafe impl PropertyTag for GetMaxClockRate {
+    type RecvType = ClockRate;
+
+    fn identifier(&self) -> u32 {
+        0x0003_0004
+    }
+}
+
+#[repr(C)]
+pub struct GetMinClockRate {
+    pub clock_id: u32,
+}
+
+unsafe impl PropertyTag for GetMinClockRate {
+    type RecvType = ClockRate;
+
+    fn identifier(&self) -> u32 {
+        0x0003_0007
+    }
+}
+
+/// Query the current rate of `clock` in Hz.
+pub fn query_clock_rate(clock: ClockId) -> Result<u32, OsError> {
+    let clock_id = clock as u32;
+    Ok(query_property(GetClockRate { clock_id })?.rate)
+}
+
+// Voltages
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum VoltageId {
+    Core = 1,
+}
+
+#[repr(C)]
+pub struct Voltage {
+    pub voltage_id: u32,
+    pub value: u32,
+}
+
+#[repr(C)]
+pub struct GetVoltage {
+    pub voltage_id: u32,
+}
+
+unsafe impl PropertyTag for GetVoltage {
+    type RecvType = Voltage;
+
+    fn identifier(&self) -> u32 {
+        0x0003_0003
+    }
+}
+
+#[repr(C)]
+pub struct GetMaxVoltage {
+    pub voltage_id: u32,
+}
+
+unsafe impl PropertyTag for GetMaxVoltage {
+    type RecvType = Voltage;
+
+    fn identifier(&self) -> u32 {
+        0x0003_0005
+    }
+}
+
+#[repr(C)]
+pub struct GetMinVoltage {
+    pub voltage_id: u32,
+}
+
+unsafe impl PropertyTag for GetMinVoltage {
+    type RecvType = Voltage;
+
+    fn identifier(&self) -> u32 {
+        0x0003_0008
+    }
+}
+
+// Temperature
+
+#[repr(C)]
+pub struct Temperature {
+    pub temperature_id: u32,
+    pub value: u32,
+}
+
+#[repr(C)]
+pub struct GetTemperature {
+    pub temperature_id: u32,
+}
+
+unsafe impl PropertyTag for GetTemperature {
+    type RecvType = Temperature;
+
+    fn identifier(&self) -> u32 {
+        0x0003_0006
+    }
+}
+
+/// The temperature above which the firmware throttles the clocks.
+#[repr(C)]
+pub struct GetMaxTemperature {
+    pub temperature_id: u32,
+}
+
+unsafe impl PropertyTag for GetMaxTemperature {
+    type RecvType = Temperature;
+
+    fn identifier(&self) -> u32 {
+        0x0003_000A
+    }
+}
+
+// Configuration
+
+/// Space reserved for the command line at first, longer ones take a second request.
+const COMMAND_LINE_LEN: usize = 1024;
+
+/// The response is as long as the command line, read it with `Mailbox::read_tag_bytes`.
+pub struct GetCommandLine {
+    /// Space to reserve for the response.
+    pub capacity: usize,
+}
+
+unsafe impl PropertyTag for GetCommandLine {
+    type RecvType = ();
+
+    fn identifier(&self) -> u32 {
+        0x0005_0001
+    }
+
+    fn send_buffer(&self) -> &[u8] {
+        &[]
+    }
+
+    fn response_capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Query the kernel command line passed by the firmware, see `cmdline.txt`.
+///
+/// The response does not fit in a stack mailbox, so this needs an allocator.
+pub fn query_command_line<A: Allocator>(alloc: &A) -> Result<Vec<u8, &A>, OsError> {
+    let mut capacity = COMMAND_LINE_LEN;
+    loop {
+        let mut mailbox = Mailbox::new(alloc)?;
+        let handle = mailbox.append_tag(GetCommandLine { capacity })?;
+        mailbox.call()?;
+        let response = match mailbox.read_tag_bytes(handle) {
+            Ok(response) => response,
+            Err(OsError::MailboxResponseTruncated(len)) if len > capacity => {
+                capacity = len;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        // The firmware may count a terminating NUL.
+        let len = response
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(response.len());
+        let mut command_line = Vec::new_in(alloc);
+        command_line
+            .try_reserve_exact(len)
+            .map_err(|_| AllocError)?;
+        command_line.extend_from_slice(&response[..len]);
+        return Ok(command_line);
+    }
+}
+
+// Summary
+
+/// The answers to the fixed size queries of `BoardInfo`, all sent in one request.
+struct BoardTags {
+    board: Result<BoardRevision, OsError>,
+    firmware: Result<u32, OsError>,
+    serial: Result<u64, OsError>,
+    mac_address: Result<[u8; 6], OsError>,
+    /// Current, min and max rates in Hz.
+    arm_clock: Result<(u32, u32, u32), OsError>,
+    core_clock: Result<(u32, u32, u32), OsError>,
+    /// Current, min and max voltages in µV.
+    core_voltage: Result<(u32, u32, u32), OsError>,
+    /// Current and max temperatures in thousandths of a degree Celsius.
+    temperature: Result<(u32, u32), OsError>,
+}
+
+impl BoardTags {
+    fn query<A: Allocator>(alloc: &A) -> Result<Self, OsError> {
+        let mut mailbox = Mailbox::new(alloc)?;
+        let board = mailbox.append_tag(GetBoardRevision)?;
+        let firmware = mailbox.append_tag(GetFirmwareRevision)?;
+        let serial = mailbox.append_tag(GetBoardSerial)?;
+        let mac_address = mailbox.append_tag(GetBoardMacAddress)?;
+        let mut clocks = [None; 2];
+        for (handles, clock) in clocks.iter_mut().zip([ClockId::Arm, ClockId::Core]) {
+            let clock_id = clock as u32;
+            let rate = mailbox.append_tag(GetClockRate { clock_id })?;
+            let min_rate = mailbox.append_tag(GetMinClockRate { clock_id })?;
+            let max_rate = mailbox.append_tag(GetMaxClockRate { clock_id })?;
+            *handles = Some((rate, min_rate, max_rate));
+        }
+        let voltage_id = VoltageId::Core as u32;
+        let voltage = mailbox.append_tag(GetVoltage { voltage_id })?;
+        let min_voltage = mailbox.append_tag(GetMinVoltage { voltage_id })?;
+        let max_voltage = mailbox.append_tag(GetMaxVoltage { voltage_id })?;
+        let temperature = mailbox.append_tag(GetTemperature { temperature_id: 0 })?;
+        let max_temperature = mailbox.append_tag(GetMaxTemperature { temperature_id: 0 })?;
+        mailbox.call()?;
+
+        let [arm_clock, core_clock] = clocks.map(|handles| {
+            // All were appended above.
+            let (rate, min_rate, max_rate) = handles.unwrap();
+            Ok((
+                mailbox.read_tag_result(rate)?.rate,
+                mailbox.read_tag_result(min_rate)?.rate,
+                mailbox.read_tag_result(max_rate)?.rate,
+            ))
+        });
+        let core_voltage = mailbox.read_tag_result(voltage).and_then(|voltage| {
+            Ok((
+                voltage.value,
+                mailbox.read_tag_result(min_voltage)?.value,
+                mailbox.read_tag_result(max_voltage)?.value,
+            ))
+        });
+        Ok(Self {
+            board: mailbox.read_tag_result(board).map(BoardRevision),
+            firmware: mailbox.read_tag_result(firmware),
+            serial: mailbox.read_tag_result(serial),
+            mac_address: mailbox.read_tag_result(mac_address),
+            arm_clock,
+            core_clock,
+            core_voltage,
+            temperature: mailbox
+                .read_tag_result(temperature)
+                .and_then(|temp| Ok((temp.value, mailbox.read_tag_result(max_temperature)?.value))),
+        })
+    }
+}
+
+/// Displays a voltage in V, to a tenth of a millivolt.
+struct Microvolts(u32);
+
+impl fmt::Display for Microvolts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:04} V",
+            self.0 / 1_000_000,
+            self.0 % 1_000_000 / 100
+        )
+    }
+}
+
+/// Everything the firmware tells about the board. QEMU only answers some of the queries, the
+/// others show their error.
+pub struct BoardInfo<'a, A: Allocator> {
+    tags: Result<BoardTags, OsError>,
+    command_line: Result<Vec<u8, &'a A>, OsError>,
+}
+
+impl<'a, A: Allocator> BoardInfo<'a, A> {
+    /// Ask the firmware, in one request for everything but the command line, which `alloc`
+    /// holds.
+    pub fn query(alloc: &'a A) -> Self {
+        Self {
+            tags: BoardTags::query(alloc),
+            command_line: query_command_line(alloc),
+        }
+    }
+
+    /// The answer picked by `get`, or the error of the whole request.
+    fn tag<T>(&self, get: impl FnOnce(&BoardTags) -> &Result<T, OsError>) -> Result<&T, &OsError> {
+        self.tags.as_ref().and_then(|tags| get(tags).as_ref())
+    }
+}
+
+impl<A: Allocator> fmt::Display for BoardInfo<'_, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tag(|tags| &tags.board) {
+            Ok(revision) => writeln!(f, "Board        : {}", revision)?,
+            Err(err) => writeln!(f, "Board        : {}", err)?,
+        }
+        match self.tag(|tags| &tags.firmware) {
+            Ok(revision) => writeln!(f, "Firmware     : revision {:#010x}", revision)?,
+            Err(err) => writeln!(f, "Firmware     : {}", err)?,
+        }
+        match self.tag(|tags| &tags.serial) {
+            Ok(serial) => writeln!(f, "Serial       : {:016x}", serial)?,
+            Err(err) => writeln!(f, "Serial       : {}", err)?,
+        }
+        match self.tag(|tags| &tags.mac_address) {
+            Ok([a, b, c, d, e, g]) => writeln!(
+                f,
+                "MAC address  : {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                a, b, c, d, e, g
+            )?,
+            Err(err) => writeln!(f, "MAC address  : {}", err)?,
+        }
+        for (name, clock) in [
+            ("ARM clock    ", self.tag(|tags| &tags.arm_clock)),
+            ("Core clock   ", self.tag(|tags| &tags.core_clock)),
+        ] {
+            match clock {
+                Ok((rate, min_rate, max_rate)) => writeln!(
+                    f,
+                    "{}: {} MHz (min {} MHz, max {} MHz)",
+                    name,
+                    rate / 1_000_000,
+                    min_rate / 1_000_000,
+                    max_rate / 1_000_000
+                )?,
+                Err(err) => writeln!(f, "{}: {}", name, err)?,
+            }
+        }
+        match self.tag(|tags| &tags.core_voltage) {
+            Ok(&(voltage, min_voltage, max_voltage)) => writeln!(
+                f,
+                "Core voltage : {} (min {}, max {})",
+                Microvolts(voltage),
+                Microvolts(min_voltage),
+                Microvolts(max_voltage)
+            )?,
+            Err(err) => writeln!(f, "Core voltage : {}", err)?,
+        }
+        match self.tag(|tags| &tags.temperature) {
+            Ok((temp, max_temp)) => writeln!(
+                f,
+                "Temperature  : {}.{} °C (max {}.{} °C)",
+                temp / 1000,
+                temp % 1000 / 100,
+                max_temp / 1000,
+                max_temp % 1000 / 100
+            )?,
+            Err(err) => writeln!(f, "Temperature  : {}", err)?,
+        }
+        match &self.command_line {
+            Ok(command_line) => writeln!(
+                f,
+                "Command line : {}",
+                str::from_utf8(command_line).unwrap_or("<invalid UTF-8>")
+            ),
+            Err(err) => writeln!(f, "Command line : {}", err),
+        }
+    }
+}
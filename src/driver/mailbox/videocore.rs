@@ -0,0 +1,308 @@
+//! The mailboxes between the ARM and the VideoCore. Mailbox 0 carries messages from the
+//! VideoCore, mailbox 1 messages to it. Each message is 32 bits: the channel in the lowest 4,
+//! the data (usually the address of a buffer) in the others.
+//!
+//! Responses are collected from the mailbox IRQ once the handler is registered, and by polling
+//! before that or with IRQs masked.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use cortex_a::asm;
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+use crate::{
+    driver::{
+        interrupt::{IrqHandler, IrqNumber, INTERRUPT_CONTROLLER},
+        mmio::{MMIODerefWrapper, MMIO_BASE},
+        DeviceDriver,
+    },
+    error::OsError,
+    exception,
+    sync::IrqSafeNullLock,
+};
+
+const VIDEOCORE_MBOX_OFFSET: usize = 0x0000_B880;
+const VIDEOCORE_MBOX_BASE: usize = MMIO_BASE + VIDEOCORE_MBOX_OFFSET;
+
+register_bitfields! {
+    u32,
+
+    Mbox_Status [
+        FULL 31,
+        EMPTY 30,
+    ],
+
+    Mbox0_Config [
+        /// Raise the mailbox IRQ while mailbox 0 is not empty.
+        DATA_IRQ_ENABLE 0,
+    ],
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => Mbox0_Read: ReadOnly<u32>),
+        (0x04 => _reserved1),
+        (0x18 => Mbox0_Status: ReadOnly<u32, Mbox_Status::Register>),
+        (0x1C => Mbox0_Config: ReadWrite<u32, Mbox0_Config::Register>),
+        (0x20 => Mbox1_Write: WriteOnly<u32>),
+        (0x24 => _reserved2),
+        (0x38 => Mbox1_Status: ReadOnly<u32, Mbox_Status::Register>),
+        (0x3C => @END),
+    }
+}
+
+type Registers = MMIODerefWrapper<RegisterBlock>;
+
+const CHANNEL_MASK: u32 = 0xF;
+const NUM_CHANNELS: usize = CHANNEL_MASK as usize + 1;
+
+/// The mailbox channels, see
+/// <https://github.com/raspberrypi/firmware/wiki/Mailboxes>.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Channel {
+    PowerManagement = 0,
+    Framebuffer = 1,
+    VirtualUart = 2,
+    Vchiq = 3,
+    Leds = 4,
+    Buttons = 5,
+    TouchScreen = 6,
+    /// Property tags from the ARM to the VideoCore, see `Mailbox`.
+    PropertyArmToVc = 8,
+    PropertyVcToArm = 9,
+}
+
+/// The state of a channel's request.
+enum Slot {
+    Idle,
+    /// Sent, the response is not there yet. The waker is woken once it is.
+    Waiting(Option<Waker>),
+    Answered(u32),
+    /// Whoever sent the request gave up on it, the response is discarded.
+    Abandoned,
+}
+
+type Wakers = [Option<Waker>; NUM_CHANNELS];
+
+struct VideoCoreMailboxInner {
+    registers: Registers,
+    slots: [Slot; NUM_CHANNELS],
+    /// Whether the IRQ handler collects the responses.
+    irq_enabled: bool,
+    /// Number of responses nobody was waiting for.
+    stray_responses: usize,
+}
+
+pub struct VideoCoreMailbox {
+    inner: IrqSafeNullLock<VideoCoreMailboxInner>,
+}
+
+/// A request sent on a channel. The response is collected with `wait` or by awaiting it.
+///
+/// Dropping it before the response arrived discards the response, but whatever the request
+/// refers to must stay valid until the VideoCore is done with it.
+pub struct PendingResponse {
+    channel: Channel,
+}
+
+impl VideoCoreMailboxInner {
+    const fn new() -> Self {
+        const IDLE: Slot = Slot::Idle;
+        Self {
+            registers: unsafe { Registers::new(VIDEOCORE_MBOX_BASE) },
+            slots: [IDLE; NUM_CHANNELS],
+            irq_enabled: false,
+            stray_responses: 0,
+        }
+    }
+
+    fn send(&mut self, channel: Channel, data: u32) -> Result<(), OsError> {
+        assert!(
+            data & CHANNEL_MASK == 0,
+            "mailbox data must be 16 byte aligned"
+        );
+        let slot = &mut self.slots[channel as usize];
+        if !matches!(slot, Slot::Idle) {
+            return Err(OsError::MailboxBusy);
+        }
+        *slot = Slot::Waiting(None);
+
+        while self
+            .registers
+            .Mbox1_Status
+            .matches_all(Mbox_Status::FULL::SET)
+        {
+            asm::nop();
+        }
+        self.registers.Mbox1_Write.set(data | channel as u32);
+        Ok(())
+    }
+
+    /// Move all messages of mailbox 0 into their channel's slot. The wakers of the requests
+    /// answered are moved to `wakers`, to be woken once the lock is released.
+    fn drain(&mut self, wakers: &mut Wakers) {
+        while !self
+            .registers
+            .Mbox0_Status
+            .matches_all(Mbox_Status::EMPTY::SET)
+        {
+            let message = self.registers.Mbox0_Read.get();
+            let channel = (message & CHANNEL_MASK) as usize;
+            let slot = &mut self.slots[channel];
+            match slot {
+                Slot::Waiting(waker) => {
+                    wakers[channel] = waker.take();
+                    *slot = Slot::Answered(message & !CHANNEL_MASK);
+                }
+                Slot::Abandoned => *slot = Slot::Idle,
+                Slot::Idle | Slot::Answered(_) => self.stray_responses += 1,
+            }
+        }
+    }
+
+    /// Returns the response on `channel`, if it arrived.
+    fn take(&mut self, channel: Channel, wakers: &mut Wakers) -> Option<u32> {
+        self.drain(wakers);
+        let slot = &mut self.slots[channel as usize];
+        match *slot {
+            Slot::Answered(data) => {
+                *slot = Slot::Idle;
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    fn abandon(&mut self, channel: Channel) {
+        let slot = &mut self.slots[channel as usize];
+        match slot {
+            Slot::Waiting(_) => *slot = Slot::Abandoned,
+            Slot::Answered(_) => *slot = Slot::Idle,
+            Slot::Idle | Slot::Abandoned => {}
+        }
+    }
+}
+
+impl VideoCoreMailbox {
+    const fn new() -> Self {
+        Self {
+            inner: IrqSafeNullLock::new(VideoCoreMailboxInner::new()),
+        }
+    }
+
+    /// Send `data` on `channel`, without waiting for the response. The lowest 4 bits of `data`
+    /// must be clear. Each channel has at most one request in flight, sending another one
+    /// before the response was collected fails with `OsError::MailboxBusy`.
+    pub fn send(&self, channel: Channel, data: u32) -> Result<PendingResponse, OsError> {
+        self.inner.lock(|inner| inner.send(channel, data))?;
+        Ok(PendingResponse { channel })
+    }
+
+    /// Number of responses nobody was waiting for.
+    #[allow(dead_code)]
+    pub fn stray_responses(&self) -> usize {
+        self.inner.lock(|inner| inner.stray_responses)
+    }
+
+    /// Run `f` with the inner state and wake the wakers of the requests it found answered.
+    fn with_inner<R>(&self, f: impl FnOnce(&mut VideoCoreMailboxInner, &mut Wakers) -> R) -> R {
+        let mut wakers = Wakers::default();
+        let ret = self.inner.lock(|inner| f(inner, &mut wakers));
+        for waker in wakers.into_iter().flatten() {
+            waker.wake();
+        }
+        ret
+    }
+}
+
+impl PendingResponse {
+    /// Returns the response, if it arrived. The data is returned without the channel.
+    pub fn try_take(&mut self) -> Option<u32> {
+        VIDEOCORE_MAILBOX.with_inner(|inner, wakers| inner.take(self.channel, wakers))
+    }
+
+    /// Wait for the response. Other interrupts are serviced in the meantime.
+    pub fn wait(self) -> u32 {
+        // With IRQs masked, nothing would wake us up from WFI. The mailbox is polled either way.
+        let irq_unmasked = !exception::asynchronous::is_local_irq_masked();
+        loop {
+            let response = VIDEOCORE_MAILBOX.with_inner(|inner, wakers| {
+                let response = inner.take(self.channel, wakers);
+                if response.is_none() && inner.irq_enabled && irq_unmasked {
+                    // IRQs are masked while the lock is held, so a response arriving after the
+                    // check above still wakes us up from WFI. Its handler runs once the lock is
+                    // released.
+                    asm::wfi();
+                }
+                response
+            });
+            if let Some(response) = response {
+                return response;
+            }
+        }
+    }
+}
+
+impl Future for PendingResponse {
+    type Output = u32;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+        let channel = self.channel;
+        VIDEOCORE_MAILBOX.with_inner(|inner, wakers| match inner.take(channel, wakers) {
+            Some(response) => Poll::Ready(response),
+            None => {
+                inner.slots[channel as usize] = Slot::Waiting(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        VIDEOCORE_MAILBOX
+            .inner
+            .lock(|inner| inner.abandon(self.channel));
+    }
+}
+
+impl DeviceDriver for VideoCoreMailbox {
+    fn init(&self) {
+        self.inner.lock(|inner| {
+            inner
+                .registers
+                .Mbox0_Config
+                .write(Mbox0_Config::DATA_IRQ_ENABLE::CLEAR)
+        });
+    }
+
+    fn register_irq_handler(&'static self) {
+        INTERRUPT_CONTROLLER.register_handler(IrqNumber::ARM_MAILBOX, "VideoCore mailbox", self);
+        self.inner.lock(|inner| {
+            inner.irq_enabled = true;
+            inner
+                .registers
+                .Mbox0_Config
+                .write(Mbox0_Config::DATA_IRQ_ENABLE::SET);
+        });
+    }
+}
+
+impl IrqHandler for VideoCoreMailbox {
+    fn handle_irq(&self) {
+        self.with_inner(|inner, wakers| inner.drain(wakers));
+    }
+}
+
+pub static VIDEOCORE_MAILBOX: VideoCoreMailbox = VideoCoreMailbox::new();
@@ -1,11 +1,24 @@
 use core::fmt::{self, Write};
 
-use crate::{print, sync::NullLock};
+use crate::{
+    collections::ring_buffer::RingBuffer,
+    error::OsError,
+    exception,
+    fs::{
+        devfs::{self, Device},
+        DeviceNumber,
+    },
+    print,
+    sync::IrqSafeNullLock,
+    tty::MINI_UART_TTY,
+};
 
 use super::{
     gpio::GPIO,
+    interrupt::{IrqHandler, IrqNumber, INTERRUPT_CONTROLLER},
+    mailbox::tags::{self, ClockId},
     mmio::{MMIODerefWrapper, MMIO_BASE},
-    DeviceDriver,
+    serial_console_port, DeviceDriver, SerialConsole, SerialPort,
 };
 use cortex_a::asm;
 use tock_registers::{
@@ -20,12 +33,40 @@ const MINI_UART_BASE: usize = MMIO_BASE + MINI_UART_OFFSET;
 register_bitfields! {
     u32,
 
+    AUX_IRQ [
+        Spi2 2,
+        Spi1 1,
+        MiniUart 0,
+    ],
+
     AUX_ENABLE [
         Spi2 2,
         Spi1 1,
         MiniUart 0,
     ],
 
+    /// Interrupt enable register. Bits 0 and 1 are swapped in the datasheet, see the BCM2835
+    /// errata.
+    AUX_MU_IER [
+        /// Marked as don't care in the datasheet, but receive interrupts only fire with both bits
+        /// set, see the BCM2835 errata.
+        RxEnable OFFSET(2) NUMBITS(2) [],
+        TxInterrupt OFFSET(1) NUMBITS(1) [],
+        RxInterrupt OFFSET(0) NUMBITS(1) [],
+    ],
+
+    AUX_MU_IIR [
+        /// Reads as `0b01` when the transmit holding register is empty and `0b10` when the receiver
+        /// holds a valid byte. On write, bit 1 clears the receive FIFO and bit 2 the transmit FIFO.
+        InterruptId OFFSET(1) NUMBITS(2) [
+            None = 0b00,
+            TxEmpty = 0b01,
+            RxReady = 0b10,
+        ],
+        /// Clear when an interrupt is pending.
+        NotPending OFFSET(0) NUMBITS(1) [],
+    ],
+
     AUX_MU_LCR [
         DataSize OFFSET(0) NUMBITS(2) [
             Mode7Bit = 0b00,
@@ -58,12 +99,12 @@ register_bitfields! {
 register_structs! {
     #[allow(non_snake_case)]
     RegisterBlock {
-        (0x00 => _reserved1),
+        (0x00 => AUX_IRQ: ReadOnly<u32, AUX_IRQ::Register>),
         (0x04 => AUX_ENABLE: ReadWrite<u32, AUX_ENABLE::Register>),
         (0x08 => _reserved2),
         (0x40 => AUX_MU_IO: ReadWrite<u32>),
-        (0x44 => AUX_MU_IER: WriteOnly<u32>),
-        (0x48 => AUX_MU_IIR: WriteOnly<u32>),
+        (0x44 => AUX_MU_IER: ReadWrite<u32, AUX_MU_IER::Register>),
+        (0x48 => AUX_MU_IIR: ReadWrite<u32, AUX_MU_IIR::Register>),
         (0x4C => AUX_MU_LCR: WriteOnly<u32, AUX_MU_LCR::Register>),
         (0x50 => AUX_MU_MCR: WriteOnly<u32, AUX_MU_MCR::Register>),
         (0x54 => AUX_MU_LSR: ReadOnly<u32, AUX_MU_LSR::Register>),
@@ -77,18 +118,41 @@ register_structs! {
 
 type Registers = MMIODerefWrapper<RegisterBlock>;
 
+#[derive(PartialEq)]
+enum BlockingMode {
+    Blocking,
+    NonBlocking,
+}
+
+/// The mini UART runs at 8N1, only the baud rate can be chosen.
+const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// VPU core clock assumed if the firmware cannot be asked for it.
+const DEFAULT_CORE_CLOCK: u32 = 250_000_000;
+
+/// Size of the software receive buffer, filled from the RX interrupt.
+const RX_BUFFER_SIZE: usize = 256;
+
 struct MiniUartInner {
     registers: Registers,
+    core_clock: u32,
+    rx_buffer: RingBuffer<u8, RX_BUFFER_SIZE>,
+    rx_dropped: usize,
 }
 
 impl MiniUartInner {
     const fn new() -> Self {
         Self {
             registers: unsafe { Registers::new(MINI_UART_BASE) },
+            core_clock: DEFAULT_CORE_CLOCK,
+            rx_buffer: RingBuffer::new(0),
+            rx_dropped: 0,
         }
     }
 
     fn init(&mut self) {
+        self.core_clock = tags::query_clock_rate(ClockId::Core).unwrap_or(DEFAULT_CORE_CLOCK);
+
         // Init UART
         self.registers.AUX_ENABLE.modify(AUX_ENABLE::MiniUart::SET);
         self.registers
@@ -99,15 +163,46 @@ impl MiniUartInner {
             .write(AUX_MU_LCR::DataSize::Mode8Bit);
         self.registers.AUX_MU_MCR.write(AUX_MU_MCR::RTS::CLEAR);
         self.registers.AUX_MU_IER.set(0);
-        self.registers.AUX_MU_BAUD.set(270);
+        // Clear both FIFOs.
+        self.registers.AUX_MU_IIR.set(0b110);
+        if self.set_baud_rate(DEFAULT_BAUD_RATE).is_err() {
+            self.core_clock = DEFAULT_CORE_CLOCK;
+            self.set_baud_rate(DEFAULT_BAUD_RATE).unwrap();
+        }
 
-        GPIO.map_uart1_pins();
+        // The mini UART shares its pins with the PL011, only take them if we are the console.
+        if serial_console_port() == SerialPort::MiniUart {
+            GPIO.map_uart1_pins();
+        }
+
+        // The interrupt only fires once the handler is registered. QEMU raises it without
+        // `RxEnable`, real hardware doesn't.
+        self.registers
+            .AUX_MU_IER
+            .write(AUX_MU_IER::RxInterrupt::SET + AUX_MU_IER::RxEnable.val(0b11));
 
         self.registers
             .AUX_MU_CNTL
             .write(AUX_MU_CNTL::Tx::SET + AUX_MU_CNTL::Rx::SET);
     }
 
+    /// Set the baud rate register.
+    ///
+    /// The mini UART derives its baud rate from the VPU core clock:
+    /// `baud_rate = core_clock / (8 * (AUX_MU_BAUD + 1))`. With a 250 MHz core clock and 115_200
+    /// baud this gives `250_000_000 / (8 * 115_200) - 1 = 270`.
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), OsError> {
+        if baud_rate == 0 {
+            return Err(OsError::InvalidBaudRate(baud_rate));
+        }
+        let divisor = (self.core_clock + 4 * baud_rate) / (8 * baud_rate);
+        if divisor == 0 || divisor > 0x1_0000 {
+            return Err(OsError::InvalidBaudRate(baud_rate));
+        }
+        self.registers.AUX_MU_BAUD.set(divisor - 1);
+        Ok(())
+    }
+
     fn putc(&mut self, c: u8) {
         while !self
             .registers
@@ -118,6 +213,59 @@ impl MiniUartInner {
         }
         self.registers.AUX_MU_IO.set(c as u32);
     }
+
+    /// Read one character from the RX FIFO, if it is not empty.
+    fn read_fifo(&mut self) -> Option<u8> {
+        if !self
+            .registers
+            .AUX_MU_LSR
+            .matches_all(AUX_MU_LSR::DataReady::SET)
+        {
+            return None;
+        }
+        Some(self.registers.AUX_MU_IO.get() as u8)
+    }
+
+    /// Receive a character, see `PL011UartInner::read_char`.
+    fn read_char(&mut self, blocking_mode: BlockingMode) -> Option<char> {
+        loop {
+            if let Some(c) = self.rx_buffer.pop().or_else(|| self.read_fifo()) {
+                return Some(c as char);
+            }
+
+            if blocking_mode == BlockingMode::NonBlocking {
+                return None;
+            }
+
+            while !self
+                .registers
+                .AUX_MU_LSR
+                .matches_all(AUX_MU_LSR::DataReady::SET)
+            {
+                asm::nop();
+            }
+        }
+    }
+
+    /// Drain the RX FIFO into the software buffer.
+    ///
+    /// The AUX interrupt is shared with the SPI masters, so check that it is really ours.
+    fn handle_rx_irq(&mut self) {
+        if !self.registers.AUX_IRQ.is_set(AUX_IRQ::MiniUart) {
+            return;
+        }
+        // Reading the FIFO empty clears the interrupt.
+        while let Some(c) = self.read_fifo() {
+            if self.rx_buffer.push(c).is_err() {
+                self.rx_dropped += 1;
+            }
+        }
+    }
+
+    fn clear_rx(&mut self) {
+        self.rx_buffer.clear();
+        self.registers.AUX_MU_IIR.set(0b010);
+    }
 }
 
 impl fmt::Write for MiniUartInner {
@@ -130,15 +278,28 @@ impl fmt::Write for MiniUartInner {
 }
 
 pub struct MiniUart {
-    inner: NullLock<MiniUartInner>,
+    inner: IrqSafeNullLock<MiniUartInner>,
 }
 
 impl MiniUart {
     const fn new() -> Self {
         Self {
-            inner: NullLock::new(MiniUartInner::new()),
+            inner: IrqSafeNullLock::new(MiniUartInner::new()),
         }
     }
+
+    /// Change the baud rate. Fails, without changing anything, if it cannot be generated from the
+    /// core clock.
+    #[allow(dead_code)]
+    pub fn set_baud_rate(&self, baud_rate: u32) -> Result<(), OsError> {
+        self.inner.lock(|inner| inner.set_baud_rate(baud_rate))
+    }
+
+    /// Number of received characters dropped because the software buffer was full.
+    #[allow(dead_code)]
+    pub fn rx_dropped(&self) -> usize {
+        self.inner.lock(|inner| inner.rx_dropped)
+    }
 }
 
 impl print::Write for MiniUart {
@@ -147,10 +308,78 @@ impl print::Write for MiniUart {
     }
 }
 
+impl SerialConsole for MiniUart {
+    fn write_bytes(&self, buf: &[u8]) {
+        self.inner.lock(|inner| {
+            for &byte in buf {
+                inner.putc(byte);
+            }
+        });
+    }
+}
+
+impl print::Read for MiniUart {
+    fn read_char(&self) -> char {
+        if exception::asynchronous::is_local_irq_masked() {
+            // Nobody drains the FIFO into the buffer, so wait on the FIFO itself.
+            return self
+                .inner
+                .lock(|inner| inner.read_char(BlockingMode::Blocking))
+                .unwrap();
+        }
+
+        loop {
+            let c = self.inner.lock(|inner| {
+                let c = inner.read_char(BlockingMode::NonBlocking);
+                if c.is_none() {
+                    // See `PL011Uart::read_char` for why this is done with the lock held.
+                    asm::wfi();
+                }
+                c
+            });
+            if let Some(c) = c {
+                return c;
+            }
+        }
+    }
+
+    fn try_read_char(&self) -> Option<char> {
+        self.inner
+            .lock(|inner| inner.read_char(BlockingMode::NonBlocking))
+    }
+
+    fn clear_rx(&self) {
+        self.inner.lock(|inner| inner.clear_rx());
+    }
+}
+
 impl DeviceDriver for MiniUart {
     fn init(&self) {
         self.inner.lock(|inner| inner.init());
     }
+
+    fn register_irq_handler(&'static self) {
+        INTERRUPT_CONTROLLER.register_handler(IrqNumber::AUX, "Mini UART", self);
+    }
+
+    fn register_device_nodes(&'static self) -> Result<(), OsError> {
+        let number = DeviceNumber {
+            major: 4,
+            minor: 64,
+        };
+        devfs::register(
+            format_args!("ttyS0"),
+            number,
+            0o620,
+            Device::Char(&MINI_UART_TTY),
+        )
+    }
+}
+
+impl IrqHandler for MiniUart {
+    fn handle_irq(&self) {
+        self.inner.lock(|inner| inner.handle_rx_irq());
+    }
 }
 
 pub static MINI_UART: MiniUart = MiniUart::new();
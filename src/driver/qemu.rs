@@ -24,9 +24,9 @@ pub static QEMU_OUTPUT: QEMUOutput = QEMUOutput::new();
 
 impl fmt::Write for QEMUOutputInner {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
+        for byte in s.bytes() {
             unsafe {
-                ptr::write_volatile(0x3F20_1000 as *mut u8, c as u8);
+                ptr::write_volatile(0x3F20_1000 as *mut u8, byte);
             }
         }
         Ok(())
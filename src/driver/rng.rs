@@ -0,0 +1,135 @@
+use tock_registers::{
+    interfaces::{ReadWriteable, Readable, Writeable},
+    register_bitfields, register_structs,
+    registers::ReadWrite,
+};
+
+use crate::{
+    error::OsError,
+    fs::{
+        devfs::{self, CharDevice, Device},
+        DeviceNumber,
+    },
+    sync::NullLock,
+};
+
+use super::{
+    mmio::{MMIODerefWrapper, MMIO_BASE},
+    DeviceDriver,
+};
+
+const RNG_OFFSET: usize = 0x0010_4000;
+const RNG_BASE: usize = MMIO_BASE + RNG_OFFSET;
+
+/// Numbers the generator throws away after being enabled, as they are not random enough.
+const WARMUP_COUNT: u32 = 0x4_0000;
+
+register_bitfields! {
+    u32,
+
+    RNG_CTRL [
+        Enable 0,
+    ],
+
+    RNG_STATUS [
+        /// Number of words ready to be read from `RNG_DATA`.
+        WordsAvailable OFFSET(24) NUMBITS(8) [],
+        WarmupCount OFFSET(0) NUMBITS(20) [],
+    ],
+
+    RNG_INT_MASK [
+        InterruptOff 0,
+    ]
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RegisterBlock {
+        (0x00 => RNG_CTRL: ReadWrite<u32, RNG_CTRL::Register>),
+        (0x04 => RNG_STATUS: ReadWrite<u32, RNG_STATUS::Register>),
+        (0x08 => RNG_DATA: ReadWrite<u32>),
+        (0x0C => _reserved),
+        (0x10 => RNG_INT_MASK: ReadWrite<u32, RNG_INT_MASK::Register>),
+        (0x14 => @END),
+    }
+}
+
+type Registers = MMIODerefWrapper<RegisterBlock>;
+
+struct RngInner {
+    registers: Registers,
+}
+
+impl RngInner {
+    const fn new() -> Self {
+        Self {
+            registers: unsafe { Registers::new(RNG_BASE) },
+        }
+    }
+
+    fn init(&mut self) {
+        self.registers
+            .RNG_STATUS
+            .write(RNG_STATUS::WarmupCount.val(WARMUP_COUNT));
+        self.registers
+            .RNG_INT_MASK
+            .modify(RNG_INT_MASK::InterruptOff::SET);
+        self.registers.RNG_CTRL.modify(RNG_CTRL::Enable::SET);
+    }
+
+    /// Wait for a random word.
+    fn next_word(&mut self) -> u32 {
+        while self.registers.RNG_STATUS.read(RNG_STATUS::WordsAvailable) == 0 {
+            core::hint::spin_loop();
+        }
+        self.registers.RNG_DATA.get()
+    }
+}
+
+/// The hardware random number generator.
+pub struct Rng {
+    inner: NullLock<RngInner>,
+}
+
+impl Rng {
+    const fn new() -> Self {
+        Self {
+            inner: NullLock::new(RngInner::new()),
+        }
+    }
+
+    /// Fill `buf` with random bytes.
+    pub fn fill(&self, buf: &mut [u8]) {
+        self.inner.lock(|inner| {
+            for chunk in buf.chunks_mut(4) {
+                let word = inner.next_word().to_le_bytes();
+                chunk.copy_from_slice(&word[..chunk.len()]);
+            }
+        });
+    }
+}
+
+impl DeviceDriver for Rng {
+    fn init(&self) {
+        self.inner.lock(|inner| inner.init());
+    }
+
+    fn register_device_nodes(&'static self) -> Result<(), OsError> {
+        let number = DeviceNumber { major: 1, minor: 8 };
+        devfs::register(format_args!("random"), number, 0o666, Device::Char(self))
+    }
+}
+
+impl CharDevice for Rng {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        self.fill(buf);
+        Ok(buf.len())
+    }
+
+    /// Writes are taken, as on Linux, but do not feed the generator.
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        Ok(buf.len())
+    }
+}
+
+pub static RNG: Rng = Rng::new();
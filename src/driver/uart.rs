@@ -1,4 +1,24 @@
-use crate::{driver, driver::mmio::MMIODerefWrapper, print, sync::NullLock};
+use crate::{
+    collections::ring_buffer::RingBuffer,
+    driver,
+    driver::{
+        gpio::GPIO,
+        interrupt::{IrqHandler, IrqNumber, INTERRUPT_CONTROLLER},
+        mailbox::tags::{self, ClockId},
+        mmio::MMIODerefWrapper,
+        serial_console_port, SerialConsole, SerialPort,
+    },
+    error::OsError,
+    exception,
+    fs::{
+        devfs::{self, Device},
+        DeviceNumber,
+    },
+    print,
+    sync::IrqSafeNullLock,
+    tty::PL011_TTY,
+};
+use bitflags::bitflags;
 use core::fmt;
 use cortex_a::asm;
 use tock_registers::{
@@ -18,6 +38,47 @@ const PL011_BASE: usize = MMIO_BASE + PL011_OFFSET;
 register_bitfields! {
     u32,
 
+    /// Data Register.
+    DR [
+        /// Overrun error. This bit is set to 1 if data is received and the receive FIFO is already
+        /// full.
+        OE OFFSET(11) NUMBITS(1) [],
+
+        /// Break error. This bit is set to 1 if a break condition was detected, indicating that
+        /// the received data input was held LOW for longer than a full-word transmission time.
+        BE OFFSET(10) NUMBITS(1) [],
+
+        /// Parity error. When set to 1, it indicates that the parity of the received data
+        /// character does not match the parity that the EPS and SPS bits in the Line Control
+        /// Register, UARTLCR_H select.
+        PE OFFSET(9) NUMBITS(1) [],
+
+        /// Framing error. When set to 1, it indicates that the received character did not have a
+        /// valid stop bit (a valid stop bit is 1).
+        FE OFFSET(8) NUMBITS(1) [],
+
+        /// Data character.
+        DATA OFFSET(0) NUMBITS(8) []
+    ],
+
+    /// Receive Status Register / Error Clear Register.
+    ///
+    /// The error flags are also reported with each character in DR. A write to this register
+    /// clears the framing, parity, break, and overrun errors.
+    RSR [
+        /// Overrun error.
+        OE OFFSET(3) NUMBITS(1) [],
+
+        /// Break error.
+        BE OFFSET(2) NUMBITS(1) [],
+
+        /// Parity error.
+        PE OFFSET(1) NUMBITS(1) [],
+
+        /// Framing error.
+        FE OFFSET(0) NUMBITS(1) []
+    ],
+
     /// Flag Register.
     FR [
         /// Transmit FIFO empty. The meaning of this bit depends on the state of the FEN bit in the
@@ -65,6 +126,13 @@ register_bitfields! {
 
     /// Line Control Register.
     LCR_H [
+        /// Stick parity select.
+        ///
+        /// - If the PEN, EPS and SPS bits are set, the parity bit is transmitted and checked as 0.
+        /// - If the PEN and SPS bits are set and EPS is cleared, the parity bit is transmitted and
+        ///   checked as 1.
+        SPS OFFSET(7) NUMBITS(1) [],
+
         /// Word length. These bits indicate the number of data bits transmitted or received in a
         /// frame.
         #[allow(clippy::enum_variant_names)]
@@ -84,11 +152,47 @@ register_bitfields! {
         FEN  OFFSET(4) NUMBITS(1) [
             FifosDisabled = 0,
             FifosEnabled = 1
-        ]
+        ],
+
+        /// Two stop bits select. If this bit is set to 1, two stop bits are transmitted at the end
+        /// of the frame. The receive logic does not check for two stop bits being received.
+        STP2 OFFSET(3) NUMBITS(1) [],
+
+        /// Even parity select. Controls the type of parity the UART uses during transmission and
+        /// reception. Has no effect when parity is disabled.
+        EPS OFFSET(2) NUMBITS(1) [
+            Odd = 0,
+            Even = 1
+        ],
+
+        /// Parity enable.
+        PEN OFFSET(1) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Send break. If this bit is set to 1, a low-level is continually output on the TXD
+        /// output, after completing transmission of the current character.
+        BRK OFFSET(0) NUMBITS(1) []
     ],
 
     /// Control Register.
     CR [
+        /// CTS hardware flow control enable. If this bit is set to 1, CTS hardware flow control is
+        /// enabled. Data is only transmitted when the nUARTCTS signal is asserted.
+        CTSEN OFFSET(15) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// RTS hardware flow control enable. If this bit is set to 1, RTS hardware flow control is
+        /// enabled. Data is only requested when there is space in the receive FIFO for it to be
+        /// received.
+        RTSEN OFFSET(14) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
         /// Receive enable. If this bit is set to 1, the receive section of the UART is enabled.
         /// Data reception occurs for either UART signals or SIR signals depending on the setting of
         /// the SIREN bit. When the UART is disabled in the middle of reception, it completes the
@@ -122,6 +226,60 @@ register_bitfields! {
         ]
     ],
 
+    /// Interrupt FIFO Level Select Register.
+    IFLS [
+        /// Receive interrupt FIFO level select. The trigger points for the receive interrupt.
+        RXIFLSEL OFFSET(3) NUMBITS(3) [
+            OneEigth = 0b000,
+            OneQuarter = 0b001,
+            OneHalf = 0b010,
+            ThreeQuarters = 0b011,
+            SevenEights = 0b100
+        ],
+
+        /// Transmit interrupt FIFO level select. The trigger points for the transmit interrupt.
+        TXIFLSEL OFFSET(0) NUMBITS(3) [
+            OneEigth = 0b000,
+            OneQuarter = 0b001,
+            OneHalf = 0b010,
+            ThreeQuarters = 0b011,
+            SevenEights = 0b100
+        ]
+    ],
+
+    /// Interrupt Mask Set/Clear Register.
+    IMSC [
+        /// Receive timeout interrupt mask. A read returns the current mask for the UARTRTINTR
+        /// interrupt.
+        ///
+        /// - On a write of 1, the mask of the UARTRTINTR interrupt is set.
+        /// - A write of 0 clears the mask.
+        RTIM OFFSET(6) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Receive interrupt mask. A read returns the current mask for the UARTRXINTR interrupt.
+        ///
+        /// - On a write of 1, the mask of the UARTRXINTR interrupt is set.
+        /// - A write of 0 clears the mask.
+        RXIM OFFSET(4) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ],
+
+    /// Masked Interrupt Status Register.
+    MIS [
+        /// Receive timeout masked interrupt status. Returns the masked interrupt state of the
+        /// UARTRTINTR interrupt.
+        RTMIS OFFSET(6) NUMBITS(1) [],
+
+        /// Receive masked interrupt status. Returns the masked interrupt state of the UARTRXINTR
+        /// interrupt.
+        RXMIS OFFSET(4) NUMBITS(1) []
+    ],
+
     /// Interrupt Clear Register.
     ICR [
         /// Meta field for all pending interrupts.
@@ -132,15 +290,19 @@ register_bitfields! {
 register_structs! {
     #[allow(non_snake_case)]
     pub RegisterBlock {
-        (0x00 => DR: ReadWrite<u32>),
-        (0x04 => _reserved1),
+        (0x00 => DR: ReadWrite<u32, DR::Register>),
+        (0x04 => RSR: ReadWrite<u32, RSR::Register>),
+        (0x08 => _reserved1),
         (0x18 => FR: ReadOnly<u32, FR::Register>),
         (0x1c => _reserved2),
         (0x24 => IBRD: WriteOnly<u32, IBRD::Register>),
         (0x28 => FBRD: WriteOnly<u32, FBRD::Register>),
         (0x2c => LCR_H: WriteOnly<u32, LCR_H::Register>),
         (0x30 => CR: WriteOnly<u32, CR::Register>),
-        (0x34 => _reserved3),
+        (0x34 => IFLS: ReadWrite<u32, IFLS::Register>),
+        (0x38 => IMSC: ReadWrite<u32, IMSC::Register>),
+        (0x3C => _reserved3),
+        (0x40 => MIS: ReadOnly<u32, MIS::Register>),
         (0x44 => ICR: WriteOnly<u32, ICR::Register>),
         (0x48 => @END),
     }
@@ -149,19 +311,104 @@ register_structs! {
 /// Abstraction for the associated MMIO registers.
 type Registers = MMIODerefWrapper<RegisterBlock>;
 
-// #[derive(PartialEq)]
-// enum BlockingMode {
-//     Blocking,
-//     NonBlocking,
-// }
+#[derive(PartialEq)]
+enum BlockingMode {
+    Blocking,
+    NonBlocking,
+}
+
+/// FIFO fill level at which the transmit or receive interrupt is raised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoLevel {
+    OneEigth,
+    OneQuarter,
+    OneHalf,
+    ThreeQuarters,
+    SevenEights,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+    /// The parity bit is always 1.
+    Mark,
+    /// The parity bit is always 0.
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Line settings of the UART.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UartConfig {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// Use RTS/CTS hardware flow control.
+    pub flow_control: bool,
+}
+
+/// 8N1 at 921_600 baud, without flow control.
+const DEFAULT_CONFIG: UartConfig = UartConfig {
+    baud_rate: 921_600,
+    data_bits: DataBits::Eight,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+    flow_control: false,
+};
+
+impl Default for UartConfig {
+    fn default() -> Self {
+        DEFAULT_CONFIG
+    }
+}
+
+bitflags! {
+    /// Receive errors reported by the UART.
+    pub struct LineErrors: u32 {
+        const FRAMING = 1 << 0;
+        const PARITY = 1 << 1;
+        const BREAK = 1 << 2;
+        const OVERRUN = 1 << 3;
+    }
+}
+
+/// UART clock assumed if the firmware cannot be asked for it. This is the value we set in
+/// config.txt.
+const DEFAULT_UART_CLOCK: u32 = 48_000_000;
+
+/// Size of the software receive buffer, filled from the RX interrupt.
+const RX_BUFFER_SIZE: usize = 256;
 
 struct PL011UartInner {
     registers: Registers,
+    config: UartConfig,
+    /// RX and TX interrupt trigger levels, kept across reconfigurations.
+    fifo_levels: (FifoLevel, FifoLevel),
+    clock_rate: u32,
+    rx_buffer: RingBuffer<u8, RX_BUFFER_SIZE>,
+    rx_dropped: usize,
+    line_errors: LineErrors,
 }
 
 /// Representation of the UART.
 pub struct PL011Uart {
-    inner: NullLock<PL011UartInner>,
+    inner: IrqSafeNullLock<PL011UartInner>,
 }
 
 impl PL011UartInner {
@@ -173,27 +420,65 @@ impl PL011UartInner {
     const fn new() -> Self {
         Self {
             registers: unsafe { Registers::new(PL011_BASE) },
+            config: DEFAULT_CONFIG,
+            fifo_levels: (FifoLevel::OneHalf, FifoLevel::OneEigth),
+            clock_rate: DEFAULT_UART_CLOCK,
+            rx_buffer: RingBuffer::new(0),
+            rx_dropped: 0,
+            line_errors: LineErrors::empty(),
         }
     }
 
-    /// Set up baud rate and characteristics.
-    ///
-    /// This results in 8N1 and 921_600 baud.
+    /// Set up the UART with the default configuration, see `DEFAULT_CONFIG`.
     ///
-    /// The calculation for the BRD is (we set the clock to 48 MHz in config.txt):
-    /// `(48_000_000 / 16) / 921_600 = 3.2552083`.
+    /// The UART clock is queried from the firmware, so that the baud rate divisors are correct
+    /// regardless of what config.txt sets it to.
+    fn init(&mut self) {
+        self.clock_rate = tags::query_clock_rate(ClockId::Uart).unwrap_or(DEFAULT_UART_CLOCK);
+        // The default baud rate is reachable with any sensible clock. Should the firmware report
+        // something odd, fall back to the clock we assume in config.txt.
+        if self.configure(self.config).is_err() {
+            self.clock_rate = DEFAULT_UART_CLOCK;
+            self.configure(DEFAULT_CONFIG).unwrap();
+        }
+
+        // The PL011 shares its pins with the mini UART, only take them if we are the console.
+        if serial_console_port() == SerialPort::Pl011 {
+            GPIO.map_uart0_pins();
+        }
+    }
+
+    /// Compute the baud rate divisors for `baud_rate`.
     ///
-    /// This means the integer part is `3` and goes into the `IBRD`.
-    /// The fractional part is `0.2552083`.
+    /// The baud rate divisor is `BRD = UARTCLK / (16 * baud_rate)`. Its integer part goes into
+    /// `IBRD`, and according to the PL011 Technical Reference Manual `FBRD` is
+    /// `INTEGER((fractional part * 64) + 0.5)`.
     ///
-    /// `FBRD` calculation according to the PL011 Technical Reference Manual:
-    /// `INTEGER((0.2552083 * 64) + 0.5) = 16`.
+    /// Working in 64ths, `BRD * 64 = UARTCLK * 4 / baud_rate`, rounded to the nearest integer.
+    /// The upper bits are the integer part and the lower 6 bits the fractional part.
     ///
-    /// Therefore, the generated baud rate divider is: `3 + 16/64 = 3.25`. Which results in a
-    /// genrated baud rate of `48_000_000 / (16 * 3.25) = 923_077`.
+    /// For example, with a 48 MHz clock and 921_600 baud: `(48_000_000 * 4) / 921_600 = 208.33`,
+    /// so `IBRD = 208 / 64 = 3` and `FBRD = 208 % 64 = 16`, resulting in a generated baud rate of
+    /// `48_000_000 / (16 * 3.25) = 923_077` (an error of 0.16%).
+    fn baud_divisors(&self, baud_rate: u32) -> Result<(u32, u32), OsError> {
+        if baud_rate == 0 {
+            return Err(OsError::InvalidBaudRate(baud_rate));
+        }
+        let baud = baud_rate as u64;
+        let div = (self.clock_rate as u64 * 4 + baud / 2) / baud;
+        let (int, frac) = (div >> 6, div & 0x3F);
+        if int == 0 || int > 0xFFFF {
+            return Err(OsError::InvalidBaudRate(baud_rate));
+        }
+        Ok((int as u32, frac as u32))
+    }
+
+    /// Apply `config`, reprogramming the UART.
     ///
-    /// Error = `((923_077 - 921_600) / 921_600) * 100 = 0.16%`.
-    fn init(&mut self) {
+    /// Nothing is changed if the baud rate cannot be generated from the UART clock.
+    fn configure(&mut self, config: UartConfig) -> Result<(), OsError> {
+        let (ibrd, fbrd) = self.baud_divisors(config.baud_rate)?;
+
         // Execution can arrive here while there are still characters queued in the TX FIFO and
         // actively being sent out by the UART hardware. If the UART is turned off in this case,
         // those queued characters would be lost.
@@ -207,8 +492,9 @@ impl PL011UartInner {
         // Turn the UART off temporarily.
         self.registers.CR.set(0);
 
-        // Clear all pending interrupts.
+        // Clear all pending interrupts and errors.
         self.registers.ICR.write(ICR::ALL::CLEAR);
+        self.registers.RSR.set(0);
 
         // From the PL011 Technical Reference Manual:
         //
@@ -216,28 +502,63 @@ impl PL011UartInner {
         // updated on a single write strobe generated by a LCR_H write. So, to internally update the
         // contents of IBRD or FBRD, a LCR_H write must always be performed at the end.
         //
-        // Set the baud rate, 8N1 and FIFO enabled.
-        self.registers.IBRD.write(IBRD::BAUD_DIVINT.val(3));
-        self.registers.FBRD.write(FBRD::BAUD_DIVFRAC.val(16));
+        // Set the baud rate, line settings and FIFO enabled.
+        self.registers.IBRD.write(IBRD::BAUD_DIVINT.val(ibrd));
+        self.registers.FBRD.write(FBRD::BAUD_DIVFRAC.val(fbrd));
+
+        let wlen = match config.data_bits {
+            DataBits::Five => LCR_H::WLEN::FiveBit,
+            DataBits::Six => LCR_H::WLEN::SixBit,
+            DataBits::Seven => LCR_H::WLEN::SevenBit,
+            DataBits::Eight => LCR_H::WLEN::EightBit,
+        };
+        let parity = match config.parity {
+            Parity::None => LCR_H::PEN::Disabled,
+            Parity::Even => LCR_H::PEN::Enabled + LCR_H::EPS::Even,
+            Parity::Odd => LCR_H::PEN::Enabled + LCR_H::EPS::Odd,
+            Parity::Mark => LCR_H::PEN::Enabled + LCR_H::EPS::Odd + LCR_H::SPS::SET,
+            Parity::Space => LCR_H::PEN::Enabled + LCR_H::EPS::Even + LCR_H::SPS::SET,
+        };
+        let stop_bits = match config.stop_bits {
+            StopBits::One => LCR_H::STP2::CLEAR,
+            StopBits::Two => LCR_H::STP2::SET,
+        };
         self.registers
             .LCR_H
-            .write(LCR_H::WLEN::EightBit + LCR_H::FEN::FifosEnabled);
+            .write(wlen + parity + stop_bits + LCR_H::FEN::FifosEnabled);
+
+        // Raise the RX interrupt at the trigger level, half full unless changed, and the RX
+        // timeout interrupt for anything less than that. The interrupts only fire once the
+        // handler is registered.
+        let (rx_level, tx_level) = self.fifo_levels;
+        self.set_fifo_trigger_levels(rx_level, tx_level);
+        self.registers
+            .IMSC
+            .write(IMSC::RXIM::Enabled + IMSC::RTIM::Enabled);
 
         // Turn the UART on.
+        let flow_control = if config.flow_control {
+            CR::CTSEN::Enabled + CR::RTSEN::Enabled
+        } else {
+            CR::CTSEN::Disabled + CR::RTSEN::Disabled
+        };
         self.registers
             .CR
-            .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled);
+            .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled + flow_control);
+
+        self.config = config;
+        Ok(())
     }
 
-    /// Send a character.
-    fn write_char(&mut self, c: char) {
+    /// Send a byte.
+    fn write_byte(&mut self, byte: u8) {
         // Spin while TX FIFO full is set, waiting for an empty slot.
         while self.registers.FR.matches_all(FR::TXFF::SET) {
             asm::nop();
         }
 
-        // Write the character to the buffer.
-        self.registers.DR.set(c as u32);
+        // Write the byte to the buffer.
+        self.registers.DR.set(byte as u32);
     }
 
     /// Block execution until the last buffered character has been physically put on the TX wire.
@@ -248,29 +569,108 @@ impl PL011UartInner {
         }
     }
 
-    // fn read_char_converting(&mut self, blocking_mode: BlockingMode) -> Option<char> {
-    //     if self.registers.FR.matches_all(FR::RXFE::SET) {
-    //         // immediately return in non-blocking mode.
-    //         if blocking_mode == BlockingMode::NonBlocking {
-    //             return None;
-    //         }
-
-    //         // Otherwise, wait until a char was received.
-    //         while self.registers.FR.matches_all(FR::RXFE::SET) {
-    //             asm::nop();
-    //         }
-    //     }
-
-    //     // Read one character.
-    //     let mut ret = self.registers.DR.get() as u8 as char;
-
-    //     // Convert carrige return to newline.
-    //     if ret == '\r' {
-    //         ret = '\n'
-    //     }
-
-    //     Some(ret)
-    // }
+    /// Set the FIFO levels at which the receive and transmit interrupts are raised. They stay
+    /// when the line settings change.
+    fn set_fifo_trigger_levels(&mut self, rx: FifoLevel, tx: FifoLevel) {
+        self.fifo_levels = (rx, tx);
+        let rx = match rx {
+            FifoLevel::OneEigth => IFLS::RXIFLSEL::OneEigth,
+            FifoLevel::OneQuarter => IFLS::RXIFLSEL::OneQuarter,
+            FifoLevel::OneHalf => IFLS::RXIFLSEL::OneHalf,
+            FifoLevel::ThreeQuarters => IFLS::RXIFLSEL::ThreeQuarters,
+            FifoLevel::SevenEights => IFLS::RXIFLSEL::SevenEights,
+        };
+        let tx = match tx {
+            FifoLevel::OneEigth => IFLS::TXIFLSEL::OneEigth,
+            FifoLevel::OneQuarter => IFLS::TXIFLSEL::OneQuarter,
+            FifoLevel::OneHalf => IFLS::TXIFLSEL::OneHalf,
+            FifoLevel::ThreeQuarters => IFLS::TXIFLSEL::ThreeQuarters,
+            FifoLevel::SevenEights => IFLS::TXIFLSEL::SevenEights,
+        };
+        self.registers.IFLS.write(rx + tx);
+    }
+
+    /// Read one character from the RX FIFO, if it is not empty.
+    ///
+    /// Receive errors flagged for the character are recorded in `line_errors`. The character is
+    /// still returned, it is up to the reader to decide what to do with it.
+    fn read_fifo(&mut self) -> Option<u8> {
+        if self.registers.FR.matches_all(FR::RXFE::SET) {
+            return None;
+        }
+        let data = self.registers.DR.extract();
+
+        let mut errors = LineErrors::empty();
+        errors.set(LineErrors::FRAMING, data.is_set(DR::FE));
+        errors.set(LineErrors::PARITY, data.is_set(DR::PE));
+        errors.set(LineErrors::BREAK, data.is_set(DR::BE));
+        errors.set(LineErrors::OVERRUN, data.is_set(DR::OE));
+        if !errors.is_empty() {
+            self.line_errors |= errors;
+            // Clear the sticky copy of the flags in RSR as well.
+            self.registers.RSR.set(0);
+        }
+
+        Some(data.read(DR::DATA) as u8)
+    }
+
+    /// Return the receive errors seen since the last call, and clear them.
+    fn take_line_errors(&mut self) -> LineErrors {
+        let rsr = self.registers.RSR.extract();
+        if rsr.is_set(RSR::OE) {
+            self.line_errors |= LineErrors::OVERRUN;
+            self.registers.RSR.set(0);
+        }
+        let errors = self.line_errors;
+        self.line_errors = LineErrors::empty();
+        errors
+    }
+
+    /// Receive a character.
+    ///
+    /// Characters already moved into the software buffer by the interrupt handler are returned
+    /// first. The hardware FIFO is checked as well, so this also works with IRQs masked.
+    fn read_char(&mut self, blocking_mode: BlockingMode) -> Option<char> {
+        loop {
+            if let Some(c) = self.rx_buffer.pop().or_else(|| self.read_fifo()) {
+                return Some(c as char);
+            }
+
+            // Immediately return in non-blocking mode.
+            if blocking_mode == BlockingMode::NonBlocking {
+                return None;
+            }
+
+            // Otherwise, wait until a char was received.
+            while self.registers.FR.matches_all(FR::RXFE::SET) {
+                asm::nop();
+            }
+        }
+    }
+
+    /// Drain the RX FIFO into the software buffer.
+    fn handle_rx_irq(&mut self) {
+        let pending = self.registers.MIS.extract();
+        if !pending.is_set(MIS::RXMIS) && !pending.is_set(MIS::RTMIS) {
+            return;
+        }
+
+        while let Some(c) = self.read_fifo() {
+            if self.rx_buffer.push(c).is_err() {
+                self.rx_dropped += 1;
+            }
+        }
+
+        // The RX interrupt clears itself once the FIFO is below the trigger level, but the
+        // timeout interrupt has to be cleared explicitly.
+        self.registers.ICR.write(ICR::ALL::CLEAR);
+    }
+
+    /// Discard all received characters, both buffered and in the FIFO.
+    fn clear_rx(&mut self) {
+        self.rx_buffer.clear();
+        while self.read_fifo().is_some() {}
+    }
 }
 
 /// Implementing `core::fmt::Write` enables usage of the `format_args!` macros, which in turn are
@@ -284,8 +684,8 @@ impl PL011UartInner {
 /// [`src/print.rs`]: ../../print/index.html
 impl fmt::Write for PL011UartInner {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            self.write_char(c);
+        for byte in s.bytes() {
+            self.write_byte(byte);
         }
 
         Ok(())
@@ -295,15 +695,72 @@ impl fmt::Write for PL011UartInner {
 impl PL011Uart {
     const fn new() -> Self {
         Self {
-            inner: NullLock::new(PL011UartInner::new()),
+            inner: IrqSafeNullLock::new(PL011UartInner::new()),
         }
     }
+
+    /// Set the FIFO levels at which the receive and transmit interrupts are raised.
+    #[allow(dead_code)]
+    pub fn set_fifo_trigger_levels(&self, rx: FifoLevel, tx: FifoLevel) {
+        self.inner
+            .lock(|inner| inner.set_fifo_trigger_levels(rx, tx));
+    }
+
+    /// Change the line settings at runtime.
+    ///
+    /// Pending output is flushed before the UART is reprogrammed. Fails, without changing
+    /// anything, if the baud rate cannot be generated from the UART clock.
+    #[allow(dead_code)]
+    pub fn configure(&self, config: UartConfig) -> Result<(), OsError> {
+        self.inner.lock(|inner| inner.configure(config))
+    }
+
+    /// The line settings currently in use.
+    #[allow(dead_code)]
+    pub fn config(&self) -> UartConfig {
+        self.inner.lock(|inner| inner.config)
+    }
+
+    /// Receive errors (framing, parity, break, overrun) seen since the last call.
+    #[allow(dead_code)]
+    pub fn take_line_errors(&self) -> LineErrors {
+        self.inner.lock(|inner| inner.take_line_errors())
+    }
+
+    /// Number of received characters dropped because the software buffer was full.
+    #[allow(dead_code)]
+    pub fn rx_dropped(&self) -> usize {
+        self.inner.lock(|inner| inner.rx_dropped)
+    }
 }
 
 impl driver::DeviceDriver for PL011Uart {
     fn init(&self) {
         self.inner.lock(|inner| inner.init());
     }
+
+    fn register_irq_handler(&'static self) {
+        INTERRUPT_CONTROLLER.register_handler(IrqNumber::PL011_UART, "PL011 UART", self);
+    }
+
+    fn register_device_nodes(&'static self) -> Result<(), OsError> {
+        let number = DeviceNumber {
+            major: 204,
+            minor: 64,
+        };
+        devfs::register(
+            format_args!("ttyAMA0"),
+            number,
+            0o620,
+            Device::Char(&PL011_TTY),
+        )
+    }
+}
+
+impl IrqHandler for PL011Uart {
+    fn handle_irq(&self) {
+        self.inner.lock(|inner| inner.handle_rx_irq());
+    }
 }
 
 impl print::Write for PL011Uart {
@@ -312,4 +769,51 @@ impl print::Write for PL011Uart {
     }
 }
 
+impl SerialConsole for PL011Uart {
+    fn write_bytes(&self, buf: &[u8]) {
+        self.inner.lock(|inner| {
+            for &byte in buf {
+                inner.write_byte(byte);
+            }
+        });
+    }
+}
+
+impl print::Read for PL011Uart {
+    fn read_char(&self) -> char {
+        if exception::asynchronous::is_local_irq_masked() {
+            // Nobody drains the FIFO into the buffer, so wait on the FIFO itself.
+            return self
+                .inner
+                .lock(|inner| inner.read_char(BlockingMode::Blocking))
+                .unwrap();
+        }
+
+        loop {
+            let c = self.inner.lock(|inner| {
+                let c = inner.read_char(BlockingMode::NonBlocking);
+                if c.is_none() {
+                    // IRQs are masked while the lock is held, so a character arriving after the
+                    // check above still wakes us up from WFI. Its handler runs once the lock is
+                    // released.
+                    asm::wfi();
+                }
+                c
+            });
+            if let Some(c) = c {
+                return c;
+            }
+        }
+    }
+
+    fn try_read_char(&self) -> Option<char> {
+        self.inner
+            .lock(|inner| inner.read_char(BlockingMode::NonBlocking))
+    }
+
+    fn clear_rx(&self) {
+        self.inner.lock(|inner| inner.clear_rx());
+    }
+}
+
 pub static PL011_UART: PL011Uart = PL011Uart::new();
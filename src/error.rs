@@ -9,6 +9,47 @@ pub enum OsError {
     Layout(LayoutError),
     InvalidDepth(u32),
     FramebufferNotAllocated,
+    MailboxCallFailed,
+    MailboxRequestRejected,
+    MailboxTagNotAnswered(u32),
+    MailboxInvalidResponse(u32),
+    MailboxResponseTruncated(usize),
+    MailboxBusy,
+    InvalidBaudRate(u32),
+    InvalidGpioPin(u32),
+    NoSdCard,
+    SdUnsupportedCard,
+    SdTimeout(&'static str),
+    SdCommandTimeout(u32),
+    SdCommandFailed(u32, u32),
+    InvalidBufferSize(usize),
+    BlockOutOfRange(u64),
+    InvalidPartitionTable(&'static str),
+    CorruptFilesystem(&'static str),
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    InvalidFileName,
+    NoSpace,
+    FileTooLarge,
+    NameTooLong,
+    TooManySymlinks,
+    BadFileDescriptor,
+    TooManyOpenFiles,
+    InvalidArgument,
+    NotSupported,
+    ReadOnlyFilesystem,
+    Busy,
+    BadAddress,
+    InvalidIoctl(u32),
+    Interrupted,
+    ConsoleTooSmall,
+    InvalidFont(&'static str),
+    InvalidImage(&'static str),
+    InvalidArchive(&'static str),
+    InvalidElf(&'static str),
 }
 
 impl From<AllocError> for OsError {
@@ -30,6 +71,63 @@ impl fmt::Display for OsError {
             OsError::Layout(err) => write!(f, "{}", err),
             OsError::InvalidDepth(depth) => write!(f, "depth of {} not supported", depth),
             OsError::FramebufferNotAllocated => write!(f, "failed to allocate framebuffer"),
+            OsError::MailboxCallFailed => write!(f, "mailbox call did not succeed"),
+            OsError::MailboxRequestRejected => write!(f, "firmware rejected the mailbox request"),
+            OsError::MailboxTagNotAnswered(tag) => {
+                write!(f, "mailbox tag {:#010x} was not answered", tag)
+            }
+            OsError::MailboxInvalidResponse(tag) => {
+                write!(f, "invalid response to mailbox tag {:#010x}", tag)
+            }
+            OsError::MailboxResponseTruncated(len) => {
+                write!(f, "mailbox response truncated, {} bytes needed", len)
+            }
+            OsError::MailboxBusy => write!(f, "mailbox channel busy"),
+            OsError::InvalidBaudRate(baud) => write!(f, "baud rate of {} not supported", baud),
+            OsError::InvalidGpioPin(pin) => write!(f, "no GPIO pin {}", pin),
+            OsError::NoSdCard => write!(f, "no SD card"),
+            OsError::SdUnsupportedCard => write!(f, "SD card not supported"),
+            OsError::SdTimeout(what) => {
+                write!(f, "SD host controller timed out waiting for {}", what)
+            }
+            OsError::SdCommandTimeout(cmd) => write!(f, "SD card did not answer CMD{}", cmd),
+            OsError::SdCommandFailed(cmd, status) => {
+                write!(f, "SD CMD{} failed with status {:#010x}", cmd, status)
+            }
+            OsError::InvalidBufferSize(len) => {
+                write!(f, "buffer of {} bytes is not a whole number of blocks", len)
+            }
+            OsError::BlockOutOfRange(block) => write!(f, "block {} is out of range", block),
+            OsError::InvalidPartitionTable(reason) => {
+                write!(f, "invalid partition table: {}", reason)
+            }
+            OsError::CorruptFilesystem(reason) => write!(f, "corrupt filesystem: {}", reason),
+            OsError::NotFound => write!(f, "no such file or directory"),
+            OsError::AlreadyExists => write!(f, "file exists"),
+            OsError::NotADirectory => write!(f, "not a directory"),
+            OsError::IsADirectory => write!(f, "is a directory"),
+            OsError::DirectoryNotEmpty => write!(f, "directory not empty"),
+            OsError::InvalidFileName => write!(f, "invalid file name"),
+            OsError::NoSpace => write!(f, "no space left on device"),
+            OsError::FileTooLarge => write!(f, "file too large"),
+            OsError::NameTooLong => write!(f, "file name too long"),
+            OsError::TooManySymlinks => write!(f, "too many levels of symbolic links"),
+            OsError::BadFileDescriptor => write!(f, "bad file descriptor"),
+            OsError::TooManyOpenFiles => write!(f, "too many open files"),
+            OsError::InvalidArgument => write!(f, "invalid argument"),
+            OsError::NotSupported => write!(f, "operation not supported"),
+            OsError::ReadOnlyFilesystem => write!(f, "read-only file system"),
+            OsError::Busy => write!(f, "device or resource busy"),
+            OsError::BadAddress => write!(f, "bad address"),
+            OsError::InvalidIoctl(request) => {
+                write!(f, "ioctl request {:#x} not supported", request)
+            }
+            OsError::Interrupted => write!(f, "interrupted by a signal"),
+            OsError::ConsoleTooSmall => write!(f, "framebuffer too small for a console"),
+            OsError::InvalidFont(reason) => write!(f, "invalid font: {}", reason),
+            OsError::InvalidImage(reason) => write!(f, "invalid image: {}", reason),
+            OsError::InvalidArchive(reason) => write!(f, "invalid archive: {}", reason),
+            OsError::InvalidElf(reason) => write!(f, "invalid ELF file: {}", reason),
         }
     }
 }
@@ -0,0 +1,141 @@
+use core::{arch::global_asm, cell::UnsafeCell, fmt};
+
+use cortex_a::{asm::barrier, registers::*};
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::{driver::interrupt::INTERRUPT_CONTROLLER, syscall};
+
+pub mod asynchronous;
+
+/// Exception class in `ESR_EL1` of an `svc` instruction in AArch64 state.
+const ESR_EC_SVC64: u64 = 0x15;
+
+global_asm!(include_str!("exception.S"));
+
+/// The register state saved by the vector table entries in `exception.S`.
+///
+/// The layout must match the order in which `CALL_WITH_CONTEXT` stores the registers.
+#[repr(C)]
+pub struct ExceptionContext {
+    /// General purpose registers x0 to x29.
+    gpr: [u64; 30],
+    /// The link register, x30.
+    lr: u64,
+    /// Exception link register, the address to return to.
+    elr_el1: u64,
+    /// Saved program status.
+    spsr_el1: u64,
+    /// Exception syndrome register.
+    esr_el1: u64,
+}
+
+impl fmt::Display for ExceptionContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "ESR_EL1:  {:#018X}", self.esr_el1)?;
+        writeln!(f, "FAR_EL1:  {:#018X}", FAR_EL1.get())?;
+        writeln!(f, "SPSR_EL1: {:#018X}", self.spsr_el1)?;
+        writeln!(f, "ELR_EL1:  {:#018X}", self.elr_el1)?;
+        writeln!(f, "LR:       {:#018X}", self.lr)?;
+        for (i, reg) in self.gpr.iter().enumerate() {
+            write!(f, "x{:<2}: {:#018X}", i, reg)?;
+            if i % 2 == 1 {
+                writeln!(f)?;
+            } else {
+                write!(f, "   ")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_exception_handler(kind: &str, ctx: &ExceptionContext) -> ! {
+    panic!("Unexpected {} exception\n{}", kind, ctx);
+}
+
+#[no_mangle]
+extern "C" fn current_el0_synchronous(ctx: &mut ExceptionContext) {
+    default_exception_handler("synchronous (EL0 stack)", ctx);
+}
+
+#[no_mangle]
+extern "C" fn current_el0_irq(ctx: &mut ExceptionContext) {
+    default_exception_handler("IRQ (EL0 stack)", ctx);
+}
+
+#[no_mangle]
+extern "C" fn current_el0_serror(ctx: &mut ExceptionContext) {
+    default_exception_handler("SError (EL0 stack)", ctx);
+}
+
+#[no_mangle]
+extern "C" fn current_elx_synchronous(ctx: &mut ExceptionContext) {
+    default_exception_handler("synchronous", ctx);
+}
+
+#[no_mangle]
+extern "C" fn current_elx_irq(_ctx: &mut ExceptionContext) {
+    INTERRUPT_CONTROLLER.handle_pending_irqs();
+}
+
+#[no_mangle]
+extern "C" fn current_elx_serror(ctx: &mut ExceptionContext) {
+    default_exception_handler("SError", ctx);
+}
+
+/// Syscalls of the user code which `process::exec` runs.
+#[no_mangle]
+extern "C" fn lower_aarch64_synchronous(ctx: &mut ExceptionContext) {
+    if ctx.esr_el1 >> 26 != ESR_EC_SVC64 {
+        default_exception_handler("synchronous (lower EL)", ctx);
+    }
+    // `ELR_EL1` already points past the `svc`. Syscalls may block waiting for interrupts, so
+    // they run with IRQs unmasked.
+    let mut args = [0; 6];
+    args.copy_from_slice(&ctx.gpr[..6]);
+    unsafe {
+        asynchronous::local_irq_unmask();
+        ctx.gpr[0] = syscall::dispatch(ctx.gpr[8], args) as u64;
+    }
+    asynchronous::local_irq_mask();
+}
+
+#[no_mangle]
+extern "C" fn lower_aarch64_irq(_ctx: &mut ExceptionContext) {
+    INTERRUPT_CONTROLLER.handle_pending_irqs();
+}
+
+#[no_mangle]
+extern "C" fn lower_aarch64_serror(ctx: &mut ExceptionContext) {
+    default_exception_handler("SError (lower EL)", ctx);
+}
+
+#[no_mangle]
+extern "C" fn lower_aarch32_synchronous(ctx: &mut ExceptionContext) {
+    default_exception_handler("synchronous (AArch32)", ctx);
+}
+
+#[no_mangle]
+extern "C" fn lower_aarch32_irq(ctx: &mut ExceptionContext) {
+    default_exception_handler("IRQ (AArch32)", ctx);
+}
+
+#[no_mangle]
+extern "C" fn lower_aarch32_serror(ctx: &mut ExceptionContext) {
+    default_exception_handler("SError (AArch32)", ctx);
+}
+
+extern "Rust" {
+    static __exception_vector_start: UnsafeCell<()>;
+}
+
+/// Point `VBAR_EL1` at the vector table.
+///
+/// # Safety
+///
+/// Must be called once, before any interrupts are unmasked.
+pub unsafe fn handling_init() {
+    VBAR_EL1.set(__exception_vector_start.get() as u64);
+
+    // Force VBAR update to complete before next instruction.
+    barrier::isb(barrier::SY);
+}
@@ -0,0 +1,60 @@
+use core::arch::asm;
+
+use cortex_a::registers::DAIF;
+use tock_registers::interfaces::{Readable, Writeable};
+
+mod daif_bits {
+    pub const IRQ: u8 = 0b0010;
+}
+
+pub fn is_local_irq_masked() -> bool {
+    DAIF.is_set(DAIF::I)
+}
+
+/// Unmask IRQs on the executing core.
+///
+/// # Safety
+///
+/// Handlers for every enabled interrupt must be registered before this is called.
+#[inline(always)]
+pub unsafe fn local_irq_unmask() {
+    asm!(
+        "msr DAIFClr, {arg}",
+        arg = const daif_bits::IRQ,
+        options(nomem, nostack, preserves_flags)
+    );
+}
+
+/// Mask IRQs on the executing core.
+#[inline(always)]
+pub fn local_irq_mask() {
+    unsafe {
+        asm!(
+            "msr DAIFSet, {arg}",
+            arg = const daif_bits::IRQ,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+/// Mask IRQs on the executing core and return the previous DAIF state.
+#[inline(always)]
+fn local_irq_mask_save() -> u64 {
+    let saved = DAIF.get();
+    local_irq_mask();
+    saved
+}
+
+/// Restore the DAIF state saved by `local_irq_mask_save`.
+#[inline(always)]
+fn local_irq_restore(saved: u64) {
+    DAIF.set(saved);
+}
+
+/// Run `f` with IRQs masked on the executing core.
+pub fn exec_with_irq_masked<T>(f: impl FnOnce() -> T) -> T {
+    let saved = local_irq_mask_save();
+    let ret = f();
+    local_irq_restore(saved);
+    ret
+}
@@ -1,9 +1,107 @@
-use crate::driver::framebuffer::Framebuffer;
+use crate::{driver::framebuffer::Pixel, graphics::DrawTarget};
 
+pub mod bdf;
 pub mod psf;
 
+/// How a glyph is placed relative to the pen position, which lies on the baseline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlyphMetrics {
+    /// Size of the glyph's bitmap.
+    pub width: usize,
+    pub height: usize,
+    /// Offset of the bitmap's left edge to the right of the pen position.
+    pub left: isize,
+    /// Offset of the bitmap's top edge above the baseline.
+    pub top: isize,
+    /// How far the pen moves to the right after the glyph.
+    pub advance: usize,
+}
+
+/// The colors text is drawn in.
+#[derive(Debug, Clone, Copy)]
+pub struct TextStyle {
+    pub fg: Pixel,
+    /// Fills the box of each glyph, `advance` wide and a line high. Without it, only the glyph
+    /// itself is drawn.
+    pub bg: Option<Pixel>,
+}
+
 pub trait Font {
-    fn render_char(&self, ch: char, fb: &mut Framebuffer, x: usize, y: usize);
+    /// Distance from the top of a line to the baseline.
+    fn ascent(&self) -> usize;
+
+    /// Distance between the tops of two lines.
+    fn line_height(&self) -> usize;
+
+    fn metrics(&self, ch: char) -> GlyphMetrics;
+
+    /// Draw `ch` with the top left corner of its box at (`x`, `y`). Returns how far to advance
+    /// to the next character.
+    fn render_char(
+        &self,
+        ch: char,
+        target: &mut dyn DrawTarget,
+        x: isize,
+        y: isize,
+        style: &TextStyle,
+    ) -> usize;
+
+    /// Draw `s` on a single line starting at (`x`, `y`). Returns its width.
+    fn render_str(
+        &self,
+        s: &str,
+        target: &mut dyn DrawTarget,
+        x: isize,
+        y: isize,
+        style: &TextStyle,
+    ) -> usize {
+        let mut width = 0;
+        for ch in s.chars() {
+            width += self.render_char(ch, target, x + width as isize, y, style);
+        }
+        width
+    }
+
+    /// Returns the width of `s` drawn on a single line.
+    fn text_width(&self, s: &str) -> usize {
+        s.chars().map(|ch| self.metrics(ch).advance).sum()
+    }
+}
 
-    fn render_str(&self, s: &str, fb: &mut Framebuffer, x: usize, y: usize);
+/// Draw a `width` x `height` glyph bitmap with its top left corner at (`x`, `y`). `is_set`
+/// returns whether the bit at (row, column) is set. Set bits are drawn in `fg`, the others in
+/// `bg` if given.
+fn draw_bitmap(
+    target: &mut dyn DrawTarget,
+    x: isize,
+    y: isize,
+    (width, height): (usize, usize),
+    is_set: impl Fn(usize, usize) -> bool,
+    fg: Pixel,
+    bg: Option<Pixel>,
+) {
+    match bg {
+        Some(bg) => {
+            // Opaque glyphs are drawn a row at a time, in chunks of up to `CHUNK` pixels.
+            const CHUNK: usize = 32;
+            let mut row_pixels = [bg; CHUNK];
+            for row in 0..height {
+                for start in (0..width).step_by(CHUNK) {
+                    let len = CHUNK.min(width - start);
+                    for (i, pixel) in row_pixels[..len].iter_mut().enumerate() {
+                        *pixel = if is_set(row, start + i) { fg } else { bg };
+                    }
+                    let row_x = x + start as isize;
+                    target.blit(row_x, y + row as isize, len, &row_pixels[..len]);
+                }
+            }
+        }
+        None => {
+            for row in 0..height {
+                for col in (0..width).filter(|&col| is_set(row, col)) {
+                    target.draw_pixel(x + col as isize, y + row as isize, fg);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,271 @@
+//! Proportional bitmap fonts in the Glyph Bitmap Distribution Format (BDF).
+
+use core::{
+    alloc::{AllocError, Allocator},
+    str,
+};
+
+use std_alloc::vec::Vec;
+
+use crate::{
+    error::OsError,
+    graphics::{DrawTarget, Rect},
+};
+
+use super::{draw_bitmap, Font, GlyphMetrics, TextStyle};
+
+/// Glyphs larger than this in either dimension are rejected.
+const MAX_GLYPH_SIZE: usize = 256;
+
+/// Glyphs tried, in order, for characters the font has no glyph for, after `DEFAULT_CHAR`.
+const REPLACEMENT_CHARS: [char; 2] = [char::REPLACEMENT_CHARACTER, '?'];
+
+/// Bounding box of a glyph bitmap, relative to the pen position on the baseline.
+#[derive(Debug, Clone, Copy, Default)]
+struct BoundingBox {
+    width: usize,
+    height: usize,
+    /// Offset of the left edge from the pen position.
+    x_off: isize,
+    /// Offset of the bottom edge above the baseline.
+    y_off: isize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BdfGlyph {
+    ch: char,
+    bbox: BoundingBox,
+    advance: usize,
+    /// Offset into `BdfFont::bitmaps`. Each row starts on a new byte.
+    bitmap_off: usize,
+}
+
+impl BdfGlyph {
+    fn bytes_per_row(&self) -> usize {
+        (self.bbox.width + 7) / 8
+    }
+}
+
+/// A bitmap font parsed from BDF, whose glyphs can differ in size and advance.
+pub struct BdfFont<A: Allocator> {
+    /// Sorted by character.
+    glyphs: Vec<BdfGlyph, A>,
+    bitmaps: Vec<u8, A>,
+    ascent: usize,
+    descent: usize,
+    replacement_glyph: usize,
+}
+
+impl<A: Allocator + Clone> BdfFont<A> {
+    pub fn new(data: &[u8], alloc: A) -> Result<Self, OsError> {
+        let data = str::from_utf8(data).map_err(|_| OsError::InvalidFont("BDF is not UTF-8"))?;
+        let mut lines = data.lines().map(str::trim);
+        match lines.next() {
+            Some(line) if line.starts_with("STARTFONT") => {}
+            _ => return Err(OsError::InvalidFont("missing STARTFONT")),
+        }
+
+        let mut glyphs = Vec::new_in(alloc.clone());
+        let mut bitmaps = Vec::new_in(alloc);
+        let mut font_bbox = BoundingBox::default();
+        let (mut ascent, mut descent) = (None, None);
+        let mut default_char = None;
+        // The glyph being parsed, whether it has an encoding, and whether it has a bitmap.
+        let mut glyph: Option<(BdfGlyph, bool, bool)> = None;
+
+        while let Some(line) = lines.next() {
+            let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+            match (keyword, &mut glyph) {
+                ("FONTBOUNDINGBOX", None) => font_bbox = parse_bbox(args)?,
+                ("FONT_ASCENT", None) => ascent = Some(parse_fields::<1>(args)?[0]),
+                ("FONT_DESCENT", None) => descent = Some(parse_fields::<1>(args)?[0]),
+                ("DEFAULT_CHAR", None) => {
+                    default_char = char::from_u32(parse_fields::<1>(args)?[0] as u32)
+                }
+                ("CHARS", None) => {
+                    let count = parse_fields::<1>(args)?[0] as usize;
+                    glyphs.try_reserve(count).map_err(|_| AllocError)?;
+                }
+                ("STARTCHAR", None) => {
+                    let new_glyph = BdfGlyph {
+                        ch: char::default(),
+                        bbox: font_bbox,
+                        advance: font_bbox.width,
+                        bitmap_off: 0,
+                    };
+                    glyph = Some((new_glyph, false, false));
+                }
+                ("ENCODING", Some((glyph, has_encoding, _))) => {
+                    // Glyphs without a standard encoding have -1, optionally followed by a
+                    // font specific one. They can't be looked up, so they are skipped.
+                    let encoding = args.split_whitespace().next().unwrap_or("");
+                    if let Some(ch) = encoding.parse::<u32>().ok().and_then(char::from_u32) {
+                        glyph.ch = ch;
+                        *has_encoding = true;
+                    }
+                }
+                ("DWIDTH", Some((glyph, _, _))) => {
+                    let [advance, _] = parse_fields::<2>(args)?;
+                    glyph.advance = advance.max(0) as usize;
+                }
+                ("BBX", Some((glyph, _, _))) => glyph.bbox = parse_bbox(args)?,
+                ("BITMAP", Some((glyph, _, has_bitmap))) => {
+                    glyph.bitmap_off = bitmaps.len();
+                    *has_bitmap = true;
+                    let bytes_per_row = glyph.bytes_per_row();
+                    bitmaps
+                        .try_reserve(bytes_per_row * glyph.bbox.height)
+                        .map_err(|_| AllocError)?;
+                    for _ in 0..glyph.bbox.height {
+                        let row = lines
+                            .next()
+                            .ok_or(OsError::InvalidFont("truncated BDF bitmap"))?;
+                        // Rows may be padded beyond the glyph width.
+                        if row.len() < bytes_per_row * 2 || !row.is_char_boundary(bytes_per_row * 2)
+                        {
+                            return Err(OsError::InvalidFont("BDF bitmap row too short"));
+                        }
+                        for i in 0..bytes_per_row {
+                            let byte = u8::from_str_radix(&row[i * 2..i * 2 + 2], 16)
+                                .map_err(|_| OsError::InvalidFont("invalid BDF bitmap"))?;
+                            bitmaps.push(byte);
+                        }
+                    }
+                }
+                ("ENDCHAR", Some((parsed, has_encoding, has_bitmap))) => {
+                    if !*has_bitmap {
+                        return Err(OsError::InvalidFont("BDF glyph has no BITMAP"));
+                    }
+                    // The bitmap was parsed with the bounding box at the time, which a later BBX
+                    // may have changed.
+                    let bitmap_len = parsed.bytes_per_row() * parsed.bbox.height;
+                    if bitmaps.len() - parsed.bitmap_off != bitmap_len {
+                        return Err(OsError::InvalidFont("BDF bitmap doesn't match its BBX"));
+                    }
+                    if *has_encoding {
+                        glyphs.try_reserve(1).map_err(|_| AllocError)?;
+                        glyphs.push(*parsed);
+                    } else {
+                        bitmaps.truncate(parsed.bitmap_off);
+                    }
+                    glyph = None;
+                }
+                ("ENDFONT", None) => break,
+                ("STARTCHAR" | "ENDFONT", Some(_)) => {
+                    return Err(OsError::InvalidFont("missing ENDCHAR"));
+                }
+                _ => {}
+            }
+        }
+        if glyphs.is_empty() {
+            return Err(OsError::InvalidFont("BDF font has no glyphs"));
+        }
+
+        glyphs.sort_unstable_by_key(|glyph| glyph.ch);
+        let find = |ch: char| glyphs.binary_search_by_key(&ch, |glyph| glyph.ch).ok();
+        let replacement_glyph = default_char
+            .into_iter()
+            .chain(REPLACEMENT_CHARS)
+            .find_map(find)
+            .unwrap_or(0);
+
+        // Without the properties, the font bounding box spans from the highest ascender to the
+        // lowest descender.
+        let ascent = ascent.unwrap_or(font_bbox.height as isize + font_bbox.y_off);
+        let descent = descent.unwrap_or(-font_bbox.y_off);
+        Ok(Self {
+            glyphs,
+            bitmaps,
+            ascent: ascent.max(0) as usize,
+            descent: descent.max(0) as usize,
+            replacement_glyph,
+        })
+    }
+
+    /// Returns the glyph for `ch`, or the replacement glyph if the font has none.
+    fn glyph(&self, ch: char) -> &BdfGlyph {
+        let index = self
+            .glyphs
+            .binary_search_by_key(&ch, |glyph| glyph.ch)
+            .unwrap_or(self.replacement_glyph);
+        &self.glyphs[index]
+    }
+}
+
+impl<A: Allocator + Clone> Font for BdfFont<A> {
+    fn ascent(&self) -> usize {
+        self.ascent
+    }
+
+    fn line_height(&self) -> usize {
+        self.ascent + self.descent
+    }
+
+    fn metrics(&self, ch: char) -> GlyphMetrics {
+        let glyph = self.glyph(ch);
+        GlyphMetrics {
+            width: glyph.bbox.width,
+            height: glyph.bbox.height,
+            left: glyph.bbox.x_off,
+            top: glyph.bbox.y_off + glyph.bbox.height as isize,
+            advance: glyph.advance,
+        }
+    }
+
+    fn render_char(
+        &self,
+        ch: char,
+        target: &mut dyn DrawTarget,
+        x: isize,
+        y: isize,
+        style: &TextStyle,
+    ) -> usize {
+        let glyph = self.glyph(ch);
+        let metrics = self.metrics(ch);
+        if let Some(bg) = style.bg {
+            let glyph_box = Rect::new(x, y, glyph.advance, self.line_height());
+            target.fill_rect(glyph_box, bg);
+        }
+
+        let bitmap = &self.bitmaps[glyph.bitmap_off..];
+        let bytes_per_row = glyph.bytes_per_row();
+        let is_set = |row: usize, col: usize| {
+            let byte = bitmap[row * bytes_per_row + col / 8];
+            (byte >> (7 - col % 8)) & 0x1 == 1
+        };
+        let left = x + metrics.left;
+        let top = y + self.ascent as isize - metrics.top;
+        let size = (metrics.width, metrics.height);
+        draw_bitmap(target, left, top, size, is_set, style.fg, None);
+        glyph.advance
+    }
+}
+
+/// Parse `N` whitespace separated integers.
+fn parse_fields<const N: usize>(args: &str) -> Result<[isize; N], OsError> {
+    let mut fields = [0; N];
+    let mut args = args.split_whitespace();
+    for field in &mut fields {
+        *field = args
+            .next()
+            .and_then(|arg| arg.parse().ok())
+            .ok_or(OsError::InvalidFont("invalid BDF number"))?;
+    }
+    Ok(fields)
+}
+
+fn parse_bbox(args: &str) -> Result<BoundingBox, OsError> {
+    let [width, height, x_off, y_off] = parse_fields::<4>(args)?;
+    let valid = |size: isize| (0..=MAX_GLYPH_SIZE as isize).contains(&size);
+    if !valid(width) || !valid(height) {
+        return Err(OsError::InvalidFont("BDF bounding box out of range"));
+    }
+    Ok(BoundingBox {
+        width: width as usize,
+        height: height as usize,
+        x_off,
+        y_off,
+    })
+}
+
+pub static DEFAULT_BDF_FONT_BYTES: &[u8] = include_bytes!("bdf/default16.bdf");
@@ -1,14 +1,41 @@
-use core::mem;
+use core::{
+    alloc::{AllocError, Allocator},
+    mem,
+    ops::ControlFlow,
+    str,
+};
 
-use crate::driver::framebuffer::{Framebuffer, Pixel, PixelOrder};
+use std_alloc::vec::Vec;
 
-use super::Font;
+use crate::{error::OsError, graphics::DrawTarget};
 
-const PSF_MAGIC: u32 = 0x864A_B572;
+use super::{draw_bitmap, Font, GlyphMetrics, TextStyle};
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_LEN: usize = 4;
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HASTAB: u8 = 0x02;
+const PSF1_MODE_HASSEQ: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_START_SEQ: u16 = 0xFFFE;
+
+const PSF2_MAGIC: u32 = 0x864A_B572;
+const PSF2_HEADER_LEN: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_START_SEQ: u8 = 0xFE;
+
+/// Longest multi-codepoint sequence which can be looked up, longer ones are skipped.
+const MAX_SEQUENCE_LEN: usize = 8;
+
+/// Glyph indices are stored as `u16`.
+const MAX_GLYPHS: usize = u16::MAX as usize + 1;
+
+/// Glyphs tried, in order, for characters the font has no glyph for.
+const REPLACEMENT_CHARS: [char; 2] = [char::REPLACEMENT_CHARACTER, '?'];
 
 #[derive(Debug)]
-#[repr(C)]
-struct PsfHeader {
+struct Psf2Header {
     magic: u32,
     version: u32,
     size: u32,
@@ -19,9 +46,45 @@ struct PsfHeader {
     width: u32,
 }
 
-pub struct PsfFont<'a> {
-    header: &'a PsfHeader,
+impl Psf2Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let field = |idx: usize| {
+            let bytes = data.get(idx * 4..idx * 4 + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        Some(Self {
+            magic: field(0)?,
+            version: field(1)?,
+            size: field(2)?,
+            flags: field(3)?,
+            num_glyphs: field(4)?,
+            bytes_per_glyph: field(5)?,
+            height: field(6)?,
+            width: field(7)?,
+        })
+    }
+}
+
+/// The table mapping Unicode characters (and sequences of them) to glyphs. Its format depends
+/// on the PSF version: UTF-8 for PSF2, UCS-2 for PSF1.
+#[derive(Clone, Copy)]
+enum UnicodeTable<'a> {
+    None,
+    Psf1(&'a [u8]),
+    Psf2(&'a [u8]),
+}
+
+/// A PC Screen Font, either PSF1 or PSF2.
+pub struct PsfFont<'a, A: Allocator> {
     glyph_data: &'a [u8],
+    unicode_table: UnicodeTable<'a>,
+    num_glyphs: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+    /// The single characters of the Unicode table with their glyph, sorted by character.
+    char_glyphs: Vec<(char, u16), A>,
+    replacement_glyph: usize,
 }
 
 pub struct Glyph<'a> {
@@ -30,30 +93,292 @@ pub struct Glyph<'a> {
     height: usize,
 }
 
-impl<'a> PsfFont<'a> {
-    pub fn new(data: &'a [u8]) -> Self {
-        let header_len = mem::size_of::<PsfHeader>();
-        let (header_data, rest_data) = (&data[..header_len], &data[header_len..]);
-        let header: &PsfHeader = unsafe { mem::transmute(&*header_data.as_ptr()) };
-        assert!(header.magic == PSF_MAGIC);
-        assert!(header.size as usize == header_len);
+impl<'a, A: Allocator + Clone> PsfFont<'a, A> {
+    pub fn new(data: &'a [u8], alloc: A) -> Result<Self, OsError> {
+        let mut font = if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data, alloc.clone())?
+        } else {
+            Self::parse_psf2(data, alloc.clone())?
+        };
+
+        // Check the whole table once, so that lookups can skip malformed entries silently.
+        let mut num_chars = 0;
+        font.walk_unicode_table(|_, seq| {
+            if seq.len() == 1 {
+                num_chars += 1;
+            }
+            ControlFlow::Continue(())
+        })?;
+        let mut char_glyphs = mem::replace(&mut font.char_glyphs, Vec::new_in(alloc));
+        char_glyphs
+            .try_reserve_exact(num_chars)
+            .map_err(|_| AllocError)?;
+        let _ = font.walk_unicode_table(|glyph, seq| {
+            if let [ch] = seq {
+                char_glyphs.push((*ch, glyph as u16));
+            }
+            ControlFlow::Continue(())
+        });
+        // The sort is stable, so for characters mapped more than once the first glyph is kept.
+        char_glyphs.sort_by_key(|&(ch, _)| ch);
+        char_glyphs.dedup_by_key(|&mut (ch, _)| ch);
+        font.char_glyphs = char_glyphs;
+
+        font.replacement_glyph = REPLACEMENT_CHARS
+            .iter()
+            .find_map(|&ch| font.glyph_index(ch))
+            .unwrap_or(0);
+        Ok(font)
+    }
+
+    fn parse_psf1(data: &'a [u8], alloc: A) -> Result<Self, OsError> {
+        let (mode, bytes_per_glyph) = match data {
+            [_, _, mode, size, ..] => (*mode, *size as usize),
+            _ => return Err(OsError::InvalidFont("truncated PSF1 header")),
+        };
+        let num_glyphs = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyphs_end = PSF1_HEADER_LEN + num_glyphs * bytes_per_glyph;
+        if bytes_per_glyph == 0 || data.len() < glyphs_end {
+            return Err(OsError::InvalidFont("truncated PSF1 glyphs"));
+        }
+
+        let unicode_table = if mode & (PSF1_MODE_HASTAB | PSF1_MODE_HASSEQ) != 0 {
+            UnicodeTable::Psf1(&data[glyphs_end..])
+        } else {
+            UnicodeTable::None
+        };
+        Ok(Self::with_glyphs(
+            &data[PSF1_HEADER_LEN..glyphs_end],
+            unicode_table,
+            num_glyphs,
+            bytes_per_glyph,
+            8,
+            bytes_per_glyph,
+            alloc,
+        ))
+    }
+
+    fn parse_psf2(data: &'a [u8], alloc: A) -> Result<Self, OsError> {
+        let header =
+            Psf2Header::parse(data).ok_or(OsError::InvalidFont("truncated PSF2 header"))?;
+        if header.magic != PSF2_MAGIC {
+            return Err(OsError::InvalidFont("not a PSF1 or PSF2 font"));
+        }
+        if header.version != 0 || (header.size as usize) < PSF2_HEADER_LEN {
+            return Err(OsError::InvalidFont("unsupported PSF2 header"));
+        }
+
+        let (width, height) = (header.width as usize, header.height as usize);
+        let num_glyphs = header.num_glyphs as usize;
+        let bytes_per_glyph = header.bytes_per_glyph as usize;
+        if num_glyphs > MAX_GLYPHS {
+            return Err(OsError::InvalidFont("too many PSF2 glyphs"));
+        }
+        if num_glyphs == 0
+            || width == 0
+            || height == 0
+            || bytes_per_glyph < (width + 7) / 8 * height
+        {
+            return Err(OsError::InvalidFont("invalid PSF2 glyph size"));
+        }
+
+        let glyphs_start = header.size as usize;
+        let glyphs_end = num_glyphs
+            .checked_mul(bytes_per_glyph)
+            .and_then(|len| len.checked_add(glyphs_start))
+            .filter(|&end| end <= data.len())
+            .ok_or(OsError::InvalidFont("truncated PSF2 glyphs"))?;
+
+        let unicode_table = if header.flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            UnicodeTable::Psf2(&data[glyphs_end..])
+        } else {
+            UnicodeTable::None
+        };
+        Ok(Self::with_glyphs(
+            &data[glyphs_start..glyphs_end],
+            unicode_table,
+            num_glyphs,
+            bytes_per_glyph,
+            width,
+            height,
+            alloc,
+        ))
+    }
+
+    fn with_glyphs(
+        glyph_data: &'a [u8],
+        unicode_table: UnicodeTable<'a>,
+        num_glyphs: usize,
+        bytes_per_glyph: usize,
+        width: usize,
+        height: usize,
+        alloc: A,
+    ) -> Self {
         Self {
-            header,
-            glyph_data: rest_data,
+            glyph_data,
+            unicode_table,
+            num_glyphs,
+            bytes_per_glyph,
+            width,
+            height,
+            char_glyphs: Vec::new_in(alloc),
+            replacement_glyph: 0,
         }
     }
 
-    fn glyph(&self, ch: char) -> Option<Glyph<'a>> {
-        let len = self.header.bytes_per_glyph as usize;
-        let off = ch as usize * len;
-        if ch as usize >= self.header.num_glyphs as usize {
-            return None;
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the glyph for `ch`, or the replacement glyph if the font has none.
+    pub fn glyph(&self, ch: char) -> Glyph<'a> {
+        let index = self.glyph_index(ch).unwrap_or(self.replacement_glyph);
+        self.glyph_at(index)
+    }
+
+    /// Returns the glyph for a sequence of characters, like a letter followed by a combining
+    /// accent. Only fonts with a Unicode table have glyphs for sequences.
+    #[allow(dead_code)]
+    pub fn sequence_glyph(&self, seq: &[char]) -> Option<Glyph<'a>> {
+        if let [ch] = seq {
+            return self.glyph_index(*ch).map(|index| self.glyph_at(index));
+        }
+        // Sequences are rare, so they are still searched for in the table itself.
+        self.find_in_unicode_table(seq)
+            .map(|index| self.glyph_at(index))
+    }
+
+    fn glyph_index(&self, ch: char) -> Option<usize> {
+        match self.unicode_table {
+            UnicodeTable::None => Some(ch as usize).filter(|&index| index < self.num_glyphs),
+            _ => self
+                .char_glyphs
+                .binary_search_by_key(&ch, |&(ch, _)| ch)
+                .ok()
+                .map(|pos| self.char_glyphs[pos].1 as usize),
+        }
+    }
+
+    fn find_in_unicode_table(&self, target: &[char]) -> Option<usize> {
+        let mut found = None;
+        // The table was validated in `new`.
+        let _ = self.walk_unicode_table(|glyph, seq| {
+            if seq == target {
+                found = Some(glyph);
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        found
+    }
+
+    fn glyph_at(&self, index: usize) -> Glyph<'a> {
+        let off = index * self.bytes_per_glyph;
+        Glyph {
+            data: &self.glyph_data[off..off + self.bytes_per_glyph],
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Call `f` with every (glyph index, character sequence) pair of the Unicode table.
+    fn walk_unicode_table(
+        &self,
+        mut f: impl FnMut(usize, &[char]) -> ControlFlow<()>,
+    ) -> Result<(), OsError> {
+        let mut seq = [char::default(); MAX_SEQUENCE_LEN];
+        match self.unicode_table {
+            UnicodeTable::None => Ok(()),
+            UnicodeTable::Psf1(mut table) => {
+                for glyph in 0..self.num_glyphs {
+                    // Each glyph has a list of single characters, then sequences each starting
+                    // with PSF1_START_SEQ, and ends with PSF1_SEPARATOR.
+                    let mut seq_len = None;
+                    loop {
+                        let value = match table {
+                            [low, high, rest @ ..] => {
+                                table = rest;
+                                u16::from_le_bytes([*low, *high])
+                            }
+                            _ => return Err(OsError::InvalidFont("truncated PSF1 Unicode table")),
+                        };
+                        if value == PSF1_SEPARATOR || value == PSF1_START_SEQ {
+                            if let Some(len @ 1..=MAX_SEQUENCE_LEN) = seq_len {
+                                if f(glyph, &seq[..len]).is_break() {
+                                    return Ok(());
+                                }
+                            }
+                            if value == PSF1_SEPARATOR {
+                                break;
+                            }
+                            seq_len = Some(0);
+                            continue;
+                        }
+
+                        let ch = char::from_u32(value as u32)
+                            .ok_or(OsError::InvalidFont("invalid character in Unicode table"))?;
+                        match &mut seq_len {
+                            None => {
+                                if f(glyph, &[ch]).is_break() {
+                                    return Ok(());
+                                }
+                            }
+                            Some(len) => {
+                                if let Some(slot) = seq.get_mut(*len) {
+                                    *slot = ch;
+                                }
+                                *len += 1;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+            UnicodeTable::Psf2(mut table) => {
+                for glyph in 0..self.num_glyphs {
+                    // Each glyph has UTF-8 encoded single characters, then sequences each
+                    // starting with PSF2_START_SEQ, and ends with PSF2_SEPARATOR. Neither byte
+                    // can occur in UTF-8.
+                    let end = table
+                        .iter()
+                        .position(|&byte| byte == PSF2_SEPARATOR)
+                        .ok_or(OsError::InvalidFont("truncated PSF2 Unicode table"))?;
+                    let entry = &table[..end];
+                    table = &table[end + 1..];
+
+                    for (idx, part) in entry.split(|&byte| byte == PSF2_START_SEQ).enumerate() {
+                        let part = str::from_utf8(part)
+                            .map_err(|_| OsError::InvalidFont("invalid UTF-8 in Unicode table"))?;
+                        if idx == 0 {
+                            for ch in part.chars() {
+                                if f(glyph, &[ch]).is_break() {
+                                    return Ok(());
+                                }
+                            }
+                            continue;
+                        }
+
+                        let mut len = 0;
+                        for ch in part.chars() {
+                            if let Some(slot) = seq.get_mut(len) {
+                                *slot = ch;
+                            }
+                            len += 1;
+                        }
+                        if (1..=MAX_SEQUENCE_LEN).contains(&len) && f(glyph, &seq[..len]).is_break()
+                        {
+                            return Ok(());
+                        }
+                    }
+                }
+                Ok(())
+            }
         }
-        Some(Glyph {
-            data: &self.glyph_data[off..off + len],
-            width: self.header.width as usize,
-            height: self.header.height as usize,
-        })
     }
 }
 
@@ -68,33 +393,41 @@ impl Glyph<'_> {
     }
 }
 
-impl Font for PsfFont<'_> {
-    fn render_char(&self, ch: char, fb: &mut Framebuffer, x: usize, y: usize) {
-        let white = Pixel::new((255, 255, 255, 255), PixelOrder::Rgb);
-        let black = Pixel::new((0, 0, 0, 255), PixelOrder::Rgb);
+impl<A: Allocator + Clone> Font for PsfFont<'_, A> {
+    /// PSF fonts don't record their baseline, assume the bottom quarter is for descenders.
+    fn ascent(&self) -> usize {
+        self.height - self.height / 4
+    }
 
-        let glyph = self.glyph(ch);
-        if let Some(glyph) = glyph {
-            for i in 0..glyph.height {
-                for j in 0..glyph.width {
-                    if glyph.value(i, j) {
-                        fb.set_pixel(x + j, y + i - glyph.height, white);
-                    } else {
-                        fb.set_pixel(x + j, y + i - glyph.height, black);
-                    }
-                }
-            }
-        }
-        // TODO: Handle invalid glyph
+    fn line_height(&self) -> usize {
+        self.height
     }
 
-    fn render_str(&self, s: &str, fb: &mut Framebuffer, x: usize, y: usize) {
-        let mut curr_x = x;
-        for ch in s.chars() {
-            self.render_char(ch, fb, curr_x, y);
-            curr_x += (self.header.width + 1) as usize;
+    fn metrics(&self, _ch: char) -> GlyphMetrics {
+        GlyphMetrics {
+            width: self.width,
+            height: self.height,
+            left: 0,
+            top: self.ascent() as isize,
+            advance: self.width,
         }
     }
+
+    fn render_char(
+        &self,
+        ch: char,
+        target: &mut dyn DrawTarget,
+        x: isize,
+        y: isize,
+        style: &TextStyle,
+    ) -> usize {
+        // The glyph covers its whole box, so the background needs no separate fill.
+        let glyph = self.glyph(ch);
+        let size = (glyph.width, glyph.height);
+        let is_set = |row, col| glyph.value(row, col);
+        draw_bitmap(target, x, y, size, is_set, style.fg, style.bg);
+        self.width
+    }
 }
 
 pub static DEFAULT_PSF_FONT_BYTES: &[u8] = include_bytes!("psf/default8x16.psfu");
@@ -0,0 +1,121 @@
+use core::ops::ControlFlow;
+
+use crate::error::OsError;
+
+pub mod dentry;
+pub mod devfs;
+pub mod fat;
+pub mod fd;
+pub mod initramfs;
+pub mod procfs;
+pub mod tmpfs;
+pub mod vfs;
+
+/// Number of an inode, unique within its filesystem.
+pub type Ino = u64;
+
+/// Longest name of a directory entry, in bytes.
+pub const NAME_MAX: usize = 255;
+/// Longest path, in bytes.
+pub const PATH_MAX: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+/// Identifies the device behind a device file, with the Linux numbering.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceNumber {
+    pub major: u32,
+    pub minor: u32,
+}
+
+/// What `stat` tells about an inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    /// The mount the inode was found on, filled in by the VFS.
+    pub dev: u32,
+    pub ino: Ino,
+    pub kind: FileKind,
+    /// Permission bits, like 0o644.
+    pub mode: u16,
+    pub nlink: u32,
+    /// The device, for device files.
+    pub rdev: DeviceNumber,
+    pub size: u64,
+    /// Preferred size of transfers.
+    pub block_size: u32,
+    /// Space used, in 512 byte units.
+    pub blocks: u64,
+}
+
+/// Called with the name, inode number and kind of each directory entry, until it breaks.
+pub type DirEntryFn<'a> = dyn FnMut(&str, Ino, FileKind) -> ControlFlow<()> + 'a;
+
+/// A filesystem the VFS can mount.
+///
+/// Inodes are only known by their numbers. Directories passed in are always directories, and
+/// names never are "." or "..", or contain a '/': the VFS takes care of these.
+pub trait FileSystem: Sync {
+    /// The root directory.
+    fn root(&self) -> Ino;
+
+    fn stat(&self, ino: Ino) -> Result<Stat, OsError>;
+
+    /// The inode called `name` in `dir`.
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, OsError>;
+
+    /// Call `f` with the entries of `dir`, skipping the first `start` ones, and "." and "..".
+    fn read_dir(&self, dir: Ino, start: u64, f: &mut DirEntryFn<'_>) -> Result<(), OsError>;
+
+    /// Read from `offset` of a file into `buf`, returning the number of bytes read.
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, OsError>;
+
+    /// Write `buf` at `offset` of a file, returning the number of bytes written.
+    fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize, OsError>;
+
+    /// Cut or extend a file to `size` bytes.
+    fn truncate(&self, ino: Ino, size: u64) -> Result<(), OsError>;
+
+    /// Create an empty file or directory `name` in `dir`.
+    fn create(&self, dir: Ino, name: &str, kind: FileKind, mode: u16) -> Result<Ino, OsError>;
+
+    /// Remove `name` from `dir`. Directories must be empty.
+    ///
+    /// The VFS only removes inodes which are still in use, by open files or as working
+    /// directories, from filesystems which `keep_unlinked` inodes.
+    fn unlink(&self, dir: Ino, name: &str) -> Result<(), OsError>;
+
+    /// Whether an unlinked inode stays usable until `release`, rather than going away at once.
+    fn keeps_unlinked(&self) -> bool {
+        false
+    }
+
+    /// Called once an unlinked inode is not used anymore, so that it can be freed.
+    fn release(&self, _ino: Ino) {}
+
+    /// Create a symbolic link `name` in `dir`, pointing to `target`.
+    fn symlink(&self, _dir: Ino, _name: &str, _target: &str) -> Result<Ino, OsError> {
+        Err(OsError::NotSupported)
+    }
+
+    /// Read the target of a symbolic link into `buf`, returning its length.
+    fn read_link(&self, _ino: Ino, _buf: &mut [u8]) -> Result<usize, OsError> {
+        Err(OsError::InvalidArgument)
+    }
+
+    /// The physical address of `len` bytes from `offset` of a file, for mapping it into memory.
+    fn mmap(&self, _ino: Ino, _offset: u64, _len: usize) -> Result<usize, OsError> {
+        Err(OsError::NotSupported)
+    }
+
+    /// Write everything cached back to the device.
+    fn sync(&self) -> Result<(), OsError> {
+        Ok(())
+    }
+}
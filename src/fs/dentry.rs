@@ -0,0 +1,241 @@
+use core::alloc::{AllocError, Allocator};
+
+use std_alloc::vec::Vec;
+
+use crate::error::OsError;
+
+use super::{
+    vfs::{Inode, MountId},
+    FileKind,
+};
+
+/// Index of a dentry in the `DentryCache`.
+pub type DentryId = usize;
+
+/// A name in the directory tree, tying it to an inode.
+pub struct Dentry<A: Allocator> {
+    name: Vec<u8, A>,
+    /// The directory containing the dentry. The root of a mount is its own parent.
+    parent: DentryId,
+    pub inode: Inode,
+    pub kind: FileKind,
+    /// Open files, working directories, mounts and cached children using the dentry. It is only
+    /// evicted when there are none.
+    refs: usize,
+    /// The filesystem mounted on the dentry, which hides its contents.
+    pub mounted: Option<MountId>,
+    /// Removed from its directory while still in use.
+    unlinked: bool,
+    /// Value of `DentryCache::clock` when the dentry was last used.
+    last_used: u64,
+}
+
+impl<A: Allocator> Dentry<A> {
+    pub fn parent(&self) -> DentryId {
+        self.parent
+    }
+}
+
+/// The dentries looked up so far, so that paths are resolved without asking the filesystems
+/// again.
+pub struct DentryCache<A: Allocator + Clone> {
+    entries: Vec<Option<Dentry<A>>, A>,
+    len: usize,
+    clock: u64,
+    /// Inodes of unlinked dentries which went away, for the VFS to release.
+    released: Vec<Inode, A>,
+}
+
+impl<A: Allocator + Copy> DentryCache<A> {
+    pub const fn new(alloc: A) -> Self {
+        Self {
+            entries: Vec::new_in(alloc),
+            len: 0,
+            clock: 0,
+            released: Vec::new_in(alloc),
+        }
+    }
+}
+
+impl<A: Allocator + Clone> DentryCache<A> {
+    /// The dentry `id`, which must exist.
+    pub fn get(&self, id: DentryId) -> &Dentry<A> {
+        self.entries[id].as_ref().expect("stale dentry")
+    }
+
+    fn get_mut(&mut self, id: DentryId) -> &mut Dentry<A> {
+        self.entries[id].as_mut().expect("stale dentry")
+    }
+
+    /// The cached child `name` of `parent`.
+    pub fn find_child(&mut self, parent: DentryId, name: &str) -> Option<DentryId> {
+        let id = self.entries.iter().position(|entry| match entry {
+            Some(entry) => {
+                entry.parent == parent && !entry.unlinked && entry.name == name.as_bytes()
+            }
+            None => false,
+        })?;
+        self.clock += 1;
+        self.get_mut(id).last_used = self.clock;
+        Some(id)
+    }
+
+    /// Add a dentry for `inode` called `name` in `parent`, or the root of a mount without one.
+    pub fn insert(
+        &mut self,
+        parent: Option<DentryId>,
+        name: &str,
+        inode: Inode,
+        kind: FileKind,
+    ) -> Result<DentryId, OsError> {
+        let mut stored_name = Vec::new_in(self.entries.allocator().clone());
+        stored_name
+            .try_reserve_exact(name.len())
+            .map_err(|_| AllocError)?;
+        stored_name.extend_from_slice(name.as_bytes());
+
+        let id = match self.entries.iter().position(Option::is_none) {
+            Some(id) => id,
+            None => {
+                self.entries.try_reserve(1).map_err(|_| AllocError)?;
+                self.entries.push(None);
+                self.entries.len() - 1
+            }
+        };
+        if let Some(parent) = parent {
+            self.get_mut(parent).refs += 1;
+        }
+        self.clock += 1;
+        self.entries[id] = Some(Dentry {
+            name: stored_name,
+            parent: parent.unwrap_or(id),
+            inode,
+            kind,
+            refs: 0,
+            mounted: None,
+            unlinked: false,
+            last_used: self.clock,
+        });
+        self.len += 1;
+        Ok(id)
+    }
+
+    /// Take a reference to `id`, keeping it in the cache.
+    pub fn get_ref(&mut self, id: DentryId) {
+        self.get_mut(id).refs += 1;
+    }
+
+    /// Drop a reference taken with `get_ref`.
+    pub fn put(&mut self, id: DentryId) {
+        let dentry = self.get_mut(id);
+        dentry.refs -= 1;
+        if dentry.refs == 0 && dentry.unlinked {
+            self.remove(id);
+        }
+    }
+
+    /// Whether `id` is used by something else than its cached children.
+    pub fn is_in_use(&self, id: DentryId) -> bool {
+        self.get(id).refs > self.child_count(id)
+    }
+
+    /// Make room to remember the inode of a dentry about to be unlinked, so that `unlink` can't
+    /// fail after the filesystem removed it.
+    pub fn reserve_unlink(&mut self) -> Result<(), OsError> {
+        self.released.try_reserve(1).map_err(|_| AllocError)?;
+        Ok(())
+    }
+
+    /// Mark `id` as removed from its directory. It goes away once it is not used anymore, and
+    /// then its inode is returned by `take_released`. Call `reserve_unlink` first.
+    pub fn unlink(&mut self, id: DentryId) {
+        let dentry = self.get_mut(id);
+        dentry.unlinked = true;
+        if dentry.refs == 0 {
+            self.remove(id);
+        }
+    }
+
+    /// An inode of an unlinked dentry which went away.
+    pub fn take_released(&mut self) -> Option<Inode> {
+        self.released.pop()
+    }
+
+    pub fn set_mounted(&mut self, id: DentryId, mount: Option<MountId>) {
+        self.get_mut(id).mounted = mount;
+    }
+
+    /// Whether a dentry of `mount` besides `root` is in use.
+    pub fn is_mount_busy(&self, mount: MountId, root: DentryId) -> bool {
+        self.entries
+            .iter()
+            .enumerate()
+            .any(|(id, entry)| match entry {
+                Some(entry) if entry.inode.mount == mount => {
+                    let children = self.child_count(id);
+                    let refs = entry.refs - children;
+                    if id == root {
+                        // The mount holds one reference.
+                        refs > 1
+                    } else {
+                        refs > 0
+                    }
+                }
+                _ => false,
+            })
+    }
+
+    fn child_count(&self, id: DentryId) -> usize {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|&(child, entry)| match entry {
+                Some(entry) => entry.parent == id && child != id,
+                None => false,
+            })
+            .count()
+    }
+
+    /// Drop all dentries of `mount`, which must not be busy.
+    pub fn remove_mount(&mut self, mount: MountId) {
+        for entry in self.entries.iter_mut() {
+            if matches!(entry, Some(dentry) if dentry.inode.mount == mount) {
+                *entry = None;
+                self.len -= 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, id: DentryId) {
+        if let Some(dentry) = self.entries[id].take() {
+            self.len -= 1;
+            if dentry.unlinked {
+                // Room was made by `reserve_unlink`.
+                self.released.push(dentry.inode);
+            }
+            if dentry.parent != id {
+                self.put(dentry.parent);
+            }
+        }
+    }
+
+    /// Evict the least recently used dentries which are not in use, until at most `max` are
+    /// left or all are in use.
+    pub fn shrink(&mut self, max: usize) {
+        while self.len > max {
+            let victim = self
+                .entries
+                .iter()
+                .enumerate()
+                .filter_map(|(id, entry)| match entry {
+                    Some(entry) if entry.refs == 0 => Some((id, entry.last_used)),
+                    _ => None,
+                })
+                .min_by_key(|&(_, last_used)| last_used);
+            match victim {
+                Some((id, _)) => self.remove(id),
+                None => break,
+            }
+        }
+    }
+}
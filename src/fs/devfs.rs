@@ -0,0 +1,365 @@
+//! `/dev`: a file for each device the drivers register.
+
+use core::{
+    fmt::{self, Write},
+    ops::ControlFlow,
+    str,
+};
+
+use crate::{block::BlockDevice, error::OsError, mmu::PAGE_SIZE, sync::NullLock};
+
+use super::{DeviceNumber, DirEntryFn, FileKind, FileSystem, Ino, Stat};
+
+use self::mem::{KMSG, NULL, ZERO};
+
+pub mod mem;
+
+/// A device read and written as a stream of bytes, like a UART.
+pub trait CharDevice: Sync {
+    /// Read into `buf`, from `offset` if the device has positions.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, OsError>;
+
+    /// Write `buf`, at `offset` if the device has positions.
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<usize, OsError>;
+
+    /// Size reported by `stat`, 0 for streams.
+    fn size(&self) -> u64 {
+        0
+    }
+
+    /// The physical address of `len` bytes from `offset`, for devices which are memory.
+    fn mmap(&self, _offset: u64, _len: usize) -> Result<usize, OsError> {
+        Err(OsError::NotSupported)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Device {
+    Char(&'static dyn CharDevice),
+    Block(&'static (dyn BlockDevice + Sync)),
+}
+
+const MAX_DEVICES: usize = 32;
+const DEVICE_NAME_MAX: usize = 16;
+/// Block devices with larger blocks can only be used through the `BlockDevice` trait.
+const MAX_BLOCK_SIZE: usize = 4096;
+
+const ROOT_INO: Ino = 0;
+
+#[derive(Clone, Copy)]
+struct DeviceNode {
+    name: [u8; DEVICE_NAME_MAX],
+    name_len: usize,
+    number: DeviceNumber,
+    mode: u16,
+    device: Device,
+}
+
+impl DeviceNode {
+    const fn new(name: &str, number: DeviceNumber, mode: u16, device: Device) -> Self {
+        let mut node = Self {
+            name: [0; DEVICE_NAME_MAX],
+            name_len: name.len(),
+            number,
+            mode,
+            device,
+        };
+        let mut i = 0;
+        while i < name.len() {
+            node.name[i] = name.as_bytes()[i];
+            i += 1;
+        }
+        node
+    }
+
+    fn name(&self) -> &str {
+        // Names are only ever copied from a `str`.
+        str::from_utf8(&self.name[..self.name_len]).unwrap()
+    }
+}
+
+/// Formats a device name into a `DeviceNode`.
+struct NameWriter<'a> {
+    name: &'a mut [u8; DEVICE_NAME_MAX],
+    len: usize,
+}
+
+impl Write for NameWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > DEVICE_NAME_MAX {
+            return Err(fmt::Error);
+        }
+        self.name[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Devices by inode number minus one, in the order they were registered. The memory devices,
+/// which do not belong to a driver, are always there.
+static DEVICES: NullLock<[Option<DeviceNode>; MAX_DEVICES]> = {
+    let mut devices = [None; MAX_DEVICES];
+    devices[0] = Some(DeviceNode::new(
+        "null",
+        DeviceNumber { major: 1, minor: 3 },
+        0o666,
+        Device::Char(&NULL),
+    ));
+    devices[1] = Some(DeviceNode::new(
+        "zero",
+        DeviceNumber { major: 1, minor: 5 },
+        0o666,
+        Device::Char(&ZERO),
+    ));
+    devices[2] = Some(DeviceNode::new(
+        "kmsg",
+        DeviceNumber {
+            major: 1,
+            minor: 11,
+        },
+        0o644,
+        Device::Char(&KMSG),
+    ));
+    NullLock::new(devices)
+};
+
+/// Add the device file `name` to `/dev`, with permissions `mode`.
+pub fn register(
+    name: fmt::Arguments,
+    number: DeviceNumber,
+    mode: u16,
+    device: Device,
+) -> Result<(), OsError> {
+    let mut node = DeviceNode::new("", number, mode, device);
+    let mut writer = NameWriter {
+        name: &mut node.name,
+        len: 0,
+    };
+    writer.write_fmt(name).map_err(|_| OsError::NameTooLong)?;
+    node.name_len = writer.len;
+
+    DEVICES.lock(|devices| {
+        let exists = devices
+            .iter()
+            .flatten()
+            .any(|other| other.name() == node.name());
+        if exists {
+            return Err(OsError::AlreadyExists);
+        }
+        let slot = devices
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(OsError::NoSpace)?;
+        *slot = Some(node);
+        Ok(())
+    })
+}
+
+fn node(ino: Ino) -> Result<DeviceNode, OsError> {
+    if ino == ROOT_INO {
+        return Err(OsError::IsADirectory);
+    }
+    DEVICES.lock(|devices| {
+        devices
+            .get(ino as usize - 1)
+            .copied()
+            .flatten()
+            .ok_or(OsError::NotFound)
+    })
+}
+
+/// Read from any `offset` of `device`, going through a block sized buffer for partial blocks.
+fn read_block_device(
+    device: &dyn BlockDevice,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<usize, OsError> {
+    let size = device.size();
+    if offset >= size {
+        return Ok(0);
+    }
+    let len = buf
+        .len()
+        .min((size - offset).min(usize::MAX as u64) as usize);
+    let block_size = device.block_size();
+    if block_size > MAX_BLOCK_SIZE {
+        return Err(OsError::NotSupported);
+    }
+    let mut bounce = [0; MAX_BLOCK_SIZE];
+    let mut done = 0;
+    while done < len {
+        let pos = offset + done as u64;
+        let block = pos / block_size as u64;
+        let in_block = (pos % block_size as u64) as usize;
+        if in_block == 0 && len - done >= block_size {
+            let whole = (len - done) / block_size * block_size;
+            device.read_blocks(block, &mut buf[done..done + whole])?;
+            done += whole;
+        } else {
+            let chunk = (block_size - in_block).min(len - done);
+            device.read_blocks(block, &mut bounce[..block_size])?;
+            buf[done..done + chunk].copy_from_slice(&bounce[in_block..in_block + chunk]);
+            done += chunk;
+        }
+    }
+    Ok(len)
+}
+
+/// Write at any `offset` of `device`, reading back the blocks which are only partly written.
+fn write_block_device(device: &dyn BlockDevice, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+    let size = device.size();
+    if offset >= size {
+        return if buf.is_empty() {
+            Ok(0)
+        } else {
+            Err(OsError::NoSpace)
+        };
+    }
+    let len = buf
+        .len()
+        .min((size - offset).min(usize::MAX as u64) as usize);
+    let block_size = device.block_size();
+    if block_size > MAX_BLOCK_SIZE {
+        return Err(OsError::NotSupported);
+    }
+    let mut bounce = [0; MAX_BLOCK_SIZE];
+    let mut done = 0;
+    while done < len {
+        let pos = offset + done as u64;
+        let block = pos / block_size as u64;
+        let in_block = (pos % block_size as u64) as usize;
+        if in_block == 0 && len - done >= block_size {
+            let whole = (len - done) / block_size * block_size;
+            device.write_blocks(block, &buf[done..done + whole])?;
+            done += whole;
+        } else {
+            let chunk = (block_size - in_block).min(len - done);
+            device.read_blocks(block, &mut bounce[..block_size])?;
+            bounce[in_block..in_block + chunk].copy_from_slice(&buf[done..done + chunk]);
+            device.write_blocks(block, &bounce[..block_size])?;
+            done += chunk;
+        }
+    }
+    Ok(len)
+}
+
+/// The filesystem mounted on `/dev`. It has a single directory, listing the registered devices.
+pub struct Devfs;
+
+impl FileSystem for Devfs {
+    fn root(&self) -> Ino {
+        ROOT_INO
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, OsError> {
+        if ino == ROOT_INO {
+            return Ok(Stat {
+                dev: 0,
+                ino,
+                kind: FileKind::Directory,
+                mode: 0o755,
+                nlink: 2,
+                rdev: DeviceNumber::default(),
+                size: 0,
+                block_size: PAGE_SIZE as u32,
+                blocks: 0,
+            });
+        }
+        let node = node(ino)?;
+        let (kind, size, block_size) = match node.device {
+            Device::Char(device) => (FileKind::CharDevice, device.size(), PAGE_SIZE),
+            Device::Block(device) => (FileKind::BlockDevice, device.size(), device.block_size()),
+        };
+        Ok(Stat {
+            dev: 0,
+            ino,
+            kind,
+            mode: node.mode,
+            nlink: 1,
+            rdev: node.number,
+            size,
+            block_size: block_size as u32,
+            blocks: 0,
+        })
+    }
+
+    fn lookup(&self, _dir: Ino, name: &str) -> Result<Ino, OsError> {
+        DEVICES.lock(|devices| {
+            devices
+                .iter()
+                .position(|node| matches!(node, Some(node) if node.name() == name))
+                .map(|index| index as Ino + 1)
+                .ok_or(OsError::NotFound)
+        })
+    }
+
+    fn read_dir(&self, _dir: Ino, start: u64, f: &mut DirEntryFn<'_>) -> Result<(), OsError> {
+        // Copied out, so that `f` may use the devices.
+        let devices = DEVICES.lock(|devices| *devices);
+        let nodes = devices
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| node.as_ref().map(|node| (index as Ino + 1, node)));
+        for (ino, node) in nodes.skip(start as usize) {
+            let kind = match node.device {
+                Device::Char(_) => FileKind::CharDevice,
+                Device::Block(_) => FileKind::BlockDevice,
+            };
+            if let ControlFlow::Break(()) = f(node.name(), ino, kind) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        match node(ino)?.device {
+            Device::Char(device) => device.read(offset, buf),
+            Device::Block(device) => read_block_device(device, offset, buf),
+        }
+    }
+
+    fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        match node(ino)?.device {
+            Device::Char(device) => device.write(offset, buf),
+            Device::Block(device) => write_block_device(device, offset, buf),
+        }
+    }
+
+    /// Devices cannot be resized, and ignore `O_TRUNC` as on Linux.
+    fn truncate(&self, ino: Ino, _size: u64) -> Result<(), OsError> {
+        node(ino).map(|_| ())
+    }
+
+    fn create(&self, _dir: Ino, _name: &str, _kind: FileKind, _mode: u16) -> Result<Ino, OsError> {
+        Err(OsError::ReadOnlyFilesystem)
+    }
+
+    fn unlink(&self, _dir: Ino, _name: &str) -> Result<(), OsError> {
+        Err(OsError::ReadOnlyFilesystem)
+    }
+
+    fn symlink(&self, _dir: Ino, _name: &str, _target: &str) -> Result<Ino, OsError> {
+        Err(OsError::ReadOnlyFilesystem)
+    }
+
+    fn mmap(&self, ino: Ino, offset: u64, len: usize) -> Result<usize, OsError> {
+        match node(ino)?.device {
+            Device::Char(device) => device.mmap(offset, len),
+            Device::Block(_) => Err(OsError::NotSupported),
+        }
+    }
+
+    fn sync(&self) -> Result<(), OsError> {
+        let devices = DEVICES.lock(|devices| *devices);
+        for node in devices.iter().flatten() {
+            if let Device::Block(device) = node.device {
+                device.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub static DEVFS: Devfs = Devfs;
@@ -0,0 +1,58 @@
+//! Devices which are not hardware: `null`, `zero` and `kmsg`.
+
+use core::str;
+
+use crate::{error::OsError, print};
+
+use super::CharDevice;
+
+/// Reads nothing, and takes anything written.
+pub struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, OsError> {
+        Ok(0)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        Ok(buf.len())
+    }
+}
+
+/// Reads zeroes, and takes anything written.
+pub struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        Ok(buf.len())
+    }
+}
+
+/// The kernel log. It reads as a file holding what is left of the log, and what is written to it
+/// is printed like with `kprint!`.
+pub struct Kmsg;
+
+impl CharDevice for Kmsg {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        Ok(print::read_log(offset, buf))
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        let text = str::from_utf8(buf).map_err(|_| OsError::InvalidArgument)?;
+        crate::kprint!("{}", text);
+        Ok(buf.len())
+    }
+
+    fn size(&self) -> u64 {
+        print::log_len() as u64
+    }
+}
+
+pub static NULL: Null = Null;
+pub static ZERO: Zero = Zero;
+pub static KMSG: Kmsg = Kmsg;
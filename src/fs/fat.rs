@@ -0,0 +1,1616 @@
+//! FAT12, FAT16 and FAT32 filesystems, with long file names.
+//!
+//! The layout is described in Microsoft's "FAT: General Overview of On-Disk Format", version
+//! 1.03. There is no real time clock, so all timestamps written are the FAT epoch, 1980-01-01.
+
+use core::{
+    alloc::{AllocError, Allocator},
+    ops::ControlFlow,
+    str,
+};
+
+use bitflags::bitflags;
+use std_alloc::vec::Vec;
+
+use crate::{block::BlockDevice, error::OsError, sync::NullLock};
+
+use super::{DeviceNumber, DirEntryFn, FileKind, FileSystem, Ino, Stat};
+
+const DIR_ENTRY_SIZE: usize = 32;
+/// First byte of a deleted directory entry.
+const ENTRY_DELETED: u8 = 0xE5;
+/// First byte of the entry after the last one in use.
+const ENTRY_END: u8 = 0x00;
+/// Stored in place of a first byte of 0xE5, which is a valid Kanji lead byte.
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+/// Set in the sequence number of the last (first stored) long name entry.
+const LFN_LAST: u8 = 0x40;
+const LFN_SEQUENCE_MASK: u8 = 0x1F;
+/// UTF-16 code units per long name entry.
+const LFN_CHARS: usize = 13;
+/// Longest long name, in UTF-16 code units.
+const MAX_LFN_LEN: usize = 255;
+/// Long name entries needed for the longest name.
+const MAX_LFN_ENTRIES: usize = (MAX_LFN_LEN + LFN_CHARS - 1) / LFN_CHARS;
+/// Offsets of the characters in a long name entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Longest name in UTF-8.
+pub const MAX_NAME_LEN: usize = MAX_LFN_LEN * 3;
+
+/// NTRes bits telling that the base name or extension of a short name is lower case.
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+/// 1980-01-01, the FAT epoch: year 0, month 1, day 1.
+const FAT_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+/// Free cluster count and next free hint of the FSInfo sector, when not known.
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+
+/// Characters not allowed in any name.
+const INVALID_NAME_CHARS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+/// Further characters not allowed in short names, replaced by '_'.
+const INVALID_SHORT_NAME_CHARS: &[u8] = b"+,;=[]";
+
+bitflags! {
+    /// Attributes of a directory entry.
+    pub struct Attributes: u8 {
+        const READ_ONLY = 0x01;
+        const HIDDEN = 0x02;
+        const SYSTEM = 0x04;
+        const VOLUME_ID = 0x08;
+        const DIRECTORY = 0x10;
+        const ARCHIVE = 0x20;
+        /// The combination marking a long name entry.
+        const LONG_NAME = 0x0F;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Smallest FAT entry value marking the end of a cluster chain.
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+}
+
+/// Where the filesystem's structures are, from the BIOS parameter block.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    fat_type: FatType,
+    bytes_per_cluster: u32,
+    /// Byte offsets of the first FAT, the fixed FAT12/16 root directory and the data region.
+    fat_offset: u64,
+    fat_size: u64,
+    num_fats: u32,
+    root_dir_offset: u64,
+    root_entry_count: u32,
+    data_offset: u64,
+    /// Valid clusters are numbered 2 to `cluster_count + 1`.
+    cluster_count: u32,
+    /// First cluster of the FAT32 root directory.
+    root_cluster: u32,
+    /// Byte offset of the FAT32 FSInfo sector.
+    fsinfo_offset: Option<u64>,
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+impl Geometry {
+    fn parse(boot: &[u8]) -> Result<Self, OsError> {
+        let invalid = |reason| Err(OsError::CorruptFilesystem(reason));
+        if boot[510..512] != [0x55, 0xAA] {
+            return invalid("missing boot sector signature");
+        }
+        let bytes_per_sector = read_u16(boot, 11) as u32;
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved_sectors = read_u16(boot, 14) as u64;
+        let num_fats = boot[16] as u32;
+        let root_entry_count = read_u16(boot, 17) as u32;
+        let total_sectors = match read_u16(boot, 19) {
+            0 => read_u32(boot, 32),
+            total => total as u32,
+        } as u64;
+        let fat_sectors = match read_u16(boot, 22) {
+            0 => read_u32(boot, 36),
+            size => size as u32,
+        } as u64;
+
+        if !(512..=4096).contains(&bytes_per_sector) || !bytes_per_sector.is_power_of_two() {
+            return invalid("bad sector size");
+        }
+        if sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two() {
+            return invalid("bad cluster size");
+        }
+        if reserved_sectors == 0 || num_fats == 0 || fat_sectors == 0 {
+            return invalid("bad FAT layout");
+        }
+
+        let sector = bytes_per_sector as u64;
+        let root_dir_sectors =
+            (root_entry_count as u64 * DIR_ENTRY_SIZE as u64 + sector - 1) / sector;
+        let meta_sectors = reserved_sectors + num_fats as u64 * fat_sectors + root_dir_sectors;
+        if total_sectors <= meta_sectors {
+            return invalid("no data region");
+        }
+        let cluster_count = (total_sectors - meta_sectors) / sectors_per_cluster as u64;
+        let fat_type = if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        let (root_cluster, fsinfo_offset) = if fat_type == FatType::Fat32 {
+            let fsinfo = match read_u16(boot, 48) {
+                0 | 0xFFFF => None,
+                fsinfo => Some(fsinfo as u64 * sector),
+            };
+            (read_u32(boot, 44), fsinfo)
+        } else {
+            if root_entry_count == 0 {
+                return invalid("no root directory");
+            }
+            (0, None)
+        };
+
+        let fat_offset = reserved_sectors * sector;
+        let fat_size = fat_sectors * sector;
+        let root_dir_offset = fat_offset + num_fats as u64 * fat_size;
+        let geometry = Self {
+            fat_type,
+            bytes_per_cluster: bytes_per_sector * sectors_per_cluster,
+            fat_offset,
+            fat_size,
+            num_fats,
+            root_dir_offset,
+            root_entry_count,
+            data_offset: root_dir_offset + root_dir_sectors * sector,
+            cluster_count: cluster_count as u32,
+            root_cluster,
+            fsinfo_offset,
+        };
+        if fat_type == FatType::Fat32 && !geometry.is_valid_cluster(root_cluster) {
+            return invalid("bad root cluster");
+        }
+        Ok(geometry)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - 2) as u64 * self.bytes_per_cluster as u64
+    }
+}
+
+/// A file or directory of the filesystem.
+///
+/// This is a copy of its directory entry: after a change through `FatFs`, only the node
+/// passed to it is up to date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FatNode {
+    /// Byte offset of the short directory entry, `None` for the root directory.
+    entry: Option<u64>,
+    /// 0 for an empty file, and for the FAT12/16 root directory.
+    first_cluster: u32,
+    size: u32,
+    attributes: Attributes,
+}
+
+impl FatNode {
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+
+    /// Size of a file in bytes, 0 for directories.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.entry.is_none()
+    }
+
+    /// A number identifying the node as long as it exists.
+    pub fn id(&self) -> u64 {
+        // The root has no entry, and entries are never at offset 0, where the boot sector is.
+        self.entry.unwrap_or(0)
+    }
+}
+
+/// An entry of a directory.
+#[derive(Clone)]
+pub struct DirEntry {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    short_name: [u8; 11],
+    node: FatNode,
+    /// Byte offsets of the long name entries and the short entry, in directory order.
+    slots: [u64; MAX_LFN_ENTRIES + 1],
+    slot_count: usize,
+}
+
+impl DirEntry {
+    /// The long name if there is one, else the short name.
+    pub fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or("?")
+    }
+
+    pub fn node(&self) -> FatNode {
+        self.node
+    }
+
+    fn is_dot_entry(&self) -> bool {
+        self.name() == "." || self.name() == ".."
+    }
+}
+
+/// Append `c` to `buf` in UTF-8, returning false if it does not fit.
+fn push_char(buf: &mut [u8], len: &mut usize, c: char) -> bool {
+    let mut utf8 = [0; 4];
+    let encoded = c.encode_utf8(&mut utf8).as_bytes();
+    if *len + encoded.len() > buf.len() {
+        return false;
+    }
+    buf[*len..*len + encoded.len()].copy_from_slice(encoded);
+    *len += encoded.len();
+    true
+}
+
+/// Checksum of a short name, stored in its long name entries.
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c)
+    })
+}
+
+/// Whether `a` and `b` are the same name. Like other FAT implementations, only ASCII letters are
+/// compared case insensitively.
+fn names_equal(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+fn check_name(name: &str) -> Result<(), OsError> {
+    let invalid = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_LFN_LEN
+        || name
+            .chars()
+            .any(|c| c < ' ' || INVALID_NAME_CHARS.contains(&c))
+        || name.ends_with('.')
+        || name.ends_with(' ');
+    if invalid {
+        Err(OsError::InvalidFileName)
+    } else {
+        Ok(())
+    }
+}
+
+/// The short name for `name` as it would be without a numeric tail, and the NTRes case bits.
+/// Returns whether the name can be stored as this short name alone, without a long name.
+fn basis_short_name(name: &str) -> ([u8; 11], u8, bool) {
+    let mut short = [b' '; 11];
+    let (base, ext) = match name.rfind('.') {
+        Some(0) | None => (name, ""),
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+    };
+
+    let mut lossless = base.len() <= 8 && ext.len() <= 3 && !base.contains('.');
+    let mut fill = |part: &str, out: &mut [u8]| -> (bool, bool) {
+        let (mut lower, mut upper) = (false, false);
+        let mut len = 0;
+        for c in part.chars() {
+            if c == ' ' || c == '.' {
+                lossless = false;
+                continue;
+            }
+            if len == out.len() {
+                lossless = false;
+                break;
+            }
+            lower |= c.is_ascii_lowercase();
+            upper |= c.is_ascii_uppercase();
+            out[len] = if !c.is_ascii() || INVALID_SHORT_NAME_CHARS.contains(&(c as u8)) {
+                lossless = false;
+                b'_'
+            } else {
+                c.to_ascii_uppercase() as u8
+            };
+            len += 1;
+        }
+        (lower, upper)
+    };
+    let (base_lower, base_upper) = fill(base, &mut short[..8]);
+    let (ext_lower, ext_upper) = fill(ext, &mut short[8..]);
+
+    // A part in mixed case needs a long name, one all in lower case has a NTRes bit.
+    let mut ntres = 0;
+    if base_lower && base_upper || ext_lower && ext_upper {
+        lossless = false;
+    }
+    if base_lower {
+        ntres |= NTRES_LOWER_BASE;
+    }
+    if ext_lower {
+        ntres |= NTRES_LOWER_EXT;
+    }
+    if short[0] == b' ' {
+        short[0] = b'_';
+        lossless = false;
+    }
+    if short[0] == ENTRY_DELETED {
+        short[0] = ENTRY_KANJI_E5;
+    }
+    (short, ntres, lossless)
+}
+
+/// Put the numeric tail "~n" into a short name.
+fn with_numeric_tail(basis: &[u8; 11], n: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    let mut rest = n;
+    while rest > 0 {
+        digits[len] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        len += 1;
+    }
+    let base_len = basis[..8].iter().position(|&c| c == b' ').unwrap_or(8);
+    let keep = base_len.min(8 - len - 1);
+    let mut short = *basis;
+    short[keep] = b'~';
+    for i in 0..len {
+        short[keep + 1 + i] = digits[len - 1 - i];
+    }
+    for c in &mut short[keep + 1 + len..8] {
+        *c = b' ';
+    }
+    short
+}
+
+/// The number of a `~N` numeric tail in `short`, if it has one.
+fn numeric_tail(short: &[u8; 11]) -> Option<u32> {
+    let tilde = short[..8].iter().rposition(|&c| c == b'~')?;
+    let digits = &short[tilde + 1..8];
+    let len = digits
+        .iter()
+        .position(|&c| c == b' ')
+        .unwrap_or(digits.len());
+    if len == 0 || digits[0] == b'0' || digits[len..].iter().any(|&c| c != b' ') {
+        return None;
+    }
+    digits[..len].iter().try_fold(0u32, |n, &c| {
+        c.is_ascii_digit().then(|| n * 10 + (c - b'0') as u32)
+    })
+}
+
+/// The name shown for a short entry: "BASE.EXT", lowered as told by the NTRes bits.
+fn decode_short_name(short: &[u8; 11], ntres: u8, name: &mut [u8], len: &mut usize) {
+    let base_len = short[..8]
+        .iter()
+        .rposition(|&c| c != b' ')
+        .map_or(0, |i| i + 1);
+    let ext_len = short[8..]
+        .iter()
+        .rposition(|&c| c != b' ')
+        .map_or(0, |i| i + 1);
+    let mut push = |c: u8, lower: bool| {
+        let c = if lower { c.to_ascii_lowercase() } else { c };
+        // Non ASCII bytes are in an unknown OEM code page.
+        let c = if c.is_ascii() { c as char } else { '_' };
+        push_char(name, len, c);
+    };
+    for (i, &c) in short[..base_len].iter().enumerate() {
+        let c = if i == 0 && c == ENTRY_KANJI_E5 {
+            ENTRY_DELETED
+        } else {
+            c
+        };
+        push(c, ntres & NTRES_LOWER_BASE != 0);
+    }
+    if ext_len > 0 {
+        push(b'.', false);
+        for &c in &short[8..8 + ext_len] {
+            push(c, ntres & NTRES_LOWER_EXT != 0);
+        }
+    }
+}
+
+/// The node of the short entry `slot` at `offset`.
+fn node_from_slot(root: FatNode, offset: u64, slot: &[u8]) -> FatNode {
+    let attributes = Attributes::from_bits_truncate(slot[11]);
+    let first_cluster = ((read_u16(slot, 20) as u32) << 16) | read_u16(slot, 26) as u32;
+    if attributes.contains(Attributes::DIRECTORY) && first_cluster == 0 {
+        // ".." of a directory in the root.
+        return root;
+    }
+    FatNode {
+        entry: Some(offset),
+        first_cluster,
+        size: if attributes.contains(Attributes::DIRECTORY) {
+            0
+        } else {
+            read_u32(slot, 28)
+        },
+        attributes,
+    }
+}
+
+/// Assembles long names from the entries preceding a short entry.
+struct LongNameReader {
+    chars: [u16; MAX_LFN_ENTRIES * LFN_CHARS],
+    slots: [u64; MAX_LFN_ENTRIES + 1],
+    /// Number of entries of the name, 0 if there is none.
+    count: usize,
+    /// Sequence number expected next.
+    next: u8,
+    checksum: u8,
+}
+
+impl LongNameReader {
+    fn new() -> Self {
+        Self {
+            chars: [0; MAX_LFN_ENTRIES * LFN_CHARS],
+            slots: [0; MAX_LFN_ENTRIES + 1],
+            count: 0,
+            next: 0,
+            checksum: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.count = 0;
+        self.next = 0;
+    }
+
+    fn push(&mut self, offset: u64, slot: &[u8]) {
+        let seq = slot[0] & LFN_SEQUENCE_MASK;
+        if slot[0] & LFN_LAST != 0 {
+            if seq == 0 || seq as usize > MAX_LFN_ENTRIES {
+                self.reset();
+                return;
+            }
+            self.count = seq as usize;
+            self.checksum = slot[13];
+        } else if self.count == 0 || seq == 0 || seq != self.next || slot[13] != self.checksum {
+            self.reset();
+            return;
+        }
+        self.slots[self.count - seq as usize] = offset;
+        let chars = &mut self.chars[(seq as usize - 1) * LFN_CHARS..][..LFN_CHARS];
+        for (c, &pos) in chars.iter_mut().zip(LFN_CHAR_OFFSETS.iter()) {
+            *c = read_u16(slot, pos);
+        }
+        self.next = seq - 1;
+    }
+
+    /// Finish the name with the short entry at `offset`, whose short name is already in `entry`,
+    /// filling `entry`'s name and slots.
+    fn finish(&mut self, offset: u64, entry: &mut DirEntry) -> bool {
+        let complete = self.count > 0 && self.next == 0;
+        let valid = complete && short_name_checksum(&entry.short_name) == self.checksum;
+        if !valid {
+            self.reset();
+            return false;
+        }
+        let units = &self.chars[..self.count * LFN_CHARS];
+        let len = units
+            .iter()
+            .position(|&c| c == 0x0000 || c == 0xFFFF)
+            .unwrap_or(units.len());
+        entry.name_len = 0;
+        for c in char::decode_utf16(units[..len].iter().copied()) {
+            let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+            push_char(&mut entry.name, &mut entry.name_len, c);
+        }
+        entry.slots[..self.count].copy_from_slice(&self.slots[..self.count]);
+        entry.slots[self.count] = offset;
+        entry.slot_count = self.count + 1;
+        self.reset();
+        true
+    }
+}
+
+struct FatInner<D: BlockDevice, A: Allocator> {
+    device: D,
+    geometry: Geometry,
+    /// One block of the device, for partial reads and writes.
+    scratch: Vec<u8, A>,
+    /// Free clusters, if known.
+    free_clusters: Option<u32>,
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    /// The FSInfo sector needs an update.
+    fsinfo_dirty: bool,
+    /// Positions in cluster chains reached lately, see `nth_cluster`.
+    cursors: [Option<ChainCursor>; CHAIN_CURSORS],
+    /// The cursor replaced next.
+    next_cursor: usize,
+}
+
+/// Number of chain positions remembered, enough for a few files accessed in turns.
+const CHAIN_CURSORS: usize = 4;
+
+/// The `index`th cluster of the chain starting at `first`.
+#[derive(Debug, Clone, Copy)]
+struct ChainCursor {
+    first: u32,
+    index: u32,
+    cluster: u32,
+}
+
+impl<D: BlockDevice, A: Allocator> FatInner<D, A> {
+    fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), OsError> {
+        let block_size = self.scratch.len();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let block = pos / block_size as u64;
+            let in_block = (pos % block_size as u64) as usize;
+            let whole = (buf.len() - done) / block_size * block_size;
+            if in_block == 0 && whole > 0 {
+                self.device
+                    .read_blocks(block, &mut buf[done..done + whole])?;
+                done += whole;
+                continue;
+            }
+            let len = (block_size - in_block).min(buf.len() - done);
+            self.device.read_blocks(block, &mut self.scratch)?;
+            buf[done..done + len].copy_from_slice(&self.scratch[in_block..in_block + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> Result<(), OsError> {
+        let block_size = self.scratch.len();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let block = pos / block_size as u64;
+            let in_block = (pos % block_size as u64) as usize;
+            let whole = (buf.len() - done) / block_size * block_size;
+            if in_block == 0 && whole > 0 {
+                self.device.write_blocks(block, &buf[done..done + whole])?;
+                done += whole;
+                continue;
+            }
+            let len = (block_size - in_block).min(buf.len() - done);
+            self.device.read_blocks(block, &mut self.scratch)?;
+            self.scratch[in_block..in_block + len].copy_from_slice(&buf[done..done + len]);
+            self.device.write_blocks(block, &self.scratch)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn fill_bytes(&mut self, offset: u64, len: u64, value: u8) -> Result<(), OsError> {
+        let chunk = [value; 512];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(chunk.len() as u64);
+            self.write_bytes(offset + done, &chunk[..n as usize])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn root(&self) -> FatNode {
+        FatNode {
+            entry: None,
+            first_cluster: self.geometry.root_cluster,
+            size: 0,
+            attributes: Attributes::DIRECTORY,
+        }
+    }
+
+    // Cluster chains
+
+    /// Byte offset and length of the FAT entry of `cluster`, in the first FAT.
+    fn fat_entry_pos(&self, cluster: u32) -> (u64, usize) {
+        let geometry = &self.geometry;
+        match geometry.fat_type {
+            FatType::Fat12 => (geometry.fat_offset + (cluster + cluster / 2) as u64, 2),
+            FatType::Fat16 => (geometry.fat_offset + cluster as u64 * 2, 2),
+            FatType::Fat32 => (geometry.fat_offset + cluster as u64 * 4, 4),
+        }
+    }
+
+    fn read_fat(&mut self, cluster: u32) -> Result<u32, OsError> {
+        let (offset, len) = self.fat_entry_pos(cluster);
+        let mut buf = [0; 4];
+        self.read_bytes(offset, &mut buf[..len])?;
+        let raw = u32::from_le_bytes(buf);
+        Ok(match self.geometry.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => raw >> 4,
+            FatType::Fat12 => raw & 0xFFF,
+            FatType::Fat16 => raw,
+            FatType::Fat32 => raw & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Set the FAT entry of `cluster` in all FATs.
+    fn write_fat(&mut self, cluster: u32, value: u32) -> Result<(), OsError> {
+        let (offset, len) = self.fat_entry_pos(cluster);
+        let mut buf = [0; 4];
+        self.read_bytes(offset, &mut buf[..len])?;
+        let raw = u32::from_le_bytes(buf);
+        let raw = match self.geometry.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => (raw & 0x000F) | (value << 4),
+            FatType::Fat12 => (raw & 0xF000) | (value & 0xFFF),
+            FatType::Fat16 => value,
+            // The upper 4 bits are reserved and must be kept.
+            FatType::Fat32 => (raw & 0xF000_0000) | (value & 0x0FFF_FFFF),
+        };
+        let bytes = raw.to_le_bytes();
+        for fat in 0..self.geometry.num_fats {
+            let fat_offset = offset + fat as u64 * self.geometry.fat_size;
+            self.write_bytes(fat_offset, &bytes[..len])?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, `None` at the end.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, OsError> {
+        let next = self.read_fat(cluster)?;
+        if next >= self.geometry.fat_type.end_of_chain() {
+            Ok(None)
+        } else if self.geometry.is_valid_cluster(next) {
+            Ok(Some(next))
+        } else {
+            Err(OsError::CorruptFilesystem("bad cluster in chain"))
+        }
+    }
+
+    /// The `index`th cluster of the chain starting at `first`, `None` if the chain is shorter.
+    ///
+    /// The walk starts from the closest position remembered in the chain, so that accessing a
+    /// file front to back doesn't walk its chain again for every cluster.
+    fn nth_cluster(&mut self, first: u32, index: u32) -> Result<Option<u32>, OsError> {
+        if !self.geometry.is_valid_cluster(first) {
+            return Ok(None);
+        }
+        let (mut cluster, start) = self.closest_cluster(first, index);
+        for _ in start..index {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(None),
+            }
+        }
+        self.remember_cluster(first, index, cluster);
+        Ok(Some(cluster))
+    }
+
+    /// The remembered cluster of the chain starting at `first` closest before the `index`th,
+    /// and its index.
+    fn closest_cluster(&self, first: u32, index: u32) -> (u32, u32) {
+        self.cursors
+            .iter()
+            .flatten()
+            .filter(|cursor| cursor.first == first && cursor.index <= index)
+            .max_by_key(|cursor| cursor.index)
+            .map_or((first, 0), |cursor| (cursor.cluster, cursor.index))
+    }
+
+    /// Remember that `cluster` is the `index`th of the chain starting at `first`.
+    fn remember_cluster(&mut self, first: u32, index: u32, cluster: u32) {
+        let cursor = ChainCursor {
+            first,
+            index,
+            cluster,
+        };
+        let slot = match self
+            .cursors
+            .iter()
+            .position(|slot| matches!(slot, Some(cursor) if cursor.first == first))
+        {
+            Some(slot) => slot,
+            None => {
+                let slot = self.next_cursor;
+                self.next_cursor = (slot + 1) % CHAIN_CURSORS;
+                slot
+            }
+        };
+        self.cursors[slot] = Some(cursor);
+    }
+
+    /// Forget the positions in the chain starting at `first`, before it is cut or freed.
+    fn forget_chain(&mut self, first: u32) {
+        for slot in &mut self.cursors {
+            if matches!(slot, Some(cursor) if cursor.first == first) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Find a free cluster, mark it as the end of a chain and append it to `prev`.
+    fn allocate_cluster(&mut self, prev: Option<u32>) -> Result<u32, OsError> {
+        let count = self.geometry.cluster_count;
+        let start = if self.geometry.is_valid_cluster(self.next_free) {
+            self.next_free
+        } else {
+            2
+        };
+        let mut cluster = start;
+        loop {
+            if self.read_fat(cluster)? == 0 {
+                break;
+            }
+            cluster = if cluster + 1 < count + 2 {
+                cluster + 1
+            } else {
+                2
+            };
+            if cluster == start {
+                return Err(OsError::NoSpace);
+            }
+        }
+
+        self.write_fat(cluster, 0x0FFF_FFFF)?;
+        if let Some(prev) = prev {
+            self.write_fat(prev, cluster)?;
+        }
+        self.next_free = cluster + 1;
+        if let Some(free) = &mut self.free_clusters {
+            *free = free.saturating_sub(1);
+        }
+        self.fsinfo_dirty = true;
+        Ok(cluster)
+    }
+
+    /// Free the chain starting at `first`.
+    fn free_chain(&mut self, first: u32) -> Result<(), OsError> {
+        self.forget_chain(first);
+        let mut cluster = Some(first).filter(|&c| self.geometry.is_valid_cluster(c));
+        let mut remaining = self.geometry.cluster_count;
+        while let Some(current) = cluster {
+            if remaining == 0 {
+                return Err(OsError::CorruptFilesystem("loop in cluster chain"));
+            }
+            remaining -= 1;
+            cluster = self.next_cluster(current)?;
+            self.write_fat(current, 0)?;
+            if let Some(free) = &mut self.free_clusters {
+                *free += 1;
+            }
+        }
+        self.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> Result<(), OsError> {
+        let offset = self.geometry.cluster_offset(cluster);
+        self.fill_bytes(offset, self.geometry.bytes_per_cluster as u64, 0)
+    }
+
+    fn count_free_clusters(&mut self) -> Result<u32, OsError> {
+        if let Some(free) = self.free_clusters {
+            return Ok(free);
+        }
+        let mut free = 0;
+        for cluster in 2..self.geometry.cluster_count + 2 {
+            if self.read_fat(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        self.free_clusters = Some(free);
+        Ok(free)
+    }
+
+    fn load_fsinfo(&mut self) -> Result<(), OsError> {
+        let offset = match self.geometry.fsinfo_offset {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+        let mut sector = [0; 512];
+        self.read_bytes(offset, &mut sector)?;
+        if read_u32(&sector, 0) != FSINFO_LEAD_SIGNATURE
+            || read_u32(&sector, 484) != FSINFO_STRUCT_SIGNATURE
+        {
+            self.geometry.fsinfo_offset = None;
+            return Ok(());
+        }
+        let free = read_u32(&sector, 488);
+        if free <= self.geometry.cluster_count {
+            self.free_clusters = Some(free);
+        }
+        let next_free = read_u32(&sector, 492);
+        if next_free != FSINFO_UNKNOWN {
+            self.next_free = next_free;
+        }
+        Ok(())
+    }
+
+    fn store_fsinfo(&mut self) -> Result<(), OsError> {
+        let offset = match self.geometry.fsinfo_offset {
+            Some(offset) if self.fsinfo_dirty => offset,
+            _ => return Ok(()),
+        };
+        let free = self.free_clusters.unwrap_or(FSINFO_UNKNOWN);
+        self.write_bytes(offset + 488, &free.to_le_bytes())?;
+        self.write_bytes(offset + 492, &self.next_free.to_le_bytes())?;
+        self.fsinfo_dirty = false;
+        Ok(())
+    }
+
+    // Directories
+
+    /// Call `f` with the byte offset and contents of each entry slot of `dir`, until it breaks.
+    ///
+    /// With `extend`, a directory in a cluster chain grows by a zeroed cluster whenever `f` has
+    /// seen all of it without breaking.
+    fn walk_dir<R>(
+        &mut self,
+        dir: &FatNode,
+        extend: bool,
+        mut f: impl FnMut(u64, &[u8]) -> ControlFlow<R>,
+    ) -> Result<Option<R>, OsError> {
+        let mut chunk = [0; 512];
+        if dir.is_root() && self.geometry.fat_type != FatType::Fat32 {
+            let len = self.geometry.root_entry_count as u64 * DIR_ENTRY_SIZE as u64;
+            let start = self.geometry.root_dir_offset;
+            let mut pos = 0;
+            while pos < len {
+                let n = (len - pos).min(chunk.len() as u64) as usize;
+                self.read_bytes(start + pos, &mut chunk[..n])?;
+                for (i, slot) in chunk[..n].chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                    let offset = start + pos + (i * DIR_ENTRY_SIZE) as u64;
+                    if let ControlFlow::Break(r) = f(offset, slot) {
+                        return Ok(Some(r));
+                    }
+                }
+                pos += n as u64;
+            }
+            return Ok(None);
+        }
+
+        let mut cluster = dir.first_cluster;
+        if !self.geometry.is_valid_cluster(cluster) {
+            return Err(OsError::CorruptFilesystem("directory without clusters"));
+        }
+        let mut remaining = self.geometry.cluster_count;
+        loop {
+            let start = self.geometry.cluster_offset(cluster);
+            let len = self.geometry.bytes_per_cluster as u64;
+            let mut pos = 0;
+            while pos < len {
+                self.read_bytes(start + pos, &mut chunk)?;
+                for (i, slot) in chunk.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                    let offset = start + pos + (i * DIR_ENTRY_SIZE) as u64;
+                    if let ControlFlow::Break(r) = f(offset, slot) {
+                        return Ok(Some(r));
+                    }
+                }
+                pos += chunk.len() as u64;
+            }
+
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None if extend => {
+                    let next = self.allocate_cluster(Some(cluster))?;
+                    self.zero_cluster(next)?;
+                    next
+                }
+                None => return Ok(None),
+            };
+            if remaining == 0 {
+                return Err(OsError::CorruptFilesystem("loop in cluster chain"));
+            }
+            remaining -= 1;
+        }
+    }
+
+    /// Call `f` with each entry of `dir`, including "." and "..", until it breaks.
+    fn for_each_entry<R>(
+        &mut self,
+        dir: &FatNode,
+        mut f: impl FnMut(&DirEntry) -> ControlFlow<R>,
+    ) -> Result<Option<R>, OsError> {
+        if !dir.is_dir() {
+            return Err(OsError::NotADirectory);
+        }
+        let root = self.root();
+        let mut lfn = LongNameReader::new();
+        let mut entry = DirEntry {
+            name: [0; MAX_NAME_LEN],
+            name_len: 0,
+            short_name: [0; 11],
+            node: root,
+            slots: [0; MAX_LFN_ENTRIES + 1],
+            slot_count: 0,
+        };
+        self.walk_dir(dir, false, |offset, slot| {
+            match slot[0] {
+                ENTRY_END => return ControlFlow::Break(None),
+                ENTRY_DELETED => {
+                    lfn.reset();
+                    return ControlFlow::Continue(());
+                }
+                _ => {}
+            }
+            let attributes = Attributes::from_bits_truncate(slot[11]);
+            if slot[11] & Attributes::LONG_NAME.bits() == Attributes::LONG_NAME.bits() {
+                lfn.push(offset, slot);
+                return ControlFlow::Continue(());
+            }
+            if attributes.contains(Attributes::VOLUME_ID) {
+                lfn.reset();
+                return ControlFlow::Continue(());
+            }
+
+            entry.short_name.copy_from_slice(&slot[..11]);
+            if !lfn.finish(offset, &mut entry) {
+                entry.name_len = 0;
+                decode_short_name(
+                    &entry.short_name,
+                    slot[12],
+                    &mut entry.name,
+                    &mut entry.name_len,
+                );
+                entry.slots[0] = offset;
+                entry.slot_count = 1;
+            }
+            entry.node = node_from_slot(root, offset, slot);
+            match f(&entry) {
+                ControlFlow::Break(r) => ControlFlow::Break(Some(r)),
+                ControlFlow::Continue(()) => ControlFlow::Continue(()),
+            }
+        })
+        .map(Option::flatten)
+    }
+
+    fn find_entry(&mut self, dir: &FatNode, name: &str) -> Result<DirEntry, OsError> {
+        self.for_each_entry(dir, |entry| {
+            if names_equal(entry.name(), name) {
+                ControlFlow::Break(entry.clone())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?
+        .ok_or(OsError::NotFound)
+    }
+
+    /// The node with the id `id`, reading its directory entry again.
+    fn node(&mut self, id: u64) -> Result<FatNode, OsError> {
+        if id == 0 {
+            return Ok(self.root());
+        }
+        let mut slot = [0; DIR_ENTRY_SIZE];
+        self.read_bytes(id, &mut slot)?;
+        let is_long_name = slot[11] & Attributes::LONG_NAME.bits() == Attributes::LONG_NAME.bits();
+        if slot[0] == ENTRY_END || slot[0] == ENTRY_DELETED || is_long_name {
+            return Err(OsError::NotFound);
+        }
+        Ok(node_from_slot(self.root(), id, &slot))
+    }
+
+    fn lookup_path(&mut self, path: &str) -> Result<FatNode, OsError> {
+        let mut node = self.root();
+        for component in path.split('/') {
+            if component.is_empty() || component == "." {
+                continue;
+            }
+            if !node.is_dir() {
+                return Err(OsError::NotADirectory);
+            }
+            if node.is_root() && component == ".." {
+                continue;
+            }
+            node = self.find_entry(&node, component)?.node;
+        }
+        Ok(node)
+    }
+
+    /// Whether `dir` contains no entries besides "." and "..".
+    fn is_empty_dir(&mut self, dir: &FatNode) -> Result<bool, OsError> {
+        let found = self.for_each_entry(dir, |entry| {
+            if entry.is_dot_entry() {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        })?;
+        Ok(found.is_none())
+    }
+
+    /// Find `count` consecutive free slots in `dir`, growing it if needed.
+    fn find_free_slots(
+        &mut self,
+        dir: &FatNode,
+        count: usize,
+    ) -> Result<[u64; MAX_LFN_ENTRIES + 1], OsError> {
+        let mut slots = [0; MAX_LFN_ENTRIES + 1];
+        let mut found = 0;
+        let done = self.walk_dir(dir, true, |offset, slot| {
+            if slot[0] == ENTRY_END || slot[0] == ENTRY_DELETED {
+                slots[found] = offset;
+                found += 1;
+                if found == count {
+                    return ControlFlow::Break(());
+                }
+            } else {
+                found = 0;
+            }
+            ControlFlow::Continue(())
+        })?;
+        match done {
+            Some(()) => Ok(slots),
+            // Only the fixed root directory can't grow.
+            None => Err(OsError::NoSpace),
+        }
+    }
+
+    fn write_entry_node(&mut self, node: &FatNode) -> Result<(), OsError> {
+        let offset = match node.entry {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+        let mut slot = [0; DIR_ENTRY_SIZE];
+        self.read_bytes(offset, &mut slot)?;
+        slot[11] = node.attributes.bits();
+        slot[20..22].copy_from_slice(&((node.first_cluster >> 16) as u16).to_le_bytes());
+        slot[24..26].copy_from_slice(&FAT_EPOCH_DATE.to_le_bytes());
+        slot[26..28].copy_from_slice(&(node.first_cluster as u16).to_le_bytes());
+        let size = if node.is_dir() { 0 } else { node.size };
+        slot[28..32].copy_from_slice(&size.to_le_bytes());
+        self.write_bytes(offset, &slot)
+    }
+
+    /// A short directory entry.
+    fn short_entry(short_name: &[u8; 11], ntres: u8, node: &FatNode) -> [u8; DIR_ENTRY_SIZE] {
+        let mut slot = [0; DIR_ENTRY_SIZE];
+        slot[..11].copy_from_slice(short_name);
+        slot[11] = node.attributes.bits();
+        slot[12] = ntres;
+        for date in [16, 18, 24] {
+            slot[date..date + 2].copy_from_slice(&FAT_EPOCH_DATE.to_le_bytes());
+        }
+        slot[20..22].copy_from_slice(&((node.first_cluster >> 16) as u16).to_le_bytes());
+        slot[26..28].copy_from_slice(&(node.first_cluster as u16).to_le_bytes());
+        slot[28..32].copy_from_slice(&node.size.to_le_bytes());
+        slot
+    }
+
+    /// A short name for `name` that is not used in `dir` yet, and whether a long name is
+    /// needed.
+    fn unique_short_name(
+        &mut self,
+        dir: &FatNode,
+        name: &str,
+    ) -> Result<([u8; 11], u8, bool), OsError> {
+        let (basis, ntres, lossless) = basis_short_name(name);
+        // Look at the directory once: whether the basis itself is taken, which of the first
+        // 64 numeric tails are, and the highest one.
+        let mut basis_taken = false;
+        let mut low_tails = 0u64;
+        let mut max_tail = 0;
+        self.for_each_entry(dir, |entry| {
+            if entry.short_name == basis {
+                basis_taken = true;
+            } else if let Some(n) = numeric_tail(&entry.short_name) {
+                if with_numeric_tail(&basis, n) == entry.short_name {
+                    if n <= 64 {
+                        low_tails |= 1 << (n - 1);
+                    }
+                    max_tail = max_tail.max(n);
+                }
+            }
+            ControlFlow::<()>::Continue(())
+        })?;
+        if lossless && !basis_taken {
+            return Ok((basis, ntres, false));
+        }
+
+        // Past the first 64 tails, take the one after the highest, which a directory can't
+        // hold enough entries to run out of.
+        let n = if low_tails != u64::MAX {
+            low_tails.trailing_ones() + 1
+        } else {
+            max_tail + 1
+        };
+        if n >= 1_000_000 {
+            return Err(OsError::NoSpace);
+        }
+        Ok((with_numeric_tail(&basis, n), 0, true))
+    }
+
+    fn create(&mut self, dir: &FatNode, name: &str, is_dir: bool) -> Result<FatNode, OsError> {
+        check_name(name)?;
+        match self.find_entry(dir, name) {
+            Ok(_) => return Err(OsError::AlreadyExists),
+            Err(OsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        let (short_name, ntres, needs_lfn) = self.unique_short_name(dir, name)?;
+        let mut units = [0u16; MAX_LFN_ENTRIES * LFN_CHARS];
+        let mut unit_count = 0;
+        for unit in name.encode_utf16() {
+            units[unit_count] = unit;
+            unit_count += 1;
+        }
+        let lfn_entries = if needs_lfn {
+            (unit_count + LFN_CHARS - 1) / LFN_CHARS
+        } else {
+            0
+        };
+        let slots = self.find_free_slots(dir, lfn_entries + 1)?;
+
+        let mut node = FatNode {
+            entry: Some(slots[lfn_entries]),
+            first_cluster: 0,
+            size: 0,
+            attributes: if is_dir {
+                Attributes::DIRECTORY
+            } else {
+                Attributes::ARCHIVE
+            },
+        };
+        if is_dir {
+            let cluster = self.allocate_cluster(None)?;
+            self.zero_cluster(cluster)?;
+            node.first_cluster = cluster;
+            let parent_cluster = if dir.is_root() { 0 } else { dir.first_cluster };
+            let parent = FatNode {
+                first_cluster: parent_cluster,
+                ..node
+            };
+            let offset = self.geometry.cluster_offset(cluster);
+            self.write_bytes(offset, &Self::short_entry(b".          ", 0, &node))?;
+            self.write_bytes(
+                offset + DIR_ENTRY_SIZE as u64,
+                &Self::short_entry(b"..         ", 0, &parent),
+            )?;
+        }
+
+        // The long name entries are stored last part first, and the name is terminated by a
+        // 0x0000 and padded with 0xFFFF.
+        let checksum = short_name_checksum(&short_name);
+        for (i, &offset) in slots[..lfn_entries].iter().enumerate() {
+            let seq = lfn_entries - i;
+            let mut slot = [0; DIR_ENTRY_SIZE];
+            slot[0] = seq as u8 | if i == 0 { LFN_LAST } else { 0 };
+            slot[11] = Attributes::LONG_NAME.bits();
+            slot[13] = checksum;
+            for (j, &pos) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                let idx = (seq - 1) * LFN_CHARS + j;
+                let unit = match idx.cmp(&unit_count) {
+                    core::cmp::Ordering::Less => units[idx],
+                    core::cmp::Ordering::Equal => 0x0000,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                slot[pos..pos + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            self.write_bytes(offset, &slot)?;
+        }
+        self.write_bytes(
+            slots[lfn_entries],
+            &Self::short_entry(&short_name, ntres, &node),
+        )?;
+        Ok(node)
+    }
+
+    fn remove(&mut self, dir: &FatNode, name: &str) -> Result<(), OsError> {
+        if name == "." || name == ".." {
+            return Err(OsError::InvalidFileName);
+        }
+        let entry = self.find_entry(dir, name)?;
+        if entry.node.is_dir() && !self.is_empty_dir(&entry.node)? {
+            return Err(OsError::DirectoryNotEmpty);
+        }
+        for &offset in &entry.slots[..entry.slot_count] {
+            self.write_bytes(offset, &[ENTRY_DELETED])?;
+        }
+        self.free_chain(entry.node.first_cluster)
+    }
+
+    // File contents
+
+    fn read(&mut self, node: &FatNode, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        if node.is_dir() {
+            return Err(OsError::IsADirectory);
+        }
+        if offset >= node.size as u64 {
+            return Ok(0);
+        }
+        let len = buf.len().min((node.size as u64 - offset) as usize);
+        let cluster_size = self.geometry.bytes_per_cluster as u64;
+        let mut index = (offset / cluster_size) as u32;
+        let mut cluster = self
+            .nth_cluster(node.first_cluster, index)?
+            .ok_or(OsError::CorruptFilesystem("file shorter than its size"))?;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let in_cluster = pos % cluster_size;
+            let n = ((cluster_size - in_cluster) as usize).min(len - done);
+            let disk_offset = self.geometry.cluster_offset(cluster) + in_cluster;
+            self.read_bytes(disk_offset, &mut buf[done..done + n])?;
+            done += n;
+            if done < len {
+                cluster = self
+                    .next_cluster(cluster)?
+                    .ok_or(OsError::CorruptFilesystem("file shorter than its size"))?;
+                index += 1;
+            }
+        }
+        self.remember_cluster(node.first_cluster, index, cluster);
+        Ok(len)
+    }
+
+    /// Make the chain of `node` hold `clusters` clusters, allocating or freeing at its end.
+    fn resize_chain(&mut self, node: &mut FatNode, clusters: u32) -> Result<(), OsError> {
+        if clusters == 0 {
+            let first = node.first_cluster;
+            node.first_cluster = 0;
+            return self.free_chain(first);
+        }
+        if !self.geometry.is_valid_cluster(node.first_cluster) {
+            node.first_cluster = self.allocate_cluster(None)?;
+        }
+        let (mut cluster, start) = self.closest_cluster(node.first_cluster, clusters - 1);
+        for _ in start + 1..clusters {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => self.allocate_cluster(Some(cluster))?,
+            };
+        }
+        if let Some(rest) = self.next_cluster(cluster)? {
+            self.forget_chain(node.first_cluster);
+            self.write_fat(cluster, 0x0FFF_FFFF)?;
+            self.free_chain(rest)?;
+        }
+        self.remember_cluster(node.first_cluster, clusters - 1, cluster);
+        Ok(())
+    }
+
+    fn clusters_for(&self, size: u64) -> u32 {
+        let cluster_size = self.geometry.bytes_per_cluster as u64;
+        ((size + cluster_size - 1) / cluster_size) as u32
+    }
+
+    fn write(&mut self, node: &mut FatNode, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        if node.is_dir() {
+            return Err(OsError::IsADirectory);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(OsError::FileTooLarge)?;
+        if offset > node.size as u64 {
+            // Writing past the end leaves a hole, which reads as zeros.
+            self.truncate(node, offset as u32)?;
+        }
+        if end > node.size as u64 {
+            let clusters = self.clusters_for(end);
+            self.resize_chain(node, clusters)?;
+        }
+
+        let cluster_size = self.geometry.bytes_per_cluster as u64;
+        let mut index = (offset / cluster_size) as u32;
+        let mut cluster = self
+            .nth_cluster(node.first_cluster, index)?
+            .ok_or(OsError::CorruptFilesystem("file shorter than its size"))?;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_cluster = pos % cluster_size;
+            let n = ((cluster_size - in_cluster) as usize).min(buf.len() - done);
+            let disk_offset = self.geometry.cluster_offset(cluster) + in_cluster;
+            self.write_bytes(disk_offset, &buf[done..done + n])?;
+            done += n;
+            if done < buf.len() {
+                cluster = self
+                    .next_cluster(cluster)?
+                    .ok_or(OsError::CorruptFilesystem("file shorter than its size"))?;
+                index += 1;
+            }
+        }
+        self.remember_cluster(node.first_cluster, index, cluster);
+
+        node.size = node.size.max(end as u32);
+        node.attributes |= Attributes::ARCHIVE;
+        self.write_entry_node(node)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&mut self, node: &mut FatNode, size: u32) -> Result<(), OsError> {
+        if node.is_dir() {
+            return Err(OsError::IsADirectory);
+        }
+        let old_size = node.size;
+        let clusters = self.clusters_for(size as u64);
+        self.resize_chain(node, clusters)?;
+        node.size = size;
+        if size > old_size {
+            // Clear what the file grew by, clusters may hold old data.
+            let cluster_size = self.geometry.bytes_per_cluster as u64;
+            let mut pos = old_size as u64;
+            while pos < size as u64 {
+                let cluster = self
+                    .nth_cluster(node.first_cluster, (pos / cluster_size) as u32)?
+                    .ok_or(OsError::CorruptFilesystem("file shorter than its size"))?;
+                let in_cluster = pos % cluster_size;
+                let n = (cluster_size - in_cluster).min(size as u64 - pos);
+                self.fill_bytes(self.geometry.cluster_offset(cluster) + in_cluster, n, 0)?;
+                pos += n;
+            }
+        }
+        self.write_entry_node(node)
+    }
+}
+
+/// A mounted FAT filesystem on a block device.
+///
+/// Files and directories are handled as `FatNode`s, looked up from the root.
+pub struct FatFs<D: BlockDevice, A: Allocator> {
+    inner: NullLock<FatInner<D, A>>,
+}
+
+impl<D: BlockDevice, A: Allocator> FatFs<D, A> {
+    /// Mount the filesystem on `device`, which should be a partition behind a `BlockCache`.
+    pub fn new(device: D, alloc: A) -> Result<Self, OsError> {
+        let block_size = device.block_size();
+        let mut scratch = Vec::new_in(alloc);
+        scratch
+            .try_reserve_exact(block_size)
+            .map_err(|_| AllocError)?;
+        scratch.resize(block_size, 0);
+
+        let mut boot = [0; 512];
+        let mut inner = FatInner {
+            device,
+            // Replaced right below, only the FAT offset matters for reading the boot sector.
+            geometry: Geometry {
+                fat_type: FatType::Fat12,
+                bytes_per_cluster: 512,
+                fat_offset: 0,
+                fat_size: 0,
+                num_fats: 0,
+                root_dir_offset: 0,
+                root_entry_count: 0,
+                data_offset: 0,
+                cluster_count: 0,
+                root_cluster: 0,
+                fsinfo_offset: None,
+            },
+            scratch,
+            free_clusters: None,
+            next_free: 2,
+            fsinfo_dirty: false,
+            cursors: [None; CHAIN_CURSORS],
+            next_cursor: 0,
+        };
+        inner.read_bytes(0, &mut boot)?;
+        inner.geometry = Geometry::parse(&boot)?;
+        let total = inner.geometry.data_offset
+            + inner.geometry.cluster_count as u64 * inner.geometry.bytes_per_cluster as u64;
+        if total > inner.device.size() {
+            return Err(OsError::CorruptFilesystem("larger than its device"));
+        }
+        inner.load_fsinfo()?;
+        Ok(Self {
+            inner: NullLock::new(inner),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.inner.lock(|inner| inner.geometry.fat_type)
+    }
+
+    pub fn cluster_size(&self) -> u32 {
+        self.inner.lock(|inner| inner.geometry.bytes_per_cluster)
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.inner.lock(|inner| inner.geometry.cluster_count)
+    }
+
+    /// Number of free clusters. Unless FSInfo tells, the whole FAT is read the first time.
+    pub fn free_clusters(&self) -> Result<u32, OsError> {
+        self.inner.lock(|inner| inner.count_free_clusters())
+    }
+
+    pub fn root(&self) -> FatNode {
+        self.inner.lock(|inner| inner.root())
+    }
+
+    /// The node whose `FatNode::id` is `id`, as it is now.
+    pub fn node(&self, id: u64) -> Result<FatNode, OsError> {
+        self.inner.lock(|inner| inner.node(id))
+    }
+
+    /// The node at `path`, relative to the root. Names are compared ignoring ASCII case.
+    #[allow(dead_code)]
+    pub fn lookup_path(&self, path: &str) -> Result<FatNode, OsError> {
+        self.inner.lock(|inner| inner.lookup_path(path))
+    }
+
+    /// The entry called `name` in `dir`.
+    pub fn lookup(&self, dir: &FatNode, name: &str) -> Result<FatNode, OsError> {
+        self.inner
+            .lock(|inner| inner.find_entry(dir, name).map(|entry| entry.node))
+    }
+
+    /// Call `f` with each entry of `dir`, including "." and "..", until it breaks.
+    pub fn read_dir<R>(
+        &self,
+        dir: &FatNode,
+        f: impl FnMut(&DirEntry) -> ControlFlow<R>,
+    ) -> Result<Option<R>, OsError> {
+        self.inner.lock(|inner| inner.for_each_entry(dir, f))
+    }
+
+    /// Read from `offset` of a file into `buf`, returning the number of bytes read.
+    pub fn read(&self, node: &FatNode, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        self.inner.lock(|inner| inner.read(node, offset, buf))
+    }
+
+    /// Write `buf` at `offset` of a file, growing it as needed. `node` is updated.
+    pub fn write(&self, node: &mut FatNode, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        self.inner.lock(|inner| inner.write(node, offset, buf))
+    }
+
+    /// Cut or extend a file to `size` bytes. `node` is updated.
+    pub fn truncate(&self, node: &mut FatNode, size: u32) -> Result<(), OsError> {
+        self.inner.lock(|inner| inner.truncate(node, size))
+    }
+
+    /// Create an empty file in `dir`.
+    pub fn create_file(&self, dir: &FatNode, name: &str) -> Result<FatNode, OsError> {
+        self.inner.lock(|inner| inner.create(dir, name, false))
+    }
+
+    /// Create an empty directory in `dir`.
+    pub fn create_dir(&self, dir: &FatNode, name: &str) -> Result<FatNode, OsError> {
+        self.inner.lock(|inner| inner.create(dir, name, true))
+    }
+
+    /// Delete the file or empty directory `name` in `dir`.
+    pub fn remove(&self, dir: &FatNode, name: &str) -> Result<(), OsError> {
+        self.inner.lock(|inner| inner.remove(dir, name))
+    }
+
+    /// Write the free cluster count back, and flush the device.
+    pub fn sync(&self) -> Result<(), OsError> {
+        self.inner.lock(|inner| {
+            inner.store_fsinfo()?;
+            inner.device.flush()
+        })
+    }
+}
+
+impl<D: BlockDevice + Send, A: Allocator + Send> FileSystem for FatFs<D, A> {
+    fn root(&self) -> Ino {
+        self.root().id()
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, OsError> {
+        let node = self.node(ino)?;
+        let cluster_size = self.cluster_size() as u64;
+        let clusters = (node.size() as u64 + cluster_size - 1) / cluster_size;
+        let mode = match (
+            node.is_dir(),
+            node.attributes().contains(Attributes::READ_ONLY),
+        ) {
+            (true, false) => 0o755,
+            (true, true) => 0o555,
+            (false, false) => 0o644,
+            (false, true) => 0o444,
+        };
+        Ok(Stat {
+            dev: 0,
+            ino,
+            kind: if node.is_dir() {
+                FileKind::Directory
+            } else {
+                FileKind::Regular
+            },
+            mode,
+            nlink: 1,
+            rdev: DeviceNumber::default(),
+            size: node.size() as u64,
+            block_size: cluster_size as u32,
+            blocks: clusters * cluster_size / 512,
+        })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, OsError> {
+        let dir = self.node(dir)?;
+        self.lookup(&dir, name).map(|node| node.id())
+    }
+
+    fn read_dir(&self, dir: Ino, start: u64, f: &mut DirEntryFn<'_>) -> Result<(), OsError> {
+        let dir = self.node(dir)?;
+        let mut skipped = 0;
+        self.read_dir(&dir, |entry| {
+            if entry.is_dot_entry() {
+                return ControlFlow::Continue(());
+            }
+            if skipped < start {
+                skipped += 1;
+                return ControlFlow::Continue(());
+            }
+            let node = entry.node();
+            let kind = if node.is_dir() {
+                FileKind::Directory
+            } else {
+                FileKind::Regular
+            };
+            f(entry.name(), node.id(), kind)
+        })?;
+        Ok(())
+    }
+
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        let node = self.node(ino)?;
+        self.read(&node, offset, buf)
+    }
+
+    fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        let mut node = self.node(ino)?;
+        self.write(&mut node, offset, buf)
+    }
+
+    fn truncate(&self, ino: Ino, size: u64) -> Result<(), OsError> {
+        let mut node = self.node(ino)?;
+        let size = u32::try_from(size).map_err(|_| OsError::FileTooLarge)?;
+        self.truncate(&mut node, size)
+    }
+
+    fn create(&self, dir: Ino, name: &str, kind: FileKind, _mode: u16) -> Result<Ino, OsError> {
+        let dir = self.node(dir)?;
+        let node = match kind {
+            FileKind::Regular => self.create_file(&dir, name)?,
+            FileKind::Directory => self.create_dir(&dir, name)?,
+            FileKind::Symlink | FileKind::CharDevice | FileKind::BlockDevice => {
+                return Err(OsError::NotSupported)
+            }
+        };
+        Ok(node.id())
+    }
+
+    fn unlink(&self, dir: Ino, name: &str) -> Result<(), OsError> {
+        let dir = self.node(dir)?;
+        self.remove(&dir, name)
+    }
+
+    fn sync(&self) -> Result<(), OsError> {
+        self.sync()
+    }
+}
@@ -0,0 +1,49 @@
+use crate::error::OsError;
+
+use super::vfs::FileId;
+
+/// A file descriptor, an index into the `FdTable` of a process.
+pub type Fd = usize;
+
+/// Open files per process, like `RLIMIT_NOFILE`.
+pub const MAX_FDS: usize = 64;
+
+/// The file descriptors of a process, each referring to a file open in the VFS.
+pub struct FdTable {
+    files: [Option<FileId>; MAX_FDS],
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        Self {
+            files: [None; MAX_FDS],
+        }
+    }
+
+    /// Add `file` at the lowest free descriptor.
+    pub fn insert(&mut self, file: FileId) -> Result<Fd, OsError> {
+        let fd = self
+            .files
+            .iter()
+            .position(Option::is_none)
+            .ok_or(OsError::TooManyOpenFiles)?;
+        self.files[fd] = Some(file);
+        Ok(fd)
+    }
+
+    pub fn get(&self, fd: Fd) -> Result<FileId, OsError> {
+        self.files
+            .get(fd)
+            .copied()
+            .flatten()
+            .ok_or(OsError::BadFileDescriptor)
+    }
+
+    /// Free `fd`, returning the file it referred to.
+    pub fn remove(&mut self, fd: Fd) -> Result<FileId, OsError> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(OsError::BadFileDescriptor)
+    }
+}
@@ -0,0 +1,287 @@
+//! Archives unpacked into the root filesystem at boot: newc cpio, as made by
+//! `find . | cpio -o -H newc`, and ustar, as made by `tar --format=ustar`.
+
+use core::str;
+
+use crate::error::OsError;
+
+use super::{
+    vfs::{OpenFlags, VFS},
+    PATH_MAX,
+};
+
+/// The archive built from the `initramfs` directory of the repository by `build.rs`.
+pub static INITRAMFS_BYTES: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initramfs.cpio"));
+
+const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+/// newc with checksums, which are not checked.
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_LEN: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const TAR_BLOCK_LEN: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// File types in the mode of a cpio entry.
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// The data is the target.
+    Symlink,
+}
+
+/// A file of an archive.
+pub struct ArchiveEntry<'a> {
+    /// Relative to the root of the archive, without a leading "./" or "/".
+    pub path: &'a str,
+    pub kind: EntryKind,
+    /// Permission bits.
+    pub mode: u16,
+    pub data: &'a [u8],
+}
+
+/// What `unpack` created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnpackStats {
+    pub files: usize,
+    pub directories: usize,
+    pub symlinks: usize,
+    /// Total size of the files.
+    pub bytes: usize,
+    /// Entries of other kinds, like devices or hard links, which were left out.
+    pub skipped: usize,
+}
+
+fn parse_hex(field: &[u8]) -> Result<u32, OsError> {
+    let field = str::from_utf8(field).map_err(|_| OsError::InvalidArchive("bad number"))?;
+    u32::from_str_radix(field, 16).map_err(|_| OsError::InvalidArchive("bad number"))
+}
+
+/// Parse a NUL or space terminated octal number.
+fn parse_octal(field: &[u8]) -> Result<u64, OsError> {
+    let end = field
+        .iter()
+        .position(|&c| c == 0 || c == b' ')
+        .unwrap_or(field.len());
+    let start = field[..end].iter().position(|&c| c != b' ').unwrap_or(end);
+    if start == end {
+        return Ok(0);
+    }
+    let field =
+        str::from_utf8(&field[start..end]).map_err(|_| OsError::InvalidArchive("bad number"))?;
+    u64::from_str_radix(field, 8).map_err(|_| OsError::InvalidArchive("bad number"))
+}
+
+/// Take `len` bytes from `archive` at `offset`.
+fn take(archive: &[u8], offset: usize, len: usize) -> Result<&[u8], OsError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| archive.get(offset..end))
+        .ok_or(OsError::InvalidArchive("truncated"))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Strip what makes `path` absolute or explicitly relative. The root itself becomes "".
+fn normalize(path: &str) -> &str {
+    let mut path = path.trim_start_matches('/');
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest.trim_start_matches('/');
+    }
+    if path == "." {
+        ""
+    } else {
+        path.trim_end_matches('/')
+    }
+}
+
+fn for_each_cpio_entry(
+    archive: &[u8],
+    f: &mut dyn FnMut(Option<ArchiveEntry>) -> Result<(), OsError>,
+) -> Result<(), OsError> {
+    let mut offset = 0;
+    loop {
+        let header = take(archive, offset, CPIO_HEADER_LEN)?;
+        if &header[..6] != CPIO_NEWC_MAGIC && &header[..6] != CPIO_CRC_MAGIC {
+            return Err(OsError::InvalidArchive("bad cpio header"));
+        }
+        let field = |n: usize| parse_hex(&header[6 + n * 8..14 + n * 8]);
+        let mode = field(1)?;
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+        if name_size == 0 {
+            return Err(OsError::InvalidArchive("empty name"));
+        }
+
+        // The name includes its NUL.
+        let name = take(archive, offset + CPIO_HEADER_LEN, name_size - 1)?;
+        let name = str::from_utf8(name).map_err(|_| OsError::InvalidArchive("bad name"))?;
+        let data_offset = align4(offset + CPIO_HEADER_LEN + name_size);
+        let data = take(archive, data_offset, file_size)?;
+        offset = align4(data_offset + file_size);
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+
+        let kind = match mode & S_IFMT {
+            S_IFREG => EntryKind::File,
+            S_IFDIR => EntryKind::Directory,
+            S_IFLNK => EntryKind::Symlink,
+            _ => {
+                f(None)?;
+                continue;
+            }
+        };
+        f(Some(ArchiveEntry {
+            path: normalize(name),
+            kind,
+            mode: (mode & 0o7777) as u16,
+            data,
+        }))?;
+    }
+}
+
+/// A tar string field, NUL terminated unless full.
+fn tar_field(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    &field[..len]
+}
+
+fn for_each_tar_entry(
+    archive: &[u8],
+    f: &mut dyn FnMut(Option<ArchiveEntry>) -> Result<(), OsError>,
+) -> Result<(), OsError> {
+    let mut offset = 0;
+    let mut path = [0u8; 256];
+    loop {
+        let header = take(archive, offset, TAR_BLOCK_LEN)?;
+        // The archive ends with two zero blocks.
+        if header.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+        if &header[257..262] != TAR_MAGIC {
+            return Err(OsError::InvalidArchive("bad tar header"));
+        }
+        // The checksum is calculated with its own field taken as spaces.
+        let checksum = parse_octal(&header[148..156])?;
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+            .sum();
+        if sum != checksum {
+            return Err(OsError::InvalidArchive("bad tar checksum"));
+        }
+
+        let mode = parse_octal(&header[100..108])? as u16 & 0o7777;
+        let size = parse_octal(&header[124..136])? as usize;
+        let data_offset = offset + TAR_BLOCK_LEN;
+        let data = take(archive, data_offset, size)?;
+        offset = data_offset + (size + TAR_BLOCK_LEN - 1) / TAR_BLOCK_LEN * TAR_BLOCK_LEN;
+
+        // The path is split in a prefix and a name.
+        let prefix = tar_field(&header[345..500]);
+        let name = tar_field(&header[0..100]);
+        let mut len = 0;
+        for part in [prefix, b"/".as_slice(), name] {
+            if prefix.is_empty() && len == 0 && part == b"/" {
+                continue;
+            }
+            path[len..len + part.len()].copy_from_slice(part);
+            len += part.len();
+        }
+        let path = str::from_utf8(&path[..len]).map_err(|_| OsError::InvalidArchive("bad name"))?;
+
+        let (kind, data) = match header[156] {
+            b'0' | 0 => (EntryKind::File, data),
+            b'5' => (EntryKind::Directory, data),
+            b'2' => (EntryKind::Symlink, tar_field(&header[157..257])),
+            _ => {
+                f(None)?;
+                continue;
+            }
+        };
+        f(Some(ArchiveEntry {
+            path: normalize(path),
+            kind,
+            mode,
+            data,
+        }))?;
+    }
+}
+
+/// Call `f` with each entry of `archive`, in order, or `None` for entries of a kind which is
+/// not supported.
+pub fn for_each_entry(
+    archive: &[u8],
+    mut f: impl FnMut(Option<ArchiveEntry>) -> Result<(), OsError>,
+) -> Result<(), OsError> {
+    if archive.starts_with(CPIO_NEWC_MAGIC) || archive.starts_with(CPIO_CRC_MAGIC) {
+        for_each_cpio_entry(archive, &mut f)
+    } else if archive.get(257..262) == Some(TAR_MAGIC) {
+        for_each_tar_entry(archive, &mut f)
+    } else {
+        Err(OsError::InvalidArchive("neither newc cpio nor ustar"))
+    }
+}
+
+/// Unpack `archive` into the root filesystem. Directories which already exist are kept, files
+/// are overwritten.
+pub fn unpack(archive: &[u8]) -> Result<UnpackStats, OsError> {
+    let mut stats = UnpackStats::default();
+    for_each_entry(archive, |entry| {
+        let entry = match entry {
+            Some(entry) if !entry.path.is_empty() => entry,
+            Some(_) => return Ok(()),
+            None => {
+                stats.skipped += 1;
+                return Ok(());
+            }
+        };
+        match entry.kind {
+            EntryKind::Directory => {
+                match VFS.mkdir(None, entry.path, entry.mode) {
+                    Ok(()) | Err(OsError::AlreadyExists) => {}
+                    Err(err) => return Err(err),
+                }
+                stats.directories += 1;
+            }
+            EntryKind::File => {
+                let flags = OpenFlags::CREATE | OpenFlags::WRITE_ONLY | OpenFlags::TRUNCATE;
+                let file = VFS.open(None, entry.path, flags, entry.mode)?;
+                let mut written = 0;
+                let mut result = Ok(());
+                while written < entry.data.len() && result.is_ok() {
+                    match VFS.write(file, &entry.data[written..]) {
+                        Ok(0) => result = Err(OsError::NoSpace),
+                        Ok(len) => written += len,
+                        Err(err) => result = Err(err),
+                    }
+                }
+                VFS.close(file)?;
+                result?;
+                stats.files += 1;
+                stats.bytes += entry.data.len();
+            }
+            EntryKind::Symlink => {
+                if entry.data.len() > PATH_MAX {
+                    return Err(OsError::NameTooLong);
+                }
+                let target = str::from_utf8(entry.data)
+                    .map_err(|_| OsError::InvalidArchive("bad symlink target"))?;
+                VFS.symlink(None, target, entry.path)?;
+                stats.symlinks += 1;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(stats)
+}
@@ -0,0 +1,391 @@
+//! `/proc`: files telling about the kernel and the processes, generated each time they are read.
+//!
+//! Files are generated again for each `read`, and cut at the offset, so reading in pieces may
+//! see the contents change in between. They have no size, as on Linux.
+
+use core::{
+    fmt::{self, Write},
+    ops::ControlFlow,
+    str,
+};
+
+use crate::{
+    cpu::{self, info::CpuInfo},
+    driver::{interrupt::INTERRUPT_CONTROLLER, mailbox::tags::BoardInfo},
+    error::OsError,
+    fs::fd::MAX_FDS,
+    kalloc::{self, BOOT_ALLOCATOR},
+    mmu::{
+        layout::{boot_alloc_bitmap_start, boot_alloc_start, code_end, rpi_phys_binary_load_addr},
+        PAGE_SIZE,
+    },
+    process::{
+        elf::{PF_R, PF_W, PF_X},
+        memory::AddressSpace,
+        Pid, Process, PROCESSES,
+    },
+};
+
+use super::{DeviceNumber, DirEntryFn, FileKind, FileSystem, Ino, Stat};
+
+type Generator = fn(&mut dyn Write) -> fmt::Result;
+type ProcessGenerator = fn(&Process, &mut dyn Write) -> fmt::Result;
+
+/// Files of the root directory.
+const FILES: [(&str, Generator); 5] = [
+    ("cpuinfo", cpuinfo),
+    ("interrupts", interrupts),
+    ("mailbox", mailbox),
+    ("meminfo", meminfo),
+    ("uptime", uptime),
+];
+
+/// Files of the directory of each process.
+const PROCESS_FILES: [(&str, ProcessGenerator); 2] = [("maps", maps), ("status", status)];
+
+const ROOT_INO: Ino = 0;
+/// `FILES` come right after the root.
+const SELF_INO: Ino = FILES.len() as Ino + 1;
+/// The directory of process `pid` is `PROCESS_INO_BASE + pid * PROCESS_INO_STRIDE`, and its
+/// files follow it.
+const PROCESS_INO_BASE: Ino = 0x100;
+const PROCESS_INO_STRIDE: Ino = 0x10;
+
+/// Longest pid, in decimal.
+const PID_NAME_MAX: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Node {
+    Root,
+    /// Index in `FILES`.
+    File(usize),
+    /// `self`, a symlink to the directory of the running process.
+    SelfLink,
+    Process(Pid),
+    /// Index in `PROCESS_FILES`.
+    ProcessFile(Pid, usize),
+}
+
+impl Node {
+    fn from_ino(ino: Ino) -> Result<Self, OsError> {
+        match ino {
+            ROOT_INO => Ok(Node::Root),
+            SELF_INO => Ok(Node::SelfLink),
+            ino if ino <= FILES.len() as Ino => Ok(Node::File(ino as usize - 1)),
+            ino if ino >= PROCESS_INO_BASE => {
+                let offset = ino - PROCESS_INO_BASE;
+                let pid =
+                    Pid::try_from(offset / PROCESS_INO_STRIDE).map_err(|_| OsError::NotFound)?;
+                // The files are gone with the process.
+                PROCESSES.with_process(pid, |_| ())?;
+                match (offset % PROCESS_INO_STRIDE) as usize {
+                    0 => Ok(Node::Process(pid)),
+                    index if index <= PROCESS_FILES.len() => Ok(Node::ProcessFile(pid, index - 1)),
+                    _ => Err(OsError::NotFound),
+                }
+            }
+            _ => Err(OsError::NotFound),
+        }
+    }
+
+    fn ino(self) -> Ino {
+        match self {
+            Node::Root => ROOT_INO,
+            Node::File(index) => index as Ino + 1,
+            Node::SelfLink => SELF_INO,
+            Node::Process(pid) => PROCESS_INO_BASE + pid as Ino * PROCESS_INO_STRIDE,
+            Node::ProcessFile(pid, index) => Node::Process(pid).ino() + index as Ino + 1,
+        }
+    }
+
+    fn kind(self) -> FileKind {
+        match self {
+            Node::Root | Node::Process(_) => FileKind::Directory,
+            Node::SelfLink => FileKind::Symlink,
+            Node::File(_) | Node::ProcessFile(..) => FileKind::Regular,
+        }
+    }
+}
+
+/// `pid` in decimal, as the name of its directory.
+fn pid_name(pid: Pid, buf: &mut [u8; PID_NAME_MAX]) -> &str {
+    let mut start = buf.len();
+    let mut rest = pid;
+    loop {
+        start -= 1;
+        buf[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    // Only ASCII digits.
+    str::from_utf8(&buf[start..]).unwrap()
+}
+
+/// The pid named by `name`, which must be as `pid_name` writes it.
+fn parse_pid(name: &str) -> Option<Pid> {
+    let digits = !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_digit());
+    if !digits || (name.len() > 1 && name.starts_with('0')) {
+        return None;
+    }
+    name.parse().ok()
+}
+
+/// Keeps what is formatted from `skip` bytes in, until `buf` is full.
+struct Window<'a> {
+    skip: u64,
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Window<'_> {
+    /// Fails once `buf` is full, to stop formatting.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let skipped = self.skip.min(s.len() as u64);
+        self.skip -= skipped;
+        let bytes = &s.as_bytes()[skipped as usize..];
+        let len = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+        if self.len == self.buf.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn cpuinfo(w: &mut dyn Write) -> fmt::Result {
+    write!(w, "{}", CpuInfo::read())
+}
+
+/// How often each interrupt line with a handler has fired, and the spurious ones which were
+/// masked, with the numbers of `IrqNumber`.
+fn interrupts(w: &mut dyn Write) -> fmt::Result {
+    writeln!(w, "           CPU0")?;
+    for stats in INTERRUPT_CONTROLLER.irq_stats() {
+        writeln!(
+            w,
+            "{:>3}: {:>10}  {}",
+            stats.irq.get(),
+            stats.count,
+            stats.name
+        )?;
+    }
+    Ok(())
+}
+
+fn mailbox(w: &mut dyn Write) -> fmt::Result {
+    write!(w, "{}", BoardInfo::query(&BOOT_ALLOCATOR))
+}
+
+fn meminfo(w: &mut dyn Write) -> fmt::Result {
+    write!(w, "{}", kalloc::meminfo())
+}
+
+/// Seconds since reset, and spent idle. Idle time is not accounted for, so it is always 0.
+fn uptime(w: &mut dyn Write) -> fmt::Result {
+    let uptime = cpu::uptime();
+    writeln!(
+        w,
+        "{}.{:02} 0.00",
+        uptime.as_secs(),
+        uptime.subsec_millis() / 10
+    )
+}
+
+/// The memory of a process, like Linux's `/proc/<pid>/maps`.
+///
+/// These are the RAM regions of the identity mapping set up at boot, with the permissions it gives
+/// them, then the memory of the user code running in the process.
+fn maps(process: &Process, w: &mut dyn Write) -> fmt::Result {
+    let (heap_pages, _) = BOOT_ALLOCATOR.page_counts();
+    let regions = [
+        (0, rpi_phys_binary_load_addr(), "rw-p", "[stack]"),
+        (rpi_phys_binary_load_addr(), code_end(), "r-xp", "[text]"),
+        (code_end(), boot_alloc_bitmap_start(), "rw-p", "[data]"),
+        (
+            boot_alloc_bitmap_start(),
+            boot_alloc_start() + heap_pages * PAGE_SIZE,
+            "rw-p",
+            "[heap]",
+        ),
+    ];
+    for (start, end, permissions, name) in regions {
+        writeln!(
+            w,
+            "{:08x}-{:08x} {} 00000000 00:00 0          {}",
+            start, end, permissions, name
+        )?;
+    }
+    for (start, end, flags) in process.memory.iter().flat_map(AddressSpace::regions) {
+        let permission = |flag, c| if flags & flag != 0 { c } else { '-' };
+        writeln!(
+            w,
+            "{:08x}-{:08x} {}{}{}p 00000000 00:00 0",
+            start,
+            end,
+            permission(PF_R, 'r'),
+            permission(PF_W, 'w'),
+            permission(PF_X, 'x')
+        )?;
+    }
+    Ok(())
+}
+
+fn status(process: &Process, w: &mut dyn Write) -> fmt::Result {
+    writeln!(w, "Name:\t{}", process.name)?;
+    // There is no scheduler, processes only ever run.
+    writeln!(w, "State:\tR (running)")?;
+    writeln!(w, "Pid:\t{}", process.pid)?;
+    // Processes do not know their parent, the kernel started them all.
+    writeln!(w, "PPid:\t0")?;
+    writeln!(w, "FDSize:\t{}", MAX_FDS)
+}
+
+/// The filesystem mounted on `/proc`.
+pub struct Procfs;
+
+impl FileSystem for Procfs {
+    fn root(&self) -> Ino {
+        ROOT_INO
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, OsError> {
+        let kind = Node::from_ino(ino)?.kind();
+        let (mode, nlink) = match kind {
+            FileKind::Directory => (0o555, 2),
+            FileKind::Symlink => (0o777, 1),
+            _ => (0o444, 1),
+        };
+        Ok(Stat {
+            dev: 0,
+            ino,
+            kind,
+            mode,
+            nlink,
+            rdev: DeviceNumber::default(),
+            size: 0,
+            block_size: PAGE_SIZE as u32,
+            blocks: 0,
+        })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, OsError> {
+        match Node::from_ino(dir)? {
+            Node::Root => {
+                if let Some(index) = FILES.iter().position(|&(file, _)| file == name) {
+                    return Ok(Node::File(index).ino());
+                }
+                if name == "self" {
+                    return Ok(SELF_INO);
+                }
+                let pid = parse_pid(name).ok_or(OsError::NotFound)?;
+                PROCESSES.with_process(pid, |_| ())?;
+                Ok(Node::Process(pid).ino())
+            }
+            Node::Process(pid) => PROCESS_FILES
+                .iter()
+                .position(|&(file, _)| file == name)
+                .map(|index| Node::ProcessFile(pid, index).ino())
+                .ok_or(OsError::NotFound),
+            _ => Err(OsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self, dir: Ino, start: u64, f: &mut DirEntryFn<'_>) -> Result<(), OsError> {
+        let mut position = 0;
+        let mut emit = |name: &str, node: Node| {
+            position += 1;
+            if position <= start {
+                ControlFlow::Continue(())
+            } else {
+                f(name, node.ino(), node.kind())
+            }
+        };
+        match Node::from_ino(dir)? {
+            Node::Root => {
+                for (index, &(name, _)) in FILES.iter().enumerate() {
+                    if emit(name, Node::File(index)).is_break() {
+                        return Ok(());
+                    }
+                }
+                if emit("self", Node::SelfLink).is_break() {
+                    return Ok(());
+                }
+                let mut pid = None;
+                while let Some(next) = PROCESSES.next_pid(pid) {
+                    let mut name = [0; PID_NAME_MAX];
+                    if emit(pid_name(next, &mut name), Node::Process(next)).is_break() {
+                        break;
+                    }
+                    pid = Some(next);
+                }
+                Ok(())
+            }
+            Node::Process(pid) => {
+                for (index, &(name, _)) in PROCESS_FILES.iter().enumerate() {
+                    if emit(name, Node::ProcessFile(pid, index)).is_break() {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(OsError::NotADirectory),
+        }
+    }
+
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        let mut window = Window {
+            skip: offset,
+            buf,
+            len: 0,
+        };
+        // An error only means that `buf` is full.
+        let _ = match Node::from_ino(ino)? {
+            Node::File(index) => (FILES[index].1)(&mut window),
+            Node::ProcessFile(pid, index) => PROCESSES.with_process(pid, |process| {
+                (PROCESS_FILES[index].1)(process, &mut window)
+            })?,
+            Node::SelfLink => return Err(OsError::InvalidArgument),
+            Node::Root | Node::Process(_) => return Err(OsError::IsADirectory),
+        };
+        Ok(window.len)
+    }
+
+    fn write(&self, _ino: Ino, _offset: u64, _buf: &[u8]) -> Result<usize, OsError> {
+        Err(OsError::ReadOnlyFilesystem)
+    }
+
+    fn truncate(&self, _ino: Ino, _size: u64) -> Result<(), OsError> {
+        Err(OsError::ReadOnlyFilesystem)
+    }
+
+    fn create(&self, _dir: Ino, _name: &str, _kind: FileKind, _mode: u16) -> Result<Ino, OsError> {
+        Err(OsError::ReadOnlyFilesystem)
+    }
+
+    fn unlink(&self, _dir: Ino, _name: &str) -> Result<(), OsError> {
+        Err(OsError::ReadOnlyFilesystem)
+    }
+
+    fn symlink(&self, _dir: Ino, _name: &str, _target: &str) -> Result<Ino, OsError> {
+        Err(OsError::ReadOnlyFilesystem)
+    }
+
+    fn read_link(&self, ino: Ino, buf: &mut [u8]) -> Result<usize, OsError> {
+        match Node::from_ino(ino)? {
+            Node::SelfLink => {
+                let mut name = [0; PID_NAME_MAX];
+                let target = pid_name(PROCESSES.current_pid(), &mut name);
+                let len = buf.len().min(target.len());
+                buf[..len].copy_from_slice(&target.as_bytes()[..len]);
+                Ok(len)
+            }
+            _ => Err(OsError::InvalidArgument),
+        }
+    }
+}
+
+pub static PROCFS: Procfs = Procfs;
@@ -0,0 +1,420 @@
+use core::{
+    alloc::{AllocError, Allocator},
+    ops::ControlFlow,
+};
+
+use std_alloc::vec::Vec;
+
+use crate::{
+    error::OsError,
+    kalloc::page::{Page, PageUse},
+    mmu::PAGE_SIZE,
+    sync::NullLock,
+};
+
+use super::{DeviceNumber, DirEntryFn, FileKind, FileSystem, Ino, Stat, NAME_MAX, PATH_MAX};
+
+/// Inode number of the root directory.
+const ROOT_INO: Ino = 0;
+
+/// Files can be this large, so that offsets fit in the `i64` of `lseek`.
+const MAX_FILE_SIZE: u64 = i64::MAX as u64;
+
+/// The contents of a file. Pages which were never written to are holes, which read as zeroes.
+struct FileData<A: Allocator> {
+    size: u64,
+    /// Pages by their index in the file, sorted.
+    pages: Vec<(u64, Page), A>,
+}
+
+impl<A: Allocator> FileData<A> {
+    fn page(&self, index: u64) -> Option<&Page> {
+        self.pages
+            .binary_search_by_key(&index, |&(i, _)| i)
+            .ok()
+            .map(|i| &self.pages[i].1)
+    }
+
+    /// The page at `index`, allocated if it is a hole.
+    fn page_mut(&mut self, index: u64) -> Result<&mut Page, OsError> {
+        let i = match self.pages.binary_search_by_key(&index, |&(i, _)| i) {
+            Ok(i) => i,
+            Err(i) => {
+                self.pages.try_reserve(1).map_err(|_| AllocError)?;
+                self.pages.insert(i, (index, Page::zeroed(PageUse::Tmpfs)?));
+                i
+            }
+        };
+        Ok(&mut self.pages[i].1)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> usize {
+        if offset >= self.size {
+            return 0;
+        }
+        let len = buf.len().min((self.size - offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let in_page = (pos % PAGE_SIZE as u64) as usize;
+            let chunk = (PAGE_SIZE - in_page).min(len - done);
+            let dest = &mut buf[done..done + chunk];
+            match self.page(pos / PAGE_SIZE as u64) {
+                Some(page) => dest.copy_from_slice(&page[in_page..in_page + chunk]),
+                None => dest.fill(0),
+            }
+            done += chunk;
+        }
+        len
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        if !matches!(offset.checked_add(buf.len() as u64), Some(end) if end <= MAX_FILE_SIZE) {
+            return Err(OsError::FileTooLarge);
+        }
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let in_page = (pos % PAGE_SIZE as u64) as usize;
+            let chunk = (PAGE_SIZE - in_page).min(buf.len() - done);
+            match self.page_mut(pos / PAGE_SIZE as u64) {
+                Ok(page) => {
+                    page[in_page..in_page + chunk].copy_from_slice(&buf[done..done + chunk])
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+            done += chunk;
+        }
+        self.size = self.size.max(offset + done as u64);
+        // Running out of memory part way is a short write.
+        match result {
+            Err(err) if done == 0 => Err(err),
+            _ => Ok(done),
+        }
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), OsError> {
+        if size > MAX_FILE_SIZE {
+            return Err(OsError::FileTooLarge);
+        }
+        if size < self.size {
+            let page_size = PAGE_SIZE as u64;
+            let kept = (size + page_size - 1) / page_size;
+            self.pages.retain(|&(index, _)| index < kept);
+            // What is left of the last page must read as zeroes if the file grows again.
+            if size % page_size != 0 {
+                if let Some((index, page)) = self.pages.last_mut() {
+                    if *index == kept - 1 {
+                        page[(size % page_size) as usize..].fill(0);
+                    }
+                }
+            }
+        }
+        self.size = size;
+        Ok(())
+    }
+}
+
+/// Names and inode numbers of the entries of a directory.
+type Entries<A> = Vec<(Vec<u8, A>, Ino), A>;
+
+enum NodeData<A: Allocator> {
+    File(FileData<A>),
+    Directory(Entries<A>),
+    /// The target.
+    Symlink(Vec<u8, A>),
+}
+
+struct TmpfsNode<A: Allocator> {
+    mode: u16,
+    data: NodeData<A>,
+    /// Removed from its directory, but kept until released by the VFS, as it is still in use.
+    unlinked: bool,
+}
+
+impl<A: Allocator> TmpfsNode<A> {
+    fn kind(&self) -> FileKind {
+        match self.data {
+            NodeData::File(_) => FileKind::Regular,
+            NodeData::Directory(_) => FileKind::Directory,
+            NodeData::Symlink(_) => FileKind::Symlink,
+        }
+    }
+
+    fn entries(&self) -> Result<&Entries<A>, OsError> {
+        match &self.data {
+            NodeData::Directory(entries) => Ok(entries),
+            _ => Err(OsError::NotADirectory),
+        }
+    }
+
+    fn entries_mut(&mut self) -> Result<&mut Entries<A>, OsError> {
+        match &mut self.data {
+            NodeData::Directory(entries) => Ok(entries),
+            _ => Err(OsError::NotADirectory),
+        }
+    }
+}
+
+struct TmpfsInner<A: Allocator + Clone> {
+    /// Nodes by inode number, `None` for a free one.
+    nodes: Vec<Option<TmpfsNode<A>>, A>,
+}
+
+impl<A: Allocator + Clone> TmpfsInner<A> {
+    fn alloc(&self) -> A {
+        self.nodes.allocator().clone()
+    }
+
+    fn node(&self, ino: Ino) -> Result<&TmpfsNode<A>, OsError> {
+        self.nodes
+            .get(ino as usize)
+            .and_then(Option::as_ref)
+            .ok_or(OsError::NotFound)
+    }
+
+    fn node_mut(&mut self, ino: Ino) -> Result<&mut TmpfsNode<A>, OsError> {
+        self.nodes
+            .get_mut(ino as usize)
+            .and_then(Option::as_mut)
+            .ok_or(OsError::NotFound)
+    }
+
+    fn file(&self, ino: Ino) -> Result<&FileData<A>, OsError> {
+        match &self.node(ino)?.data {
+            NodeData::File(file) => Ok(file),
+            NodeData::Directory(_) => Err(OsError::IsADirectory),
+            NodeData::Symlink(_) => Err(OsError::InvalidArgument),
+        }
+    }
+
+    fn file_mut(&mut self, ino: Ino) -> Result<&mut FileData<A>, OsError> {
+        match &mut self.node_mut(ino)?.data {
+            NodeData::File(file) => Ok(file),
+            NodeData::Directory(_) => Err(OsError::IsADirectory),
+            NodeData::Symlink(_) => Err(OsError::InvalidArgument),
+        }
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, OsError> {
+        self.node(dir)?
+            .entries()?
+            .iter()
+            .find(|(entry, _)| entry == name.as_bytes())
+            .map(|&(_, ino)| ino)
+            .ok_or(OsError::NotFound)
+    }
+
+    fn copy(&self, bytes: &[u8]) -> Result<Vec<u8, A>, OsError> {
+        let mut copy = Vec::new_in(self.alloc());
+        copy.try_reserve_exact(bytes.len())
+            .map_err(|_| AllocError)?;
+        copy.extend_from_slice(bytes);
+        Ok(copy)
+    }
+
+    /// Add a node called `name` to `dir`.
+    fn create(
+        &mut self,
+        dir: Ino,
+        name: &str,
+        mode: u16,
+        data: NodeData<A>,
+    ) -> Result<Ino, OsError> {
+        if name.len() > NAME_MAX {
+            return Err(OsError::NameTooLong);
+        }
+        match self.lookup(dir, name) {
+            Ok(_) => return Err(OsError::AlreadyExists),
+            Err(OsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        // Nothing can be added to a removed directory, even while it is a working directory.
+        if self.node(dir)?.unlinked {
+            return Err(OsError::NotFound);
+        }
+        let stored_name = self.copy(name.as_bytes())?;
+        let ino = match self.nodes.iter().position(Option::is_none) {
+            Some(ino) => ino,
+            None => {
+                self.nodes.try_reserve(1).map_err(|_| AllocError)?;
+                self.nodes.push(None);
+                self.nodes.len() - 1
+            }
+        } as Ino;
+        let entries = self.node_mut(dir)?.entries_mut()?;
+        entries.try_reserve(1).map_err(|_| AllocError)?;
+        entries.push((stored_name, ino));
+        self.nodes[ino as usize] = Some(TmpfsNode {
+            mode,
+            data,
+            unlinked: false,
+        });
+        Ok(ino)
+    }
+
+    /// Remove `name` from `dir`. Its node, and so its inode number, stay until `release`.
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), OsError> {
+        let ino = self.lookup(dir, name)?;
+        if let NodeData::Directory(entries) = &self.node(ino)?.data {
+            if !entries.is_empty() {
+                return Err(OsError::DirectoryNotEmpty);
+            }
+        }
+        let entries = self.node_mut(dir)?.entries_mut()?;
+        entries.retain(|&(_, entry)| entry != ino);
+        self.node_mut(ino)?.unlinked = true;
+        Ok(())
+    }
+
+    /// Free an unlinked node and its pages.
+    fn release(&mut self, ino: Ino) {
+        if matches!(self.node(ino), Ok(node) if node.unlinked) {
+            self.nodes[ino as usize] = None;
+        }
+    }
+}
+
+/// A filesystem keeping everything in memory. The contents of files are kept in pages accounted
+/// for as `PageUse::Tmpfs`, only allocated when written to.
+pub struct Tmpfs<A: Allocator + Clone> {
+    inner: NullLock<TmpfsInner<A>>,
+}
+
+impl<A: Allocator + Clone> Tmpfs<A> {
+    /// An empty filesystem, with a root directory of mode `mode`.
+    pub fn new(mode: u16, alloc: A) -> Result<Self, OsError> {
+        let mut nodes = Vec::new_in(alloc.clone());
+        nodes.try_reserve(1).map_err(|_| AllocError)?;
+        nodes.push(Some(TmpfsNode {
+            mode,
+            data: NodeData::Directory(Vec::new_in(alloc)),
+            unlinked: false,
+        }));
+        Ok(Self {
+            inner: NullLock::new(TmpfsInner { nodes }),
+        })
+    }
+}
+
+impl<A: Allocator + Clone + Send> FileSystem for Tmpfs<A> {
+    fn root(&self) -> Ino {
+        ROOT_INO
+    }
+
+    fn stat(&self, ino: Ino) -> Result<Stat, OsError> {
+        self.inner.lock(|inner| {
+            let node = inner.node(ino)?;
+            let (nlink, size, pages) = match &node.data {
+                // Each subdirectory refers to it with "..".
+                NodeData::Directory(entries) => {
+                    let subdirs = entries
+                        .iter()
+                        .filter(|&&(_, entry)| {
+                            matches!(inner.node(entry), Ok(node) if node.kind() == FileKind::Directory)
+                        })
+                        .count();
+                    (2 + subdirs as u32, 0, 0)
+                }
+                NodeData::File(file) => (1, file.size, file.pages.len() as u64),
+                NodeData::Symlink(target) => (1, target.len() as u64, 0),
+            };
+            let nlink = if node.unlinked { 0 } else { nlink };
+            Ok(Stat {
+                dev: 0,
+                ino,
+                kind: node.kind(),
+                mode: node.mode,
+                nlink,
+                rdev: DeviceNumber::default(),
+                size,
+                block_size: PAGE_SIZE as u32,
+                blocks: pages * (PAGE_SIZE as u64 / 512),
+            })
+        })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Ino, OsError> {
+        self.inner.lock(|inner| inner.lookup(dir, name))
+    }
+
+    fn read_dir(&self, dir: Ino, start: u64, f: &mut DirEntryFn<'_>) -> Result<(), OsError> {
+        self.inner.lock(|inner| {
+            for (name, ino) in inner.node(dir)?.entries()?.iter().skip(start as usize) {
+                let name = core::str::from_utf8(name).unwrap_or("?");
+                let kind = inner.node(*ino)?.kind();
+                if let ControlFlow::Break(()) = f(name, *ino, kind) {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn read(&self, ino: Ino, offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        self.inner
+            .lock(|inner| Ok(inner.file(ino)?.read(offset, buf)))
+    }
+
+    fn write(&self, ino: Ino, offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        self.inner
+            .lock(|inner| inner.file_mut(ino)?.write(offset, buf))
+    }
+
+    fn truncate(&self, ino: Ino, size: u64) -> Result<(), OsError> {
+        self.inner.lock(|inner| inner.file_mut(ino)?.truncate(size))
+    }
+
+    fn create(&self, dir: Ino, name: &str, kind: FileKind, mode: u16) -> Result<Ino, OsError> {
+        self.inner.lock(|inner| {
+            let data = match kind {
+                FileKind::Regular => NodeData::File(FileData {
+                    size: 0,
+                    pages: Vec::new_in(inner.alloc()),
+                }),
+                FileKind::Directory => NodeData::Directory(Vec::new_in(inner.alloc())),
+                FileKind::Symlink => return Err(OsError::InvalidArgument),
+                FileKind::CharDevice | FileKind::BlockDevice => return Err(OsError::NotSupported),
+            };
+            inner.create(dir, name, mode, data)
+        })
+    }
+
+    fn unlink(&self, dir: Ino, name: &str) -> Result<(), OsError> {
+        self.inner.lock(|inner| inner.unlink(dir, name))
+    }
+
+    fn keeps_unlinked(&self) -> bool {
+        true
+    }
+
+    fn release(&self, ino: Ino) {
+        self.inner.lock(|inner| inner.release(ino));
+    }
+
+    fn symlink(&self, dir: Ino, name: &str, target: &str) -> Result<Ino, OsError> {
+        if target.is_empty() || target.len() > PATH_MAX {
+            return Err(OsError::InvalidArgument);
+        }
+        self.inner.lock(|inner| {
+            let target = inner.copy(target.as_bytes())?;
+            inner.create(dir, name, 0o777, NodeData::Symlink(target))
+        })
+    }
+
+    fn read_link(&self, ino: Ino, buf: &mut [u8]) -> Result<usize, OsError> {
+        self.inner.lock(|inner| match &inner.node(ino)?.data {
+            NodeData::Symlink(target) => {
+                let len = buf.len().min(target.len());
+                buf[..len].copy_from_slice(&target[..len]);
+                Ok(len)
+            }
+            _ => Err(OsError::InvalidArgument),
+        })
+    }
+}
@@ -0,0 +1,740 @@
+use core::{alloc::AllocError, str};
+
+use bitflags::bitflags;
+use std_alloc::vec::Vec;
+
+use crate::{
+    error::OsError,
+    kalloc::{BootAllocator, BOOT_ALLOCATOR},
+    sync::NullLock,
+};
+
+use super::{
+    dentry::{DentryCache, DentryId},
+    DirEntryFn, FileKind, FileSystem, Ino, Stat, NAME_MAX, PATH_MAX,
+};
+
+/// Dentries kept cached while they are not in use.
+const DENTRY_CACHE_SIZE: usize = 256;
+/// Symbolic links followed while resolving one path, as on Linux.
+const MAX_SYMLINKS: u32 = 40;
+
+/// Index of a mount in the mount table.
+pub type MountId = usize;
+/// Index of an open file in the VFS, shared by all file descriptors referring to it.
+pub type FileId = usize;
+
+/// An inode of a mounted filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Inode {
+    pub mount: MountId,
+    pub ino: Ino,
+}
+
+bitflags! {
+    /// Flags of `open`, with the values Linux uses on AArch64. Without `WRITE_ONLY` or
+    /// `READ_WRITE`, the file is opened read only.
+    pub struct OpenFlags: u32 {
+        const WRITE_ONLY = 0o1;
+        const READ_WRITE = 0o2;
+        const CREATE = 0o100;
+        const EXCLUSIVE = 0o200;
+        const TRUNCATE = 0o1000;
+        const APPEND = 0o2000;
+        const DIRECTORY = 0o40000;
+        const NO_FOLLOW = 0o100000;
+        /// Accepted, but without an effect: only the kernel calls `exec`, with no such files.
+        const CLOSE_ON_EXEC = 0o2000000;
+    }
+}
+
+impl OpenFlags {
+    pub fn readable(&self) -> bool {
+        !self.contains(OpenFlags::WRITE_ONLY)
+    }
+
+    pub fn writable(&self) -> bool {
+        self.intersects(OpenFlags::WRITE_ONLY | OpenFlags::READ_WRITE)
+    }
+}
+
+/// Where `seek` counts from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Set,
+    Current,
+    End,
+}
+
+struct Mount {
+    fs: &'static dyn FileSystem,
+    root: DentryId,
+    /// The dentry the filesystem is mounted on, `None` for the root filesystem.
+    mountpoint: Option<DentryId>,
+}
+
+struct OpenFile {
+    dentry: DentryId,
+    /// Position in bytes, or in entries for directories.
+    offset: u64,
+    flags: OpenFlags,
+    /// File descriptors referring to the file.
+    refs: usize,
+}
+
+struct VfsInner {
+    mounts: Vec<Option<Mount>, &'static BootAllocator>,
+    dentries: DentryCache<&'static BootAllocator>,
+    files: Vec<Option<OpenFile>, &'static BootAllocator>,
+}
+
+/// Find a free slot in `slots`, adding one if needed.
+fn free_slot<T>(slots: &mut Vec<Option<T>, &'static BootAllocator>) -> Result<usize, OsError> {
+    match slots.iter().position(Option::is_none) {
+        Some(idx) => Ok(idx),
+        None => {
+            slots.try_reserve(1).map_err(|_| AllocError)?;
+            slots.push(None);
+            Ok(slots.len() - 1)
+        }
+    }
+}
+
+impl VfsInner {
+    fn get_mount(&self, id: MountId) -> &Mount {
+        self.mounts[id].as_ref().expect("stale mount")
+    }
+
+    fn fs(&self, dentry: DentryId) -> (&'static dyn FileSystem, Inode) {
+        let inode = self.dentries.get(dentry).inode;
+        (self.get_mount(inode.mount).fs, inode)
+    }
+
+    fn file(&self, file: FileId) -> Result<&OpenFile, OsError> {
+        self.files
+            .get(file)
+            .and_then(Option::as_ref)
+            .ok_or(OsError::BadFileDescriptor)
+    }
+
+    fn file_mut(&mut self, file: FileId) -> Result<&mut OpenFile, OsError> {
+        self.files
+            .get_mut(file)
+            .and_then(Option::as_mut)
+            .ok_or(OsError::BadFileDescriptor)
+    }
+
+    /// The root of the root filesystem.
+    fn root(&self) -> Result<DentryId, OsError> {
+        match self.mounts.first() {
+            Some(Some(mount)) => Ok(mount.root),
+            _ => Err(OsError::NotFound),
+        }
+    }
+
+    /// The root of what is mounted on `dentry`, or `dentry` itself.
+    fn follow_mounts(&self, mut dentry: DentryId) -> DentryId {
+        while let Some(mount) = self.dentries.get(dentry).mounted {
+            dentry = self.get_mount(mount).root;
+        }
+        dentry
+    }
+
+    /// The directory containing `dentry`, leaving mounts through their mount points.
+    fn parent(&self, mut dentry: DentryId) -> DentryId {
+        loop {
+            let parent = self.dentries.get(dentry).parent();
+            if parent != dentry {
+                return parent;
+            }
+            let mount = self.dentries.get(dentry).inode.mount;
+            match self.get_mount(mount).mountpoint {
+                Some(mountpoint) => dentry = mountpoint,
+                // ".." of the root is the root.
+                None => return dentry,
+            }
+        }
+    }
+
+    /// The dentry called `name` in the directory `dir`, looking it up if it is not cached.
+    fn lookup(&mut self, dir: DentryId, name: &str) -> Result<DentryId, OsError> {
+        if name.len() > NAME_MAX {
+            return Err(OsError::NameTooLong);
+        }
+        if let Some(dentry) = self.dentries.find_child(dir, name) {
+            return Ok(dentry);
+        }
+        let (fs, inode) = self.fs(dir);
+        let ino = fs.lookup(inode.ino, name)?;
+        let kind = fs.stat(ino)?.kind;
+        let inode = Inode {
+            mount: inode.mount,
+            ino,
+        };
+        self.dentries.insert(Some(dir), name, inode, kind)
+    }
+
+    /// Resolve `path`, relative to `start` or the root if it is `None`. A symbolic link at the
+    /// end of the path is only followed with `follow`.
+    fn resolve(
+        &mut self,
+        start: Option<DentryId>,
+        path: &str,
+        follow: bool,
+    ) -> Result<DentryId, OsError> {
+        let mut links = 0;
+        self.walk(start, path, follow, &mut links)
+    }
+
+    fn walk(
+        &mut self,
+        start: Option<DentryId>,
+        path: &str,
+        follow: bool,
+        links: &mut u32,
+    ) -> Result<DentryId, OsError> {
+        if path.is_empty() {
+            return Err(OsError::NotFound);
+        }
+        if path.len() > PATH_MAX {
+            return Err(OsError::NameTooLong);
+        }
+        let mut current = match start {
+            Some(start) if !path.starts_with('/') => start,
+            _ => self.root()?,
+        };
+        current = self.follow_mounts(current);
+        // A trailing slash asks for a directory, so a symbolic link there is followed.
+        let follow = follow || path.ends_with('/');
+
+        let mut components = path.split('/').filter(|name| !name.is_empty()).peekable();
+        while let Some(name) = components.next() {
+            if self.dentries.get(current).kind != FileKind::Directory {
+                return Err(OsError::NotADirectory);
+            }
+            match name {
+                "." => continue,
+                ".." => {
+                    current = self.parent(current);
+                    continue;
+                }
+                _ => {}
+            }
+
+            let child = self.lookup(current, name)?;
+            let is_last = components.peek().is_none();
+            if self.dentries.get(child).kind == FileKind::Symlink && (follow || !is_last) {
+                *links += 1;
+                if *links > MAX_SYMLINKS {
+                    return Err(OsError::TooManySymlinks);
+                }
+                let mut target = Vec::new_in(&BOOT_ALLOCATOR);
+                target.try_reserve_exact(PATH_MAX).map_err(|_| AllocError)?;
+                target.resize(PATH_MAX, 0);
+                let (fs, inode) = self.fs(child);
+                let len = fs.read_link(inode.ino, &mut target)?;
+                let target =
+                    str::from_utf8(&target[..len]).map_err(|_| OsError::InvalidArgument)?;
+                current = self.walk(Some(current), target, true, links)?;
+            } else {
+                current = self.follow_mounts(child);
+            }
+        }
+
+        if path.ends_with('/') && self.dentries.get(current).kind != FileKind::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        Ok(current)
+    }
+
+    /// Resolve all of `path` but its last component, returning the directory and the name of the
+    /// last component in it.
+    fn resolve_parent<'p>(
+        &mut self,
+        start: Option<DentryId>,
+        path: &'p str,
+    ) -> Result<(DentryId, &'p str), OsError> {
+        let trimmed = path.trim_end_matches('/');
+        if trimmed.is_empty() {
+            // "/" has no parent to create it in, or remove it from.
+            return Err(if path.is_empty() {
+                OsError::NotFound
+            } else {
+                OsError::Busy
+            });
+        }
+        let (dir_path, name) = match trimmed.rfind('/') {
+            Some(slash) => (&trimmed[..slash + 1], &trimmed[slash + 1..]),
+            None => (".", trimmed),
+        };
+        if name == "." || name == ".." {
+            return Err(OsError::InvalidArgument);
+        }
+        let dir = self.resolve(start, dir_path, true)?;
+        if self.dentries.get(dir).kind != FileKind::Directory {
+            return Err(OsError::NotADirectory);
+        }
+        Ok((dir, name))
+    }
+
+    fn stat(&self, dentry: DentryId) -> Result<Stat, OsError> {
+        let (fs, inode) = self.fs(dentry);
+        let stat = fs.stat(inode.ino)?;
+        Ok(Stat {
+            dev: inode.mount as u32,
+            ..stat
+        })
+    }
+
+    fn open(
+        &mut self,
+        start: Option<DentryId>,
+        path: &str,
+        flags: OpenFlags,
+        mode: u16,
+    ) -> Result<FileId, OsError> {
+        let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+        let dentry = if flags.contains(OpenFlags::CREATE) {
+            let (dir, name) = self.resolve_parent(start, path)?;
+            match self.lookup(dir, name) {
+                Ok(_) if flags.contains(OpenFlags::EXCLUSIVE) => {
+                    return Err(OsError::AlreadyExists)
+                }
+                Ok(_) => self.resolve(Some(dir), name, follow)?,
+                Err(OsError::NotFound) => {
+                    let (fs, inode) = self.fs(dir);
+                    let ino = fs.create(inode.ino, name, FileKind::Regular, mode)?;
+                    let inode = Inode {
+                        mount: inode.mount,
+                        ino,
+                    };
+                    self.dentries
+                        .insert(Some(dir), name, inode, FileKind::Regular)?
+                }
+                Err(err) => return Err(err),
+            }
+        } else {
+            self.resolve(start, path, follow)?
+        };
+
+        match self.dentries.get(dentry).kind {
+            // Only left unfollowed with `NO_FOLLOW`.
+            FileKind::Symlink => return Err(OsError::TooManySymlinks),
+            FileKind::Directory if flags.writable() => return Err(OsError::IsADirectory),
+            FileKind::Directory => {}
+            _ if flags.contains(OpenFlags::DIRECTORY) => return Err(OsError::NotADirectory),
+            _ => {}
+        }
+        let id = free_slot(&mut self.files)?;
+        if flags.contains(OpenFlags::TRUNCATE) && flags.writable() {
+            let (fs, inode) = self.fs(dentry);
+            fs.truncate(inode.ino, 0)?;
+        }
+        self.dentries.get_ref(dentry);
+        self.files[id] = Some(OpenFile {
+            dentry,
+            offset: 0,
+            flags,
+            refs: 1,
+        });
+        Ok(id)
+    }
+
+    fn close(&mut self, file: FileId) -> Result<(), OsError> {
+        let open_file = self.file_mut(file)?;
+        open_file.refs -= 1;
+        if open_file.refs == 0 {
+            let dentry = open_file.dentry;
+            self.files[file] = None;
+            self.dentries.put(dentry);
+        }
+        Ok(())
+    }
+
+    fn mkdir(&mut self, start: Option<DentryId>, path: &str, mode: u16) -> Result<(), OsError> {
+        let (dir, name) = self.resolve_parent(start, path)?;
+        match self.lookup(dir, name) {
+            Ok(_) => Err(OsError::AlreadyExists),
+            Err(OsError::NotFound) => {
+                let (fs, inode) = self.fs(dir);
+                fs.create(inode.ino, name, FileKind::Directory, mode)?;
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        start: Option<DentryId>,
+        target: &str,
+        path: &str,
+    ) -> Result<(), OsError> {
+        if target.is_empty() {
+            return Err(OsError::NotFound);
+        }
+        if target.len() > PATH_MAX {
+            return Err(OsError::NameTooLong);
+        }
+        let (dir, name) = self.resolve_parent(start, path)?;
+        match self.lookup(dir, name) {
+            Ok(_) => Err(OsError::AlreadyExists),
+            Err(OsError::NotFound) => {
+                let (fs, inode) = self.fs(dir);
+                fs.symlink(inode.ino, name, target)?;
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn unlink(
+        &mut self,
+        start: Option<DentryId>,
+        path: &str,
+        remove_dir: bool,
+    ) -> Result<(), OsError> {
+        let (dir, name) = self.resolve_parent(start, path)?;
+        let dentry = self.lookup(dir, name)?;
+        if self.dentries.get(dentry).mounted.is_some() {
+            return Err(OsError::Busy);
+        }
+        match (self.dentries.get(dentry).kind, remove_dir) {
+            (FileKind::Directory, false) => return Err(OsError::IsADirectory),
+            (FileKind::Directory, true) => {}
+            (_, true) => return Err(OsError::NotADirectory),
+            (_, false) => {}
+        }
+        let (fs, inode) = self.fs(dir);
+        if self.dentries.is_in_use(dentry) && !fs.keeps_unlinked() {
+            return Err(OsError::Busy);
+        }
+        self.dentries.reserve_unlink()?;
+        fs.unlink(inode.ino, name)?;
+        self.dentries.unlink(dentry);
+        Ok(())
+    }
+
+    fn mount(&mut self, path: &str, fs: &'static dyn FileSystem) -> Result<(), OsError> {
+        let mountpoint = if self.mounts.iter().all(Option::is_none) {
+            // The first mount is the root filesystem.
+            if path != "/" {
+                return Err(OsError::NotFound);
+            }
+            None
+        } else {
+            let dentry = self.resolve(None, path, true)?;
+            if self.dentries.get(dentry).kind != FileKind::Directory {
+                return Err(OsError::NotADirectory);
+            }
+            Some(dentry)
+        };
+
+        let id = free_slot(&mut self.mounts)?;
+        let inode = Inode {
+            mount: id,
+            ino: fs.root(),
+        };
+        let root = self.dentries.insert(None, "", inode, FileKind::Directory)?;
+        self.dentries.get_ref(root);
+        if let Some(mountpoint) = mountpoint {
+            self.dentries.get_ref(mountpoint);
+            self.dentries.set_mounted(mountpoint, Some(id));
+        }
+        self.mounts[id] = Some(Mount {
+            fs,
+            root,
+            mountpoint,
+        });
+        Ok(())
+    }
+
+    fn unmount(&mut self, path: &str) -> Result<(), OsError> {
+        let root = self.resolve(None, path, true)?;
+        let id = self.dentries.get(root).inode.mount;
+        let mount = self.get_mount(id);
+        if mount.root != root {
+            return Err(OsError::InvalidArgument);
+        }
+        // The root filesystem stays.
+        let mountpoint = mount.mountpoint.ok_or(OsError::Busy)?;
+        if self.dentries.is_mount_busy(id, root) {
+            return Err(OsError::Busy);
+        }
+        mount.fs.sync()?;
+        self.dentries.remove_mount(id);
+        self.dentries.set_mounted(mountpoint, None);
+        self.dentries.put(mountpoint);
+        self.mounts[id] = None;
+        Ok(())
+    }
+}
+
+/// The virtual filesystem, joining all mounted filesystems into one tree.
+///
+/// Paths are resolved from a start directory, `None` standing for the root, like the working
+/// directory of a process. Open files are referred to by their `FileId`, which the file
+/// descriptor tables of processes map to.
+pub struct Vfs {
+    inner: NullLock<VfsInner>,
+}
+
+impl Vfs {
+    const fn new() -> Self {
+        Self {
+            inner: NullLock::new(VfsInner {
+                mounts: Vec::new_in(&BOOT_ALLOCATOR),
+                dentries: DentryCache::new(&BOOT_ALLOCATOR),
+                files: Vec::new_in(&BOOT_ALLOCATOR),
+            }),
+        }
+    }
+
+    /// Run `f`, then evict dentries which are not needed anymore, and release the unlinked
+    /// inodes which are not used anymore.
+    fn with_inner<R>(&self, f: impl FnOnce(&mut VfsInner) -> R) -> R {
+        self.inner.lock(|inner| {
+            let result = f(inner);
+            inner.dentries.shrink(DENTRY_CACHE_SIZE);
+            while let Some(inode) = inner.dentries.take_released() {
+                inner.get_mount(inode.mount).fs.release(inode.ino);
+            }
+            result
+        })
+    }
+
+    /// Mount `fs` on the directory `path`. The first filesystem mounted must go to "/".
+    pub fn mount(&self, path: &str, fs: &'static dyn FileSystem) -> Result<(), OsError> {
+        self.with_inner(|inner| inner.mount(path, fs))
+    }
+
+    /// Unmount the filesystem mounted on `path`, which must not be in use.
+    #[allow(dead_code)]
+    pub fn unmount(&self, path: &str) -> Result<(), OsError> {
+        self.with_inner(|inner| inner.unmount(path))
+    }
+
+    /// Open the file at `path`, creating it with `mode` if asked to.
+    pub fn open(
+        &self,
+        start: Option<DentryId>,
+        path: &str,
+        flags: OpenFlags,
+        mode: u16,
+    ) -> Result<FileId, OsError> {
+        self.with_inner(|inner| inner.open(start, path, flags, mode))
+    }
+
+    /// Drop a reference to an open file, closing it once there are none.
+    pub fn close(&self, file: FileId) -> Result<(), OsError> {
+        self.with_inner(|inner| inner.close(file))
+    }
+
+    /// Read from the current position of a file, and move past what was read.
+    pub fn read(&self, file: FileId, buf: &mut [u8]) -> Result<usize, OsError> {
+        // The filesystem is called without the lock held, as reading may block.
+        let (fs, inode, offset) = self.inner.lock(|inner| {
+            let open_file = inner.file(file)?;
+            if !open_file.flags.readable() {
+                return Err(OsError::BadFileDescriptor);
+            }
+            if inner.dentries.get(open_file.dentry).kind == FileKind::Directory {
+                return Err(OsError::IsADirectory);
+            }
+            let (fs, inode) = inner.fs(open_file.dentry);
+            Ok((fs, inode, open_file.offset))
+        })?;
+        let len = fs.read(inode.ino, offset, buf)?;
+        self.inner.lock(|inner| {
+            inner.file_mut(file)?.offset = offset + len as u64;
+            Ok(len)
+        })
+    }
+
+    /// Write at the current position of a file, or its end if opened with `APPEND`, and move
+    /// past what was written.
+    pub fn write(&self, file: FileId, buf: &[u8]) -> Result<usize, OsError> {
+        let (fs, inode, offset, append) = self.inner.lock(|inner| {
+            let open_file = inner.file(file)?;
+            if !open_file.flags.writable() {
+                return Err(OsError::BadFileDescriptor);
+            }
+            let (fs, inode) = inner.fs(open_file.dentry);
+            let append = open_file.flags.contains(OpenFlags::APPEND);
+            Ok((fs, inode, open_file.offset, append))
+        })?;
+        let offset = if append {
+            fs.stat(inode.ino)?.size
+        } else {
+            offset
+        };
+        let len = fs.write(inode.ino, offset, buf)?;
+        self.inner.lock(|inner| {
+            inner.file_mut(file)?.offset = offset + len as u64;
+            Ok(len)
+        })
+    }
+
+    /// Move the position of a file, returning the new one. Directories can only be rewound.
+    pub fn seek(&self, file: FileId, offset: i64, whence: Whence) -> Result<u64, OsError> {
+        self.inner.lock(|inner| {
+            let open_file = inner.file(file)?;
+            let dentry = open_file.dentry;
+            let is_dir = inner.dentries.get(dentry).kind == FileKind::Directory;
+            let base = match whence {
+                Whence::Set => 0,
+                Whence::Current => open_file.offset,
+                Whence::End => inner.stat(dentry)?.size,
+            };
+            let position = if offset < 0 {
+                base.checked_sub(offset.unsigned_abs())
+            } else {
+                base.checked_add(offset as u64)
+            }
+            .ok_or(OsError::InvalidArgument)?;
+            if is_dir && (whence != Whence::Set || position != 0) {
+                return Err(OsError::InvalidArgument);
+            }
+            inner.file_mut(file)?.offset = position;
+            Ok(position)
+        })
+    }
+
+    /// Call `f` with the entries of a directory from its current position, including "." and
+    /// "..", until it breaks. The position moves past the entries `f` took.
+    pub fn read_dir(&self, file: FileId, f: &mut DirEntryFn<'_>) -> Result<(), OsError> {
+        let (fs, inode, parent_ino, mut position) = self.inner.lock(|inner| {
+            let open_file = inner.file(file)?;
+            let dentry = open_file.dentry;
+            if inner.dentries.get(dentry).kind != FileKind::Directory {
+                return Err(OsError::NotADirectory);
+            }
+            let (fs, inode) = inner.fs(dentry);
+            let parent_ino = inner.dentries.get(inner.parent(dentry)).inode.ino;
+            Ok((fs, inode, parent_ino, open_file.offset))
+        })?;
+
+        if position == 0 {
+            if f(".", inode.ino, FileKind::Directory).is_break() {
+                return Ok(());
+            }
+            position = 1;
+        }
+        if position == 1 {
+            if f("..", parent_ino, FileKind::Directory).is_break() {
+                return self.set_dir_position(file, position);
+            }
+            position = 2;
+        }
+        let result = fs.read_dir(inode.ino, position - 2, &mut |name, ino, kind| {
+            let flow = f(name, ino, kind);
+            if flow.is_continue() {
+                position += 1;
+            }
+            flow
+        });
+        self.set_dir_position(file, position)?;
+        result
+    }
+
+    fn set_dir_position(&self, file: FileId, position: u64) -> Result<(), OsError> {
+        self.inner.lock(|inner| {
+            inner.file_mut(file)?.offset = position;
+            Ok(())
+        })
+    }
+
+    /// Stat the inode at `path`. A symbolic link at the end is only followed with `follow`.
+    pub fn stat(&self, start: Option<DentryId>, path: &str, follow: bool) -> Result<Stat, OsError> {
+        self.with_inner(|inner| {
+            let dentry = inner.resolve(start, path, follow)?;
+            inner.stat(dentry)
+        })
+    }
+
+    /// Stat the inode of an open file.
+    pub fn stat_file(&self, file: FileId) -> Result<Stat, OsError> {
+        self.inner.lock(|inner| {
+            let dentry = inner.file(file)?.dentry;
+            inner.stat(dentry)
+        })
+    }
+
+    /// Create the directory `path`.
+    pub fn mkdir(&self, start: Option<DentryId>, path: &str, mode: u16) -> Result<(), OsError> {
+        self.with_inner(|inner| inner.mkdir(start, path, mode))
+    }
+
+    /// Create a symbolic link at `path`, pointing to `target`.
+    pub fn symlink(
+        &self,
+        start: Option<DentryId>,
+        target: &str,
+        path: &str,
+    ) -> Result<(), OsError> {
+        self.with_inner(|inner| inner.symlink(start, target, path))
+    }
+
+    /// Remove the file, or with `remove_dir` the empty directory, at `path`. If it is still
+    /// open or a working directory, it stays usable on filesystems which keep unlinked inodes,
+    /// and elsewhere removing it fails with `Busy`.
+    pub fn unlink(
+        &self,
+        start: Option<DentryId>,
+        path: &str,
+        remove_dir: bool,
+    ) -> Result<(), OsError> {
+        self.with_inner(|inner| inner.unlink(start, path, remove_dir))
+    }
+
+    /// The physical address of `len` bytes from `offset` of an open file, for mapping it.
+    pub fn mmap(&self, file: FileId, offset: u64, len: usize) -> Result<usize, OsError> {
+        let (fs, inode) = self
+            .inner
+            .lock(|inner| Ok::<_, OsError>(inner.fs(inner.file(file)?.dentry)))?;
+        fs.mmap(inode.ino, offset, len)
+    }
+
+    /// The dentry of an open file, to resolve paths relative to it. It stays valid while the
+    /// file is open.
+    pub fn file_dentry(&self, file: FileId) -> Result<DentryId, OsError> {
+        self.inner.lock(|inner| Ok(inner.file(file)?.dentry))
+    }
+
+    /// Resolve the directory at `path` and keep it cached, e.g. as a working directory, until
+    /// `put_dentry`.
+    pub fn get_dir(&self, start: Option<DentryId>, path: &str) -> Result<DentryId, OsError> {
+        self.with_inner(|inner| {
+            let dentry = inner.resolve(start, path, true)?;
+            if inner.dentries.get(dentry).kind != FileKind::Directory {
+                return Err(OsError::NotADirectory);
+            }
+            inner.dentries.get_ref(dentry);
+            Ok(dentry)
+        })
+    }
+
+    /// Drop a dentry taken with `get_dir`.
+    pub fn put_dentry(&self, dentry: DentryId) {
+        self.with_inner(|inner| inner.dentries.put(dentry));
+    }
+
+    /// Write everything cached by all filesystems back.
+    pub fn sync(&self) -> Result<(), OsError> {
+        let mut result = Ok(());
+        let count = self.inner.lock(|inner| inner.mounts.len());
+        for id in 0..count {
+            // The lock is not held while a filesystem syncs.
+            let fs = self.inner.lock(|inner| {
+                let mount = inner.mounts.get(id).and_then(Option::as_ref);
+                mount.map(|mount| mount.fs)
+            });
+            if let Some(Err(err)) = fs.map(|fs| fs.sync()) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
+
+pub static VFS: Vfs = Vfs::new();
@@ -0,0 +1,176 @@
+use crate::driver::framebuffer::{Pixel, PixelOrder};
+
+/// An axis aligned rectangle. The coordinates may be negative or past the screen, drawing
+/// operations clip it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: isize,
+    pub y: isize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: isize, y: isize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// The x coordinate one past the right edge.
+    pub const fn right(&self) -> isize {
+        self.x + self.width as isize
+    }
+
+    /// The y coordinate one past the bottom edge.
+    pub const fn bottom(&self) -> isize {
+        self.y + self.height as isize
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn contains(&self, x: isize, y: isize) -> bool {
+        x >= self.x && x < self.right() && y >= self.y && y < self.bottom()
+    }
+
+    /// Returns the area covered by both rectangles, or `None` if they don't overlap.
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if x < right && y < bottom {
+            Some(Rect::new(x, y, (right - x) as usize, (bottom - y) as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the smallest rectangle covering both rectangles.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rect::new(x, y, (right - x) as usize, (bottom - y) as usize)
+    }
+
+    fn area(&self) -> usize {
+        self.width * self.height
+    }
+}
+
+const MAX_DIRTY_RECTS: usize = 16;
+
+/// The parts of a screen which changed, as a small set of rectangles.
+///
+/// Once `MAX_DIRTY_RECTS` are tracked, new rectangles are merged into the one which grows the
+/// least, so the region may cover more than what actually changed.
+#[derive(Debug, Clone, Copy)]
+pub struct DirtyRegion {
+    rects: [Rect; MAX_DIRTY_RECTS],
+    len: usize,
+}
+
+impl DirtyRegion {
+    pub const fn new() -> Self {
+        Self {
+            rects: [Rect::new(0, 0, 0, 0); MAX_DIRTY_RECTS],
+            len: 0,
+        }
+    }
+
+    pub fn rects(&self) -> &[Rect] {
+        &self.rects[..self.len]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty()
+            || self
+                .rects()
+                .iter()
+                .any(|r| r.intersect(&rect) == Some(rect))
+        {
+            return;
+        }
+
+        // Merge with a rectangle if the union covers no more than the new rectangle itself, e.g.
+        // with the glyph just to the left.
+        let merge_cost = |r: &Rect| r.union(&rect).area() - r.area();
+        let cheapest = (0..self.len).min_by_key(|&idx| merge_cost(&self.rects[idx]));
+        match cheapest {
+            Some(idx) if merge_cost(&self.rects[idx]) <= rect.area() => {
+                self.rects[idx] = self.rects[idx].union(&rect)
+            }
+            Some(idx) if self.len == MAX_DIRTY_RECTS => {
+                self.rects[idx] = self.rects[idx].union(&rect)
+            }
+            _ => {
+                self.rects[self.len] = rect;
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Add all rectangles of `other`.
+    pub fn add_region(&mut self, other: &DirtyRegion) {
+        for rect in other.rects() {
+            self.add(*rect);
+        }
+    }
+}
+
+/// Something pixels can be drawn into, like the framebuffer or an image. Coordinates may lie
+/// outside of it, drawing is clipped.
+pub trait DrawTarget {
+    /// Returns the width and height in pixels.
+    fn size(&self) -> (usize, usize);
+
+    /// The order the colors of the pixels drawn need to be in.
+    #[allow(dead_code)]
+    fn pixel_order(&self) -> PixelOrder;
+
+    fn draw_pixel(&mut self, x: isize, y: isize, pixel: Pixel);
+
+    fn fill_rect(&mut self, rect: Rect, pixel: Pixel) {
+        let (width, height) = self.size();
+        if let Some(rect) = rect.intersect(&Rect::new(0, 0, width, height)) {
+            for y in rect.y..rect.bottom() {
+                for x in rect.x..rect.right() {
+                    self.draw_pixel(x, y, pixel);
+                }
+            }
+        }
+    }
+
+    /// Copy an image with rows of `width` pixels to (`x`, `y`).
+    fn blit(&mut self, x: isize, y: isize, width: usize, pixels: &[Pixel]) {
+        if width == 0 {
+            return;
+        }
+        for (row, row_pixels) in pixels.chunks_exact(width).enumerate() {
+            for (col, pixel) in row_pixels.iter().enumerate() {
+                self.draw_pixel(x + col as isize, y + row as isize, *pixel);
+            }
+        }
+    }
+}
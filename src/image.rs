@@ -0,0 +1,100 @@
+use core::alloc::{AllocError, Allocator};
+
+use std_alloc::vec::Vec;
+
+use crate::{
+    driver::framebuffer::{Framebuffer, Pixel, PixelOrder},
+    error::OsError,
+    graphics::DrawTarget,
+};
+
+pub mod bmp;
+pub mod qoi;
+
+/// Decoded images larger than this in either dimension are rejected.
+const MAX_DIMENSION: usize = 4096;
+
+/// An image in memory, with its pixels in the framebuffer's pixel order. It can also be drawn
+/// into, e.g. to render text off screen.
+pub struct Image<A: Allocator> {
+    width: usize,
+    height: usize,
+    #[allow(dead_code)]
+    pixel_order: PixelOrder,
+    /// The rows of the image from top to bottom, without padding.
+    pixels: Vec<Pixel, A>,
+    /// Whether all pixels are fully opaque, so the image can be drawn without blending.
+    opaque: bool,
+}
+
+impl<A: Allocator> Image<A> {
+    fn new(width: usize, height: usize, pixel_order: PixelOrder, pixels: Vec<Pixel, A>) -> Self {
+        debug_assert_eq!(pixels.len(), width * height);
+        let opaque = pixels.iter().all(|pixel| pixel.alpha() == 255);
+        Self {
+            width,
+            height,
+            pixel_order,
+            pixels,
+            opaque,
+        }
+    }
+
+    /// Returns an image with all pixels set to `pixel`.
+    #[allow(dead_code)]
+    pub fn filled(
+        width: usize,
+        height: usize,
+        pixel: Pixel,
+        pixel_order: PixelOrder,
+        alloc: A,
+    ) -> Result<Self, OsError> {
+        let mut pixels = Vec::new_in(alloc);
+        pixels
+            .try_reserve_exact(width * height)
+            .map_err(|_| AllocError)?;
+        pixels.resize(width * height, pixel);
+        Ok(Self::new(width, height, pixel_order, pixels))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    #[allow(dead_code)]
+    pub fn pixels(&self) -> &[Pixel] {
+        &self.pixels
+    }
+
+    /// Draw the image with its top left corner at (`x`, `y`), blended over the screen where it
+    /// is translucent.
+    pub fn draw(&self, fb: &mut Framebuffer, x: isize, y: isize) {
+        if self.opaque {
+            fb.blit(x, y, self.width, &self.pixels);
+        } else {
+            fb.blit_blended(x, y, self.width, &self.pixels);
+        }
+    }
+}
+
+impl<A: Allocator> DrawTarget for Image<A> {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn pixel_order(&self) -> PixelOrder {
+        self.pixel_order
+    }
+
+    fn draw_pixel(&mut self, x: isize, y: isize, pixel: Pixel) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            self.pixels[y as usize * self.width + x as usize] = pixel;
+            // Stays false once a translucent pixel was drawn, even if it is covered later.
+            self.opaque &= pixel.alpha() == 255;
+        }
+    }
+}
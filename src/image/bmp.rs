@@ -0,0 +1,188 @@
+//! Decoder for uncompressed Windows bitmaps: 1, 4 and 8 bits per pixel with a palette, and 24
+//! or 32 bits per pixel, optionally with bit fields.
+
+use core::alloc::{AllocError, Allocator};
+
+use std_alloc::vec::Vec;
+
+use crate::{
+    driver::framebuffer::{Pixel, PixelOrder},
+    error::OsError,
+};
+
+use super::{Image, MAX_DIMENSION};
+
+const MAGIC: [u8; 2] = *b"BM";
+const FILE_HEADER_LEN: usize = 14;
+const INFO_HEADER_LEN: usize = 40;
+/// Starting with the V4 header, the bit fields include an alpha mask.
+const V4_HEADER_LEN: usize = 108;
+
+const TRUNCATED_HEADER: OsError = OsError::InvalidImage("truncated BMP header");
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+/// Masks selecting the red, green, blue and alpha bits of a pixel.
+#[derive(Debug, Clone, Copy)]
+struct BitFields {
+    red: u32,
+    green: u32,
+    blue: u32,
+    alpha: u32,
+}
+
+impl BitFields {
+    /// The layout of 32 bit pixels without bit fields: BGR with an unused byte.
+    const BGRX: BitFields = BitFields {
+        red: 0x00FF_0000,
+        green: 0x0000_FF00,
+        blue: 0x0000_00FF,
+        alpha: 0,
+    };
+
+    fn decode(&self, bits: u32) -> (u8, u8, u8, u8) {
+        let alpha = if self.alpha == 0 {
+            255
+        } else {
+            extract(bits, self.alpha)
+        };
+        (
+            extract(bits, self.red),
+            extract(bits, self.green),
+            extract(bits, self.blue),
+            alpha,
+        )
+    }
+}
+
+/// Returns the bits of `bits` selected by `mask`, scaled to 8 bits.
+fn extract(bits: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let value = (bits & mask) >> mask.trailing_zeros();
+    let max = mask >> mask.trailing_zeros();
+    ((value as u64 * 255 + max as u64 / 2) / max as u64) as u8
+}
+
+fn read_u16(data: &[u8], off: usize) -> Option<u16> {
+    let bytes = data.get(off..off + 2)?;
+    Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    let bytes = data.get(off..off + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Decode the bitmap in `data` to pixels in `pixel_order`.
+#[allow(dead_code)]
+pub fn decode<A: Allocator>(
+    data: &[u8],
+    pixel_order: PixelOrder,
+    alloc: A,
+) -> Result<Image<A>, OsError> {
+    if data.get(..2) != Some(&MAGIC[..]) {
+        return Err(OsError::InvalidImage("bad BMP magic"));
+    }
+    let data_off = read_u32(data, 10).ok_or(TRUNCATED_HEADER)? as usize;
+    let header_len = read_u32(data, FILE_HEADER_LEN).ok_or(TRUNCATED_HEADER)? as usize;
+    if header_len < INFO_HEADER_LEN {
+        // The OS/2 core header is not supported.
+        return Err(OsError::InvalidImage("unsupported BMP header"));
+    }
+    let field = |off: usize| read_u32(data, FILE_HEADER_LEN + off).ok_or(TRUNCATED_HEADER);
+    let width = field(4)? as i32;
+    let height = field(8)? as i32;
+    let bpp = read_u16(data, FILE_HEADER_LEN + 14).ok_or(TRUNCATED_HEADER)?;
+    let compression = field(16)?;
+    let colors_used = field(32)? as usize;
+
+    // A negative height means that the rows are stored top to bottom.
+    let top_down = height < 0;
+    let (width, height) = (
+        width.unsigned_abs() as usize,
+        height.unsigned_abs() as usize,
+    );
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(OsError::InvalidImage("BMP dimensions out of range"));
+    }
+
+    let bit_fields = match (compression, bpp) {
+        (BI_RGB, 1 | 4 | 8 | 24) => None,
+        (BI_RGB, 32) => Some(BitFields::BGRX),
+        (BI_BITFIELDS, 32) => {
+            // The masks follow the info header, or are part of the newer headers.
+            let masks_off = FILE_HEADER_LEN + INFO_HEADER_LEN;
+            let mask = |idx: usize| {
+                read_u32(data, masks_off + idx * 4)
+                    .ok_or(OsError::InvalidImage("truncated BMP bit fields"))
+            };
+            Some(BitFields {
+                red: mask(0)?,
+                green: mask(1)?,
+                blue: mask(2)?,
+                alpha: if header_len >= V4_HEADER_LEN {
+                    mask(3)?
+                } else {
+                    0
+                },
+            })
+        }
+        _ => return Err(OsError::InvalidImage("unsupported BMP format")),
+    };
+
+    let palette = if bpp <= 8 {
+        let max_colors = 1 << bpp;
+        let num_colors = if colors_used == 0 {
+            max_colors
+        } else {
+            colors_used.min(max_colors)
+        };
+        let palette_off = FILE_HEADER_LEN + header_len;
+        data.get(palette_off..palette_off + num_colors * 4)
+            .ok_or(OsError::InvalidImage("truncated BMP palette"))?
+    } else {
+        &[]
+    };
+
+    // Rows are padded to a multiple of 4 bytes.
+    let row_len = (width * bpp as usize + 31) / 32 * 4;
+    let pixel_data = data
+        .get(data_off..)
+        .and_then(|pixel_data| pixel_data.get(..row_len * height))
+        .ok_or(OsError::InvalidImage("truncated BMP pixel data"))?;
+
+    let mut pixels = Vec::new_in(alloc);
+    pixels
+        .try_reserve_exact(width * height)
+        .map_err(|_| AllocError)?;
+    for y in 0..height {
+        let src_y = if top_down { y } else { height - 1 - y };
+        let row = &pixel_data[src_y * row_len..][..row_len];
+        for x in 0..width {
+            let rgba = match (bpp, bit_fields) {
+                (24, _) => {
+                    let bgr = &row[x * 3..x * 3 + 3];
+                    (bgr[2], bgr[1], bgr[0], 255)
+                }
+                (32, Some(bit_fields)) => {
+                    let bits = u32::from_le_bytes(row[x * 4..x * 4 + 4].try_into().unwrap());
+                    bit_fields.decode(bits)
+                }
+                _ => {
+                    let bit = x * bpp as usize;
+                    let shift = 8 - bpp as usize - bit % 8;
+                    let idx = (row[bit / 8] >> shift) as usize & ((1 << bpp) - 1);
+                    let entry = palette
+                        .get(idx * 4..idx * 4 + 4)
+                        .ok_or(OsError::InvalidImage("BMP palette index out of range"))?;
+                    (entry[2], entry[1], entry[0], 255)
+                }
+            };
+            pixels.push(Pixel::new(rgba, pixel_order));
+        }
+    }
+    Ok(Image::new(width, height, pixel_order, pixels))
+}
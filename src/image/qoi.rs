@@ -0,0 +1,138 @@
+//! Decoder for the "Quite OK Image" format, see <https://qoiformat.org/qoi-specification.pdf>.
+
+use core::alloc::{AllocError, Allocator};
+
+use std_alloc::vec::Vec;
+
+use crate::{
+    driver::framebuffer::{Pixel, PixelOrder},
+    error::OsError,
+};
+
+use super::{Image, MAX_DIMENSION};
+
+const MAGIC: [u8; 4] = *b"qoif";
+const HEADER_LEN: usize = 14;
+const END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xC0;
+const OP_MASK: u8 = 0xC0;
+
+const TRUNCATED_DATA: OsError = OsError::InvalidImage("truncated QOI data");
+
+pub static SPLASH_LOGO_QOI_BYTES: &[u8] = include_bytes!("qoi/logo.qoi");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rgba {
+    red: u8,
+    green: u8,
+    blue: u8,
+    alpha: u8,
+}
+
+impl Rgba {
+    fn hash(&self) -> usize {
+        (self.red as usize * 3
+            + self.green as usize * 5
+            + self.blue as usize * 7
+            + self.alpha as usize * 11)
+            % 64
+    }
+}
+
+/// Decode the QOI image in `data` to pixels in `pixel_order`.
+pub fn decode<A: Allocator>(
+    data: &[u8],
+    pixel_order: PixelOrder,
+    alloc: A,
+) -> Result<Image<A>, OsError> {
+    if data.len() < HEADER_LEN + END_MARKER.len() {
+        return Err(OsError::InvalidImage("truncated QOI header"));
+    }
+    if data[..4] != MAGIC {
+        return Err(OsError::InvalidImage("bad QOI magic"));
+    }
+    let width = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+    let height = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+    let (channels, colorspace) = (data[12], data[13]);
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(OsError::InvalidImage("QOI dimensions out of range"));
+    }
+    if !(channels == 3 || channels == 4) || colorspace > 1 {
+        return Err(OsError::InvalidImage("bad QOI header"));
+    }
+    let (chunks, end) = data[HEADER_LEN..].split_at(data.len() - HEADER_LEN - END_MARKER.len());
+    if end != END_MARKER {
+        return Err(OsError::InvalidImage("missing QOI end marker"));
+    }
+
+    let mut pixels = Vec::new_in(alloc);
+    pixels
+        .try_reserve_exact(width * height)
+        .map_err(|_| AllocError)?;
+
+    let mut index = [Rgba {
+        red: 0,
+        green: 0,
+        blue: 0,
+        alpha: 0,
+    }; 64];
+    let mut px = Rgba {
+        red: 0,
+        green: 0,
+        blue: 0,
+        alpha: 255,
+    };
+    let mut bytes = chunks.iter().copied();
+    let mut next = || bytes.next().ok_or(TRUNCATED_DATA);
+    while pixels.len() < width * height {
+        let op = next()?;
+        let mut run = 1;
+        match op {
+            OP_RGB => {
+                px.red = next()?;
+                px.green = next()?;
+                px.blue = next()?;
+            }
+            OP_RGBA => {
+                px.red = next()?;
+                px.green = next()?;
+                px.blue = next()?;
+                px.alpha = next()?;
+            }
+            _ => match op & OP_MASK {
+                OP_INDEX => px = index[(op & !OP_MASK) as usize],
+                OP_DIFF => {
+                    px.red = px.red.wrapping_add((op >> 4 & 0x3).wrapping_sub(2));
+                    px.green = px.green.wrapping_add((op >> 2 & 0x3).wrapping_sub(2));
+                    px.blue = px.blue.wrapping_add((op & 0x3).wrapping_sub(2));
+                }
+                OP_LUMA => {
+                    let diff = next()?;
+                    let diff_green = (op & !OP_MASK).wrapping_sub(32);
+                    px.red = px
+                        .red
+                        .wrapping_add(diff_green.wrapping_add(diff >> 4).wrapping_sub(8));
+                    px.green = px.green.wrapping_add(diff_green);
+                    px.blue = px
+                        .blue
+                        .wrapping_add(diff_green.wrapping_add(diff & 0xF).wrapping_sub(8));
+                }
+                OP_RUN => run = (op & !OP_MASK) as usize + 1,
+                _ => unreachable!(),
+            },
+        }
+        index[px.hash()] = px;
+
+        let pixel = Pixel::new((px.red, px.green, px.blue, px.alpha), pixel_order);
+        for _ in 0..run.min(width * height - pixels.len()) {
+            pixels.push(pixel);
+        }
+    }
+    Ok(Image::new(width, height, pixel_order, pixels))
+}
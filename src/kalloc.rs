@@ -1,8 +1,109 @@
 pub mod bitmap_alloc;
 pub mod fixed_buffer_alloc;
+pub mod page;
 
 pub use core::alloc::{AllocError, Allocator, Layout};
 
+use core::{fmt, ptr::NonNull};
+
+use crate::{mmu::PAGE_SIZE, sync::NullLock};
+
+use self::{
+    bitmap_alloc::BitmapAllocator,
+    page::{pages_in_use, PageUse},
+};
+
+/// The allocator for data which lives as long as the kernel, like the framebuffer console.
+///
+/// It is a `BitmapAllocator` behind a lock, so that it can be a `static` and handed out as
+/// `&'static BootAllocator`. Allocations fail until `init` is called.
+pub struct BootAllocator {
+    inner: NullLock<Option<BitmapAllocator>>,
+}
+
+impl BootAllocator {
+    const fn new() -> Self {
+        Self {
+            inner: NullLock::new(None),
+        }
+    }
+
+    /// Set up the allocator with its bitmap at `bitmap_addr`, handing out memory from `base`.
+    pub fn init(&self, bitmap_addr: usize, base: usize) {
+        self.inner.lock(|inner| {
+            assert!(inner.is_none(), "BootAllocator initialised twice");
+            *inner = Some(BitmapAllocator::new(bitmap_addr, base));
+        });
+    }
+
+    /// The number of pages managed, and of those which are at least partly allocated.
+    pub fn page_counts(&self) -> (usize, usize) {
+        self.inner.lock(|inner| {
+            inner.as_ref().map_or((0, 0), |inner| {
+                (inner.page_count(), inner.used_page_count())
+            })
+        })
+    }
+}
+
+unsafe impl Allocator for BootAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner
+            .lock(|inner| inner.as_ref().ok_or(AllocError)?.allocate(layout))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.inner.lock(|inner| {
+            inner
+                .as_ref()
+                .expect("deallocate on uninitialised BootAllocator")
+                .deallocate(ptr, layout)
+        });
+    }
+}
+
+pub static BOOT_ALLOCATOR: BootAllocator = BootAllocator::new();
+
+/// How memory is used, in bytes. It displays like Linux's `/proc/meminfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemInfo {
+    pub total: usize,
+    pub free: usize,
+    /// Contents of tmpfs files, called `Shmem` by Linux.
+    pub tmpfs: usize,
+    /// Memory of user processes, called `AnonPages` by Linux.
+    pub user: usize,
+    pub page_tables: usize,
+}
+
+/// Memory use of the boot allocator.
+pub fn meminfo() -> MemInfo {
+    let (total, used) = BOOT_ALLOCATOR.page_counts();
+    MemInfo {
+        total: total * PAGE_SIZE,
+        free: (total - used) * PAGE_SIZE,
+        tmpfs: pages_in_use(PageUse::Tmpfs) * PAGE_SIZE,
+        user: pages_in_use(PageUse::User) * PAGE_SIZE,
+        page_tables: pages_in_use(PageUse::PageTables) * PAGE_SIZE,
+    }
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = [
+            ("MemTotal:", self.total),
+            ("MemFree:", self.free),
+            ("Shmem:", self.tmpfs),
+            ("AnonPages:", self.user),
+            ("PageTables:", self.page_tables),
+        ];
+        for (name, bytes) in lines {
+            writeln!(f, "{:<16}{:>8} kB", name, bytes >> 10)?;
+        }
+        Ok(())
+    }
+}
+
 // This is a complete stub for the global allocator.
 // Do NOT use the global allocator for anything, it returns
 // null for alloc and panics in dealloc.
@@ -43,6 +43,16 @@ impl BitmapAllocator {
         }
     }
 
+    /// Number of pages handed out from, one per bit of the bitmap.
+    pub fn page_count(&self) -> usize {
+        self.page_map.borrow().len()
+    }
+
+    /// Number of pages which are at least partly allocated.
+    pub fn used_page_count(&self) -> usize {
+        self.page_map.borrow().count_ones()
+    }
+
     fn last_page(&self) -> Option<LastPage> {
         self.last_page.get()
     }
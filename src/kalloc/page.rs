@@ -0,0 +1,90 @@
+//! Memory handed out a page at a time, accounted for by what it is used for.
+
+use core::{
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    slice,
+};
+
+use crate::{
+    kalloc::{AllocError, Allocator, Layout, BOOT_ALLOCATOR},
+    mmu::PAGE_SIZE,
+    sync::NullLock,
+};
+
+/// What a page is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageUse {
+    /// Contents of tmpfs files.
+    Tmpfs,
+    /// Memory of user processes.
+    User,
+    /// Page tables of user processes.
+    PageTables,
+}
+
+impl PageUse {
+    const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        match self {
+            PageUse::Tmpfs => 0,
+            PageUse::User => 1,
+            PageUse::PageTables => 2,
+        }
+    }
+}
+
+/// Number of pages in use, by `PageUse::index`.
+static PAGES_IN_USE: NullLock<[usize; PageUse::COUNT]> = NullLock::new([0; PageUse::COUNT]);
+
+/// Number of pages allocated for `usage`.
+pub fn pages_in_use(usage: PageUse) -> usize {
+    PAGES_IN_USE.lock(|pages| pages[usage.index()])
+}
+
+fn page_layout() -> Layout {
+    Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+}
+
+/// A page from the boot allocator, freed on drop.
+pub struct Page {
+    ptr: NonNull<u8>,
+    usage: PageUse,
+}
+
+/// Safety: The page is owned, like a `Box`.
+unsafe impl Send for Page {}
+
+impl Page {
+    /// Allocate a page filled with zeroes.
+    pub fn zeroed(usage: PageUse) -> Result<Self, AllocError> {
+        let ptr = BOOT_ALLOCATOR.allocate_zeroed(page_layout())?;
+        PAGES_IN_USE.lock(|pages| pages[usage.index()] += 1);
+        Ok(Self {
+            ptr: ptr.cast(),
+            usage,
+        })
+    }
+}
+
+impl Deref for Page {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), PAGE_SIZE) }
+    }
+}
+
+impl DerefMut for Page {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        PAGES_IN_USE.lock(|pages| pages[self.usage.index()] -= 1);
+        unsafe { BOOT_ALLOCATOR.deallocate(self.ptr, page_layout()) };
+    }
+}
@@ -7,19 +7,44 @@
 
 extern crate alloc as std_alloc;
 
-use core::mem;
+use core::{alloc::Allocator, mem, ops::ControlFlow, str};
 
 use bitflags::bitflags;
-use std_alloc::vec::Vec;
+use std_alloc::{boxed::Box, vec::Vec};
 use tock_registers::interfaces::{Readable, Writeable};
 
 use crate::{
-    driver::{framebuffer::Framebuffer, mmio::MMIO_BASE},
+    block::{
+        cache::BlockCache,
+        partition::{self, Partition, PartitionInfo, TableKind},
+        ram_disk::RamDisk,
+        BlockDevice,
+    },
+    driver::{
+        console::{Console, FramebufferConsole},
+        emmc::{EMMC, MMC_BLOCK_MAJOR},
+        framebuffer::{self, Framebuffer, FramebufferConfig, Pixel},
+        mailbox::tags,
+        mmio::MMIO_BASE,
+        SerialPort,
+    },
+    error::OsError,
     fonts::{
+        bdf::{BdfFont, DEFAULT_BDF_FONT_BYTES},
         psf::{PsfFont, DEFAULT_PSF_FONT_BYTES},
-        Font,
+        Font, TextStyle,
+    },
+    fs::{
+        devfs::{self, Device, DEVFS},
+        fat::FatFs,
+        initramfs::{self, INITRAMFS_BYTES},
+        procfs::PROCFS,
+        tmpfs::Tmpfs,
+        vfs::{OpenFlags, VFS},
+        DeviceNumber, FileKind, FileSystem,
     },
-    kalloc::{bitmap_alloc::BitmapAllocator, fixed_buffer_alloc::FixedSliceAlloc},
+    image::qoi::{self, SPLASH_LOGO_QOI_BYTES},
+    kalloc::{fixed_buffer_alloc::FixedSliceAlloc, BootAllocator, BOOT_ALLOCATOR},
     mmu::{
         layout::*,
         paging::{
@@ -28,18 +53,32 @@ use crate::{
         },
         PAGE_SIZE,
     },
+    process::PROCESSES,
 };
 
+mod block;
 mod boot;
+mod collections;
 mod cpu;
 mod driver;
 mod error;
+mod exception;
 mod fonts;
+mod fs;
+mod graphics;
+mod image;
 mod kalloc;
 mod mmu;
 mod panic;
 mod print;
+mod process;
 mod sync;
+mod syscall;
+mod tty;
+
+/// The UART used for `kprintln!` and console input, unless the command line chooses one with
+/// `console=ttyAMA0` or `console=ttyS0`. QEMU connects `-serial stdio` to the PL011.
+const SERIAL_CONSOLE: SerialPort = SerialPort::Pl011;
 
 unsafe fn kernel_init() -> ! {
     let mem_limits = {
@@ -56,13 +95,269 @@ unsafe fn kernel_init() -> ! {
     lower_table.setup_identity_map(&mem_limits);
     load_pagetables(lower_table as *mut PageTables as _, 0);
 
+    exception::handling_init();
+
+    BOOT_ALLOCATOR.init(boot_alloc_bitmap_start(), boot_alloc_start());
+    let alloc = &BOOT_ALLOCATOR;
+
+    // Nothing can be printed yet, so a command line which can't be read is passed over.
+    let serial_console = tags::query_command_line(alloc)
+        .ok()
+        .and_then(|command_line| SerialPort::from_command_line(&command_line))
+        .unwrap_or(SERIAL_CONSOLE);
+    driver::select_serial_console(serial_console);
     for driver in driver::drivers() {
         driver.init();
     }
-    kernel_main();
+    for driver in driver::drivers() {
+        driver.register_irq_handler();
+        if let Err(err) = driver.register_device_nodes() {
+            kprintln!("Failed to add device nodes: {}", err);
+        }
+    }
+    let console = DeviceNumber { major: 5, minor: 1 };
+    if let Err(err) = devfs::register(
+        format_args!("console"),
+        console,
+        0o600,
+        Device::Char(&tty::CONSOLE_DEVICE),
+    ) {
+        kprintln!("Failed to add device nodes: {}", err);
+    }
+    exception::asynchronous::local_irq_unmask();
+
+    PROCESSES.init().unwrap();
+
+    let mut framebuffer = Framebuffer::new(&FramebufferConfig::default(), alloc).unwrap();
+    framebuffer.enable_shadow_buffer(alloc).unwrap();
+    if let Err(err) = draw_splash(&mut framebuffer, alloc) {
+        kprintln!("Failed to draw the splash screen: {}", err);
+    }
+
+    kernel_main(framebuffer);
+}
+
+/// Draw the logo in the middle of the screen with the name below, until the console takes over.
+fn draw_splash<A: Allocator + Clone>(
+    framebuffer: &mut Framebuffer,
+    alloc: A,
+) -> Result<(), OsError> {
+    let logo = qoi::decode(
+        SPLASH_LOGO_QOI_BYTES,
+        framebuffer.pixel_order(),
+        alloc.clone(),
+    )?;
+    let background = Pixel::new((0, 0, 0, 255), framebuffer.pixel_order());
+    framebuffer.fill_rows(0, framebuffer.height(), background);
+    let x = (framebuffer.width() as isize - logo.width() as isize) / 2;
+    let y = (framebuffer.height() as isize - logo.height() as isize) / 2;
+    logo.draw(framebuffer, x, y);
+
+    let font = BdfFont::new(DEFAULT_BDF_FONT_BYTES, alloc)?;
+    let name = "LittleOS";
+    let style = TextStyle {
+        fg: Pixel::new((255, 255, 255, 255), framebuffer.pixel_order()),
+        bg: None,
+    };
+    let x = (framebuffer.width() as isize - font.text_width(name) as isize) / 2;
+    let y = y + logo.height() as isize + font.line_height() as isize;
+    font.render_str(name, framebuffer, x, y, &style);
+    framebuffer.present()
+}
+
+/// Write blocks through a cache to a RAM disk, and check that they read back, both from the disk
+/// and through the cache.
+fn check_block_cache<A: Allocator + Clone>(alloc: A) -> Result<(), OsError> {
+    const BLOCK_SIZE: usize = 512;
+    const BLOCK_COUNT: u64 = 64;
+    let disk = RamDisk::new(BLOCK_SIZE, BLOCK_COUNT, alloc.clone())?;
+    let cache = BlockCache::new(&disk, 8, alloc)?;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    for i in 0..BLOCK_COUNT {
+        block.fill(i as u8);
+        cache.write_blocks(i, &block)?;
+    }
+    cache.flush()?;
+    for i in 0..BLOCK_COUNT {
+        disk.read_blocks(i, &mut block)?;
+        let from_disk = block.iter().all(|&b| b == i as u8);
+        cache.read_blocks(i, &mut block)?;
+        let from_cache = block.iter().all(|&b| b == i as u8);
+        if !from_disk || !from_cache {
+            kprintln!("RAM disk     : block {} reads back wrong", i);
+            return Ok(());
+        }
+    }
+    kprintln!("RAM disk     : ok, {:?}", cache.stats());
+    Ok(())
+}
+
+/// Print the partition table of `device`, naming the partitions after `name`, and return the
+/// partitions.
+fn print_partitions<A: Allocator + Clone>(
+    name: &str,
+    device: &dyn BlockDevice,
+    alloc: A,
+) -> Option<Vec<PartitionInfo, A>> {
+    let (kind, partitions) = match partition::read_partition_table(device, alloc) {
+        Ok(table) => table,
+        Err(err) => {
+            kprintln!("Partitions   : {}", err);
+            return None;
+        }
+    };
+    match kind {
+        TableKind::Mbr => kprintln!("Partitions   : MBR"),
+        TableKind::Gpt { disk_guid } => kprintln!("Partitions   : GPT, disk {}", disk_guid),
+    }
+    for partition in partitions.iter() {
+        kprint!(
+            "  {}p{:<3}: {} MiB at block {}, {}",
+            name,
+            partition.number,
+            (partition.block_count * device.block_size() as u64) >> 20,
+            partition.start,
+            partition.kind
+        );
+        if partition.bootable {
+            kprint!(", bootable");
+        }
+        if let Err(err) = Partition::new(device, *partition) {
+            kprint!(", {}", err);
+        }
+        if partition.name().next().is_some() {
+            kprint!(", \"");
+            for c in partition.name() {
+                kprint!("{}", c);
+            }
+            kprint!("\"");
+        }
+        kprintln!();
+    }
+    Some(partitions)
+}
+
+/// Mount a tmpfs as the root and unpack the embedded initramfs into it.
+///
+/// Only the archive built into the kernel is used: one loaded by the firmware after the kernel
+/// would be overwritten by the boot allocator, whose heap starts right after the image, and there
+/// is no device tree parser to find it with anyway.
+fn mount_initramfs(alloc: &'static BootAllocator) -> Result<(), OsError> {
+    let tmpfs = Tmpfs::new(0o755, alloc)?;
+    VFS.mount("/", Box::leak(Box::new_in(tmpfs, alloc)))?;
+    let stats = initramfs::unpack(INITRAMFS_BYTES)?;
+    kprintln!(
+        "Initramfs    : {} files ({} bytes), {} directories, {} symlinks, {} skipped",
+        stats.files,
+        stats.bytes,
+        stats.directories,
+        stats.symlinks,
+        stats.skipped
+    );
+    Ok(())
+}
+
+/// Create the directory `path` with `mode`, unless it exists.
+fn ensure_dir(path: &str, mode: u16) -> Result<(), OsError> {
+    match VFS.mkdir(None, path, mode) {
+        Ok(()) | Err(OsError::AlreadyExists) => Ok(()),
+        Err(err) => Err(err),
+    }
 }
 
-fn kernel_main() -> ! {
+/// Mount an empty tmpfs on "/tmp", as scratch space.
+fn mount_tmp(alloc: &'static BootAllocator) -> Result<(), OsError> {
+    ensure_dir("/tmp", 0o1777)?;
+    let tmpfs = Tmpfs::new(0o1777, alloc)?;
+    VFS.mount("/tmp", Box::leak(Box::new_in(tmpfs, alloc)))
+}
+
+/// Mount a filesystem which has no device, like devfs, on the directory `path`.
+fn mount_virtual(path: &str, fs: &'static dyn FileSystem) -> Result<(), OsError> {
+    ensure_dir(path, 0o755)?;
+    VFS.mount(path, fs)
+}
+
+/// Add a block device to "/dev" for each partition of the SD card.
+///
+/// `mounted` is the partition a filesystem is mounted from and its cache, which the node of that
+/// partition goes through so writes don't bypass it. The others go straight to the card.
+fn register_partitions(
+    partitions: &[PartitionInfo],
+    mounted: Option<(u32, &'static (dyn BlockDevice + Sync))>,
+    alloc: &'static BootAllocator,
+) -> Result<(), OsError> {
+    for &info in partitions {
+        let device: &'static (dyn BlockDevice + Sync) = match mounted {
+            Some((number, cache)) if number == info.number => cache,
+            _ => Box::leak(Box::new_in(Partition::new(&EMMC, info)?, alloc)),
+        };
+        let number = DeviceNumber {
+            major: MMC_BLOCK_MAJOR,
+            minor: info.number,
+        };
+        devfs::register(
+            format_args!("mmcblk0p{}", info.number),
+            number,
+            0o660,
+            Device::Block(device),
+        )?;
+    }
+    Ok(())
+}
+
+/// Mount the FAT filesystem on `partition` of the SD card on "/boot", and return the cache it
+/// reads the partition through.
+fn mount_boot(
+    partition: PartitionInfo,
+    alloc: &'static BootAllocator,
+) -> Result<&'static (dyn BlockDevice + Sync), OsError> {
+    let device = Partition::new(&EMMC, partition)?;
+    let cache = Box::leak(Box::new_in(BlockCache::new(device, 64, alloc)?, alloc));
+    let fs = FatFs::new(&*cache, alloc)?;
+    let cluster_size = fs.cluster_size() as u64;
+    let free = fs.free_clusters()? as u64 * cluster_size;
+    let total = fs.cluster_count() as u64 * cluster_size;
+    kprintln!(
+        "Filesystem   : {:?} on partition {}, {} MiB free of {} MiB",
+        fs.fat_type(),
+        partition.number,
+        free >> 20,
+        total >> 20
+    );
+    ensure_dir("/boot", 0o755)?;
+    VFS.mount("/boot", Box::leak(Box::new_in(fs, alloc)))?;
+    EMMC.set_mounted(partition.start, partition.block_count);
+    Ok(cache)
+}
+
+/// Open "/dev/console" as the standard input, output and error of the kernel process, which
+/// `/init` runs in.
+fn open_console() -> Result<(), OsError> {
+    for _ in 0..3 {
+        let file = VFS.open(None, "/dev/console", OpenFlags::READ_WRITE, 0)?;
+        PROCESSES.with_current(|process| process.files.insert(file))?;
+    }
+    Ok(())
+}
+
+/// List the entries of the directory at `path`.
+fn list_dir(path: &str) -> Result<(), OsError> {
+    let dir = VFS.open(None, path, OpenFlags::DIRECTORY, 0)?;
+    let listed = VFS.read_dir(dir, &mut |name, _, kind| {
+        match kind {
+            FileKind::Directory => kprintln!("  {}/", name),
+            FileKind::Symlink => kprintln!("  {}@", name),
+            _ => kprintln!("  {}", name),
+        }
+        ControlFlow::Continue(())
+    });
+    VFS.close(dir)?;
+    listed
+}
+
+fn kernel_main(framebuffer: Framebuffer) -> ! {
     kprintln!("Hello, from LittleOS!");
 
     let sp: usize;
@@ -77,18 +372,18 @@ fn kernel_main() -> ! {
         kprintln!("Failed to retrieve current execution level");
     }
 
-    let alloc = BitmapAllocator::new(boot_alloc_bitmap_start(), boot_alloc_start());
+    let alloc = &BOOT_ALLOCATOR;
 
     {
         kprintln!("Using a Vec ...");
-        let mut nums = Vec::new_in(&alloc);
+        let mut nums = Vec::new_in(alloc);
         const NUMS_COUNT: usize = 10;
         nums.reserve(NUMS_COUNT);
         for i in 0..NUMS_COUNT {
             nums.push((i + 1) * 2);
         }
 
-        let mut floats: Vec<f32, _> = Vec::new_in(&alloc);
+        let mut floats: Vec<f32, _> = Vec::new_in(alloc);
         const FLOATS_COUNT: usize = 15;
         floats.resize(FLOATS_COUNT, 0.5);
 
@@ -100,13 +395,102 @@ fn kernel_main() -> ! {
         kprintln!("floats start =  {:#018X}", floats.as_ptr() as usize);
     }
 
-    let mut framebuffer = Framebuffer::new(&alloc).unwrap();
+    match framebuffer::query_display_size() {
+        Ok((width, height)) => kprintln!("Display size: {}x{}", width, height),
+        Err(err) => kprintln!("Failed to query the display size: {}", err),
+    }
+    match framebuffer::query_edid(alloc, 0) {
+        Ok(edid) => match edid.preferred_mode() {
+            Some((width, height)) => kprintln!("EDID preferred mode: {}x{}", width, height),
+            None => kprintln!("EDID has no preferred mode (valid: {})", edid.is_valid()),
+        },
+        Err(err) => kprintln!("Failed to read the EDID: {}", err),
+    }
+
     kprintln!("{:?}", framebuffer);
 
-    let psf_font = PsfFont::new(DEFAULT_PSF_FONT_BYTES);
-    psf_font.render_str("Hello World!", &mut framebuffer, 0, 20);
+    let psf_font = PsfFont::new(DEFAULT_PSF_FONT_BYTES, alloc).unwrap();
+    let console = Console::new(framebuffer, psf_font, alloc).unwrap();
+    kprintln!(
+        "Mirroring the console to the framebuffer ({}x{} characters)",
+        console.cols(),
+        console.rows()
+    );
+    let fb_console = Box::leak(Box::new_in(FramebufferConsole::new(console), alloc));
+    print::set_mirror(fb_console);
+    kprintln!("\x1B[1;32mHello, from LittleOS!\x1B[0m");
+    kprint!("{}", tags::BoardInfo::query(alloc));
+    if let Err(err) = check_block_cache(alloc) {
+        kprintln!("RAM disk     : {}", err);
+    }
+    if let Err(err) = mount_initramfs(alloc) {
+        kprintln!("Initramfs    : {}", err);
+    }
+    if let Err(err) = mount_tmp(alloc) {
+        kprintln!("/tmp         : {}", err);
+    }
+    if let Err(err) = mount_virtual("/dev", &DEVFS) {
+        kprintln!("/dev         : {}", err);
+    }
+    if let Err(err) = mount_virtual("/proc", &PROCFS) {
+        kprintln!("/proc        : {}", err);
+    }
+    kprint!("{}", kalloc::meminfo());
+    match EMMC.init_card() {
+        Ok(card) => {
+            kprintln!("SD card      : {}", card);
+            let partitions = print_partitions("mmcblk0", &EMMC, alloc);
+            let fat = partitions
+                .iter()
+                .flatten()
+                .find(|partition| partition.kind.is_fat());
+            let mut mounted = None;
+            if let Some(&partition) = fat {
+                match mount_boot(partition, alloc) {
+                    Ok(cache) => mounted = Some((partition.number, cache)),
+                    Err(err) => kprintln!("Filesystem   : {}", err),
+                }
+            }
+            if let Some(partitions) = &partitions {
+                if let Err(err) = register_partitions(partitions, mounted, alloc) {
+                    kprintln!("Partitions   : {}", err);
+                }
+            }
+        }
+        Err(err) => kprintln!("SD card      : {}", err),
+    }
+    kprintln!("Root directory:");
+    if let Err(err) = list_dir("/") {
+        kprintln!("  {}", err);
+    }
+    kprintln!("Devices:");
+    if let Err(err) = list_dir("/dev") {
+        kprintln!("  {}", err);
+    }
+    if let Err(err) = open_console() {
+        kprintln!("/dev/console : {}", err);
+    }
+    match process::exec("/init") {
+        Ok(status) => kprintln!("/init exited with status {}", status),
+        Err(err) => kprintln!("Failed to run /init: {}", err),
+    }
 
-    cpu::wait_forever();
+    kprintln!("Lines typed on the console are echoed back ...");
+    let mut line = [0u8; 256];
+    loop {
+        kprint!("> ");
+        match tty::CONSOLE_TTY.read(&mut line) {
+            Ok(0) => kprintln!("<EOF>"),
+            Ok(len) => kprint!(
+                "{}",
+                str::from_utf8(&line[..len]).unwrap_or("<invalid UTF-8>\n")
+            ),
+            Err(err) => kprintln!("{}", err),
+        }
+        while let Some((pgrp, signal)) = tty::CONSOLE_TTY.take_signal() {
+            kprintln!("{:?} for process group {}", signal, pgrp);
+        }
+    }
 }
 
 const ENTRIES_PER_PAGE: usize = PAGE_SIZE / mem::size_of::<u64>();
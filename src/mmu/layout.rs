@@ -73,11 +73,11 @@ pub struct MemLimits {
 
 pub fn get_memory_limits<A: Allocator>(alloc: &A) -> Result<MemLimits, OsError> {
     let mut mbox = Mailbox::new(alloc)?;
-    mbox.append_tag(GetVcMemory)?;
-    mbox.append_tag(GetArmMemory)?;
+    let vc_mem = mbox.append_tag(GetVcMemory)?;
+    let arm_mem = mbox.append_tag(GetArmMemory)?;
     mbox.call()?;
-    let vc_mem = mbox.read_tag_result::<GetVcMemory>(0).unwrap();
-    let arm_mem = mbox.read_tag_result::<GetArmMemory>(1).unwrap();
+    let vc_mem = mbox.read_tag_result(vc_mem)?;
+    let arm_mem = mbox.read_tag_result(arm_mem)?;
 
     Ok(MemLimits {
         arm_base: arm_mem.base as usize,
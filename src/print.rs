@@ -1,14 +1,117 @@
 use core::fmt;
 
-use crate::driver::{qemu_console, serial_console};
+use crate::{driver::qemu_console, sync::NullLock, tty::CONSOLE_TTY};
 
 pub trait Write {
     fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result;
+
+    /// Make everything written so far visible, for outputs which hold some of it back.
+    fn flush(&self) -> fmt::Result {
+        Ok(())
+    }
+}
+
+pub trait Read {
+    /// Block until a character is received and return it.
+    fn read_char(&self) -> char;
+
+    /// Return a received character, or `None` if there is none pending.
+    fn try_read_char(&self) -> Option<char>;
+
+    /// Discard all pending input.
+    fn clear_rx(&self);
+}
+
+/// Number of bytes of `kprint!` output kept for `/dev/kmsg`.
+const LOG_SIZE: usize = 16 * 1024;
+
+/// The latest output of `kprint!`.
+struct KernelLog {
+    buf: [u8; LOG_SIZE],
+    /// Number of bytes ever logged. Byte `i` is kept at `i % LOG_SIZE`, until overwritten.
+    written: u64,
+}
+
+impl KernelLog {
+    fn len(&self) -> usize {
+        self.written.min(LOG_SIZE as u64) as usize
+    }
+}
+
+impl fmt::Write for KernelLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.buf[(self.written % LOG_SIZE as u64) as usize] = b;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+static KERNEL_LOG: NullLock<KernelLog> = NullLock::new(KernelLog {
+    buf: [0; LOG_SIZE],
+    written: 0,
+});
+
+/// Number of bytes in the kernel log.
+pub fn log_len() -> usize {
+    KERNEL_LOG.lock(|log| log.len())
+}
+
+/// Read the kernel log into `buf`, `offset` bytes from the oldest byte still kept.
+pub fn read_log(offset: u64, buf: &mut [u8]) -> usize {
+    KERNEL_LOG.lock(|log| {
+        let len = log.len() as u64;
+        if offset >= len {
+            return 0;
+        }
+        let start = log.written - len + offset;
+        let count = buf.len().min((len - offset) as usize);
+        for (i, b) in buf[..count].iter_mut().enumerate() {
+            *b = log.buf[((start + i as u64) % LOG_SIZE as u64) as usize];
+        }
+        count
+    })
+}
+
+/// A second output for `kprint!`, e.g. the framebuffer console.
+static MIRROR: NullLock<Option<&'static (dyn Write + Sync)>> = NullLock::new(None);
+
+/// Mirror everything printed with `kprint!` to `output`, in addition to the serial console.
+pub fn set_mirror(output: &'static (dyn Write + Sync)) {
+    MIRROR.lock(|mirror| *mirror = Some(output));
+}
+
+/// Write `args` to the mirror only, like the echo of console input.
+pub fn write_mirror(args: fmt::Arguments) {
+    if let Some(mirror) = MIRROR.lock(|mirror| *mirror) {
+        mirror.write_fmt(args).unwrap();
+    }
+}
+
+/// Make everything written to the mirror visible, e.g. a prompt before waiting for input.
+pub fn flush_mirror() {
+    if let Some(mirror) = MIRROR.lock(|mirror| *mirror) {
+        mirror.flush().unwrap();
+    }
+}
+
+/// Passes each piece of `kprint!` output to the log, the console TTY and the mirror, so the
+/// arguments are only formatted once.
+struct Tee;
+
+impl fmt::Write for Tee {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        KERNEL_LOG.lock(|log| fmt::Write::write_str(log, s))?;
+        CONSOLE_TTY.write_fmt(format_args!("{}", s))?;
+        write_mirror(format_args!("{}", s));
+        Ok(())
+    }
 }
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    serial_console().write_fmt(args).unwrap();
+    fmt::Write::write_fmt(&mut Tee, args).unwrap();
 }
 
 #[doc(hidden)]
@@ -0,0 +1,182 @@
+use core::{alloc::AllocError, arch::global_asm};
+
+use std_alloc::vec::Vec;
+
+use crate::{
+    error::OsError,
+    fs::{
+        dentry::DentryId,
+        fd::FdTable,
+        vfs::{OpenFlags, VFS},
+        FileKind,
+    },
+    kalloc::{BootAllocator, BOOT_ALLOCATOR},
+    sync::NullLock,
+};
+
+use self::{
+    elf::Elf,
+    memory::{AddressSpace, USER_STACK_TOP},
+};
+
+pub mod elf;
+pub mod memory;
+
+global_asm!(include_str!("process/user.S"));
+
+extern "C" {
+    fn __enter_user(entry: usize, stack: usize) -> i32;
+    fn __leave_user(status: i32) -> !;
+}
+
+pub type Pid = u32;
+
+/// What the kernel knows about a process.
+///
+/// There is no scheduler yet: the only process is the kernel itself, pid 0, which syscalls act
+/// on. `exec` runs user code in it until that exits.
+pub struct Process {
+    pub pid: Pid,
+    pub name: &'static str,
+    pub files: FdTable,
+    /// The working directory, `None` for the root.
+    pub cwd: Option<DentryId>,
+    /// The memory of the user code running, if any.
+    pub memory: Option<AddressSpace>,
+}
+
+struct ProcessTable {
+    processes: Vec<Process, &'static BootAllocator>,
+    current: Pid,
+}
+
+pub struct Processes {
+    inner: NullLock<ProcessTable>,
+}
+
+impl Processes {
+    const fn new() -> Self {
+        Self {
+            inner: NullLock::new(ProcessTable {
+                processes: Vec::new_in(&BOOT_ALLOCATOR),
+                current: 0,
+            }),
+        }
+    }
+
+    /// Add the kernel as the first process. The boot allocator must be set up.
+    pub fn init(&self) -> Result<(), OsError> {
+        self.inner.lock(|inner| {
+            assert!(inner.processes.is_empty(), "Processes initialised twice");
+            inner.processes.try_reserve(1).map_err(|_| AllocError)?;
+            inner.processes.push(Process {
+                pid: 0,
+                name: "kernel",
+                files: FdTable::new(),
+                cwd: None,
+                memory: None,
+            });
+            Ok(())
+        })
+    }
+
+    /// Call `f` with the running process.
+    pub fn with_current<R>(&self, f: impl FnOnce(&mut Process) -> R) -> R {
+        self.inner.lock(|inner| {
+            let current = inner.current;
+            let process = inner
+                .processes
+                .iter_mut()
+                .find(|process| process.pid == current)
+                .expect("no current process");
+            f(process)
+        })
+    }
+
+    pub fn current_pid(&self) -> Pid {
+        self.inner.lock(|inner| inner.current)
+    }
+
+    /// Call `f` with the process `pid`.
+    pub fn with_process<R>(&self, pid: Pid, f: impl FnOnce(&Process) -> R) -> Result<R, OsError> {
+        self.inner.lock(|inner| {
+            let process = inner
+                .processes
+                .iter()
+                .find(|process| process.pid == pid)
+                .ok_or(OsError::NotFound)?;
+            Ok(f(process))
+        })
+    }
+
+    /// The lowest pid above `after`, to go through the processes without holding the table.
+    pub fn next_pid(&self, after: Option<Pid>) -> Option<Pid> {
+        self.inner.lock(|inner| {
+            inner
+                .processes
+                .iter()
+                .map(|process| process.pid)
+                .filter(|&pid| !matches!(after, Some(after) if pid <= after))
+                .min()
+        })
+    }
+}
+
+pub static PROCESSES: Processes = Processes::new();
+
+/// Read all of the regular file at `path`.
+fn read_file(path: &str) -> Result<Vec<u8, &'static BootAllocator>, OsError> {
+    let file = VFS.open(None, path, OpenFlags::empty(), 0)?;
+    let read = || {
+        let stat = VFS.stat_file(file)?;
+        if stat.kind != FileKind::Regular {
+            return Err(OsError::InvalidArgument);
+        }
+        let mut data = Vec::new_in(&BOOT_ALLOCATOR);
+        let size = usize::try_from(stat.size).map_err(|_| OsError::FileTooLarge)?;
+        data.try_reserve_exact(size).map_err(|_| AllocError)?;
+        data.resize(size, 0);
+        let mut len = 0;
+        while len < size {
+            match VFS.read(file, &mut data[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        data.truncate(len);
+        Ok(data)
+    };
+    let data = read();
+    VFS.close(file)?;
+    data
+}
+
+/// Run the executable at `path` at EL0 in the running process, and return its exit status.
+///
+/// There is no scheduler to switch to another process, so the kernel waits here until it exits.
+pub fn exec(path: &str) -> Result<i32, OsError> {
+    let (mut memory, entry) = {
+        let data = read_file(path)?;
+        let elf = Elf::parse(&data)?;
+        let mut memory = AddressSpace::new()?;
+        for segment in elf.segments() {
+            memory.load(&segment, elf.segment_data(&segment))?;
+        }
+        (memory, elf.entry() as usize)
+    };
+    memory.map_stack()?;
+    memory.activate();
+    PROCESSES.with_current(|process| process.memory = Some(memory));
+    let status = unsafe { __enter_user(entry, USER_STACK_TOP) };
+    PROCESSES.with_current(|process| process.memory = None);
+    Ok(status)
+}
+
+/// End the user code running, making `exec` return `status`.
+///
+/// # Safety
+///
+/// Must be called from a syscall of the user code, with nothing left to drop on the kernel stack.
+pub unsafe fn exit(status: i32) -> ! {
+    __leave_user(status)
+}
@@ -0,0 +1,135 @@
+//! Just enough of ELF64 to check that a file is an AArch64 executable, and find what to load.
+
+use crate::error::OsError;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_AARCH64: u16 = 183;
+const PT_LOAD: u32 = 1;
+
+/// Flags of a segment.
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const HEADER_LEN: usize = 64;
+const PROGRAM_HEADER_LEN: usize = 56;
+
+/// A segment to load into memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    /// Size in memory, the part past the file contents being zeroed.
+    pub mem_size: u64,
+    /// Where the contents are in the file.
+    pub offset: u64,
+    pub file_size: u64,
+    /// `PF_X`, `PF_W` and `PF_R`.
+    pub flags: u32,
+}
+
+/// A checked ELF executable.
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    entry: u64,
+    program_headers: usize,
+    program_header_count: usize,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, OsError> {
+        if bytes.len() < HEADER_LEN || !bytes.starts_with(ELF_MAGIC) {
+            return Err(OsError::InvalidElf("bad magic"));
+        }
+        if bytes[4] != ELFCLASS64 || bytes[5] != ELFDATA2LSB || bytes[6] != EV_CURRENT {
+            return Err(OsError::InvalidElf("not a little endian ELF64 file"));
+        }
+        if !matches!(u16_at(bytes, 16), ET_EXEC | ET_DYN) {
+            return Err(OsError::InvalidElf("not an executable"));
+        }
+        if u16_at(bytes, 18) != EM_AARCH64 {
+            return Err(OsError::InvalidElf("not for AArch64"));
+        }
+
+        let entry = u64_at(bytes, 24);
+        let program_headers = u64_at(bytes, 32);
+        let entry_size = u16_at(bytes, 54) as usize;
+        let program_header_count = u16_at(bytes, 56) as usize;
+        if program_header_count == 0 {
+            return Err(OsError::InvalidElf("no program headers"));
+        }
+        if entry_size != PROGRAM_HEADER_LEN {
+            return Err(OsError::InvalidElf("bad program header size"));
+        }
+        let end = program_headers
+            .checked_add((program_header_count * PROGRAM_HEADER_LEN) as u64)
+            .ok_or(OsError::InvalidElf("program headers out of the file"))?;
+        if end > bytes.len() as u64 {
+            return Err(OsError::InvalidElf("program headers out of the file"));
+        }
+
+        let elf = Self {
+            bytes,
+            entry,
+            program_headers: program_headers as usize,
+            program_header_count,
+        };
+        for segment in elf.segments() {
+            let in_file = matches!(
+                segment.offset.checked_add(segment.file_size),
+                Some(end) if end <= bytes.len() as u64
+            );
+            if !in_file || segment.file_size > segment.mem_size {
+                return Err(OsError::InvalidElf("bad segment"));
+            }
+        }
+        let has_entry = elf
+            .segments()
+            .any(|segment| segment.vaddr <= entry && entry - segment.vaddr < segment.mem_size);
+        if !has_entry {
+            return Err(OsError::InvalidElf("entry point out of the segments"));
+        }
+        Ok(elf)
+    }
+
+    /// Address of the first instruction.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// The contents of `segment` in the file.
+    pub fn segment_data(&self, segment: &Segment) -> &'a [u8] {
+        let offset = segment.offset as usize;
+        &self.bytes[offset..offset + segment.file_size as usize]
+    }
+
+    /// The `PT_LOAD` segments.
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        (0..self.program_header_count)
+            .map(|i| &self.bytes[self.program_headers + i * PROGRAM_HEADER_LEN..])
+            .filter(|header| u32_at(header, 0) == PT_LOAD)
+            .map(|header| Segment {
+                flags: u32_at(header, 4),
+                offset: u64_at(header, 8),
+                vaddr: u64_at(header, 16),
+                file_size: u64_at(header, 32),
+                mem_size: u64_at(header, 40),
+            })
+    }
+}
@@ -0,0 +1,219 @@
+//! The memory of a process at EL0.
+//!
+//! The kernel identity maps the first GiB through the first entry of the level 1 table behind
+//! `TTBR0_EL1`. User memory is mapped in the second GiB, through the second entry, which points
+//! to the level 2 table of the running process.
+
+use core::{arch::asm, iter, slice};
+
+use std_alloc::vec::Vec;
+
+use crate::{
+    error::OsError,
+    kalloc::{
+        page::{Page, PageUse},
+        AllocError, BootAllocator, BOOT_ALLOCATOR,
+    },
+    mmu::{
+        align_down,
+        layout::ttbr0_el1_start,
+        paging::{AccessPermission, MemAttrIdx, PageDescriptor, Shareability, TableDescriptor},
+        PAGE_SIZE,
+    },
+};
+
+use super::elf::{Segment, PF_R, PF_W, PF_X};
+
+/// The addresses user code can use.
+pub const USER_START: usize = 1 << 30;
+pub const USER_END: usize = 2 << 30;
+
+/// The stack is at the top of the user addresses.
+pub const USER_STACK_TOP: usize = USER_END;
+const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+const L1_INDEX: usize = USER_START >> 30;
+const L2_BLOCK_SIZE: usize = 2 << 20;
+const ENTRIES_PER_TABLE: usize = PAGE_SIZE / 8;
+
+/// The entries of the page table in `page`.
+fn table_entries(page: &mut Page) -> &mut [u64] {
+    unsafe { slice::from_raw_parts_mut(page.as_mut_ptr() as *mut u64, ENTRIES_PER_TABLE) }
+}
+
+fn table_descriptor(table: &Page) -> u64 {
+    let mut desc = TableDescriptor::new(table.as_ptr() as _);
+    desc.set_af(true);
+    desc.into()
+}
+
+/// A descriptor for the page at `addr`, which user code accesses as the `PF_*` `flags` say.
+fn page_descriptor(addr: usize, flags: u32) -> u64 {
+    let mut desc = PageDescriptor::new(addr);
+    desc.set_af(true);
+    desc.set_sh(Shareability::Inner);
+    desc.set_attr_idx(MemAttrIdx::Normal);
+    if flags & PF_W != 0 {
+        desc.set_ap(AccessPermission::ReadWrite);
+    } else {
+        desc.set_ap(AccessPermission::ReadOnly);
+    }
+    desc.set_xn(flags & PF_X == 0);
+    desc.set_pxn(true);
+    desc.into()
+}
+
+/// Make the page table changes visible, and drop what the TLBs remember of the old ones.
+fn flush_tlb() {
+    // The caches are off, so the code written to the pages needs no cleaning.
+    unsafe { asm!("dsb ishst", "tlbi vmalle1", "dsb ish", "isb") };
+}
+
+/// The pages of a process and the page tables mapping them.
+pub struct AddressSpace {
+    l2_table: Page,
+    /// Level 3 tables, by their index in `l2_table`.
+    l3_tables: Vec<(usize, Page), &'static BootAllocator>,
+    /// Mapped pages by address, with their `PF_*` flags, sorted.
+    pages: Vec<(usize, u32, Page), &'static BootAllocator>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, OsError> {
+        Ok(Self {
+            l2_table: Page::zeroed(PageUse::PageTables)?,
+            l3_tables: Vec::new_in(&BOOT_ALLOCATOR),
+            pages: Vec::new_in(&BOOT_ALLOCATOR),
+        })
+    }
+
+    /// The level 3 table for `addr`, added if there is none yet.
+    fn l3_table(&mut self, addr: usize) -> Result<&mut [u64], OsError> {
+        let index = (addr - USER_START) / L2_BLOCK_SIZE;
+        let i = match self
+            .l3_tables
+            .binary_search_by_key(&index, |(index, _)| *index)
+        {
+            Ok(i) => i,
+            Err(i) => {
+                self.l3_tables.try_reserve(1).map_err(|_| AllocError)?;
+                let table = Page::zeroed(PageUse::PageTables)?;
+                table_entries(&mut self.l2_table)[index] = table_descriptor(&table);
+                self.l3_tables.insert(i, (index, table));
+                i
+            }
+        };
+        Ok(table_entries(&mut self.l3_tables[i].1))
+    }
+
+    /// Map a zeroed page at `addr` with the `PF_*` `flags`, or add them to the page there.
+    fn map(&mut self, addr: usize, flags: u32) -> Result<&mut Page, OsError> {
+        let i = match self.pages.binary_search_by_key(&addr, |(addr, ..)| *addr) {
+            Ok(i) => {
+                self.pages[i].1 |= flags;
+                i
+            }
+            Err(i) => {
+                self.pages.try_reserve(1).map_err(|_| AllocError)?;
+                self.pages
+                    .insert(i, (addr, flags, Page::zeroed(PageUse::User)?));
+                i
+            }
+        };
+        let (_, flags, ref page) = self.pages[i];
+        let desc = page_descriptor(page.as_ptr() as usize, flags);
+        self.l3_table(addr)?[addr / PAGE_SIZE % ENTRIES_PER_TABLE] = desc;
+        Ok(&mut self.pages[i].2)
+    }
+
+    /// Map `segment` of an executable and copy `data`, its contents, into it.
+    ///
+    /// Segments don't need to be page aligned, those sharing a page get the flags of both.
+    pub fn load(&mut self, segment: &Segment, data: &[u8]) -> Result<(), OsError> {
+        let start = segment.vaddr as usize;
+        let in_user = matches!(
+            start.checked_add(segment.mem_size as usize),
+            Some(end) if start >= USER_START && end <= USER_END
+        );
+        if !in_user {
+            return Err(OsError::InvalidElf("segment out of the user addresses"));
+        }
+        let end = start + segment.mem_size as usize;
+        for addr in (align_down(start, PAGE_SIZE)..end).step_by(PAGE_SIZE) {
+            let page = self.map(addr, segment.flags)?;
+            let from = addr.max(start);
+            let to = (addr + PAGE_SIZE).min(start + data.len());
+            if from < to {
+                page[from - addr..to - addr].copy_from_slice(&data[from - start..to - start]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Map the stack, below `USER_STACK_TOP`.
+    pub fn map_stack(&mut self) -> Result<(), OsError> {
+        for addr in (USER_STACK_TOP - USER_STACK_SIZE..USER_STACK_TOP).step_by(PAGE_SIZE) {
+            self.map(addr, PF_R | PF_W)?;
+        }
+        Ok(())
+    }
+
+    /// The ranges of mapped addresses, with the `PF_*` flags of their pages.
+    pub fn regions(&self) -> impl Iterator<Item = (usize, usize, u32)> + '_ {
+        let mut pages = self.pages.iter().peekable();
+        iter::from_fn(move || {
+            let &(start, flags, _) = pages.next()?;
+            let mut end = start + PAGE_SIZE;
+            while pages
+                .next_if(|&&(addr, next_flags, _)| addr == end && next_flags == flags)
+                .is_some()
+            {
+                end += PAGE_SIZE;
+            }
+            Some((start, end, flags))
+        })
+    }
+
+    /// Make the user addresses map this address space.
+    pub fn activate(&self) {
+        let l1_table = ttbr0_el1_start() as *mut u64;
+        unsafe {
+            l1_table
+                .add(L1_INDEX)
+                .write_volatile(table_descriptor(&self.l2_table))
+        };
+        flush_tlb();
+    }
+
+    /// The number of bytes from `addr`, up to `max`, which user code can read, and write too if
+    /// `write`.
+    pub fn accessible_len(&self, addr: usize, max: usize, write: bool) -> usize {
+        if !(USER_START..USER_END).contains(&addr) {
+            return 0;
+        }
+        let mut len = 0;
+        while len < max {
+            let page = align_down(addr + len, PAGE_SIZE);
+            let flags = match self.pages.binary_search_by_key(&page, |(addr, ..)| *addr) {
+                Ok(i) => self.pages[i].1,
+                Err(_) => break,
+            };
+            if write && flags & PF_W == 0 {
+                break;
+            }
+            len = page + PAGE_SIZE - addr;
+        }
+        len.min(max)
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Unmap the user addresses if they map this address space.
+    fn drop(&mut self) {
+        let entry = unsafe { (ttbr0_el1_start() as *mut u64).add(L1_INDEX) };
+        if unsafe { entry.read_volatile() } == table_descriptor(&self.l2_table) {
+            unsafe { entry.write_volatile(0) };
+            flush_tlb();
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use core::cell::UnsafeCell;
 
+use crate::exception;
+
 pub struct NullLock<T>
 where
     T: ?Sized,
@@ -29,3 +31,36 @@ impl<T> NullLock<T> {
         f(data)
     }
 }
+
+/// A `NullLock` which additionally masks IRQs on the executing core while the lock is held.
+///
+/// Use this for data that is shared with interrupt handlers.
+pub struct IrqSafeNullLock<T>
+where
+    T: ?Sized,
+{
+    data: UnsafeCell<T>,
+}
+
+/// Safety: This is safe only when the kernel is uniprocessor.
+/// Replace with spinlock when MMU is enabled.
+unsafe impl<T> Send for IrqSafeNullLock<T> where T: ?Sized + Send {}
+
+/// Safety: This is safe only when the kernel is uniprocessor.
+/// Replace with spinlock when MMU is enabled.
+unsafe impl<T> Sync for IrqSafeNullLock<T> where T: ?Sized + Send {}
+
+impl<T> IrqSafeNullLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Safety: This is safe only when the kernel is uniprocessor.
+    /// Replace with spinlock when MMU is enabled.
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let data = unsafe { &mut *self.data.get() };
+        exception::asynchronous::exec_with_irq_masked(|| f(data))
+    }
+}
@@ -0,0 +1,399 @@
+use core::{mem, ops::ControlFlow, slice, str};
+
+use crate::{
+    error::OsError,
+    fs::{
+        dentry::DentryId,
+        fd::Fd,
+        vfs::{FileId, OpenFlags, Whence, VFS},
+        DeviceNumber, FileKind, Stat, PATH_MAX,
+    },
+    mmu::PAGE_SIZE,
+    process::{self, PROCESSES},
+};
+
+/// Syscall numbers, the same as on Linux for AArch64.
+mod number {
+    pub const MKDIRAT: u64 = 34;
+    pub const UNLINKAT: u64 = 35;
+    pub const CHDIR: u64 = 49;
+    pub const OPENAT: u64 = 56;
+    pub const CLOSE: u64 = 57;
+    pub const GETDENTS64: u64 = 61;
+    pub const LSEEK: u64 = 62;
+    pub const READ: u64 = 63;
+    pub const WRITE: u64 = 64;
+    pub const NEWFSTATAT: u64 = 79;
+    pub const FSTAT: u64 = 80;
+    pub const SYNC: u64 = 81;
+    pub const EXIT: u64 = 93;
+    pub const EXIT_GROUP: u64 = 94;
+    pub const MUNMAP: u64 = 215;
+    pub const MMAP: u64 = 222;
+}
+
+/// Error numbers returned, negated, by syscalls.
+mod errno {
+    pub const ENOENT: i64 = 2;
+    pub const EINTR: i64 = 4;
+    pub const EIO: i64 = 5;
+    pub const ENOEXEC: i64 = 8;
+    pub const EBADF: i64 = 9;
+    pub const ENOMEM: i64 = 12;
+    pub const EFAULT: i64 = 14;
+    pub const EBUSY: i64 = 16;
+    pub const EEXIST: i64 = 17;
+    pub const ENOTDIR: i64 = 20;
+    pub const EISDIR: i64 = 21;
+    pub const EINVAL: i64 = 22;
+    pub const EMFILE: i64 = 24;
+    pub const ENOTTY: i64 = 25;
+    pub const EFBIG: i64 = 27;
+    pub const ENOSPC: i64 = 28;
+    pub const EROFS: i64 = 30;
+    pub const ENAMETOOLONG: i64 = 36;
+    pub const ENOSYS: i64 = 38;
+    pub const ENOTEMPTY: i64 = 39;
+    pub const ELOOP: i64 = 40;
+    pub const EOPNOTSUPP: i64 = 95;
+}
+
+/// `dirfd` standing for the working directory.
+const AT_FDCWD: i64 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_REMOVEDIR: u64 = 0x200;
+
+/// `flags` of `mmap`.
+const MAP_SHARED: u64 = 0x01;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+/// File types in `st_mode`.
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+
+/// File types in `d_type`.
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_BLK: u8 = 6;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/// `struct stat` of Linux on AArch64.
+#[repr(C)]
+#[derive(Default)]
+struct LinuxStat {
+    st_dev: u64,
+    st_ino: u64,
+    st_mode: u32,
+    st_nlink: u32,
+    st_uid: u32,
+    st_gid: u32,
+    st_rdev: u64,
+    __pad1: u64,
+    st_size: i64,
+    st_blksize: i32,
+    __pad2: i32,
+    st_blocks: i64,
+    st_atime: i64,
+    st_atime_nsec: u64,
+    st_mtime: i64,
+    st_mtime_nsec: u64,
+    st_ctime: i64,
+    st_ctime_nsec: u64,
+    __unused: [u32; 2],
+}
+
+impl From<Stat> for LinuxStat {
+    fn from(stat: Stat) -> Self {
+        let file_type = match stat.kind {
+            FileKind::Regular => S_IFREG,
+            FileKind::Directory => S_IFDIR,
+            FileKind::Symlink => S_IFLNK,
+            FileKind::CharDevice => S_IFCHR,
+            FileKind::BlockDevice => S_IFBLK,
+        };
+        // The encoding of `new_encode_dev`, leaving room for 20 bit minor numbers.
+        let DeviceNumber { major, minor } = stat.rdev;
+        let rdev = (minor & 0xFF) | (major & 0xFFF) << 8 | (minor & !0xFF) << 12;
+        Self {
+            st_dev: stat.dev as u64,
+            st_ino: stat.ino,
+            st_mode: file_type | stat.mode as u32,
+            st_nlink: stat.nlink,
+            st_rdev: rdev as u64,
+            st_size: stat.size as i64,
+            st_blksize: stat.block_size as i32,
+            st_blocks: stat.blocks as i64,
+            ..Self::default()
+        }
+    }
+}
+
+fn errno(err: &OsError) -> i64 {
+    match err {
+        OsError::Alloc(_) => errno::ENOMEM,
+        OsError::NotFound => errno::ENOENT,
+        OsError::AlreadyExists => errno::EEXIST,
+        OsError::NotADirectory => errno::ENOTDIR,
+        OsError::IsADirectory => errno::EISDIR,
+        OsError::DirectoryNotEmpty => errno::ENOTEMPTY,
+        OsError::InvalidFileName | OsError::InvalidArgument => errno::EINVAL,
+        OsError::NoSpace => errno::ENOSPC,
+        OsError::FileTooLarge => errno::EFBIG,
+        OsError::NameTooLong => errno::ENAMETOOLONG,
+        OsError::TooManySymlinks => errno::ELOOP,
+        OsError::BadFileDescriptor => errno::EBADF,
+        OsError::TooManyOpenFiles => errno::EMFILE,
+        OsError::NotSupported => errno::EOPNOTSUPP,
+        OsError::ReadOnlyFilesystem => errno::EROFS,
+        OsError::Busy => errno::EBUSY,
+        OsError::BadAddress => errno::EFAULT,
+        OsError::Interrupted => errno::EINTR,
+        OsError::InvalidIoctl(_) => errno::ENOTTY,
+        OsError::InvalidElf(_) => errno::ENOEXEC,
+        _ => errno::EIO,
+    }
+}
+
+// Pointers passed to syscalls are checked against the memory of the current process. The slices
+// made from them stay valid until the syscall returns, as nothing unmaps that memory in between.
+
+/// The number of bytes from `ptr`, up to `max`, which the current process can read, and write too
+/// if `write`.
+fn user_accessible_len(ptr: u64, max: usize, write: bool) -> usize {
+    PROCESSES.with_current(|process| {
+        process
+            .memory
+            .as_ref()
+            .map_or(0, |memory| memory.accessible_len(ptr as usize, max, write))
+    })
+}
+
+/// Check that the current process can access the `len` bytes at `ptr`, and write them if `write`.
+fn check_user(ptr: u64, len: u64, write: bool) -> Result<usize, OsError> {
+    let len = usize::try_from(len).map_err(|_| OsError::BadAddress)?;
+    if (ptr as usize).checked_add(len).is_none() || user_accessible_len(ptr, len, write) < len {
+        return Err(OsError::BadAddress);
+    }
+    Ok(len)
+}
+
+/// # Safety
+///
+/// The slice must not be used after the syscall returns.
+unsafe fn user_buf<'a>(ptr: u64, len: u64) -> Result<&'a [u8], OsError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    let len = check_user(ptr, len, false)?;
+    Ok(slice::from_raw_parts(ptr as *const u8, len))
+}
+
+/// # Safety
+///
+/// The slice must not be used after the syscall returns.
+unsafe fn user_buf_mut<'a>(ptr: u64, len: u64) -> Result<&'a mut [u8], OsError> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    let len = check_user(ptr, len, true)?;
+    Ok(slice::from_raw_parts_mut(ptr as *mut u8, len))
+}
+
+/// # Safety
+///
+/// The string must not be used after the syscall returns.
+unsafe fn user_str<'a>(ptr: u64) -> Result<&'a str, OsError> {
+    let accessible = user_accessible_len(ptr, PATH_MAX + 1, false);
+    let bytes = slice::from_raw_parts(ptr as *const u8, accessible);
+    let len = match bytes.iter().position(|&byte| byte == 0) {
+        Some(len) => len,
+        None if accessible > PATH_MAX => return Err(OsError::NameTooLong),
+        None => return Err(OsError::BadAddress),
+    };
+    str::from_utf8(&bytes[..len]).map_err(|_| OsError::InvalidArgument)
+}
+
+fn put_user_stat(ptr: u64, stat: Stat) -> Result<(), OsError> {
+    if ptr as usize % mem::align_of::<LinuxStat>() != 0 {
+        return Err(OsError::BadAddress);
+    }
+    check_user(ptr, mem::size_of::<LinuxStat>() as u64, true)?;
+    unsafe { (ptr as *mut LinuxStat).write(stat.into()) };
+    Ok(())
+}
+
+/// Handle the syscall `number` with the arguments from x0 to x5, returning the result for x0:
+/// a negated error number on failure, as on Linux.
+///
+/// # Safety
+///
+/// Must be called for a syscall of the user code running, with nothing left to drop on the kernel
+/// stack, as `exit` doesn't return.
+pub unsafe fn dispatch(number: u64, args: [u64; 6]) -> i64 {
+    let [a0, a1, a2, a3, a4, a5] = args;
+    let result = match number {
+        number::MKDIRAT => {
+            user_str(a1).and_then(|path| mkdirat(a0 as i64, path, a2 as u16).map(|()| 0))
+        }
+        number::UNLINKAT => user_str(a1).and_then(|path| unlinkat(a0 as i64, path, a2).map(|()| 0)),
+        number::CHDIR => user_str(a0).and_then(|path| chdir(path).map(|()| 0)),
+        number::OPENAT => user_str(a1).and_then(|path| {
+            // Like Linux, flags which are not known are ignored.
+            let flags = OpenFlags::from_bits_truncate(a2 as u32);
+            openat(a0 as i64, path, flags, a3 as u16).map(|fd| fd as u64)
+        }),
+        number::CLOSE => close(a0 as Fd).map(|()| 0),
+        number::GETDENTS64 => {
+            user_buf_mut(a1, a2).and_then(|buf| getdents64(a0 as Fd, buf).map(|len| len as u64))
+        }
+        number::LSEEK => lseek(a0 as Fd, a1 as i64, a2),
+        number::READ => user_buf_mut(a1, a2).and_then(|buf| read(a0 as Fd, buf).map(|n| n as u64)),
+        number::WRITE => user_buf(a1, a2).and_then(|buf| write(a0 as Fd, buf).map(|n| n as u64)),
+        number::NEWFSTATAT => user_str(a1)
+            .and_then(|path| fstatat(a0 as i64, path, a3))
+            .and_then(|stat| put_user_stat(a2, stat).map(|()| 0)),
+        number::FSTAT => fstat(a0 as Fd).and_then(|stat| put_user_stat(a1, stat).map(|()| 0)),
+        number::SYNC => VFS.sync().map(|()| 0),
+        number::EXIT | number::EXIT_GROUP => process::exit(a0 as i32),
+        number::MUNMAP => Ok(0),
+        number::MMAP => mmap(a1 as usize, a3, a4 as Fd, a5).map(|addr| addr as u64),
+        _ => return -errno::ENOSYS,
+    };
+    match result {
+        Ok(value) => value as i64,
+        Err(err) => -errno(&err),
+    }
+}
+
+fn file(fd: Fd) -> Result<FileId, OsError> {
+    PROCESSES.with_current(|process| process.files.get(fd))
+}
+
+/// The directory relative paths start from: the working directory for `AT_FDCWD`, else the
+/// open directory `dirfd`.
+fn start_dir(dirfd: i64) -> Result<Option<DentryId>, OsError> {
+    if dirfd == AT_FDCWD {
+        return Ok(PROCESSES.with_current(|process| process.cwd));
+    }
+    let fd = usize::try_from(dirfd).map_err(|_| OsError::BadFileDescriptor)?;
+    VFS.file_dentry(file(fd)?).map(Some)
+}
+
+fn openat(dirfd: i64, path: &str, flags: OpenFlags, mode: u16) -> Result<Fd, OsError> {
+    let start = start_dir(dirfd)?;
+    let file = VFS.open(start, path, flags, mode & 0o7777)?;
+    let fd = PROCESSES.with_current(|process| process.files.insert(file));
+    if fd.is_err() {
+        VFS.close(file)?;
+    }
+    fd
+}
+
+fn close(fd: Fd) -> Result<(), OsError> {
+    let file = PROCESSES.with_current(|process| process.files.remove(fd))?;
+    VFS.close(file)
+}
+
+fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, OsError> {
+    VFS.read(file(fd)?, buf)
+}
+
+fn write(fd: Fd, buf: &[u8]) -> Result<usize, OsError> {
+    VFS.write(file(fd)?, buf)
+}
+
+/// Map `len` bytes from `offset` of the file `fd`.
+///
+/// Only files which are memory, like `/dev/fb0`, can be mapped: the mapping is the memory itself,
+/// at its physical address in the identity mapping of the kernel. User code has no access to that
+/// yet, it isn't added to its `AddressSpace`. For the same reason `munmap` has nothing to do.
+fn mmap(len: usize, flags: u64, fd: Fd, offset: u64) -> Result<usize, OsError> {
+    if flags & (MAP_FIXED | MAP_ANONYMOUS) != 0 || flags & MAP_SHARED == 0 {
+        return Err(OsError::NotSupported);
+    }
+    if offset % PAGE_SIZE as u64 != 0 || len == 0 {
+        return Err(OsError::InvalidArgument);
+    }
+    VFS.mmap(file(fd)?, offset, len)
+}
+
+fn lseek(fd: Fd, offset: i64, whence: u64) -> Result<u64, OsError> {
+    let whence = match whence {
+        SEEK_SET => Whence::Set,
+        SEEK_CUR => Whence::Current,
+        SEEK_END => Whence::End,
+        _ => return Err(OsError::InvalidArgument),
+    };
+    VFS.seek(file(fd)?, offset, whence)
+}
+
+/// Fill `buf` with `struct linux_dirent64` records, returning their total length.
+fn getdents64(fd: Fd, buf: &mut [u8]) -> Result<usize, OsError> {
+    /// Size of the fixed fields of a record: d_ino, d_off, d_reclen and d_type.
+    const HEADER_LEN: usize = 19;
+    let mut len = 0;
+    let mut full = false;
+    VFS.read_dir(file(fd)?, &mut |name, ino, kind| {
+        // The name is NUL terminated, and records are 8 byte aligned.
+        let record_len = (HEADER_LEN + name.len() + 1 + 7) & !7;
+        if len + record_len > buf.len() {
+            full = true;
+            return ControlFlow::Break(());
+        }
+        let record = &mut buf[len..len + record_len];
+        record.fill(0);
+        record[0..8].copy_from_slice(&ino.to_le_bytes());
+        // d_off is left 0, positions are only known to the VFS.
+        record[16..18].copy_from_slice(&(record_len as u16).to_le_bytes());
+        record[18] = match kind {
+            FileKind::Regular => DT_REG,
+            FileKind::Directory => DT_DIR,
+            FileKind::Symlink => DT_LNK,
+            FileKind::CharDevice => DT_CHR,
+            FileKind::BlockDevice => DT_BLK,
+        };
+        record[HEADER_LEN..HEADER_LEN + name.len()].copy_from_slice(name.as_bytes());
+        len += record_len;
+        ControlFlow::Continue(())
+    })?;
+    if len == 0 && full {
+        // Not even one entry fits.
+        return Err(OsError::InvalidArgument);
+    }
+    Ok(len)
+}
+
+fn fstatat(dirfd: i64, path: &str, flags: u64) -> Result<Stat, OsError> {
+    let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+    VFS.stat(start_dir(dirfd)?, path, follow)
+}
+
+fn fstat(fd: Fd) -> Result<Stat, OsError> {
+    VFS.stat_file(file(fd)?)
+}
+
+fn mkdirat(dirfd: i64, path: &str, mode: u16) -> Result<(), OsError> {
+    VFS.mkdir(start_dir(dirfd)?, path, mode & 0o7777)
+}
+
+fn unlinkat(dirfd: i64, path: &str, flags: u64) -> Result<(), OsError> {
+    VFS.unlink(start_dir(dirfd)?, path, flags & AT_REMOVEDIR != 0)
+}
+
+fn chdir(path: &str) -> Result<(), OsError> {
+    let cwd = PROCESSES.with_current(|process| process.cwd);
+    let dir = VFS.get_dir(cwd, path)?;
+    if let Some(old) = PROCESSES.with_current(|process| process.cwd.replace(dir)) {
+        VFS.put_dentry(old);
+    }
+    Ok(())
+}
@@ -0,0 +1,573 @@
+use core::{fmt, str};
+
+use crate::{
+    collections::ring_buffer::RingBuffer,
+    driver::{self, SerialConsole, SerialPort},
+    error::OsError,
+    fs::devfs::CharDevice,
+    print,
+    sync::IrqSafeNullLock,
+};
+
+use self::termios::{
+    InputFlags, LocalFlags, OutputFlags, Termios, VEOF, VERASE, VINTR, VKILL, VMIN, VQUIT, VSUSP,
+    VWERASE,
+};
+
+pub mod termios;
+
+/// Maximum length of a line in canonical mode.
+const MAX_CANON: usize = 255;
+/// Size of the queue of input ready to be read.
+const READ_QUEUE_SIZE: usize = 1024;
+/// Maximum number of complete lines waiting to be read.
+const MAX_LINES: usize = 32;
+/// Maximum number of signals waiting to be delivered.
+const MAX_PENDING_SIGNALS: usize = 8;
+
+pub type ProcessGroupId = u32;
+
+/// Signals generated by the line discipline. The values match the Linux signal numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Signal {
+    /// Generated by the INTR character, usually Ctrl-C.
+    Interrupt = 2,
+    /// Generated by the QUIT character, usually Ctrl-\.
+    Quit = 3,
+    /// Generated by the SUSP character, usually Ctrl-Z.
+    TerminalStop = 20,
+}
+
+/// When a new `Termios` takes effect, see `tcsetattr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetAttrAction {
+    /// Immediately.
+    Now,
+    /// After all output has been transmitted.
+    Drain,
+    /// After all output has been transmitted, discarding all pending input.
+    Flush,
+}
+
+// ioctl requests, with the Linux numbering.
+const TCGETS: u32 = 0x5401;
+const TCSETS: u32 = 0x5402;
+const TCSETSW: u32 = 0x5403;
+const TCSETSF: u32 = 0x5404;
+const TIOCGPGRP: u32 = 0x540F;
+const TIOCSPGRP: u32 = 0x5410;
+
+/// The state of the line discipline: the line being edited and the input ready to be read.
+struct LineDiscipline {
+    line: [u8; MAX_CANON],
+    line_len: usize,
+    read_queue: RingBuffer<u8, READ_QUEUE_SIZE>,
+    /// Lengths of the complete lines in `read_queue`, in canonical mode.
+    line_lens: RingBuffer<usize, MAX_LINES>,
+    /// Bytes left of the line currently being read, if a read stopped in the middle of it.
+    partial_line: Option<usize>,
+}
+
+impl LineDiscipline {
+    const fn new() -> Self {
+        Self {
+            line: [0; MAX_CANON],
+            line_len: 0,
+            read_queue: RingBuffer::new(0),
+            line_lens: RingBuffer::new(0),
+            partial_line: None,
+        }
+    }
+
+    fn flush_input(&mut self) {
+        self.line_len = 0;
+        self.read_queue.clear();
+        self.line_lens.clear();
+        self.partial_line = None;
+    }
+
+    /// Move the line being edited to the read queue.
+    fn commit_line(&mut self) {
+        if self.line_lens.is_full() || self.read_queue.len() + self.line_len > READ_QUEUE_SIZE {
+            // Drop the line rather than splitting it.
+            self.line_len = 0;
+            return;
+        }
+        for &c in &self.line[..self.line_len] {
+            self.read_queue.push(c).unwrap();
+        }
+        self.line_lens.push(self.line_len).unwrap();
+        self.line_len = 0;
+    }
+
+    /// Read (part of) the next complete line.
+    fn read_line(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let remaining = match self.partial_line.take() {
+            Some(remaining) => remaining,
+            None => self.line_lens.pop()?,
+        };
+        let count = remaining.min(buf.len());
+        for b in &mut buf[..count] {
+            *b = self.read_queue.pop().unwrap();
+        }
+        if count < remaining {
+            self.partial_line = Some(remaining - count);
+        }
+        Some(count)
+    }
+
+    /// Read whatever is in the read queue, in non-canonical mode.
+    fn read_raw(&mut self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buf.len() {
+            match self.read_queue.pop() {
+                Some(c) => buf[count] = c,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
+/// Applies the output processing of `termios` to everything written through it.
+struct OutputProcessor<'a> {
+    device: &'a dyn SerialConsole,
+    oflag: OutputFlags,
+}
+
+impl OutputProcessor<'_> {
+    /// Write `buf`, which doesn't need to be text.
+    fn write_bytes(&mut self, buf: &[u8]) {
+        if !self.oflag.contains(OutputFlags::OPOST) {
+            self.device.write_bytes(buf);
+            return;
+        }
+
+        let mut rest = buf;
+        while let Some(idx) = rest.iter().position(|&b| b == b'\n' || b == b'\r') {
+            self.device.write_bytes(&rest[..idx]);
+            let translated: &[u8] = match rest[idx] {
+                b'\n' if self.oflag.contains(OutputFlags::ONLCR) => b"\r\n",
+                b'\r' if self.oflag.contains(OutputFlags::OCRNL) => b"\n",
+                b'\n' => b"\n",
+                _ => b"\r",
+            };
+            self.device.write_bytes(translated);
+            rest = &rest[idx + 1..];
+        }
+        self.device.write_bytes(rest);
+    }
+}
+
+impl fmt::Write for OutputProcessor<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+struct TtyInner {
+    device: &'static dyn SerialConsole,
+    termios: Termios,
+    ldisc: LineDiscipline,
+    foreground_pgrp: ProcessGroupId,
+    pending_signals: RingBuffer<(ProcessGroupId, Signal), MAX_PENDING_SIGNALS>,
+    /// Set when a signal is generated, so that a blocked reader can be interrupted.
+    interrupted: bool,
+}
+
+/// A terminal: a character device with a line discipline in front of it.
+///
+/// The lock masks IRQs while it is held, and is never held while waiting for input. IRQ handlers
+/// must not print through the terminal all the same: `kprint!` also takes the kernel log and the
+/// mirror, whose locks don't mask IRQs.
+pub struct Tty {
+    inner: IrqSafeNullLock<TtyInner>,
+}
+
+impl TtyInner {
+    fn output(&self) -> OutputProcessor<'_> {
+        OutputProcessor {
+            device: self.device,
+            oflag: self.termios.oflag,
+        }
+    }
+
+    /// Echo to the device, and to the mirror of the console output like `kprint!` does.
+    fn echo(&self, args: fmt::Arguments) {
+        let _ = fmt::Write::write_fmt(&mut self.output(), args);
+        print::write_mirror(args);
+        print::flush_mirror();
+    }
+
+    fn echo_char(&self, c: u8) {
+        let lflag = self.termios.lflag;
+        let is_control = c < 0x20 && c != b'\n' && c != b'\t' || c == 0x7F;
+        if is_control && lflag.contains(LocalFlags::ECHOCTL) {
+            self.echo(format_args!("^{}", (c ^ 0x40) as char));
+        } else {
+            self.echo(format_args!("{}", c as char));
+        }
+    }
+
+    fn echo_erase(&self, count: usize) {
+        for _ in 0..count {
+            self.echo(format_args!("\x08 \x08"));
+        }
+    }
+
+    fn raise_signal(&mut self, signal: Signal) {
+        if !self.termios.lflag.contains(LocalFlags::NOFLSH) {
+            self.ldisc.flush_input();
+        }
+        // Nobody consumes the signals yet, so the oldest one is dropped rather than the newest.
+        if self.pending_signals.is_full() {
+            self.pending_signals.pop();
+        }
+        let _ = self.pending_signals.push((self.foreground_pgrp, signal));
+        self.interrupted = true;
+    }
+
+    /// Run one received character through the line discipline.
+    fn receive(&mut self, c: u8) {
+        let iflag = self.termios.iflag;
+        let lflag = self.termios.lflag;
+        let cc = self.termios.cc;
+
+        let mut c = c;
+        if iflag.contains(InputFlags::ISTRIP) {
+            c &= 0x7F;
+        }
+        if c == b'\r' {
+            if iflag.contains(InputFlags::IGNCR) {
+                return;
+            }
+            if iflag.contains(InputFlags::ICRNL) {
+                c = b'\n';
+            }
+        } else if c == b'\n' && iflag.contains(InputFlags::INLCR) {
+            c = b'\r';
+        }
+
+        let is_cc = |idx: usize| cc[idx] != termios::VDISABLE && cc[idx] == c;
+
+        // Signals are only queued for `Tty::take_signal`, nothing delivers them to processes yet.
+        if lflag.contains(LocalFlags::ISIG) {
+            let signal = if is_cc(VINTR) {
+                Some(Signal::Interrupt)
+            } else if is_cc(VQUIT) {
+                Some(Signal::Quit)
+            } else if is_cc(VSUSP) {
+                Some(Signal::TerminalStop)
+            } else {
+                None
+            };
+            if let Some(signal) = signal {
+                if lflag.contains(LocalFlags::ECHO) {
+                    self.echo_char(c);
+                    self.echo_char(b'\n');
+                }
+                self.raise_signal(signal);
+                return;
+            }
+        }
+
+        if !lflag.contains(LocalFlags::ICANON) {
+            if self.ldisc.read_queue.push(c).is_ok() && lflag.contains(LocalFlags::ECHO) {
+                self.echo_char(c);
+            }
+            return;
+        }
+
+        let echo = lflag.contains(LocalFlags::ECHO);
+        if is_cc(VERASE) {
+            if self.ldisc.line_len > 0 {
+                self.ldisc.line_len -= 1;
+                if echo && lflag.contains(LocalFlags::ECHOE) {
+                    self.echo_erase(1);
+                }
+            }
+        } else if is_cc(VWERASE) {
+            let line = &self.ldisc.line[..self.ldisc.line_len];
+            let trimmed = line.iter().rposition(|c| !c.is_ascii_whitespace());
+            let word_start = trimmed
+                .and_then(|end| line[..end].iter().rposition(|c| c.is_ascii_whitespace()))
+                .map_or(0, |idx| idx + 1);
+            let erased = self.ldisc.line_len - word_start;
+            self.ldisc.line_len = word_start;
+            if echo && lflag.contains(LocalFlags::ECHOE) {
+                self.echo_erase(erased);
+            }
+        } else if is_cc(VKILL) {
+            let erased = self.ldisc.line_len;
+            self.ldisc.line_len = 0;
+            if echo && lflag.contains(LocalFlags::ECHOK) {
+                self.echo_erase(erased);
+            }
+        } else if is_cc(VEOF) {
+            // Terminates the line without being part of it. On an empty line this makes the
+            // reader see end-of-file.
+            self.ldisc.commit_line();
+        } else if c == b'\n' {
+            if self.ldisc.line_len < MAX_CANON {
+                self.ldisc.line[self.ldisc.line_len] = c;
+                self.ldisc.line_len += 1;
+            }
+            if echo || lflag.contains(LocalFlags::ECHONL) {
+                self.echo_char(c);
+            }
+            self.ldisc.commit_line();
+        } else if self.ldisc.line_len < MAX_CANON - 1 {
+            // The last slot is kept for the newline.
+            self.ldisc.line[self.ldisc.line_len] = c;
+            self.ldisc.line_len += 1;
+            if echo {
+                self.echo_char(c);
+            }
+        }
+    }
+
+    /// Process all input the device has pending.
+    fn poll_device(&mut self) {
+        while let Some(c) = self.device.try_read_char() {
+            self.receive(c as u8);
+        }
+    }
+
+    /// Read what is ready, or return `None` if the reader has to wait for more input.
+    fn try_read(&mut self, buf: &mut [u8]) -> Option<Result<usize, OsError>> {
+        self.poll_device();
+        if self.interrupted {
+            return Some(Err(OsError::Interrupted));
+        }
+
+        if self.termios.lflag.contains(LocalFlags::ICANON) {
+            self.ldisc.read_line(buf).map(Ok)
+        } else {
+            // VTIME is not supported, there is no timer to implement it with.
+            let min = (self.termios.cc[VMIN] as usize).min(buf.len());
+            if self.ldisc.read_queue.len() >= min {
+                Some(Ok(self.ldisc.read_raw(buf)))
+            } else {
+                None
+            }
+        }
+    }
+
+    fn set_attr(&mut self, action: SetAttrAction, termios: &Termios) {
+        if action == SetAttrAction::Flush {
+            self.ldisc.flush_input();
+            self.device.clear_rx();
+        }
+        // Leaving canonical mode makes the line being edited available as is.
+        if self.termios.lflag.contains(LocalFlags::ICANON)
+            && !termios.lflag.contains(LocalFlags::ICANON)
+            && self.ldisc.line_len > 0
+        {
+            self.ldisc.commit_line();
+            self.ldisc.line_lens.clear();
+            self.ldisc.partial_line = None;
+        }
+        self.termios = *termios;
+    }
+}
+
+impl Tty {
+    pub const fn new(device: &'static dyn SerialConsole) -> Self {
+        Self {
+            inner: IrqSafeNullLock::new(TtyInner {
+                device,
+                termios: Termios::cooked(),
+                ldisc: LineDiscipline::new(),
+                foreground_pgrp: 0,
+                pending_signals: RingBuffer::new((0, Signal::Interrupt)),
+                interrupted: false,
+            }),
+        }
+    }
+
+    /// Read input. In canonical mode this blocks until a line is complete, and returns at most
+    /// one line. Returns 0 on end-of-file, and `OsError::Interrupted` if a signal was generated
+    /// while waiting.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, OsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let device = self.inner.lock(|inner| {
+            inner.interrupted = false;
+            inner.device
+        });
+        loop {
+            if let Some(result) = self.inner.lock(|inner| inner.try_read(buf)) {
+                return result;
+            }
+            // Nothing to return yet, so block for the next character, without the lock. Show
+            // what was printed so far first, as it is likely a prompt.
+            print::flush_mirror();
+            let c = device.read_char();
+            self.inner.lock(|inner| inner.receive(c as u8));
+        }
+    }
+
+    /// Write `buf` as is, apart from the output processing.
+    pub fn write(&self, buf: &[u8]) -> usize {
+        self.inner.lock(|inner| inner.output().write_bytes(buf));
+        buf.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn tcgetattr(&self) -> Termios {
+        self.inner.lock(|inner| inner.termios)
+    }
+
+    #[allow(dead_code)]
+    pub fn tcsetattr(&self, action: SetAttrAction, termios: &Termios) {
+        self.inner.lock(|inner| inner.set_attr(action, termios));
+    }
+
+    #[allow(dead_code)]
+    pub fn tcgetpgrp(&self) -> ProcessGroupId {
+        self.inner.lock(|inner| inner.foreground_pgrp)
+    }
+
+    #[allow(dead_code)]
+    pub fn tcsetpgrp(&self, pgrp: ProcessGroupId) {
+        self.inner.lock(|inner| inner.foreground_pgrp = pgrp);
+    }
+
+    /// Take the oldest signal generated for a foreground process group.
+    ///
+    /// There is no process management yet, so signals are queued here until something takes
+    /// them and delivers them.
+    pub fn take_signal(&self) -> Option<(ProcessGroupId, Signal)> {
+        self.inner.lock(|inner| inner.pending_signals.pop())
+    }
+
+    /// Terminal control, with the Linux request numbers. `arg` points to the request's argument.
+    ///
+    /// # Safety
+    ///
+    /// `arg` must be valid for the request: a `Termios` for `TCGETS` and `TCSETS*`, and a
+    /// `ProcessGroupId` for `TIOCGPGRP` and `TIOCSPGRP`.
+    #[allow(dead_code)]
+    pub unsafe fn ioctl(&self, request: u32, arg: usize) -> Result<usize, OsError> {
+        match request {
+            TCGETS => *(arg as *mut Termios) = self.tcgetattr(),
+            TCSETS => self.tcsetattr(SetAttrAction::Now, &*(arg as *const Termios)),
+            TCSETSW => self.tcsetattr(SetAttrAction::Drain, &*(arg as *const Termios)),
+            TCSETSF => self.tcsetattr(SetAttrAction::Flush, &*(arg as *const Termios)),
+            TIOCGPGRP => *(arg as *mut ProcessGroupId) = self.tcgetpgrp(),
+            TIOCSPGRP => self.tcsetpgrp(*(arg as *const ProcessGroupId)),
+            _ => return Err(OsError::InvalidIoctl(request)),
+        }
+        Ok(0)
+    }
+}
+
+impl print::Write for Tty {
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        self.inner
+            .lock(|inner| fmt::Write::write_fmt(&mut inner.output(), args))
+    }
+}
+
+/// Forwards to whichever UART is currently the serial console.
+struct SelectedSerialConsole;
+
+impl print::Write for SelectedSerialConsole {
+    fn write_fmt(&self, args: fmt::Arguments) -> fmt::Result {
+        driver::serial_console().write_fmt(args)
+    }
+}
+
+impl SerialConsole for SelectedSerialConsole {
+    fn write_bytes(&self, buf: &[u8]) {
+        driver::serial_console().write_bytes(buf)
+    }
+}
+
+impl print::Read for SelectedSerialConsole {
+    fn read_char(&self) -> char {
+        driver::serial_console().read_char()
+    }
+
+    fn try_read_char(&self) -> Option<char> {
+        driver::serial_console().try_read_char()
+    }
+
+    fn clear_rx(&self) {
+        driver::serial_console().clear_rx()
+    }
+}
+
+/// The terminal on the serial console. `kprint!` goes through it.
+pub static CONSOLE_TTY: Tty = Tty::new(&SelectedSerialConsole);
+
+/// `CONSOLE_TTY` as a device file, "/dev/console", whichever UART it is on.
+pub struct ConsoleDevice;
+
+impl CharDevice for ConsoleDevice {
+    /// Blocks until there is input, then returns what has arrived.
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        CONSOLE_TTY.read(buf)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        Ok(CONSOLE_TTY.write(buf))
+    }
+}
+
+pub static CONSOLE_DEVICE: ConsoleDevice = ConsoleDevice;
+
+/// A UART as a device file. The serial console goes through `CONSOLE_TTY`, the other UART is
+/// read and written as is.
+pub struct SerialDevice {
+    port: SerialPort,
+}
+
+impl SerialDevice {
+    const fn new(port: SerialPort) -> Self {
+        Self { port }
+    }
+
+    fn is_console(&self) -> bool {
+        driver::serial_console_port() == self.port
+    }
+}
+
+impl CharDevice for SerialDevice {
+    /// Blocks until there is input, then returns what has arrived.
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, OsError> {
+        if self.is_console() {
+            return CONSOLE_TTY.read(buf);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // The UARTs receive bytes, handed out as chars.
+        let uart = driver::serial_port(self.port);
+        buf[0] = uart.read_char() as u8;
+        let mut len = 1;
+        while len < buf.len() {
+            match uart.try_read_char() {
+                Some(c) => buf[len] = c as u8,
+                None => break,
+            }
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> Result<usize, OsError> {
+        if self.is_console() {
+            return Ok(CONSOLE_TTY.write(buf));
+        }
+        driver::serial_port(self.port).write_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+pub static PL011_TTY: SerialDevice = SerialDevice::new(SerialPort::Pl011);
+pub static MINI_UART_TTY: SerialDevice = SerialDevice::new(SerialPort::MiniUart);
@@ -0,0 +1,140 @@
+//! Terminal attributes, laid out like the Linux `struct termios` so they can be copied to and from
+//! userspace as is.
+
+use bitflags::bitflags;
+
+bitflags! {
+    /// Input modes.
+    #[repr(transparent)]
+    pub struct InputFlags: u32 {
+        /// Strip off the eighth bit.
+        const ISTRIP = 0o000040;
+        /// Translate NL to CR on input.
+        const INLCR = 0o000100;
+        /// Ignore CR on input.
+        const IGNCR = 0o000200;
+        /// Translate CR to NL on input (unless IGNCR is set).
+        const ICRNL = 0o000400;
+    }
+}
+
+bitflags! {
+    /// Output modes.
+    #[repr(transparent)]
+    pub struct OutputFlags: u32 {
+        /// Enable output processing. None of the other flags have an effect without it.
+        const OPOST = 0o000001;
+        /// Map NL to CR-NL on output.
+        const ONLCR = 0o000004;
+        /// Map CR to NL on output.
+        const OCRNL = 0o000010;
+    }
+}
+
+bitflags! {
+    /// Local modes.
+    #[repr(transparent)]
+    pub struct LocalFlags: u32 {
+        /// Generate signals for the INTR, QUIT and SUSP characters. They are only queued, see
+        /// `Tty::take_signal`: nothing delivers them to processes yet.
+        const ISIG = 0o000001;
+        /// Canonical mode: input is made available line by line, with line editing.
+        const ICANON = 0o000002;
+        /// Echo input characters.
+        const ECHO = 0o000010;
+        /// In canonical mode, ERASE erases the preceding character on screen.
+        const ECHOE = 0o000020;
+        /// In canonical mode, KILL erases the current line on screen.
+        const ECHOK = 0o000040;
+        /// In canonical mode, echo NL even if ECHO is not set.
+        const ECHONL = 0o000100;
+        /// Do not flush the input queue when generating signals.
+        const NOFLSH = 0o000200;
+        /// Echo control characters as `^X`.
+        const ECHOCTL = 0o001000;
+    }
+}
+
+/// Number of control characters.
+pub const NCCS: usize = 19;
+
+// Indices into `Termios::cc`.
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSUSP: usize = 10;
+pub const VWERASE: usize = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Termios {
+    pub iflag: InputFlags,
+    pub oflag: OutputFlags,
+    /// Control modes. Line settings are configured on the UART itself, so this is kept only for
+    /// layout compatibility.
+    pub cflag: u32,
+    pub lflag: LocalFlags,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+/// Control character value which disables the corresponding function.
+pub const VDISABLE: u8 = 0;
+
+const fn ctrl(c: u8) -> u8 {
+    c & 0x1F
+}
+
+impl Termios {
+    /// The usual "cooked" settings: canonical mode with echo, signals and CR/NL translation.
+    pub const fn cooked() -> Self {
+        let mut cc = [VDISABLE; NCCS];
+        cc[VINTR] = ctrl(b'C');
+        cc[VQUIT] = ctrl(b'\\');
+        cc[VERASE] = 0x7F;
+        cc[VKILL] = ctrl(b'U');
+        cc[VEOF] = ctrl(b'D');
+        cc[VTIME] = 0;
+        cc[VMIN] = 1;
+        cc[VSUSP] = ctrl(b'Z');
+        cc[VWERASE] = ctrl(b'W');
+        Self {
+            iflag: InputFlags::ICRNL,
+            oflag: OutputFlags::from_bits_truncate(
+                OutputFlags::OPOST.bits() | OutputFlags::ONLCR.bits(),
+            ),
+            cflag: 0,
+            lflag: LocalFlags::from_bits_truncate(
+                LocalFlags::ISIG.bits()
+                    | LocalFlags::ICANON.bits()
+                    | LocalFlags::ECHO.bits()
+                    | LocalFlags::ECHOE.bits()
+                    | LocalFlags::ECHOK.bits()
+                    | LocalFlags::ECHOCTL.bits(),
+            ),
+            line: 0,
+            cc,
+        }
+    }
+
+    /// Like `cfmakeraw`: no input or output processing, no echo, no signals.
+    #[allow(dead_code)]
+    pub fn make_raw(&mut self) {
+        self.iflag -=
+            InputFlags::ISTRIP | InputFlags::INLCR | InputFlags::IGNCR | InputFlags::ICRNL;
+        self.oflag -= OutputFlags::OPOST;
+        self.lflag -= LocalFlags::ECHO | LocalFlags::ECHONL | LocalFlags::ICANON | LocalFlags::ISIG;
+        self.cc[VMIN] = 1;
+        self.cc[VTIME] = 0;
+    }
+}
+
+impl Default for Termios {
+    fn default() -> Self {
+        Self::cooked()
+    }
+}